@@ -1,7 +1,7 @@
 use bincode::{config, Decode, Encode};
 use std::fmt::{Display, Formatter};
 
-use crate::pow::pow;
+use crate::pow::{hash_block, hash_transactions, pow, validate_hash};
 use crate::tools::{get_timestamp, hash2str};
 use crate::transaction::Transaction;
 
@@ -9,7 +9,7 @@ pub type ByteData = Vec<u8>;
 pub type Hash = [u8; 32];
 pub type TimeStamp = u128;
 
-#[derive(Encode, Decode)]
+#[derive(Clone, Encode, Decode)]
 pub struct Block {
     /// Block created time
     pub timestamp: TimeStamp,
@@ -21,6 +21,34 @@ pub struct Block {
     pub hash: Hash,
     /// Random number to participate in hash calculation
     pub nonce: u64,
+    /// Number of blocks before this block in the chain, it's 0 for genesis block
+    pub height: u64,
+}
+
+/// Header of block, proof of work can be verified without transactions
+#[derive(Clone, Debug, PartialEq, Eq, Encode, Decode)]
+pub struct BlockHeader {
+    pub timestamp: TimeStamp,
+    /// Hash of transaction ids in the block
+    pub tx_hash: Hash,
+    pub prev_block_hash: Option<Hash>,
+    pub hash: Hash,
+    pub nonce: u64,
+    pub height: u64,
+}
+
+impl BlockHeader {
+    /// Check whether the hash is calculated from the header, and meets the proof of work
+    /// requirements
+    pub fn is_valid_pow(&self) -> bool {
+        let hash = hash_block(
+            self.timestamp,
+            &self.tx_hash,
+            &self.prev_block_hash,
+            self.nonce,
+        );
+        hash == self.hash && validate_hash(&hash)
+    }
 }
 
 impl Display for Block {
@@ -35,7 +63,8 @@ impl Display for Block {
         }
         write!(
             f,
-            "timestamp: {}\nprevious block hash: {}\ntransactions: {}\nhash: {}",
+            "height: {}\ntimestamp: {}\nprevious block hash: {}\ntransactions: {}\nhash: {}",
+            self.height,
             self.timestamp,
             prev_hash_str,
             transactions,
@@ -46,8 +75,17 @@ impl Display for Block {
 
 impl Block {
     /// Create a new block
-    pub fn new(transactions: Vec<Transaction>, prev_block_hash: Option<Hash>) -> Self {
-        let timestamp = get_timestamp();
+    pub fn new(transactions: Vec<Transaction>, prev_block_hash: Option<Hash>, height: u64) -> Self {
+        Self::with_timestamp(transactions, prev_block_hash, height, get_timestamp())
+    }
+
+    /// Create a new block created at `timestamp`
+    pub fn with_timestamp(
+        transactions: Vec<Transaction>,
+        prev_block_hash: Option<Hash>,
+        height: u64,
+        timestamp: TimeStamp,
+    ) -> Self {
         let (hash, nonce) = pow(timestamp, &transactions, &prev_block_hash);
         Self {
             timestamp,
@@ -55,12 +93,31 @@ impl Block {
             prev_block_hash,
             hash,
             nonce,
+            height,
         }
     }
 
     /// Create a genesis block
     pub fn new_genesis_block(coinbase: Transaction) -> Self {
-        Self::new(vec![coinbase], None)
+        Self::new(vec![coinbase], None, 0)
+    }
+
+    /// Header of the block
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            timestamp: self.timestamp,
+            tx_hash: hash_transactions(&self.transactions),
+            prev_block_hash: self.prev_block_hash,
+            hash: self.hash,
+            nonce: self.nonce,
+            height: self.height,
+        }
+    }
+
+    /// Check whether the hash of block is calculated from it's content, and meets the proof of
+    /// work requirements
+    pub fn is_valid_pow(&self) -> bool {
+        self.header().is_valid_pow()
     }
 
     /// Serialize block to bytes
@@ -80,4 +137,16 @@ impl Block {
 #[cfg(test)]
 mod block_test {
     use super::*;
+    use crate::wallet::{derive_key_pair, Wallet};
+
+    #[test]
+    fn encode_and_decode_block() {
+        let address = Wallet::new(derive_key_pair(&[0u8; 16], &[0]).as_slice()).get_address();
+        let block = Block::new_genesis_block(Transaction::new_coinbase_tx(&address, None, 0, 0));
+        let decoded = Block::decode(block.encode());
+        assert_eq!(decoded.hash, block.hash);
+        assert_eq!(decoded.nonce, block.nonce);
+        assert_eq!(decoded.transactions[0].id, block.transactions[0].id);
+        assert!(decoded.is_valid_pow());
+    }
 }
@@ -1,14 +1,73 @@
 use std::collections::hash_map::Entry::{Occupied, Vacant};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{Display, Formatter};
 use std::path::Path;
 use std::rc::Rc;
 
+use bincode::config;
 use rusty_leveldb::{Options, DB};
 
-use crate::block::{Block, Hash};
-use crate::tools::hash2str;
-use crate::transaction::{hash_transaction, TXInput, TXOutput, Transaction, UTXO};
-use crate::wallet::{extract_pub_key_hash, Wallets};
+use crate::block::{Block, ByteData, Hash, TimeStamp};
+use crate::block_filter::{filter_header, BlockFilter};
+use crate::coin_selection::{Coin, CoinSelection};
+use crate::mempool::{Mempool, MAX_MEMPOOL_SIZE, MIN_RELAY_FEE_RATE};
+use crate::merkle::MerkleProof;
+use crate::partial_tx::PartialTx;
+use crate::script::{Script, MAX_DATA_CARRIER_LEN};
+use crate::spv::TxProof;
+use crate::tools::{get_timestamp, hash2str};
+use crate::transaction::{
+    asset_id, mint_authority, Htlc, Issuance, TXInput, TXOutput, Transaction, LOCK_TIME_THRESHOLD,
+    SUBSIDY, UTXO,
+};
+use crate::wallet::{address_script, hash_pub_key, pub_key_hash_address, Wallets};
+
+/// Information of an issued asset
+pub struct AssetInfo {
+    pub name: String,
+    /// Total issued and minted value
+    pub supply: u64,
+    pub mintable: bool,
+}
+
+/// Options of building a transaction
+#[derive(Default)]
+pub struct TxOptions {
+    /// Strategy of selecting unspent outputs
+    pub strategy: CoinSelection,
+    /// The transaction can't be mined before this block height, or timestamp if it's not less
+    /// than `LOCK_TIME_THRESHOLD`
+    pub lock_time: u64,
+    /// Each input can't be mined until it's spent output is confirmed for this number of blocks
+    pub relative_lock: u32,
+    /// Fee paid to miner
+    pub fee: u64,
+}
+
+/// Why a transaction is not accepted
+#[derive(Debug, PartialEq, Eq)]
+pub enum TxError {
+    /// The transaction breaks consensus rules, e.g. it has invalid id, script, signature or
+    /// value, it can never be mined
+    Invalid(String),
+    /// The transaction can't be accepted now, e.g. it's not final, it spends unknown outputs, or
+    /// it doesn't pay enough fee to replace pending transactions
+    Rejected(String),
+}
+
+impl Display for TxError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TxError::Invalid(err) | TxError::Rejected(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<TxError> for String {
+    fn from(err: TxError) -> Self {
+        err.to_string()
+    }
+}
 
 pub struct BlockChain {
     /// The hash value of latest block
@@ -19,7 +78,14 @@ pub struct BlockChain {
 
 const DB_FILE: &str = "blockchain";
 const LATEST_HASH: &str = "l";
+const MEMPOOL: &str = "m";
+/// Prefix of keys of block filters, it's followed by the block hash
+const FILTER_PREFIX: &str = "f";
 const GENESIS_COINBASE_DATA: &str = "";
+/// A block must be later than the median time of this number of blocks before it
+const MEDIAN_TIME_BLOCKS: usize = 11;
+/// Max time a block can be ahead of local time, in milliseconds
+const MAX_FUTURE_BLOCK_TIME: TimeStamp = 2 * 60 * 60 * 1000;
 
 impl BlockChain {
     pub fn create(address: String) -> Self {
@@ -33,6 +99,8 @@ impl BlockChain {
         let coinbase = Transaction::new_coinbase_tx(
             address.as_str(),
             Some(String::from(GENESIS_COINBASE_DATA)),
+            0,
+            0,
         );
         let genesis = Block::new_genesis_block(coinbase);
         println!("Create genesis block success: {}", genesis);
@@ -40,7 +108,39 @@ impl BlockChain {
         db.put_block(&genesis);
         db.put_hash(LATEST_HASH, &genesis.hash);
         let tip = genesis.hash;
-        Self { db, tip }
+        let mut block_chain = Self { db, tip };
+        block_chain.get_filter(&tip);
+        block_chain
+    }
+
+    /// Create blockchain database with genesis block received from other nodes
+    pub fn create_with_genesis(genesis: &Block) -> Result<Self, String> {
+        if Path::new(DB_FILE).exists() {
+            return Err(format!("Blockchain database {} already exists", DB_FILE));
+        }
+        Self::open_with_genesis(Options::default(), genesis)
+    }
+
+    /// Create blockchain with genesis block in memory, nothing is written to disk. It's used by
+    /// simulated nodes
+    #[cfg(test)]
+    pub fn create_in_memory(genesis: &Block) -> Result<Self, String> {
+        Self::open_with_genesis(rusty_leveldb::in_memory(), genesis)
+    }
+
+    fn open_with_genesis(opt: Options, genesis: &Block) -> Result<Self, String> {
+        if genesis.prev_block_hash.is_some() || genesis.height != 0 || !genesis.is_valid_pow() {
+            return Err(format!("Invalid genesis block {}", hash2str(&genesis.hash)));
+        }
+        let mut db = DB::open(DB_FILE, opt).unwrap();
+        db.put_block(genesis);
+        db.put_hash(LATEST_HASH, &genesis.hash);
+        let mut block_chain = Self {
+            db,
+            tip: genesis.hash,
+        };
+        block_chain.get_filter(&genesis.hash);
+        Ok(block_chain)
     }
 
     pub fn get() -> Option<Self> {
@@ -53,21 +153,607 @@ impl BlockChain {
         db.get_hash(LATEST_HASH).map(|tip| Self { db, tip })
     }
 
-    /// Add a new block to the chain
-    pub fn mine_block(&mut self, transactions: Vec<Transaction>) {
-        println!("Add new block, mining...");
+    /// Add a new block to the chain, transactions are verified before mining
+    pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<(), String> {
         // Get hash value of latest block
         let last_hash = self
             .db
             .get_hash(LATEST_HASH)
             .expect("Add block failed, there were no blocks");
-        // Create and save block
-        let new_block = Block::new(transactions, Some(last_hash));
+        let height = self.tip_height() + 1;
+        self.verify_transactions(&transactions, height, get_timestamp())?;
+        println!("Add new block, mining...");
+        // Create and save block, it must be later than the past blocks even if local time isn't
+        let timestamp = get_timestamp().max(self.median_time_past(&last_hash) + 1);
+        let new_block = Block::with_timestamp(transactions, Some(last_hash), height, timestamp);
         println!("Add block success:\n{}", new_block);
         self.db.put_block(&new_block);
+        self.get_filter(&new_block.hash);
         // Update latest hash value for blockchain and database
         self.tip = new_block.hash;
         self.db.put_hash(LATEST_HASH, &new_block.hash);
+        // Mined transactions are not pending anymore
+        let mut mempool = self.get_mempool();
+        mempool.remove_mined(&new_block.transactions);
+        self.put_mempool(&mempool);
+        Ok(())
+    }
+
+    /// Add a block received from other nodes, it's verified on top of it's parent which may not
+    /// be the latest block. The chain switches to the branch of the block once it becomes the
+    /// highest one. Returns whether the latest block is changed
+    pub fn add_block(&mut self, block: &Block) -> Result<bool, String> {
+        if self.has_block(&block.hash) {
+            return Ok(false);
+        }
+        if !block.is_valid_pow() {
+            return Err(format!(
+                "Block {} has invalid proof of work",
+                hash2str(&block.hash)
+            ));
+        }
+        let parent = match block
+            .prev_block_hash
+            .and_then(|hash| self.db.get_block(&hash))
+        {
+            Some(parent) => parent,
+            None => {
+                return Err(format!(
+                    "Parent of block {} is unknown",
+                    hash2str(&block.hash)
+                ))
+            }
+        };
+        if block.height != parent.height + 1 {
+            return Err(format!(
+                "Block {} has invalid height",
+                hash2str(&block.hash)
+            ));
+        }
+        if block.timestamp <= self.median_time_past(&parent.hash) {
+            return Err(format!(
+                "Block {} is not later than the median time of past blocks",
+                hash2str(&block.hash)
+            ));
+        }
+        if block.timestamp > get_timestamp() + MAX_FUTURE_BLOCK_TIME {
+            return Err(format!(
+                "Block {} is too far in the future",
+                hash2str(&block.hash)
+            ));
+        }
+        // Unspent outputs are collected from the latest block, so verify the block on top of it's
+        // parent for a while
+        let tip = self.tip;
+        self.tip = parent.hash;
+        let verified = self.verify_transactions(&block.transactions, block.height, block.timestamp);
+        self.tip = tip;
+        verified?;
+
+        self.db.put_block(block);
+        self.get_filter(&block.hash);
+        if block.height <= self.tip_height() {
+            return Ok(false);
+        }
+        self.switch_tip(block);
+        Ok(true)
+    }
+
+    /// Median timestamp of block `hash` and the blocks before it, at most `MEDIAN_TIME_BLOCKS`
+    /// blocks are counted
+    fn median_time_past(&mut self, hash: &Hash) -> TimeStamp {
+        let mut timestamps = vec![];
+        let mut next = Some(*hash);
+        while let Some(block) = next.and_then(|hash| self.db.get_block(&hash)) {
+            timestamps.push(block.timestamp);
+            if timestamps.len() >= MEDIAN_TIME_BLOCKS {
+                break;
+            }
+            next = block.prev_block_hash;
+        }
+        timestamps.sort();
+        timestamps.get(timestamps.len() / 2).copied().unwrap_or(0)
+    }
+
+    /// Make `block` the latest block, transactions of blocks which are not in the chain anymore
+    /// are added back to mempool
+    fn switch_tip(&mut self, block: &Block) {
+        let main_chain: HashSet<Hash> = self.main_chain_hashes().into_iter().collect();
+        // Find the fork point, and the blocks which are connected
+        let mut connected = vec![];
+        let mut fork = block.prev_block_hash;
+        while let Some(hash) = fork.filter(|hash| !main_chain.contains(hash)) {
+            let b = self
+                .db
+                .get_block(&hash)
+                .expect("Can not find block of branch");
+            fork = b.prev_block_hash;
+            connected.push(b);
+        }
+        // Blocks after the fork point are disconnected
+        let mut disconnected = vec![];
+        for b in BlockChainIter::new(self) {
+            if Some(b.hash) == fork {
+                break;
+            }
+            disconnected.push(b);
+        }
+
+        self.tip = block.hash;
+        self.db.put_hash(LATEST_HASH, &block.hash);
+        let mut mempool = self.get_mempool();
+        if disconnected.is_empty() {
+            mempool.remove_mined(&block.transactions);
+            self.put_mempool(&mempool);
+            return;
+        }
+        println!(
+            "Reorganize chain, {} blocks are disconnected",
+            disconnected.len()
+        );
+        let mined: HashSet<Hash> = connected
+            .iter()
+            .chain([block])
+            .flat_map(|b| b.transactions.iter().map(|tx| tx.id))
+            .collect();
+        // Transactions of disconnected blocks come before the pending ones which may spend them
+        let candidates: Vec<Transaction> = disconnected
+            .into_iter()
+            .rev()
+            .flat_map(|b| b.transactions.into_iter())
+            .filter(|tx| !tx.is_coinbase_tx())
+            .chain(mempool.txs().iter().cloned())
+            .filter(|tx| !mined.contains(&tx.id))
+            .collect();
+        let height = block.height + 1;
+        let mut pending: Vec<Transaction> = vec![];
+        let mut mempool = Mempool::default();
+        for tx in candidates {
+            pending.push(tx.clone());
+            match self.verify_transactions(&pending, height, get_timestamp()) {
+                Ok(fees) => mempool.add(tx, fees.last().copied().unwrap_or(0)),
+                Err(_) => {
+                    pending.pop();
+                }
+            }
+        }
+        mempool.trim(MAX_MEMPOOL_SIZE);
+        self.put_mempool(&mempool);
+    }
+
+    /// Check whether block `hash` is saved, it may not be in the chain of the latest block
+    pub fn has_block(&mut self, hash: &Hash) -> bool {
+        self.db.get(hash.as_slice()).is_some()
+    }
+
+    /// Hashes of blocks in the chain, from genesis block to the latest block
+    pub fn main_chain_hashes(&mut self) -> Vec<Hash> {
+        let mut hashes: Vec<Hash> = BlockChainIter::new(self).map(|b| b.hash).collect();
+        hashes.reverse();
+        hashes
+    }
+
+    /// Transactions in the main chain which pay to `scripts` or spend outputs paid to them, with
+    /// Merkle proofs that they are in their blocks. They are in order of height
+    pub fn find_tx_proofs(&mut self, scripts: &HashSet<Script>) -> Vec<TxProof> {
+        let mut proofs = vec![];
+        // Outputs paid to the scripts
+        let mut outputs: HashSet<(Hash, usize)> = HashSet::new();
+        for hash in self.main_chain_hashes() {
+            let block = match self.get_block(Some(&hash)) {
+                Some(block) => block,
+                None => continue,
+            };
+            let ids: Vec<Hash> = block.transactions.iter().map(|tx| tx.id).collect();
+            for (index, tx) in block.transactions.iter().enumerate() {
+                let spends = tx.v_in.iter().any(|input| {
+                    input
+                        .tx_id
+                        .zip(input.v_out_idx)
+                        .is_some_and(|outpoint| outputs.contains(&outpoint))
+                });
+                let mut pays = false;
+                for (out_idx, out) in tx.v_out.iter().enumerate() {
+                    if scripts.contains(&out.script_pub_key) {
+                        outputs.insert((tx.id, out_idx));
+                        pays = true;
+                    }
+                }
+                if spends || pays {
+                    proofs.push(TxProof {
+                        block_hash: hash,
+                        tx: tx.clone(),
+                        proof: MerkleProof::new(&ids, index),
+                    });
+                }
+            }
+        }
+        proofs
+    }
+
+    /// Filter of block `hash` and it's filter header, returns `None` if the block is unknown.
+    /// Filters are saved alongside blocks, filters of blocks saved before are built on demand
+    pub fn get_filter(&mut self, hash: &Hash) -> Option<(BlockFilter, Hash)> {
+        if let Some(filter) = self.db.get_filter(hash) {
+            return Some(filter);
+        }
+        // Find ancestors without filter, filter headers are built from the oldest one
+        let mut blocks = vec![];
+        let mut prev_header = [0u8; 32];
+        let mut cur = Some(*hash);
+        while let Some(hash) = cur {
+            if let Some((_, header)) = self.db.get_filter(&hash) {
+                prev_header = header;
+                break;
+            }
+            let block = self.db.get_block(&hash)?;
+            cur = block.prev_block_hash;
+            blocks.push(block);
+        }
+        let mut result = None;
+        for block in blocks.iter().rev() {
+            let filter = BlockFilter::of_block(block);
+            prev_header = filter_header(&filter.hash(), &prev_header);
+            self.db.put_filter(&block.hash, &filter, &prev_header);
+            result = Some((filter, prev_header));
+        }
+        result
+    }
+
+    /// Hashes of some blocks from the latest block back to genesis block, they are dense at first
+    /// and sparse later, so that other nodes can find the fork point with few hashes
+    pub fn block_locator(&mut self) -> Vec<Hash> {
+        build_locator(&self.main_chain_hashes())
+    }
+
+    /// Mine a block with all of the pending transactions in mempool, a coinbase transaction which
+    /// collects the reward and fees is added if `reward_to` is provided
+    pub fn mine_pending(&mut self, reward_to: Option<&str>) -> Result<(), String> {
+        let mut transactions = vec![];
+        let pending = self.get_mempool().txs().to_vec();
+        if let Some(address) = reward_to {
+            address_script(address)?;
+            let height = self.tip_height() + 1;
+            let fees = self.verify_transactions(&pending, height, get_timestamp())?;
+            let fees = checked_sum(fees).ok_or("Fees of pending transactions overflow")?;
+            transactions.push(Transaction::new_coinbase_tx(address, None, height, fees));
+        }
+        transactions.extend(pending);
+        if transactions.is_empty() {
+            return Err(String::from("There are no pending transactions"));
+        }
+        self.mine_block(transactions)
+    }
+
+    /// Verify transaction and add it to mempool, it must be able to be mined in the next block.
+    /// A transaction spending the same outputs as pending transactions replaces them and their
+    /// descendants, if it pays higher fee than all of them and higher fee rate than each of the
+    /// conflicting ones. Transactions of the lowest fee rate are evicted when mempool is full
+    pub fn submit_tx(&mut self, tx: Transaction) -> Result<(), TxError> {
+        self.accept_tx(tx, 0)
+    }
+
+    /// Submit a transaction relayed by peers, it must pay at least the minimum relay fee
+    pub fn submit_relayed_tx(&mut self, tx: Transaction) -> Result<(), TxError> {
+        self.accept_tx(tx, MIN_RELAY_FEE_RATE)
+    }
+
+    /// Add transaction to mempool if it pays at least `min_fee_rate` per 1000 bytes
+    fn accept_tx(&mut self, tx: Transaction, min_fee_rate: u64) -> Result<(), TxError> {
+        let mut mempool = self.get_mempool();
+        if mempool.contains(&tx.id) {
+            return Err(TxError::Rejected(format!(
+                "Transaction {} is already pending",
+                hash2str(&tx.id)
+            )));
+        }
+        if tx.is_coinbase_tx() {
+            return Err(TxError::Invalid(String::from(
+                "Coinbase transaction can only be mined in block",
+            )));
+        }
+        let height = self.tip_height() + 1;
+        let timestamp = get_timestamp();
+        let conflicts = mempool.conflicts(std::slice::from_ref(&tx));
+        let evicted = mempool.with_descendants(&conflicts);
+        // Verify with the remaining pending transactions, so that the transaction can spend
+        // outputs of them, but can't spend the same outputs
+        let mut transactions: Vec<Transaction> = mempool
+            .txs()
+            .iter()
+            .filter(|pending| !evicted.contains(&pending.id))
+            .cloned()
+            .collect();
+        transactions.push(tx.clone());
+        let fees = self.verify_transactions(&transactions, height, timestamp)?;
+        let fee = fees.last().copied().unwrap_or(0);
+
+        let size = tx.encode().len();
+        let min_fee = (size as u64 * min_fee_rate).div_ceil(1000);
+        if fee < min_fee {
+            return Err(TxError::Rejected(format!(
+                "Fee {} is lower than the minimum relay fee {}",
+                fee, min_fee
+            )));
+        }
+        // Fees of pending transactions which will be replaced
+        let replaced_fees: Vec<(&Transaction, u64)> = mempool
+            .txs()
+            .iter()
+            .filter(|pending| evicted.contains(&pending.id))
+            .map(|pending| (pending, mempool.fee(&pending.id).unwrap_or(0)))
+            .collect();
+        let total_replaced =
+            checked_sum(replaced_fees.iter().map(|(_, fee)| *fee)).ok_or_else(|| {
+                TxError::Rejected(String::from("Fees of replaced transactions overflow"))
+            })?;
+        if !evicted.is_empty() && fee <= total_replaced {
+            return Err(TxError::Rejected(format!(
+                "Replacement fee {} must be higher than {} of replaced transactions",
+                fee, total_replaced
+            )));
+        }
+        for (pending, pending_fee) in replaced_fees.iter() {
+            if conflicts.contains(&pending.id)
+                && !is_higher_fee_rate(fee, &tx, *pending_fee, pending)
+            {
+                return Err(TxError::Rejected(format!(
+                    "Replacement fee rate must be higher than transaction {}",
+                    hash2str(&pending.id)
+                )));
+            }
+        }
+        if !evicted.is_empty() {
+            println!("Replace {} pending transactions", evicted.len());
+        }
+        mempool.remove(&evicted);
+        let tx_id = tx.id;
+        mempool.add(tx, fee);
+        let trimmed = mempool.trim(MAX_MEMPOOL_SIZE);
+        if trimmed.contains(&tx_id) {
+            return Err(TxError::Rejected(format!(
+                "Mempool is full, fee rate of transaction {} is too low",
+                hash2str(&tx_id)
+            )));
+        }
+        if !trimmed.is_empty() {
+            println!(
+                "Evict {} pending transactions of low fee rate",
+                trimmed.len()
+            );
+        }
+        self.put_mempool(&mempool);
+        Ok(())
+    }
+
+    /// Get transactions which are waiting to be mined
+    pub fn get_mempool(&mut self) -> Mempool {
+        match self.db.get(MEMPOOL.as_bytes()) {
+            Some(data) => Mempool::decode(data),
+            None => Mempool::default(),
+        }
+    }
+
+    /// Save pending transactions
+    fn put_mempool(&mut self, mempool: &Mempool) {
+        self.db
+            .put(MEMPOOL.as_bytes(), mempool.encode().as_slice())
+            .expect("Can not save mempool to database");
+        self.db.flush().expect("Can not flush database");
+    }
+
+    /// Height of the latest block
+    pub fn tip_height(&mut self) -> u64 {
+        let tip = self.tip;
+        self.db
+            .get_block(&tip)
+            .map(|block| block.height)
+            .expect("Can not find the latest block")
+    }
+
+    /// Verify transactions which will be packed into a new block of `height` created at
+    /// `timestamp`, every input must unlock an unspent output, and the value of inputs must cover
+    /// the value of outputs. A transaction may spend outputs of preceding transactions in the same
+    /// block. Time locked transactions and inputs must be unlocked at the block. Returns the fee
+    /// of each transaction
+    pub fn verify_transactions(
+        &mut self,
+        transactions: &[Transaction],
+        height: u64,
+        timestamp: TimeStamp,
+    ) -> Result<Vec<u64>, TxError> {
+        // Unspent outputs, key is transaction id and output index
+        let mut utxo: HashMap<(Hash, usize), Rc<TXOutput>> = HashMap::new();
+        for (tx_id, outs) in self.find_all_utxo() {
+            for (out, idx) in outs {
+                utxo.insert((tx_id, idx), out);
+            }
+        }
+        // Confirmed heights of transactions which are referenced by relative locked inputs
+        let locked_tx_ids: HashSet<Hash> = transactions
+            .iter()
+            .flat_map(|tx| tx.v_in.iter())
+            .filter(|input| input.sequence > 0)
+            .filter_map(|input| input.tx_id)
+            .collect();
+        let confirmed_heights = self.find_tx_heights(&locked_tx_ids);
+
+        let mut fees = vec![];
+        for (i, tx) in transactions.iter().enumerate() {
+            if !tx.has_valid_id() {
+                return Err(TxError::Invalid(format!(
+                    "Transaction {} has invalid id",
+                    hash2str(&tx.id)
+                )));
+            }
+            if tx.is_coinbase_tx() {
+                // Coinbase transaction can only be the first one
+                let has_asset =
+                    tx.issuance.is_some() || tx.v_out.iter().any(|out| out.asset.is_some());
+                if i != 0 || has_asset {
+                    return Err(TxError::Invalid(format!(
+                        "Invalid coinbase transaction {}",
+                        hash2str(&tx.id)
+                    )));
+                }
+            }
+            if !tx.is_final(height, timestamp) {
+                return Err(TxError::Rejected(format!(
+                    "Transaction {} is locked until {} {}",
+                    hash2str(&tx.id),
+                    if tx.lock_time < LOCK_TIME_THRESHOLD {
+                        "height"
+                    } else {
+                        "timestamp"
+                    },
+                    tx.lock_time
+                )));
+            }
+            if !tx.is_coinbase_tx() {
+                if tx.v_in.is_empty() {
+                    return Err(TxError::Invalid(format!(
+                        "Transaction {} doesn't have inputs",
+                        hash2str(&tx.id)
+                    )));
+                }
+                // Every input must refer to an output, the output point isn't guessed
+                let out_points: Option<Vec<(Hash, usize)>> = tx
+                    .v_in
+                    .iter()
+                    .map(|input| input.tx_id.zip(input.v_out_idx))
+                    .collect();
+                let out_points = match out_points {
+                    Some(out_points) => out_points,
+                    None => {
+                        return Err(TxError::Invalid(format!(
+                            "Input of transaction {} doesn't refer to an output",
+                            hash2str(&tx.id)
+                        )))
+                    }
+                };
+                // Relative locked inputs can only spend outputs which are confirmed enough blocks
+                for input in tx.v_in.iter().filter(|input| input.sequence > 0) {
+                    let confirmed = input.tx_id.and_then(|tx_id| confirmed_heights.get(&tx_id));
+                    match confirmed {
+                        Some(h) if h + input.sequence as u64 <= height => {}
+                        _ => {
+                            return Err(TxError::Rejected(format!(
+                                "Input of transaction {} is locked for {} blocks after it's output is confirmed",
+                                hash2str(&tx.id),
+                                input.sequence
+                            )))
+                        }
+                    }
+                }
+
+                // Collect referenced outputs, and mark them as spent
+                let mut prev_outputs = vec![];
+                for out_point in out_points {
+                    match utxo.remove(&out_point) {
+                        Some(out) => prev_outputs.push(out),
+                        None => {
+                            return Err(TxError::Rejected(format!(
+                                "Transaction {} spends an unknown or spent output",
+                                hash2str(&tx.id)
+                            )))
+                        }
+                    }
+                }
+                fees.push(verify_asset_values(tx, &prev_outputs).map_err(TxError::Invalid)?);
+                tx.verify(&prev_outputs).map_err(TxError::Invalid)?;
+            } else {
+                fees.push(0);
+            }
+            for (idx, out) in tx.v_out.iter().enumerate() {
+                if !out.script_pub_key.is_unspendable() {
+                    utxo.insert((tx.id, idx), Rc::clone(out));
+                    continue;
+                }
+                // Unspendable outputs can only be data carriers, so that the size limit of data
+                // can't be bypassed by other unspendable scripts
+                match out.script_pub_key.carried_data() {
+                    Some(data) if data.len() <= MAX_DATA_CARRIER_LEN => {}
+                    _ => {
+                        return Err(TxError::Invalid(format!(
+                            "Transaction {} has an unspendable output which isn't a data carrier of at most {} bytes",
+                            hash2str(&tx.id),
+                            MAX_DATA_CARRIER_LEN
+                        )))
+                    }
+                }
+            }
+        }
+        // Coinbase transaction can't create more value than the reward and fees
+        if let Some(coinbase) = transactions.first().filter(|tx| tx.is_coinbase_tx()) {
+            let output_value = checked_sum(coinbase.v_out.iter().map(|out| out.value));
+            let max_value = checked_sum(fees.iter().copied().chain([SUBSIDY]));
+            let is_valid = match (output_value, max_value) {
+                (Some(output_value), Some(max_value)) => output_value <= max_value,
+                _ => false,
+            };
+            if !is_valid {
+                return Err(TxError::Invalid(format!(
+                    "Invalid coinbase transaction {}",
+                    hash2str(&coinbase.id)
+                )));
+            }
+        }
+        Ok(fees)
+    }
+
+    /// Get block by it's hash, or the latest block if `hash` is `None`
+    pub fn get_block(&mut self, hash: Option<&Hash>) -> Option<Block> {
+        let hash = *hash.unwrap_or(&self.tip);
+        self.db.get_block(&hash)
+    }
+
+    /// Find transaction `tx_id` in the chain, returns it with height of the block containing it
+    pub fn find_transaction(&mut self, tx_id: &Hash) -> Option<(Transaction, u64)> {
+        for b in BlockChainIter::new(self) {
+            let height = b.height;
+            if let Some(tx) = b.transactions.into_iter().find(|tx| tx.id == *tx_id) {
+                return Some((tx, height));
+            }
+        }
+        None
+    }
+
+    /// Find input in the chain which spends output `out_idx` of transaction `tx_id`, returns it
+    /// with the spending transaction
+    pub fn find_spending_input(
+        &mut self,
+        tx_id: &Hash,
+        out_idx: usize,
+    ) -> Option<(Transaction, Rc<TXInput>)> {
+        for b in BlockChainIter::new(self) {
+            for tx in b.transactions {
+                let input = tx
+                    .v_in
+                    .iter()
+                    .find(|input| input.tx_id == Some(*tx_id) && input.v_out_idx == Some(out_idx))
+                    .cloned();
+                if let Some(input) = input {
+                    return Some((tx, input));
+                }
+            }
+        }
+        None
+    }
+
+    /// Find heights of blocks which contain transactions `tx_ids`
+    fn find_tx_heights(&mut self, tx_ids: &HashSet<Hash>) -> HashMap<Hash, u64> {
+        let mut heights = HashMap::new();
+        if tx_ids.is_empty() {
+            return heights;
+        }
+        for b in BlockChainIter::new(self) {
+            for tx in b.transactions.iter() {
+                if tx_ids.contains(&tx.id) {
+                    heights.insert(tx.id, b.height);
+                }
+            }
+        }
+        heights
     }
 
     /// Print all of the blocks of the chain
@@ -78,8 +764,18 @@ impl BlockChain {
         }
     }
 
-    /// Find unspent transaction outputs for specific address
-    pub fn find_utxo(&mut self, pub_key_hash: &[u8]) -> UTXO {
+    /// Find unspent transaction outputs which are locked with `script`
+    pub fn find_utxo(&mut self, script: &Script) -> UTXO {
+        self.collect_utxo(|txo| txo.script_pub_key == *script)
+    }
+
+    /// Find all of the unspent transaction outputs
+    pub fn find_all_utxo(&mut self) -> UTXO {
+        self.collect_utxo(|_| true)
+    }
+
+    /// Find unspent transaction outputs which are accepted by `filter`
+    fn collect_utxo(&mut self, filter: impl Fn(&TXOutput) -> bool) -> UTXO {
         // Unspent transaction outputs
         let mut utxo: UTXO = HashMap::new();
         // Spent transaction outputs, key is transaction id, value is a set of spent output index
@@ -95,11 +791,9 @@ impl BlockChain {
                         Some(idx_set) => idx_set.contains(&i),
                         None => false,
                     };
-                    // Whether the output can be unlocked by your address
-                    let can_unlock = txo.is_locking_with_key(pub_key_hash);
-
-                    if !is_spent && can_unlock {
-                        // Collect your unspent outputs
+                    // Unspendable outputs are never collected
+                    if !is_spent && !txo.script_pub_key.is_unspendable() && filter(txo) {
+                        // Collect unspent outputs
                         let output = (Rc::clone(txo), i);
                         match utxo.entry(tx.id) {
                             Occupied(o) => {
@@ -118,124 +812,761 @@ impl BlockChain {
 
                 // Iterate each input, collect spent outputs
                 for txi in tx.v_in {
-                    let can_unlock = txi.use_key(pub_key_hash);
-                    if can_unlock {
-                        // Collect your spent outputs
-                        let out_idx = txi.v_out_idx.unwrap();
-                        match stxo.entry(txi.tx_id.unwrap()) {
-                            Occupied(o) => {
-                                o.into_mut().insert(out_idx);
-                            }
-                            Vacant(v) => {
-                                v.insert(HashSet::from([out_idx]));
-                            }
-                        };
-                    }
+                    let out_idx = txi.v_out_idx.unwrap();
+                    match stxo.entry(txi.tx_id.unwrap()) {
+                        Occupied(o) => {
+                            o.into_mut().insert(out_idx);
+                        }
+                        Vacant(v) => {
+                            v.insert(HashSet::from([out_idx]));
+                        }
+                    };
                 }
             }
         }
         utxo
     }
 
+    /// Collect public key hashes which have been used to lock any output in the chain
+    pub fn used_key_hashes(&mut self) -> HashSet<ByteData> {
+        let mut key_hashes = HashSet::new();
+        for b in BlockChainIter::new(self) {
+            for tx in b.transactions {
+                for txo in tx.v_out {
+                    if let Some(pub_key_hash) = txo.pub_key_hash() {
+                        key_hashes.insert(Vec::from(pub_key_hash));
+                    }
+                }
+            }
+        }
+        key_hashes
+    }
+
+    /// Find transactions which spend or receive outputs locked with any of `scripts`, the latest
+    /// transaction comes first
+    pub fn find_history(&mut self, scripts: &HashSet<Script>) -> Vec<Transaction> {
+        // Transactions are iterated from the latest one, so outputs are collected at first, then
+        // the transactions which spend them can be found
+        let mut txs = vec![];
+        let mut received: HashSet<(Hash, usize)> = HashSet::new();
+        for b in BlockChainIter::new(self) {
+            for tx in b.transactions {
+                for (i, out) in tx.v_out.iter().enumerate() {
+                    if scripts.contains(&out.script_pub_key) {
+                        received.insert((tx.id, i));
+                    }
+                }
+                txs.push(tx);
+            }
+        }
+
+        txs.into_iter()
+            .filter(|tx| {
+                let is_recv = tx
+                    .v_out
+                    .iter()
+                    .any(|out| scripts.contains(&out.script_pub_key));
+                let is_spent = !tx.is_coinbase_tx()
+                    && tx.v_in.iter().any(|input| {
+                        received.contains(&(input.tx_id.unwrap(), input.v_out_idx.unwrap()))
+                    });
+                is_recv || is_spent
+            })
+            .collect()
+    }
+
     /// New transaction, send `amount` of value from `from` to `to`
-    pub fn new_tx(&mut self, from: &str, to: &str, amount: u64) -> Result<Transaction, String> {
-        // Find minimum set of unspent outputs to transfer amount value
-        let (utxo, valid_amount) = self.find_spendable_outputs(from, amount);
-        if valid_amount < amount {
+    pub fn new_tx(
+        &mut self,
+        from: &str,
+        to: &str,
+        amount: u64,
+        options: &TxOptions,
+    ) -> Result<Transaction, String> {
+        let output = TXOutput {
+            value: amount,
+            script_pub_key: address_script(to)?,
+            asset: None,
+        };
+        self.new_signed_tx(from, vec![output], None, options)
+    }
+
+    /// New transaction which carries `data` in an unspendable output, it's paid by `from`
+    pub fn new_data_tx(
+        &mut self,
+        from: &str,
+        data: &[u8],
+        options: &TxOptions,
+    ) -> Result<Transaction, String> {
+        if data.is_empty() || data.len() > MAX_DATA_CARRIER_LEN {
             return Err(format!(
-                "Cannot transfer {} from {} to {}, not enough funds",
-                amount, from, to
+                "Data must be 1 to {} bytes, got {} bytes",
+                MAX_DATA_CARRIER_LEN,
+                data.len()
             ));
         }
-        let mut inputs: Vec<Rc<TXInput>> = Vec::new();
+        let output = TXOutput {
+            value: 0,
+            script_pub_key: Script::data_carrier(data),
+            asset: None,
+        };
+        self.new_signed_tx(from, vec![output], None, options)
+    }
 
-        // Create inputs
+    /// New transaction which locks `amount` of value from `from` in hash time locked contract
+    /// `htlc`
+    pub fn new_htlc_tx(
+        &mut self,
+        from: &str,
+        htlc: &Htlc,
+        amount: u64,
+        options: &TxOptions,
+    ) -> Result<Transaction, String> {
+        let output = TXOutput {
+            value: amount,
+            script_pub_key: htlc.script(),
+            asset: None,
+        };
+        self.new_signed_tx(from, vec![output], None, options)
+    }
+
+    /// New transaction which locks `amount` of value from `from` in a pay to script hash output
+    /// of `redeem_script`, it funds a payment channel
+    pub fn new_funding_tx(
+        &mut self,
+        from: &str,
+        redeem_script: &Script,
+        amount: u64,
+        options: &TxOptions,
+    ) -> Result<Transaction, String> {
+        let output = TXOutput {
+            value: amount,
+            script_pub_key: Script::p2sh(&redeem_script.hash()),
+            asset: None,
+        };
+        self.new_signed_tx(from, vec![output], None, options)
+    }
+
+    /// New transaction which spends the contract output of transaction `contract_id` to `to`, it
+    /// claims the contract if `secret` is provided, otherwise refunds it. The spending key must be
+    /// in wallets
+    pub fn spend_htlc_tx(
+        &mut self,
+        contract_id: &Hash,
+        secret: Option<&[u8]>,
+        to: Option<&str>,
+    ) -> Result<Transaction, String> {
+        let (contract_tx, _) = match self.find_transaction(contract_id) {
+            Some(found) => found,
+            None => {
+                return Err(format!(
+                    "Can not find transaction {}",
+                    hash2str(contract_id)
+                ))
+            }
+        };
+        let (out_idx, out, htlc) = match find_htlc(&contract_tx) {
+            Some(found) => found,
+            None => return Err(String::from("Transaction doesn't have contract output")),
+        };
+        // Claim to recipient, or refund to the sender of contract
+        let (owner, lock_time) = match secret {
+            Some(_) => (pub_key_hash_address(&htlc.recipient), 0),
+            None => (pub_key_hash_address(&htlc.refund), htlc.lock_time),
+        };
+        let wallet = match Wallets::new().get_wallet(&owner) {
+            Some(w) => w,
+            None => return Err(format!("Can not get wallet for address {}", owner)),
+        };
+        let input = TXInput {
+            tx_id: Some(*contract_id),
+            v_out_idx: Some(out_idx),
+            script_sig: Script::default(),
+            sequence: 0,
+        };
+        let output = TXOutput::new(out.value, to.unwrap_or(&owner));
+        let mut tx = Transaction::new(vec![Rc::new(input)], vec![Rc::new(output)], lock_time);
+
+        let signature = wallet.sign(&tx.sighash(0, &out.script_pub_key));
+        let script_sig = match secret {
+            Some(secret) => Htlc::claim_script(signature.as_ref(), wallet.public_key(), secret),
+            None => Htlc::refund_script(signature.as_ref(), wallet.public_key()),
+        };
+        tx.set_script_sig(0, script_sig);
+        Ok(tx)
+    }
+
+    /// New transaction with `outputs` which is paid and signed by wallet of `from`, assets of
+    /// `issuance` are issued to `from`
+    fn new_signed_tx(
+        &mut self,
+        from: &str,
+        outputs: Vec<TXOutput>,
+        issuance: Option<Issuance>,
+        options: &TxOptions,
+    ) -> Result<Transaction, String> {
+        // Get wallet of sender, which is used to unlock outputs
+        let mut wallets = Wallets::new();
+        if wallets.is_watch_only(from) {
+            return Err(format!(
+                "Address {} is watch-only, can not spend from it",
+                from
+            ));
+        }
+        if wallets.get_multisig_script(from).is_some() {
+            return Err(format!(
+                "Address {} is multisig, spend it with multisig-spend",
+                from
+            ));
+        }
+        let wallet = match wallets.get_wallet(from) {
+            Some(w) => w,
+            None => return Err(format!("Can not get wallet for address {}", from)),
+        };
+
+        // Send change to a new address, so that payments are not linked by the address. Wallets
+        // without mnemonic can't derive it, the change is sent back to `from`
+        let from_script = Script::p2pkh(&hash_pub_key(wallet.public_key()));
+        let change_address = wallets.next_change_address();
+        let mut tx = self.build_tx(
+            &from_script,
+            outputs,
+            issuance,
+            options,
+            change_address.as_deref().unwrap_or(from),
+        )?;
+        // The change address is only saved once the transaction is built and pays to it
+        if let Some(change_address) = change_address {
+            let change_script = address_script(&change_address)?;
+            if tx
+                .v_out
+                .iter()
+                .any(|out| out.script_pub_key == change_script)
+            {
+                wallets.create_change_address();
+            }
+        }
+
+        // Sign each input, all of the spent outputs are locked with public key hash of `from`
+        for i in 0..tx.v_in.len() {
+            let signature = wallet.sign(&tx.sighash(i, &from_script));
+            let script_sig = Script::p2pkh_unlock(signature.as_ref(), wallet.public_key());
+            tx.set_script_sig(i, script_sig);
+        }
+
+        Ok(tx)
+    }
+
+    /// New transaction to send `amount` of value from multisig address `from` to `to`, it needs
+    /// to be signed by co-signers. The change is sent back to `from`
+    pub fn new_multisig_tx(
+        &mut self,
+        from: &str,
+        to: &str,
+        amount: u64,
+        options: &TxOptions,
+    ) -> Result<PartialTx, String> {
+        let wallets = Wallets::new();
+        let redeem_script = match wallets.get_multisig_script(from) {
+            Some(script) => script.clone(),
+            None => return Err(format!("Address {} is not a multisig address", from)),
+        };
+        let output = TXOutput {
+            value: amount,
+            script_pub_key: address_script(to)?,
+            asset: None,
+        };
+        let from_script = Script::p2sh(&redeem_script.hash());
+        let tx = self.build_tx(&from_script, vec![output], None, options, from)?;
+        let redeem_scripts = vec![redeem_script; tx.v_in.len()];
+        Ok(PartialTx::new(tx, redeem_scripts))
+    }
+
+    /// New transaction which replaces pending transaction `tx_id` with higher `fee`, the increase
+    /// is paid by it's change output. The fee is doubled if it's not provided
+    pub fn new_bump_fee_tx(
+        &mut self,
+        tx_id: &Hash,
+        fee: Option<u64>,
+    ) -> Result<Transaction, String> {
+        let mempool = self.get_mempool();
+        let original = match mempool.get(tx_id) {
+            Some(tx) => tx.clone(),
+            None => return Err(format!("Transaction {} is not pending", hash2str(tx_id))),
+        };
+        // Outputs which may be spent by the transaction, including outputs of pending transactions
+        let mut outputs: HashMap<(Hash, usize), Rc<TXOutput>> = HashMap::new();
+        for (id, outs) in self.find_all_utxo() {
+            for (out, idx) in outs {
+                outputs.insert((id, idx), out);
+            }
+        }
+        for pending in mempool.txs() {
+            for (idx, out) in pending.v_out.iter().enumerate() {
+                outputs.insert((pending.id, idx), Rc::clone(out));
+            }
+        }
+        let prev_outputs: Vec<Rc<TXOutput>> = match original
+            .v_in
+            .iter()
+            .map(|input| {
+                input
+                    .tx_id
+                    .zip(input.v_out_idx)
+                    .and_then(|out_point| outputs.get(&out_point).cloned())
+            })
+            .collect()
+        {
+            Some(outs) => outs,
+            None => {
+                return Err(String::from(
+                    "Can not find outputs spent by the transaction",
+                ))
+            }
+        };
+
+        let native_value = |outs: &[Rc<TXOutput>]| -> u64 {
+            let values = outs
+                .iter()
+                .filter(|out| out.asset.is_none())
+                .map(|out| out.value);
+            // Values of a verified transaction don't overflow
+            checked_sum(values).unwrap_or(u64::MAX)
+        };
+        let old_fee = native_value(&prev_outputs).saturating_sub(native_value(&original.v_out));
+        let new_fee = fee.unwrap_or(old_fee.saturating_mul(2).max(old_fee.saturating_add(1)));
+        if new_fee <= old_fee {
+            return Err(format!("New fee must be higher than {}", old_fee));
+        }
+        let increase = new_fee - old_fee;
+
+        // Take the increase from change output
         let wallets = Wallets::new();
-        let wallet = wallets
-            .get_wallet(from)
-            .expect(format!("Can not get wallet for address {}", from).as_str());
-        for (txid, idx_set) in utxo {
-            for idx in idx_set {
+        let change_idx = original.v_out.iter().position(|out| {
+            let is_change = match out.pub_key_hash() {
+                Some(hash) => wallets.is_change(&pub_key_hash_address(hash)),
+                None => false,
+            };
+            is_change && out.asset.is_none() && out.value >= increase
+        });
+        let change_idx = match change_idx {
+            Some(idx) => idx,
+            None => return Err(String::from("No change output can pay the increased fee")),
+        };
+        let mut v_out = vec![];
+        for (idx, out) in original.v_out.iter().enumerate() {
+            if idx != change_idx {
+                v_out.push(Rc::clone(out));
+            } else if out.value > increase {
+                v_out.push(Rc::new(TXOutput {
+                    value: out.value - increase,
+                    script_pub_key: out.script_pub_key.clone(),
+                    asset: None,
+                }));
+            }
+        }
+        let v_in = original
+            .v_in
+            .iter()
+            .map(|input| {
+                Rc::new(TXInput {
+                    tx_id: input.tx_id,
+                    v_out_idx: input.v_out_idx,
+                    script_sig: Script::default(),
+                    sequence: input.sequence,
+                })
+            })
+            .collect();
+        let mut tx = Transaction::new(v_in, v_out, original.lock_time);
+        if let Some(issuance) = original.issuance {
+            tx = tx.with_issuance(issuance);
+        }
+
+        // Sign each input with wallet key which locks the spent output
+        for (i, prev) in prev_outputs.iter().enumerate() {
+            let wallet = match prev
+                .pub_key_hash()
+                .and_then(|hash| wallets.get_wallet(&pub_key_hash_address(hash)))
+            {
+                Some(w) => w,
+                None => return Err(format!("Can not get wallet to sign input {}", i)),
+            };
+            let signature = wallet.sign(&tx.sighash(i, &prev.script_pub_key));
+            let script_sig = Script::p2pkh_unlock(signature.as_ref(), wallet.public_key());
+            tx.set_script_sig(i, script_sig);
+        }
+        Ok(tx)
+    }
+
+    /// Build an unsigned transaction with `outputs` which spends outputs locked with
+    /// `from_script`, the change of each asset is sent to `change_address`. Assets of `issuance`
+    /// are issued to `from_script`
+    fn build_tx(
+        &mut self,
+        from_script: &Script,
+        mut outputs: Vec<TXOutput>,
+        issuance: Option<Issuance>,
+        options: &TxOptions,
+        change_address: &str,
+    ) -> Result<Transaction, String> {
+        // Values of each asset to transfer, native coin is `None`
+        let mut amounts: BTreeMap<Option<Hash>, u64> = BTreeMap::new();
+        for out in &outputs {
+            add_value(&mut amounts, out.asset, out.value).ok_or("Value of outputs is too large")?;
+        }
+        add_value(&mut amounts, None, options.fee).ok_or("Fee is too large")?;
+        if let Some(Issuance {
+            asset: Some(asset),
+            amount,
+            ..
+        }) = &issuance
+        {
+            // Minted value doesn't come from inputs, but the mint authority is spent and sent
+            // back to the issuer
+            let value = amounts.entry(Some(*asset)).or_default();
+            *value = value.saturating_sub(*amount);
+            let authority = mint_authority(asset);
+            *amounts.entry(Some(authority)).or_default() += 1;
+            outputs.push(TXOutput {
+                value: 1,
+                script_pub_key: from_script.clone(),
+                asset: Some(authority),
+            });
+        }
+        // A transaction spends at least one output even if it only carries data, all of the
+        // selected value is sent back as change
+        let is_empty = amounts.values().all(|value| *value == 0);
+        if is_empty {
+            amounts.insert(None, 1);
+        }
+
+        let mut inputs: Vec<Rc<TXInput>> = Vec::new();
+        let mut changes = vec![];
+        for (asset, amount) in amounts {
+            if amount == 0 {
+                continue;
+            }
+            // Select unspent outputs to transfer amount value
+            let coins =
+                match self.find_spendable_outputs(from_script, asset, amount, options.strategy) {
+                    Some(c) => c,
+                    None => {
+                        return Err(format!(
+                            "Cannot transfer {}{}, not enough funds",
+                            amount,
+                            match asset {
+                                Some(asset) => format!(" of asset {}", hash2str(&asset)),
+                                None => String::new(),
+                            }
+                        ))
+                    }
+                };
+            let valid_amount = checked_sum(coins.iter().map(|c| c.value))
+                .ok_or("Value of selected outputs overflows")?;
+            let spent = if is_empty { 0 } else { amount };
+            if valid_amount > spent {
+                changes.push((asset, valid_amount - spent));
+            }
+            // Create inputs, they are signed after outputs are created
+            for coin in coins {
                 let input = TXInput {
-                    tx_id: Some(txid),
-                    v_out_idx: Some(idx),
-                    pub_key: Vec::from(wallet.public_key()),
-                    signature: Some(Vec::from("not implemented yet".as_bytes())),
+                    tx_id: Some(coin.tx_id),
+                    v_out_idx: Some(coin.out_idx),
+                    script_sig: Script::default(),
+                    sequence: options.relative_lock,
                 };
                 inputs.push(Rc::new(input));
             }
         }
-        // Create outputs
-        let mut outputs = Vec::new();
-        // Create output for `to` address
-        let out1 = TXOutput::new(amount, to);
-        outputs.push(Rc::new(out1));
-        if valid_amount > amount {
-            // A change for `from` address
-            let out2 = TXOutput::new(valid_amount - amount, from);
-            outputs.push(Rc::new(out2));
+        if !changes.is_empty() {
+            // Changes for sender
+            let change_script = address_script(change_address)?;
+            for (asset, value) in changes {
+                outputs.push(TXOutput {
+                    value,
+                    script_pub_key: change_script.clone(),
+                    asset,
+                });
+            }
+        }
+        if let Some(Issuance {
+            asset: None,
+            amount,
+            mintable,
+            ..
+        }) = &issuance
+        {
+            // New asset is issued to the issuer, it's id is derived from the first input
+            let asset = asset_id(
+                &inputs[0].tx_id.unwrap_or_default(),
+                inputs[0].v_out_idx.unwrap_or(0),
+            );
+            outputs.push(TXOutput {
+                value: *amount,
+                script_pub_key: from_script.clone(),
+                asset: Some(asset),
+            });
+            if *mintable {
+                outputs.push(TXOutput {
+                    value: 1,
+                    script_pub_key: from_script.clone(),
+                    asset: Some(mint_authority(&asset)),
+                });
+            }
+        }
+        let outputs = outputs.into_iter().map(Rc::new).collect();
+        let tx = Transaction::new(inputs, outputs, options.lock_time);
+        match issuance {
+            Some(issuance) => Ok(tx.with_issuance(issuance)),
+            None => Ok(tx),
         }
-        // Transaction hash
-        let tx_hash = hash_transaction(&inputs, &outputs);
-
-        Ok(Transaction {
-            id: tx_hash,
-            v_in: inputs,
-            v_out: outputs,
-        })
     }
 
-    /// Find balance of address `addr`
+    /// Find balance of native coin of address `addr`
     pub fn get_balance(&mut self, addr: &str) -> u64 {
-        let mut balance = 0u64;
-        let pub_key_hash = extract_pub_key_hash(addr);
-        let utxo = self.find_utxo(pub_key_hash.as_slice());
+        self.get_asset_balances(addr).remove(&None).unwrap_or(0)
+    }
+
+    /// Find balance of each asset of address `addr`, native coin is `None`
+    pub fn get_asset_balances(&mut self, addr: &str) -> BTreeMap<Option<Hash>, u64> {
+        let mut balances = BTreeMap::new();
+        let script = match address_script(addr) {
+            Ok(s) => s,
+            Err(_) => return balances,
+        };
+        let utxo = self.find_utxo(&script);
         for (_, outs) in utxo.iter() {
             for (out, _) in outs {
-                balance += out.value;
+                let balance = balances.entry(out.asset).or_default();
+                // Issued assets may be minted beyond the max value
+                *balance = balance.saturating_add(out.value);
             }
         }
-        balance
+        balances
     }
 
-    /// Find the unspent outputs of `address` which it's accumulated value are just bigger than amount
+    /// Find all assets issued in the chain
+    pub fn find_assets(&mut self) -> BTreeMap<Hash, AssetInfo> {
+        let mut assets = BTreeMap::new();
+        let mut minted: Vec<(Hash, u64)> = vec![];
+        for b in BlockChainIter::new(self) {
+            for tx in b.transactions.iter() {
+                let issuance = match &tx.issuance {
+                    Some(issuance) => issuance,
+                    None => continue,
+                };
+                if issuance.asset.is_some() {
+                    minted.extend(tx.issued_assets());
+                    continue;
+                }
+                if let Some((asset, supply)) = tx.issued_assets().first() {
+                    let info = AssetInfo {
+                        name: issuance.name.clone(),
+                        supply: *supply,
+                        mintable: issuance.mintable,
+                    };
+                    assets.insert(*asset, info);
+                }
+            }
+        }
+        // Blocks are iterated from the latest one, mints may be found before issuance
+        for (asset, value) in minted {
+            if let Some(info) = assets.get_mut(&asset) {
+                info.supply = info.supply.saturating_add(value);
+            }
+        }
+        assets
+    }
+
+    /// New transaction which issues `amount` of a new asset named `name` to `from`, more value can
+    /// be minted later if it's `mintable`
+    pub fn new_issue_tx(
+        &mut self,
+        from: &str,
+        name: &str,
+        amount: u64,
+        mintable: bool,
+        options: &TxOptions,
+    ) -> Result<Transaction, String> {
+        let issuance = Issuance {
+            asset: None,
+            amount,
+            mintable,
+            name: String::from(name),
+        };
+        self.new_signed_tx(from, vec![], Some(issuance), options)
+    }
+
+    /// New transaction which mints `amount` of `asset` to `to`, `from` must hold the mint
+    /// authority of the asset
+    pub fn new_mint_tx(
+        &mut self,
+        from: &str,
+        asset: &Hash,
+        to: &str,
+        amount: u64,
+        options: &TxOptions,
+    ) -> Result<Transaction, String> {
+        let output = TXOutput {
+            value: amount,
+            script_pub_key: address_script(to)?,
+            asset: Some(*asset),
+        };
+        let issuance = Issuance {
+            asset: Some(*asset),
+            amount,
+            mintable: false,
+            name: String::new(),
+        };
+        self.new_signed_tx(from, vec![output], Some(issuance), options)
+    }
+
+    /// New transaction, send `amount` of `asset` from `from` to `to`
+    pub fn new_asset_tx(
+        &mut self,
+        from: &str,
+        to: &str,
+        asset: &Hash,
+        amount: u64,
+        options: &TxOptions,
+    ) -> Result<Transaction, String> {
+        let output = TXOutput {
+            value: amount,
+            script_pub_key: address_script(to)?,
+            asset: Some(*asset),
+        };
+        self.new_signed_tx(from, vec![output], None, options)
+    }
+
+    /// Select unspent outputs of `asset` locked with `script` to transfer `amount` of value with
+    /// `strategy`
     ///
-    /// Returns a tuple:
-    /// The first element is a map, which key is transaction id, value is unspent
-    /// outputs index array.
-    /// The second element is accumulated value of unspent outputs in first element
+    /// Returns selected outputs, or `None` if there isn't enough funds
     fn find_spendable_outputs(
         &mut self,
-        address: &str,
+        script: &Script,
+        asset: Option<Hash>,
         amount: u64,
-    ) -> (HashMap<Hash, Vec<usize>>, u64) {
+        strategy: CoinSelection,
+    ) -> Option<Vec<Coin>> {
         // Find all unspent outputs
-        let pub_key_hash = extract_pub_key_hash(address);
-        let all_utxo = self.find_utxo(pub_key_hash.as_slice());
-        // Returned unspent outputs map
-        let mut utxo: HashMap<Hash, Vec<usize>> = HashMap::new();
-        // Accumulate outputs value
-        let mut acc_value = 0u64;
-        'outer: for (txid, tx_outs) in all_utxo {
+        let all_utxo = self.find_utxo(script);
+        let mut coins = vec![];
+        for (tx_id, tx_outs) in all_utxo {
             for (out, out_idx) in tx_outs {
-                // Collect unspent output
-                match utxo.entry(txid) {
-                    Occupied(o) => {
-                        o.into_mut().push(out_idx);
-                    }
-                    Vacant(v) => {
-                        v.insert(vec![out_idx]);
-                    }
-                }
-                acc_value += out.value;
-                // Stop collect if accumulated value is just bigger than amount
-                if acc_value >= amount {
-                    break 'outer;
+                if out.asset != asset {
+                    continue;
                 }
+                coins.push(Coin {
+                    tx_id,
+                    out_idx,
+                    value: out.value,
+                });
             }
         }
-        (utxo, acc_value)
+        strategy.select(&coins, amount)
+    }
+}
+
+/// Locator of chain `hashes` which are in order of height, see `BlockChain::block_locator`
+pub fn build_locator(hashes: &[Hash]) -> Vec<Hash> {
+    let mut locator = vec![];
+    let mut step = 1;
+    let mut idx = hashes.len();
+    while idx > 0 {
+        locator.push(hashes[idx - 1]);
+        if locator.len() >= 10 {
+            step *= 2;
+        }
+        idx = idx.saturating_sub(step);
+    }
+    // Genesis block is always included
+    if locator.last() != hashes.first() {
+        locator.extend(hashes.first());
+    }
+    locator
+}
+
+/// Verify value of each asset is conserved by `tx`, `prev_outputs` are the outputs spent by it.
+/// The value of native coin in inputs must cover outputs, the rest is fee. The value of other
+/// assets in inputs and issuance must equal to outputs. Minting an asset must spend it's mint
+/// authority. Returns the fee
+fn verify_asset_values(tx: &Transaction, prev_outputs: &[Rc<TXOutput>]) -> Result<u64, String> {
+    let overflow = || format!("Values of transaction {} overflow", hash2str(&tx.id));
+    // Values of each asset, native coin is `None`
+    let mut input_values: BTreeMap<Option<Hash>, u64> = BTreeMap::new();
+    for out in prev_outputs {
+        add_value(&mut input_values, out.asset, out.value).ok_or_else(overflow)?;
+    }
+    let mut output_values: BTreeMap<Option<Hash>, u64> = BTreeMap::new();
+    for out in tx.v_out.iter() {
+        add_value(&mut output_values, out.asset, out.value).ok_or_else(overflow)?;
+    }
+    if let Some(Issuance {
+        asset: Some(asset), ..
+    }) = &tx.issuance
+    {
+        if !input_values.contains_key(&Some(mint_authority(asset))) {
+            return Err(format!(
+                "Transaction {} mints asset {} without it's mint authority",
+                hash2str(&tx.id),
+                hash2str(asset)
+            ));
+        }
+    }
+    for (asset, value) in tx.issued_assets() {
+        add_value(&mut input_values, Some(asset), value).ok_or_else(overflow)?;
     }
+
+    let native_input = input_values.remove(&None).unwrap_or(0);
+    let native_output = output_values.remove(&None).unwrap_or(0);
+    if native_input < native_output {
+        return Err(format!(
+            "Outputs of transaction {} exceed it's inputs",
+            hash2str(&tx.id)
+        ));
+    }
+    if input_values != output_values {
+        return Err(format!(
+            "Transaction {} doesn't conserve value of assets",
+            hash2str(&tx.id)
+        ));
+    }
+    Ok(native_input - native_output)
+}
+
+/// Add `value` of `asset` to `values`, returns `None` if the sum overflows
+fn add_value(
+    values: &mut BTreeMap<Option<Hash>, u64>,
+    asset: Option<Hash>,
+    value: u64,
+) -> Option<()> {
+    let sum = values.entry(asset).or_default();
+    *sum = sum.checked_add(value)?;
+    Some(())
+}
+
+/// Sum of `values`, returns `None` if it overflows
+fn checked_sum(values: impl IntoIterator<Item = u64>) -> Option<u64> {
+    values
+        .into_iter()
+        .try_fold(0u64, |sum, value| sum.checked_add(value))
+}
+
+/// Whether fee rate of `tx` paying `fee` is higher than `other` paying `other_fee`, fee rate is
+/// fee per byte of encoded transaction
+fn is_higher_fee_rate(fee: u64, tx: &Transaction, other_fee: u64, other: &Transaction) -> bool {
+    let size = tx.encode().len() as u128;
+    let other_size = other.encode().len() as u128;
+    fee as u128 * other_size > other_fee as u128 * size
+}
+
+/// Find the first hash time locked contract output of `tx`, returns it's index, the output and
+/// the contract
+pub fn find_htlc(tx: &Transaction) -> Option<(usize, Rc<TXOutput>, Htlc)> {
+    tx.v_out.iter().enumerate().find_map(|(idx, out)| {
+        Htlc::from_script(&out.script_pub_key).map(|htlc| (idx, Rc::clone(out), htlc))
+    })
 }
 
 impl Drop for BlockChain {
@@ -256,6 +1587,12 @@ trait BlockDB {
 
     /// Save block to database
     fn put_block(&mut self, block: &Block);
+
+    /// Get filter of block `hash` and it's filter header from database
+    fn get_filter(&mut self, hash: &Hash) -> Option<(BlockFilter, Hash)>;
+
+    /// Save filter of block `hash` and it's filter header to database
+    fn put_filter(&mut self, hash: &Hash, filter: &BlockFilter, header: &Hash);
 }
 
 impl BlockDB for DB {
@@ -271,6 +1608,8 @@ impl BlockDB for DB {
     fn put_hash(&mut self, key: &str, hash: &Hash) {
         self.put(key.as_bytes(), hash.as_slice())
             .unwrap_or_else(|_| panic!("Can not save hash {} to {}", hash2str(hash), key));
+        // Node may be killed without closing database, so write the log at once
+        self.flush().expect("Can not flush database");
     }
 
     fn get_block(&mut self, hash: &Hash) -> Option<Block> {
@@ -285,6 +1624,27 @@ impl BlockDB for DB {
         self.put(block.hash.as_slice(), block.encode().as_slice())
             .unwrap_or_else(|_| panic!("Can not save Block {} to database", block));
     }
+
+    fn get_filter(&mut self, hash: &Hash) -> Option<(BlockFilter, Hash)> {
+        let data = self.get(filter_key(hash).as_slice())?;
+        let (filter, _) = bincode::decode_from_slice(data.as_slice(), config::standard())
+            .unwrap_or_else(|_| panic!("Invalid filter of block {}", hash2str(hash)));
+        Some(filter)
+    }
+
+    fn put_filter(&mut self, hash: &Hash, filter: &BlockFilter, header: &Hash) {
+        let data = bincode::encode_to_vec((filter, header), config::standard())
+            .expect("Can not encode block filter");
+        self.put(filter_key(hash).as_slice(), data.as_slice())
+            .unwrap_or_else(|_| panic!("Can not save filter of block {}", hash2str(hash)));
+    }
+}
+
+/// Database key of filter of block `hash`
+fn filter_key(hash: &Hash) -> ByteData {
+    let mut key = FILTER_PREFIX.as_bytes().to_vec();
+    key.extend_from_slice(hash);
+    key
 }
 
 pub struct BlockChainIter<'a> {
@@ -324,3 +1684,334 @@ impl<'a> Iterator for BlockChainIter<'a> {
         block
     }
 }
+
+#[cfg(test)]
+mod block_chain_test {
+    use super::*;
+    use crate::script::Op;
+    use crate::transaction::hash_transaction;
+    use crate::wallet::{derive_key_pair, Wallet};
+
+    /// Spend output `idx` of `prev` owned by `wallet`, paying `values` back to it
+    fn spend(wallet: &Wallet, prev: &Transaction, idx: usize, values: &[u64]) -> Transaction {
+        let input = TXInput {
+            tx_id: Some(prev.id),
+            v_out_idx: Some(idx),
+            script_sig: Script::default(),
+            sequence: 0,
+        };
+        let outputs = values
+            .iter()
+            .map(|value| Rc::new(TXOutput::new(*value, &wallet.get_address())))
+            .collect();
+        let mut tx = Transaction::new(vec![Rc::new(input)], outputs, 0);
+        let signature = wallet.sign(&tx.sighash(0, &prev.v_out[idx].script_pub_key));
+        tx.set_script_sig(
+            0,
+            Script::p2pkh_unlock(signature.as_ref(), wallet.public_key()),
+        );
+        tx
+    }
+
+    #[test]
+    fn reject_overflowing_values() {
+        let address = Wallet::new(derive_key_pair(&[0u8; 16], &[0]).as_slice()).get_address();
+        let huge = Rc::new(TXOutput::new(u64::MAX / 2 + 1, &address));
+        let mut tx = Transaction::new_coinbase_tx(&address, None, 1, 0);
+        tx.v_out = vec![Rc::clone(&huge), Rc::clone(&huge)];
+        tx.id = hash_transaction(&tx.v_in, &tx.v_out, tx.lock_time, &tx.issuance);
+        // The sum wraps to 0, which must not pass as less than the input
+        let prev_outputs = [Rc::new(TXOutput::new(1, &address))];
+        assert!(verify_asset_values(&tx, &prev_outputs).is_err());
+
+        let genesis = Block::new_genesis_block(Transaction::new_coinbase_tx(&address, None, 0, 0));
+        let mut block_chain = BlockChain::create_in_memory(&genesis).unwrap();
+        let result = block_chain.verify_transactions(&[tx], 1, get_timestamp());
+        assert!(
+            matches!(result, Err(TxError::Invalid(err)) if err.starts_with("Invalid coinbase"))
+        );
+    }
+
+    #[test]
+    fn classify_rejected_transactions() {
+        let address = Wallet::new(derive_key_pair(&[0u8; 16], &[0]).as_slice()).get_address();
+        let genesis = Block::new_genesis_block(Transaction::new_coinbase_tx(&address, None, 0, 0));
+        let mut block_chain = BlockChain::create_in_memory(&genesis).unwrap();
+        let coinbase = Transaction::new_coinbase_tx(&address, None, 1, 0);
+        assert!(matches!(
+            block_chain.submit_tx(coinbase),
+            Err(TxError::Invalid(_))
+        ));
+
+        // Spending an unknown output may be valid once the parent is known
+        let input = TXInput {
+            tx_id: Some([1u8; 32]),
+            v_out_idx: Some(0),
+            script_sig: Script::default(),
+            sequence: 0,
+        };
+        let output = Rc::new(TXOutput::new(1, &address));
+        let orphan = Transaction::new(vec![Rc::new(input)], vec![Rc::clone(&output)], 0);
+        assert!(matches!(
+            block_chain.submit_tx(orphan),
+            Err(TxError::Rejected(_))
+        ));
+
+        // A transaction without inputs and with forged id breaks consensus rules
+        let mut forged = Transaction::new(vec![], vec![output], 0);
+        assert!(matches!(
+            block_chain.verify_transactions(std::slice::from_ref(&forged), 1, get_timestamp()),
+            Err(TxError::Invalid(_))
+        ));
+        forged.id = [2u8; 32];
+        assert!(matches!(
+            block_chain.submit_tx(forged),
+            Err(TxError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn reject_input_without_out_point() {
+        let wallet = Wallet::new(derive_key_pair(&[0u8; 16], &[0]).as_slice());
+        let address = wallet.get_address();
+        let genesis = Block::new_genesis_block(Transaction::new_coinbase_tx(&address, None, 0, 0));
+        let mut block_chain = BlockChain::create_in_memory(&genesis).unwrap();
+        let coinbase = &genesis.transactions[0];
+        // The input has no output index, it must not be taken as the first output
+        let input = TXInput {
+            tx_id: Some(coinbase.id),
+            v_out_idx: None,
+            script_sig: Script::default(),
+            sequence: 0,
+        };
+        let output = Rc::new(TXOutput::new(SUBSIDY, &address));
+        let mut tx = Transaction::new(vec![Rc::new(input)], vec![output], 0);
+        let signature = wallet.sign(&tx.sighash(0, &coinbase.v_out[0].script_pub_key));
+        tx.set_script_sig(
+            0,
+            Script::p2pkh_unlock(signature.as_ref(), wallet.public_key()),
+        );
+        assert!(matches!(
+            block_chain.submit_tx(tx),
+            Err(TxError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn limit_data_of_unspendable_outputs() {
+        let address = Wallet::new(derive_key_pair(&[0u8; 16], &[0]).as_slice()).get_address();
+        let genesis = Block::new_genesis_block(Transaction::new_coinbase_tx(&address, None, 0, 0));
+        let mut block_chain = BlockChain::create_in_memory(&genesis).unwrap();
+        let data = vec![0u8; MAX_DATA_CARRIER_LEN];
+        let scripts = [
+            Script::data_carrier(&data),
+            Script::data_carrier(&[0u8; MAX_DATA_CARRIER_LEN + 1]),
+            Script(vec![
+                Op::Return,
+                Op::PushData(data.clone()),
+                Op::PushData(data),
+            ]),
+        ];
+        let results: Vec<bool> = scripts
+            .into_iter()
+            .map(|script| {
+                let mut coinbase = Transaction::new_coinbase_tx(&address, None, 1, 0);
+                coinbase.v_out.push(Rc::new(TXOutput {
+                    value: 0,
+                    script_pub_key: script,
+                    asset: None,
+                }));
+                coinbase.id = hash_transaction(
+                    &coinbase.v_in,
+                    &coinbase.v_out,
+                    coinbase.lock_time,
+                    &coinbase.issuance,
+                );
+                block_chain
+                    .verify_transactions(&[coinbase], 1, get_timestamp())
+                    .is_ok()
+            })
+            .collect();
+        assert_eq!(results, vec![true, false, false]);
+    }
+
+    #[test]
+    fn require_minimum_relay_fee() {
+        let wallet = Wallet::new(derive_key_pair(&[0u8; 16], &[0]).as_slice());
+        let genesis = Block::new_genesis_block(Transaction::new_coinbase_tx(
+            &wallet.get_address(),
+            None,
+            0,
+            0,
+        ));
+        let coinbase = &genesis.transactions[0];
+        let free = spend(&wallet, coinbase, 0, &[SUBSIDY]);
+        let paid = spend(&wallet, coinbase, 0, &[SUBSIDY - 1]);
+
+        let mut block_chain = BlockChain::create_in_memory(&genesis).unwrap();
+        assert!(matches!(
+            block_chain.submit_relayed_tx(free.clone()),
+            Err(TxError::Rejected(err)) if err.contains("minimum relay fee")
+        ));
+        assert!(block_chain.submit_relayed_tx(paid.clone()).is_ok());
+        assert_eq!(block_chain.get_mempool().fee(&paid.id), Some(1));
+
+        // Transactions of our own are not limited
+        let mut block_chain = BlockChain::create_in_memory(&genesis).unwrap();
+        assert!(block_chain.submit_tx(free).is_ok());
+    }
+
+    #[test]
+    fn reject_invalid_block_timestamps() {
+        let address = Wallet::new(derive_key_pair(&[0u8; 16], &[0]).as_slice()).get_address();
+        let genesis = Block::new_genesis_block(Transaction::new_coinbase_tx(&address, None, 0, 0));
+        let mut block_chain = BlockChain::create_in_memory(&genesis).unwrap();
+        let block_at = |timestamp: TimeStamp| {
+            let coinbase = Transaction::new_coinbase_tx(&address, None, 1, 0);
+            Block::with_timestamp(vec![coinbase], Some(genesis.hash), 1, timestamp)
+        };
+
+        let stale = block_at(genesis.timestamp);
+        assert!(block_chain.add_block(&stale).is_err());
+        let future = block_at(get_timestamp() + MAX_FUTURE_BLOCK_TIME + 60_000);
+        assert!(block_chain.add_block(&future).is_err());
+        assert_eq!(
+            block_chain.add_block(&block_at(genesis.timestamp + 1)),
+            Ok(true)
+        );
+    }
+
+    #[test]
+    fn enforce_relative_lock_in_blocks() {
+        let wallet = Wallet::new(derive_key_pair(&[0u8; 16], &[0]).as_slice());
+        let address = wallet.get_address();
+        let genesis = Block::new_genesis_block(Transaction::new_coinbase_tx(&address, None, 0, 0));
+        let mut block_chain = BlockChain::create_in_memory(&genesis).unwrap();
+        let coinbase = &genesis.transactions[0];
+        // The output of genesis block can be spent 2 blocks after it
+        let input = TXInput {
+            tx_id: Some(coinbase.id),
+            v_out_idx: Some(0),
+            script_sig: Script::default(),
+            sequence: 2,
+        };
+        let output = Rc::new(TXOutput::new(SUBSIDY, &address));
+        let mut tx = Transaction::new(vec![Rc::new(input)], vec![output], 0);
+        let signature = wallet.sign(&tx.sighash(0, &coinbase.v_out[0].script_pub_key));
+        tx.set_script_sig(
+            0,
+            Script::p2pkh_unlock(signature.as_ref(), wallet.public_key()),
+        );
+        let block_with = |txs: Vec<Transaction>, prev: &Block| {
+            let height = prev.height + 1;
+            let mut transactions = vec![Transaction::new_coinbase_tx(&address, None, height, 0)];
+            transactions.extend(txs);
+            Block::with_timestamp(transactions, Some(prev.hash), height, prev.timestamp + 1)
+        };
+
+        let early = block_with(vec![tx.clone()], &genesis);
+        assert!(matches!(
+            block_chain.add_block(&early),
+            Err(err) if err.contains("locked for 2 blocks")
+        ));
+        let empty = block_with(vec![], &genesis);
+        assert_eq!(block_chain.add_block(&empty), Ok(true));
+        let unlocked = block_with(vec![tx], &empty);
+        assert_eq!(block_chain.add_block(&unlocked), Ok(true));
+    }
+
+    #[test]
+    fn replace_by_fee() {
+        let wallet = Wallet::new(derive_key_pair(&[0u8; 16], &[0]).as_slice());
+        let genesis = Block::new_genesis_block(Transaction::new_coinbase_tx(
+            &wallet.get_address(),
+            None,
+            0,
+            0,
+        ));
+        let mut block_chain = BlockChain::create_in_memory(&genesis).unwrap();
+        let coinbase = &genesis.transactions[0];
+        let original = spend(&wallet, coinbase, 0, &[SUBSIDY - 2]);
+        let child = spend(&wallet, &original, 0, &[SUBSIDY - 3]);
+        assert!(block_chain.submit_tx(original.clone()).is_ok());
+        assert!(block_chain.submit_tx(child.clone()).is_ok());
+        let is_rejected = |result: Result<(), TxError>, reason: &str| matches!(result, Err(TxError::Rejected(err)) if err.contains(reason));
+
+        // The replacement must pay more than the 3 paid by the original and it's child
+        let lower = spend(&wallet, coinbase, 0, &[SUBSIDY - 3, 1]);
+        assert!(is_rejected(
+            block_chain.submit_tx(lower),
+            "Replacement fee 2"
+        ));
+        let equal = spend(&wallet, coinbase, 0, &[SUBSIDY - 3]);
+        assert!(is_rejected(
+            block_chain.submit_tx(equal),
+            "Replacement fee 3"
+        ));
+        // A higher fee isn't enough if the replacement is so large that it's fee rate is lower
+        let mut values = vec![1; 20];
+        values[0] = SUBSIDY - 4 - 19;
+        let large = spend(&wallet, coinbase, 0, &values);
+        assert!(!is_higher_fee_rate(4, &large, 2, &original));
+        assert!(is_rejected(block_chain.submit_tx(large), "fee rate"));
+        assert!(block_chain.get_mempool().contains(&child.id));
+
+        // The replaced transaction is evicted with it's descendants
+        let replacement = spend(&wallet, coinbase, 0, &[SUBSIDY - 10]);
+        assert!(block_chain.submit_tx(replacement.clone()).is_ok());
+        let mempool = block_chain.get_mempool();
+        assert_eq!(mempool.txs().len(), 1);
+        assert!(mempool.contains(&replacement.id));
+        assert!(!mempool.contains(&original.id) && !mempool.contains(&child.id));
+    }
+
+    #[test]
+    fn count_watch_only_in_balance_and_refuse_spending() {
+        let watched = Wallet::new(derive_key_pair(&[0u8; 16], &[0]).as_slice()).get_address();
+        let genesis = Block::new_genesis_block(Transaction::new_coinbase_tx(&watched, None, 0, 0));
+        let mut block_chain = BlockChain::create_in_memory(&genesis).unwrap();
+        let mut wallets = Wallets::new();
+        wallets.add_watch_only(&watched).unwrap();
+
+        let total: u64 = wallets
+            .get_addresses()
+            .iter()
+            .map(|address| block_chain.get_balance(address))
+            .sum();
+        assert_eq!(total, SUBSIDY);
+        let result = block_chain.new_tx(&watched, &watched, 1, &TxOptions::default());
+        assert!(matches!(result, Err(err) if err.contains("watch-only")));
+    }
+
+    #[test]
+    fn send_change_to_fresh_derived_address() {
+        let mut wallets = Wallets::new();
+        let (from, mnemonic) = wallets.create_wallet();
+        assert!(mnemonic.is_some());
+        assert!(wallets.create_wallet().1.is_none());
+        let genesis = Block::new_genesis_block(Transaction::new_coinbase_tx(&from, None, 0, 0));
+        let mut block_chain = BlockChain::create_in_memory(&genesis).unwrap();
+        let to = Wallet::new(derive_key_pair(&[0u8; 16], &[0]).as_slice()).get_address();
+        let options = TxOptions::default();
+
+        // A transaction which can't be built doesn't take a change address
+        let change = wallets.next_change_address().unwrap();
+        assert!(block_chain
+            .new_tx(&from, &to, SUBSIDY + 1, &options)
+            .is_err());
+        assert_eq!(Wallets::new().next_change_address(), Some(change.clone()));
+
+        let tx = block_chain.new_tx(&from, &to, 10, &options).unwrap();
+        let change_out = tx
+            .v_out
+            .iter()
+            .find(|out| out.value == SUBSIDY - 10)
+            .unwrap();
+        assert_eq!(change_out.script_pub_key, address_script(&change).unwrap());
+        assert_ne!(change, from);
+        let wallets = Wallets::new();
+        assert!(wallets.is_change(&change));
+        // The next transaction sends change to another address
+        assert_ne!(wallets.next_change_address(), Some(change));
+    }
+}
@@ -0,0 +1,252 @@
+use bincode::{Decode, Encode};
+use sha2::{Digest, Sha256};
+
+use crate::block::{Block, ByteData, Hash};
+use crate::script::Script;
+
+/// Number of low bits of a value which are written as they are in Golomb-Rice coding
+const FILTER_P: u8 = 19;
+/// Inverse of the false positive rate, items are hashed to range of `FILTER_M` times the number
+/// of items
+const FILTER_M: u64 = 784_931;
+/// Max number of filters or filter headers in a request
+pub const MAX_FILTERS: usize = 1000;
+
+/// Hash `item` to a number less than `range`, `key` is the block hash so that items collide in
+/// different ways in different blocks
+fn hash_to_range(key: &Hash, item: &[u8], range: u64) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(key);
+    hasher.update(item);
+    let hash: Hash = hasher.finalize().into();
+    let value = u64::from_le_bytes(hash[..8].try_into().unwrap());
+    ((value as u128 * range as u128) >> 64) as u64
+}
+
+/// Sorted hashed values of `items`
+fn hashed_items(key: &Hash, items: &[ByteData], range: u64) -> Vec<u64> {
+    let mut values: Vec<u64> = items
+        .iter()
+        .map(|item| hash_to_range(key, item, range))
+        .collect();
+    values.sort_unstable();
+    values
+}
+
+/// Filter item of an output locked by `script`, which is the public key hash or script hash
+pub fn script_item(script: &Script) -> Option<ByteData> {
+    script
+        .p2pkh_hash()
+        .or_else(|| script.p2sh_hash())
+        .map(Vec::from)
+}
+
+/// Filter item of an output spent by an input
+pub fn outpoint_item(tx_id: &Hash, out_idx: usize) -> ByteData {
+    let mut item = tx_id.to_vec();
+    item.extend_from_slice(&(out_idx as u64).to_le_bytes());
+    item
+}
+
+/// Items of block filter, which are hashes of output scripts and outputs spent by the block
+pub fn block_items(block: &Block) -> Vec<ByteData> {
+    let mut items = vec![];
+    for tx in block.transactions.iter() {
+        for input in tx.v_in.iter() {
+            if let Some((tx_id, out_idx)) = input.tx_id.zip(input.v_out_idx) {
+                items.push(outpoint_item(&tx_id, out_idx));
+            }
+        }
+        for out in tx.v_out.iter() {
+            items.extend(script_item(&out.script_pub_key));
+        }
+    }
+    items.sort();
+    items.dedup();
+    items
+}
+
+/// Golomb-coded set of items of a block. Light clients download filters to find blocks which
+/// may touch their addresses, without telling the addresses to nodes. A filter may match items
+/// which are not in the block, but never misses items which are in the block
+#[derive(Clone, Debug, PartialEq, Eq, Encode, Decode)]
+pub struct BlockFilter {
+    /// Number of items
+    pub count: u32,
+    /// Differences between sorted hashed items, coded by Golomb-Rice coding
+    pub data: ByteData,
+}
+
+impl BlockFilter {
+    /// Build filter of `items`, `key` is the block hash
+    pub fn new(key: &Hash, items: &[ByteData]) -> Self {
+        let count = items.len() as u32;
+        let mut writer = BitWriter::default();
+        let mut last = 0;
+        for value in hashed_items(key, items, count as u64 * FILTER_M) {
+            let delta = value - last;
+            for _ in 0..delta >> FILTER_P {
+                writer.write_bit(true);
+            }
+            writer.write_bit(false);
+            writer.write_bits(delta, FILTER_P);
+            last = value;
+        }
+        Self {
+            count,
+            data: writer.bytes,
+        }
+    }
+
+    /// Filter of `block`
+    pub fn of_block(block: &Block) -> Self {
+        Self::new(&block.hash, &block_items(block))
+    }
+
+    /// Whether any of `items` may be in the filter, `key` is the block hash
+    pub fn matches_any(&self, key: &Hash, items: &[ByteData]) -> bool {
+        if self.count == 0 || items.is_empty() {
+            return false;
+        }
+        let targets = hashed_items(key, items, self.count as u64 * FILTER_M);
+        let mut targets = targets.iter().peekable();
+        let mut reader = BitReader::new(&self.data);
+        let mut value: u64 = 0;
+        for _ in 0..self.count {
+            let mut quotient = 0;
+            loop {
+                match reader.read_bit() {
+                    Some(true) => quotient += 1,
+                    Some(false) => break,
+                    // The filter is malformed
+                    None => return false,
+                }
+            }
+            let remainder = match reader.read_bits(FILTER_P) {
+                Some(remainder) => remainder,
+                None => return false,
+            };
+            value = value.saturating_add((quotient << FILTER_P) + remainder);
+            // Skip items which are less than the value, they are not in the filter
+            while targets.next_if(|target| **target < value).is_some() {}
+            match targets.peek() {
+                Some(target) if **target == value => return true,
+                Some(_) => {}
+                None => return false,
+            }
+        }
+        false
+    }
+
+    /// Hash of the filter, which is committed by filter header
+    pub fn hash(&self) -> Hash {
+        let mut hasher = Sha256::new();
+        hasher.update(self.count.to_le_bytes());
+        hasher.update(&self.data);
+        hasher.finalize().into()
+    }
+}
+
+/// Filter header of a block, which commits to the filter of the block and filter header of it's
+/// parent, so the filter header of the latest block commits to filters of the whole chain.
+/// `prev_header` of genesis block is all zero
+pub fn filter_header(filter_hash: &Hash, prev_header: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(filter_hash);
+    hasher.update(prev_header);
+    hasher.finalize().into()
+}
+
+/// Write bits from the highest bit of each byte
+#[derive(Default)]
+struct BitWriter {
+    bytes: ByteData,
+    /// Number of bits written
+    len: usize,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.len.is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> (self.len % 8);
+        }
+        self.len += 1;
+    }
+
+    /// Write low `count` bits of `value`, the highest bit is first
+    fn write_bits(&mut self, value: u64, count: u8) {
+        for i in (0..count).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    /// Number of bits read
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.bytes.get(self.pos / 8)?;
+        let bit = byte & (0x80 >> (self.pos % 8)) != 0;
+        self.pos += 1;
+        Some(bit)
+    }
+
+    fn read_bits(&mut self, count: u8) -> Option<u64> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = (value << 1) | self.read_bit()? as u64;
+        }
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod block_filter_test {
+    use super::*;
+    use crate::transaction::Transaction;
+    use crate::wallet::{address_script, derive_key_pair, Wallet};
+
+    #[test]
+    fn match_items_of_block() {
+        let items: Vec<ByteData> = (0..100u32).map(|i| i.to_le_bytes().to_vec()).collect();
+        let key = [3u8; 32];
+        let filter = BlockFilter::new(&key, &items);
+        for item in items.iter() {
+            assert!(filter.matches_any(&key, std::slice::from_ref(item)));
+        }
+        let others: Vec<ByteData> = (100..200u32).map(|i| i.to_le_bytes().to_vec()).collect();
+        assert!(!filter.matches_any(&key, &others));
+        assert!(filter.matches_any(&key, &[others[0].clone(), items[50].clone()]));
+        assert!(!BlockFilter::new(&key, &[]).matches_any(&key, &items));
+
+        let address = Wallet::new(derive_key_pair(&[0u8; 16], &[0]).as_slice()).get_address();
+        let other = Wallet::new(derive_key_pair(&[0u8; 16], &[1]).as_slice()).get_address();
+        let block = Block::new(
+            vec![Transaction::new_coinbase_tx(&address, None, 0, 0)],
+            None,
+            0,
+        );
+        let filter = BlockFilter::of_block(&block);
+        let item = |address: &str| script_item(&address_script(address).unwrap()).unwrap();
+        assert!(filter.matches_any(&block.hash, &[item(&address)]));
+        assert!(!filter.matches_any(&block.hash, &[item(&other)]));
+
+        // Header commits to the filter and the previous header
+        let header = filter_header(&filter.hash(), &[0u8; 32]);
+        assert_ne!(header, filter_header(&filter.hash(), &[1u8; 32]));
+        let mut corrupt = filter.clone();
+        corrupt.data[0] ^= 1;
+        assert_ne!(header, filter_header(&corrupt.hash(), &[0u8; 32]));
+    }
+}
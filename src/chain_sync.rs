@@ -0,0 +1,399 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::block::{Block, BlockHeader, Hash, TimeStamp};
+use crate::node::PeerId;
+use crate::tools::hash2str;
+
+/// Max number of headers in a headers message
+pub const MAX_HEADERS: usize = 2000;
+/// Max number of blocks requested from a peer at the same time
+const MAX_BLOCKS_IN_FLIGHT: usize = 16;
+/// Only blocks in this distance from the first block waiting to be connected are requested, so
+/// that downloaded blocks don't take too much memory
+const DOWNLOAD_WINDOW: usize = 1024;
+/// Peers which don't reply headers or blocks in this time are stalled, in milliseconds
+const STALL_TIMEOUT: TimeStamp = 10_000;
+/// Max number of headers whose blocks are not connected, so that peers can't fill memory with
+/// headers
+const MAX_STORED_HEADERS: usize = 10 * MAX_HEADERS;
+/// Headers which are not in the best header chain are dropped once they are this number of
+/// blocks lower than the best header
+const FORK_WINDOW: u64 = 100;
+
+/// Headers first synchronization, the header chain is downloaded and verified by proof of work
+/// at first, then blocks of the best header chain are downloaded from several peers in parallel,
+/// and they are connected in order
+#[derive(Default)]
+pub struct ChainSync {
+    /// Verified headers whose blocks are not connected yet, key is block hash
+    headers: HashMap<Hash, BlockHeader>,
+    /// The highest verified header
+    best_header: Option<BlockHeader>,
+    /// Peer which headers are requested from, and the time of request
+    header_peer: Option<(PeerId, TimeStamp)>,
+    /// Blocks of the best header chain waiting to be connected, in order of height
+    pending: VecDeque<Hash>,
+    /// Requested blocks, value is the peer and time of request
+    in_flight: HashMap<Hash, (PeerId, TimeStamp)>,
+    /// Downloaded blocks waiting for their parents to be connected, with the peers sent them
+    downloaded: HashMap<Hash, (PeerId, Block)>,
+    /// Blocks which peers don't have, e.g. the peers are in another branch
+    unavailable: HashSet<(PeerId, Hash)>,
+    /// Last reported progress in percent
+    reported: Option<u64>,
+}
+
+impl ChainSync {
+    /// Whether headers are being downloaded
+    pub fn is_requesting_headers(&self) -> bool {
+        self.header_peer.is_some()
+    }
+
+    /// Whether there are blocks waiting to be downloaded or connected
+    pub fn is_downloading(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Height of the highest verified header
+    pub fn best_header_height(&self) -> Option<u64> {
+        self.best_header.as_ref().map(|header| header.height)
+    }
+
+    /// Hash of the highest verified header whose block is not connected
+    pub fn best_header_hash(&self) -> Option<Hash> {
+        self.best_header
+            .as_ref()
+            .filter(|header| self.headers.contains_key(&header.hash))
+            .map(|header| header.hash)
+    }
+
+    /// Whether no more headers can be stored until blocks are connected
+    pub fn is_full(&self) -> bool {
+        self.headers.len() >= MAX_STORED_HEADERS
+    }
+
+    /// Headers are requested from `peer`
+    pub fn request_headers(&mut self, peer: PeerId, now: TimeStamp) {
+        self.header_peer = Some((peer, now));
+    }
+
+    /// Verify and add headers received from `peer`. `known_height` returns height of a block
+    /// which is already in the chain. Returns the number of new headers, headers after the limit
+    /// of stored headers are ignored
+    pub fn add_headers(
+        &mut self,
+        peer: PeerId,
+        headers: &[BlockHeader],
+        mut known_height: impl FnMut(&Hash) -> Option<u64>,
+    ) -> Result<usize, String> {
+        if matches!(self.header_peer, Some((p, _)) if p == peer) {
+            self.header_peer = None;
+        }
+        let mut count = 0;
+        for header in headers {
+            if self.headers.contains_key(&header.hash) || known_height(&header.hash).is_some() {
+                continue;
+            }
+            if self.is_full() {
+                break;
+            }
+            if !header.is_valid_pow() {
+                return Err(format!(
+                    "Header {} has invalid proof of work",
+                    hash2str(&header.hash)
+                ));
+            }
+            let parent_height = match header.prev_block_hash {
+                Some(prev) => match self.headers.get(&prev) {
+                    Some(parent) => Some(parent.height),
+                    None => known_height(&prev),
+                },
+                None => None,
+            };
+            let expected_height = match (header.prev_block_hash, parent_height) {
+                (None, _) => 0,
+                (Some(_), Some(height)) => height + 1,
+                (Some(_), None) => {
+                    return Err(format!(
+                        "Parent of header {} is unknown",
+                        hash2str(&header.hash)
+                    ))
+                }
+            };
+            if header.height != expected_height {
+                return Err(format!(
+                    "Header {} has invalid height",
+                    hash2str(&header.hash)
+                ));
+            }
+            if self.best_header_height() < Some(header.height) {
+                self.best_header = Some(header.clone());
+            }
+            self.headers.insert(header.hash, header.clone());
+            count += 1;
+        }
+        self.schedule();
+        self.prune();
+        Ok(count)
+    }
+
+    /// Drop headers which are not in the best header chain and fall `FORK_WINDOW` blocks behind
+    /// the best header, with blocks requested or downloaded for them
+    fn prune(&mut self) {
+        let best_height = match self.best_header_height() {
+            Some(height) => height,
+            None => return,
+        };
+        let best_chain: HashSet<&Hash> = self.pending.iter().collect();
+        self.headers.retain(|hash, header| {
+            best_chain.contains(hash) || header.height + FORK_WINDOW >= best_height
+        });
+        let headers = &self.headers;
+        self.in_flight.retain(|hash, _| headers.contains_key(hash));
+        self.downloaded.retain(|hash, _| headers.contains_key(hash));
+        self.unavailable
+            .retain(|(_, hash)| headers.contains_key(hash));
+    }
+
+    /// Schedule blocks of the best header chain to download
+    fn schedule(&mut self) {
+        let mut pending = VecDeque::new();
+        let mut cur = self.best_header.as_ref().map(|header| header.hash);
+        while let Some(header) = cur.and_then(|hash| self.headers.get(&hash)) {
+            pending.push_front(header.hash);
+            cur = header.prev_block_hash;
+        }
+        self.pending = pending;
+    }
+
+    /// Blocks to request from `peer` which has blocks up to `peer_height`
+    pub fn next_requests(&mut self, peer: PeerId, peer_height: u64, now: TimeStamp) -> Vec<Hash> {
+        let in_flight = self.in_flight.values().filter(|(p, _)| *p == peer).count();
+        let mut requests = vec![];
+        for hash in self.pending.iter().take(DOWNLOAD_WINDOW) {
+            if in_flight + requests.len() >= MAX_BLOCKS_IN_FLIGHT {
+                break;
+            }
+            if self.in_flight.contains_key(hash)
+                || self.downloaded.contains_key(hash)
+                || self.unavailable.contains(&(peer, *hash))
+            {
+                continue;
+            }
+            match self.headers.get(hash) {
+                Some(header) if header.height <= peer_height => requests.push(*hash),
+                _ => break,
+            }
+        }
+        for hash in requests.iter() {
+            self.in_flight.insert(*hash, (peer, now));
+        }
+        requests
+    }
+
+    /// Whether block `hash` is requested from `peer`
+    pub fn is_requested(&self, peer: PeerId, hash: &Hash) -> bool {
+        matches!(self.in_flight.get(hash), Some((p, _)) if *p == peer)
+    }
+
+    /// `peer` doesn't have block `hash`, it's requested from other peers
+    pub fn not_found(&mut self, peer: PeerId, hash: &Hash) {
+        if self.is_requested(peer, hash) {
+            self.in_flight.remove(hash);
+            self.unavailable.insert((peer, *hash));
+        }
+    }
+
+    /// Add a block received from `peer`, returns `false` if it's not requested from the peer
+    pub fn add_block(&mut self, peer: PeerId, block: Block) -> Result<bool, String> {
+        match self.in_flight.get(&block.hash) {
+            Some((p, _)) if *p == peer => {}
+            _ => return Ok(false),
+        }
+        self.in_flight.remove(&block.hash);
+        // The block must match the verified header
+        if self.headers.get(&block.hash) != Some(&block.header()) {
+            return Err(format!(
+                "Block {} doesn't match it's header",
+                hash2str(&block.hash)
+            ));
+        }
+        self.downloaded.insert(block.hash, (peer, block));
+        Ok(true)
+    }
+
+    /// Take downloaded blocks which can be connected in order, with the peers sent them
+    pub fn take_connectable(&mut self) -> Vec<(PeerId, Block)> {
+        let mut blocks = vec![];
+        while let Some(hash) = self.pending.front() {
+            match self.downloaded.remove(hash) {
+                Some(item) => {
+                    self.headers.remove(hash);
+                    self.pending.pop_front();
+                    blocks.push(item);
+                }
+                None => break,
+            }
+        }
+        blocks
+    }
+
+    /// Peers which don't reply requested headers or blocks in time
+    pub fn stalled_peers(&self, now: TimeStamp) -> HashSet<PeerId> {
+        self.in_flight
+            .values()
+            .chain(self.header_peer.iter())
+            .filter(|(_, time)| now.saturating_sub(*time) > STALL_TIMEOUT)
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+
+    /// Forget requests to a disconnected peer, so that they can be sent to other peers
+    pub fn remove_peer(&mut self, peer: PeerId) {
+        self.in_flight.retain(|_, (p, _)| *p != peer);
+        self.unavailable.retain(|(p, _)| *p != peer);
+        if matches!(self.header_peer, Some((p, _)) if p == peer) {
+            self.header_peer = None;
+        }
+    }
+
+    /// Give up blocks which are not connected, e.g. one of them is invalid
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Progress in percent if it has grown by 10 percent since the last report, `height` is the
+    /// height of the latest connected block
+    pub fn progress(&mut self, height: u64) -> Option<u64> {
+        let target = self.best_header_height()?;
+        let percent = (height.min(target) * 100)
+            .checked_div(target)
+            .unwrap_or(100);
+        if self
+            .reported
+            .is_some_and(|reported| reported / 10 == percent / 10)
+        {
+            return None;
+        }
+        self.reported = Some(percent);
+        Some(percent)
+    }
+}
+
+#[cfg(test)]
+mod chain_sync_test {
+    use super::*;
+    use crate::transaction::Transaction;
+    use crate::wallet::{derive_key_pair, Wallet};
+
+    /// Create a chain of `len` blocks from genesis
+    fn new_chain(len: u64) -> Vec<Block> {
+        let mut blocks = vec![];
+        extend_chain(&mut blocks, len as usize, 0);
+        blocks
+    }
+
+    /// Append `len` blocks to `blocks`, which are mined to address of `index`
+    fn extend_chain(blocks: &mut Vec<Block>, len: usize, index: u32) {
+        let address = Wallet::new(derive_key_pair(&[0u8; 16], &[index]).as_slice()).get_address();
+        for _ in 0..len {
+            let height = blocks.last().map_or(0, |b| b.height + 1);
+            let coinbase = Transaction::new_coinbase_tx(&address, None, height, 0);
+            let prev = blocks.last().map(|b| b.hash);
+            blocks.push(Block::new(vec![coinbase], prev, height));
+        }
+    }
+
+    #[test]
+    fn download_blocks_from_several_peers() {
+        let blocks = new_chain(40);
+        let headers: Vec<BlockHeader> = blocks.iter().map(|b| b.header()).collect();
+        // Genesis block is known
+        let genesis = blocks[0].hash;
+        let known = |hash: &Hash| if *hash == genesis { Some(0) } else { None };
+        let mut sync = ChainSync::default();
+        let mut forged = headers[5].clone();
+        forged.height = 10;
+        assert!(sync.add_headers(0, &[forged], known).is_err());
+        assert_eq!(sync.add_headers(0, &headers, known), Ok(39));
+        assert_eq!(sync.best_header_height(), Some(39));
+
+        // Blocks are requested from two peers, the second one only has 20 blocks
+        let first = sync.next_requests(0, 39, 0);
+        let second = sync.next_requests(1, 20, 0);
+        assert_eq!(
+            first,
+            headers[1..17].iter().map(|h| h.hash).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            second,
+            headers[17..21].iter().map(|h| h.hash).collect::<Vec<_>>()
+        );
+
+        // Blocks are connected in order
+        assert_eq!(sync.add_block(1, blocks[17].clone()), Ok(true));
+        assert_eq!(sync.add_block(1, blocks[2].clone()), Ok(false));
+        assert!(sync.take_connectable().is_empty());
+        for b in blocks[1..17].iter() {
+            assert_eq!(sync.add_block(0, b.clone()), Ok(true));
+        }
+        let connected: Vec<u64> = sync
+            .take_connectable()
+            .iter()
+            .map(|(_, b)| b.height)
+            .collect();
+        assert_eq!(connected, (1..18).collect::<Vec<_>>());
+        assert_eq!(sync.progress(17), Some(43));
+        assert_eq!(sync.progress(18), None);
+
+        // The stalled peer is dropped, and it's blocks are requested from another peer
+        assert!(sync.stalled_peers(STALL_TIMEOUT).is_empty());
+        assert_eq!(sync.stalled_peers(STALL_TIMEOUT + 1), HashSet::from([1]));
+        sync.remove_peer(1);
+        let retry = sync.next_requests(0, 39, STALL_TIMEOUT + 1);
+        assert_eq!(
+            retry[..3],
+            [headers[18].hash, headers[19].hash, headers[20].hash]
+        );
+
+        // A block which the peer doesn't have is not requested from it again
+        sync.not_found(0, &headers[18].hash);
+        let retry = sync.next_requests(0, 39, STALL_TIMEOUT + 1);
+        assert_eq!(retry, vec![headers[34].hash]);
+    }
+
+    #[test]
+    fn drop_stale_fork_and_limit_headers() {
+        let blocks = new_chain(FORK_WINDOW + 10);
+        let genesis = blocks[0].hash;
+        let known = |hash: &Hash| if *hash == genesis { Some(0) } else { None };
+        let mut sync = ChainSync::default();
+
+        // A fork from genesis is kept until it falls behind the best header
+        let mut fork = blocks[..1].to_vec();
+        extend_chain(&mut fork, 5, 1);
+        let fork: Vec<BlockHeader> = fork[1..].iter().map(|b| b.header()).collect();
+        assert_eq!(sync.add_headers(0, &fork, known), Ok(5));
+        assert_eq!(sync.next_requests(0, 5, 0).len(), 5);
+        let headers: Vec<BlockHeader> = blocks[1..].iter().map(|b| b.header()).collect();
+        assert_eq!(sync.add_headers(1, &headers[..10], known), Ok(10));
+        assert_eq!(sync.headers.len(), 15);
+        assert_eq!(
+            sync.add_headers(1, &headers[10..], known),
+            Ok(FORK_WINDOW as usize - 1)
+        );
+        assert_eq!(sync.headers.len(), headers.len());
+        assert!(fork.iter().all(|h| !sync.is_requested(0, &h.hash)));
+
+        // Headers after the limit are ignored
+        let mut blocks = blocks;
+        extend_chain(&mut blocks, MAX_STORED_HEADERS - headers.len() + 1, 0);
+        let headers: Vec<BlockHeader> = blocks[1..].iter().map(|b| b.header()).collect();
+        assert_eq!(
+            sync.add_headers(1, &headers, known),
+            Ok(MAX_STORED_HEADERS - FORK_WINDOW as usize - 9)
+        );
+        assert!(sync.is_full());
+        assert_eq!(sync.best_header_height(), Some(MAX_STORED_HEADERS as u64));
+    }
+}
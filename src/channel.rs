@@ -0,0 +1,773 @@
+use bincode::{config, Decode, Encode};
+use ring::rand::{self, SecureRandom};
+use sha2::{Digest, Sha256};
+use std::rc::Rc;
+
+use crate::block::ByteData;
+use crate::codec::MAX_MESSAGE_SIZE;
+use crate::script::{Op, Script};
+use crate::tools::{bytes2hex, hex2bytes};
+use crate::transaction::{TXInput, TXOutput, Transaction};
+use crate::wallet::{hash_pub_key, verify_signature, Wallet};
+
+/// Default number of blocks the owner of a commitment transaction waits before spending it's own
+/// output, the counterparty can take the output in this period if the commitment is revoked
+pub const DEFAULT_CHANNEL_DELAY: u32 = 6;
+/// Length of seed of revocation secrets
+const REVOCATION_SEED_LEN: usize = 32;
+
+/// State of a payment channel
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Encode, Decode)]
+pub enum ChannelState {
+    /// The funding transaction isn't submitted, or the first commitment isn't signed
+    Opening,
+    /// Payments can be made
+    Open,
+    /// A payment of the amount was sent, waiting for the counterparty to acknowledge it
+    AwaitingAck(u64),
+    /// A payment was acknowledged, waiting for the counterparty to revoke it's old commitment
+    AwaitingRevoke,
+    /// The channel is closed, payments can't be made
+    Closed,
+}
+
+/// Messages exchanged by two peers of a channel, they are passed as hex text
+#[derive(Encode, Decode)]
+pub enum ChannelMessage {
+    /// Funder proposes a channel with it's signed funding transaction
+    Open {
+        funding_tx: Transaction,
+        funding_idx: usize,
+        delay: u32,
+        pub_key: ByteData,
+        revocation_hash: ByteData,
+        next_revocation_hash: ByteData,
+    },
+    /// Acceptor signs the first commitment of funder
+    Accept {
+        pub_key: ByteData,
+        signature: ByteData,
+        revocation_hash: ByteData,
+        next_revocation_hash: ByteData,
+    },
+    /// Funder signs the first commitment of acceptor, and submits the funding transaction
+    Funded { signature: ByteData },
+    /// Payer signs new commitment of payee
+    Update { amount: u64, signature: ByteData },
+    /// Payee signs new commitment of payer, and revokes it's old commitment
+    Ack {
+        signature: ByteData,
+        secret: ByteData,
+        next_revocation_hash: ByteData,
+    },
+    /// Payer revokes it's old commitment
+    Revoke {
+        secret: ByteData,
+        next_revocation_hash: ByteData,
+    },
+    /// Signature of the transaction which closes the channel cooperatively
+    Close { signature: ByteData },
+}
+
+impl ChannelMessage {
+    /// Encode to hex text, so that it can be passed to the counterparty
+    pub fn encode(&self) -> String {
+        let config = config::standard();
+        let data = bincode::encode_to_vec(self, config).expect("Can not encode ChannelMessage");
+        bytes2hex(data.as_slice())
+    }
+
+    /// Decode from hex text created by `encode`
+    pub fn decode(text: &str) -> Result<Self, String> {
+        let data = match hex2bytes(text.trim()) {
+            Some(d) => d,
+            None => return Err(String::from("Invalid hex text of channel message")),
+        };
+        // The text comes from the counterparty, so lengths in it are limited
+        let config = config::standard().with_limit::<MAX_MESSAGE_SIZE>();
+        match bincode::decode_from_slice(data.as_slice(), config) {
+            Ok((message, _)) => Ok(message),
+            Err(err) => Err(format!("Can not decode channel message: {}", err)),
+        }
+    }
+}
+
+/// Bidirectional payment channel between two peers. The funding output is locked with a 2-of-2
+/// multisig script, each peer holds a commitment transaction spending it which is signed by the
+/// counterparty. A payment replaces both commitments, the old ones are revoked by revealing their
+/// revocation secrets, so that the counterparty can take all of the value if a revoked commitment
+/// is submitted
+#[derive(Encode, Decode)]
+pub struct Channel {
+    pub funding_tx: Transaction,
+    pub funding_idx: usize,
+    pub capacity: u64,
+    /// Whether the local peer created the funding transaction
+    pub is_funder: bool,
+    /// Wallet address whose key signs for local peer
+    pub local_address: String,
+    pub local_pub_key: ByteData,
+    pub remote_pub_key: ByteData,
+    /// Relative lock of commitment outputs which pay to their owners
+    pub delay: u32,
+    /// Number of current commitments
+    pub number: u64,
+    pub local_balance: u64,
+    pub remote_balance: u64,
+    pub state: ChannelState,
+    /// Signature of counterparty on current local commitment
+    remote_signature: ByteData,
+    /// Seed of local revocation secrets
+    seed: ByteData,
+    /// Revocation hash of current remote commitment
+    remote_revocation_hash: ByteData,
+    /// Revocation hash of next remote commitment
+    remote_next_revocation_hash: ByteData,
+    /// Secrets of revoked remote commitments
+    revoked_secrets: Vec<ByteData>,
+}
+
+impl Channel {
+    /// Open a channel funded by output `funding_idx` of `funding_tx`, returns the channel and the
+    /// message for the counterparty
+    pub fn open(
+        funding_tx: Transaction,
+        funding_idx: usize,
+        local_address: &str,
+        local_pub_key: &[u8],
+        remote_pub_key: &[u8],
+        delay: u32,
+    ) -> (Self, ChannelMessage) {
+        let capacity = funding_tx.v_out[funding_idx].value;
+        let channel = Self {
+            funding_tx,
+            funding_idx,
+            capacity,
+            is_funder: true,
+            local_address: String::from(local_address),
+            local_pub_key: Vec::from(local_pub_key),
+            remote_pub_key: Vec::from(remote_pub_key),
+            delay,
+            number: 0,
+            local_balance: capacity,
+            remote_balance: 0,
+            state: ChannelState::Opening,
+            remote_signature: vec![],
+            seed: generate_seed(),
+            remote_revocation_hash: vec![],
+            remote_next_revocation_hash: vec![],
+            revoked_secrets: vec![],
+        };
+        let message = ChannelMessage::Open {
+            funding_tx: channel.funding_tx.clone(),
+            funding_idx,
+            delay,
+            pub_key: channel.local_pub_key.clone(),
+            revocation_hash: channel.revocation_hash(0),
+            next_revocation_hash: channel.revocation_hash(1),
+        };
+        (channel, message)
+    }
+
+    /// Accept a channel proposed by `message`, the funding output must be locked with public keys
+    /// of funder and `wallet`
+    pub fn accept(
+        message: ChannelMessage,
+        local_address: &str,
+        wallet: &Wallet,
+    ) -> Result<(Self, ChannelMessage), String> {
+        let (funding_tx, funding_idx, delay, remote_pub_key, revocation_hash, next_revocation_hash) =
+            match message {
+                ChannelMessage::Open {
+                    funding_tx,
+                    funding_idx,
+                    delay,
+                    pub_key,
+                    revocation_hash,
+                    next_revocation_hash,
+                } => (
+                    funding_tx,
+                    funding_idx,
+                    delay,
+                    pub_key,
+                    revocation_hash,
+                    next_revocation_hash,
+                ),
+                _ => return Err(String::from("It's not a channel open message")),
+            };
+        let local_pub_key = Vec::from(wallet.public_key());
+        let funding_script = multisig_script(&remote_pub_key, &local_pub_key);
+        let capacity = match funding_tx.v_out.get(funding_idx) {
+            Some(out) if out.script_pub_key == Script::p2sh(&funding_script.hash()) => out.value,
+            _ => {
+                return Err(String::from(
+                    "Funding output isn't locked with both public keys",
+                ))
+            }
+        };
+        let channel = Self {
+            funding_tx,
+            funding_idx,
+            capacity,
+            is_funder: false,
+            local_address: String::from(local_address),
+            local_pub_key,
+            remote_pub_key,
+            delay,
+            number: 0,
+            local_balance: 0,
+            remote_balance: capacity,
+            state: ChannelState::Opening,
+            remote_signature: vec![],
+            seed: generate_seed(),
+            remote_revocation_hash: revocation_hash,
+            remote_next_revocation_hash: next_revocation_hash,
+            revoked_secrets: vec![],
+        };
+        let signature = channel.sign_remote_commitment(wallet, channel.local_balance);
+        let message = ChannelMessage::Accept {
+            pub_key: channel.local_pub_key.clone(),
+            signature,
+            revocation_hash: channel.revocation_hash(0),
+            next_revocation_hash: channel.revocation_hash(1),
+        };
+        Ok((channel, message))
+    }
+
+    /// Send `amount` of value to the counterparty, returns the message for the counterparty
+    pub fn pay(&mut self, amount: u64, wallet: &Wallet) -> Result<ChannelMessage, String> {
+        if self.state != ChannelState::Open {
+            return Err(format!("Can not pay in {:?} state", self.state));
+        }
+        if amount == 0 || amount > self.local_balance {
+            return Err(format!(
+                "Can not pay {}, local balance is {}",
+                amount, self.local_balance
+            ));
+        }
+        let signature = self.sign_next_remote_commitment(wallet, self.local_balance - amount);
+        self.state = ChannelState::AwaitingAck(amount);
+        Ok(ChannelMessage::Update { amount, signature })
+    }
+
+    /// Process `message` from the counterparty, returns the reply message if there is one, and
+    /// the transaction to submit if there is one
+    pub fn receive(
+        &mut self,
+        message: ChannelMessage,
+        wallet: &Wallet,
+    ) -> Result<(Option<ChannelMessage>, Option<Transaction>), String> {
+        match (message, self.state) {
+            (
+                ChannelMessage::Accept {
+                    pub_key,
+                    signature,
+                    revocation_hash,
+                    next_revocation_hash,
+                },
+                ChannelState::Opening,
+            ) if self.is_funder => {
+                if pub_key != self.remote_pub_key {
+                    return Err(String::from("Channel is accepted by another public key"));
+                }
+                self.verify_local_commitment(0, self.local_balance, &signature)?;
+                self.remote_signature = signature;
+                self.remote_revocation_hash = revocation_hash;
+                self.remote_next_revocation_hash = next_revocation_hash;
+                let signature = self.sign_remote_commitment(wallet, self.local_balance);
+                self.state = ChannelState::Open;
+                Ok((
+                    Some(ChannelMessage::Funded { signature }),
+                    Some(self.funding_tx.clone()),
+                ))
+            }
+            (ChannelMessage::Funded { signature }, ChannelState::Opening) if !self.is_funder => {
+                self.verify_local_commitment(0, self.local_balance, &signature)?;
+                self.remote_signature = signature;
+                self.state = ChannelState::Open;
+                Ok((None, None))
+            }
+            (ChannelMessage::Update { amount, signature }, ChannelState::Open) => {
+                if amount == 0 || amount > self.remote_balance {
+                    return Err(format!(
+                        "Can not receive {}, remote balance is {}",
+                        amount, self.remote_balance
+                    ));
+                }
+                let local_balance = self.local_balance + amount;
+                self.verify_local_commitment(self.number + 1, local_balance, &signature)?;
+                let reply_signature = self.sign_next_remote_commitment(wallet, local_balance);
+                // Revoke current local commitment
+                let message = ChannelMessage::Ack {
+                    signature: reply_signature,
+                    secret: self.revocation_secret(self.number),
+                    next_revocation_hash: self.revocation_hash(self.number + 2),
+                };
+                self.advance(local_balance, signature);
+                self.state = ChannelState::AwaitingRevoke;
+                Ok((Some(message), None))
+            }
+            (
+                ChannelMessage::Ack {
+                    signature,
+                    secret,
+                    next_revocation_hash,
+                },
+                ChannelState::AwaitingAck(amount),
+            ) => {
+                let local_balance = self.local_balance - amount;
+                self.verify_local_commitment(self.number + 1, local_balance, &signature)?;
+                self.revoke_remote(secret, next_revocation_hash)?;
+                let message = ChannelMessage::Revoke {
+                    secret: self.revocation_secret(self.number),
+                    next_revocation_hash: self.revocation_hash(self.number + 2),
+                };
+                self.advance(local_balance, signature);
+                self.state = ChannelState::Open;
+                Ok((Some(message), None))
+            }
+            (
+                ChannelMessage::Revoke {
+                    secret,
+                    next_revocation_hash,
+                },
+                ChannelState::AwaitingRevoke,
+            ) => {
+                self.revoke_remote(secret, next_revocation_hash)?;
+                self.state = ChannelState::Open;
+                Ok((None, None))
+            }
+            (ChannelMessage::Close { signature }, ChannelState::Open) => {
+                let mut tx = self.close_tx();
+                let sighash = tx.sighash(0, &self.funding_script());
+                if !verify_signature(&self.remote_pub_key, &sighash, &signature) {
+                    return Err(String::from("Invalid signature of closing transaction"));
+                }
+                let local_signature = Vec::from(wallet.sign(&sighash).as_ref());
+                tx.set_script_sig(0, self.funding_unlock(local_signature, signature));
+                self.state = ChannelState::Closed;
+                Ok((None, Some(tx)))
+            }
+            (_, state) => Err(format!("Unexpected message in {:?} state", state)),
+        }
+    }
+
+    /// Close the channel cooperatively, returns the message for the counterparty who submits the
+    /// closing transaction
+    pub fn close(&mut self, wallet: &Wallet) -> Result<ChannelMessage, String> {
+        if self.state != ChannelState::Open {
+            return Err(format!("Can not close in {:?} state", self.state));
+        }
+        let tx = self.close_tx();
+        let signature = wallet.sign(&tx.sighash(0, &self.funding_script()));
+        self.state = ChannelState::Closed;
+        Ok(ChannelMessage::Close {
+            signature: Vec::from(signature.as_ref()),
+        })
+    }
+
+    /// Close the channel without the counterparty, returns current local commitment signed by
+    /// both peers
+    pub fn force_close(&mut self, wallet: &Wallet) -> Result<Transaction, String> {
+        if self.remote_signature.is_empty() {
+            return Err(String::from(
+                "Local commitment isn't signed by the counterparty",
+            ));
+        }
+        let mut tx = self.local_commitment(self.number, self.local_balance);
+        let signature = wallet.sign(&tx.sighash(0, &self.funding_script()));
+        let script_sig =
+            self.funding_unlock(Vec::from(signature.as_ref()), self.remote_signature.clone());
+        tx.set_script_sig(0, script_sig);
+        self.state = ChannelState::Closed;
+        Ok(tx)
+    }
+
+    /// Spend outputs of commitment `closing_tx` which belong to local peer to `to`, they are the
+    /// local output after the delay, or the remote output if the commitment is revoked
+    pub fn sweep(
+        &self,
+        closing_tx: &Transaction,
+        wallet: &Wallet,
+        to: &str,
+    ) -> Result<Transaction, String> {
+        let mut inputs = vec![];
+        let mut unlocks = vec![];
+        let mut value = 0;
+        for (idx, out) in closing_tx.v_out.iter().enumerate() {
+            let keys = match parse_revocable_script(&out.script_pub_key) {
+                Some(keys) => keys,
+                None => continue,
+            };
+            let (sequence, secret) = if keys.owner_pub_key == self.local_pub_key.as_slice() {
+                (self.delay, None)
+            } else if keys.other_pub_key == self.local_pub_key.as_slice() {
+                match self
+                    .revoked_secrets
+                    .iter()
+                    .find(|secret| hash_secret(secret) == keys.revocation_hash)
+                {
+                    Some(secret) => (0, Some(secret.clone())),
+                    None => continue,
+                }
+            } else {
+                continue;
+            };
+            inputs.push(Rc::new(TXInput {
+                tx_id: Some(closing_tx.id),
+                v_out_idx: Some(idx),
+                script_sig: Script::default(),
+                sequence,
+            }));
+            unlocks.push((Rc::clone(out), secret));
+            value += out.value;
+        }
+        if inputs.is_empty() {
+            return Err(String::from("There are no outputs to sweep"));
+        }
+        let output = TXOutput::new(value, to);
+        let mut tx = Transaction::new(inputs, vec![Rc::new(output)], 0);
+        for (i, (out, secret)) in unlocks.into_iter().enumerate() {
+            let signature = Vec::from(wallet.sign(&tx.sighash(i, &out.script_pub_key)).as_ref());
+            let script_sig = match secret {
+                Some(secret) => Script(vec![
+                    Op::PushData(signature),
+                    Op::PushData(secret),
+                    Op::Num(1),
+                ]),
+                None => Script(vec![Op::PushData(signature), Op::Num(0)]),
+            };
+            tx.set_script_sig(i, script_sig);
+        }
+        Ok(tx)
+    }
+
+    /// Encode to hex text, so that the channel can be saved to file
+    pub fn encode(&self) -> String {
+        let config = config::standard();
+        let data = bincode::encode_to_vec(self, config).expect("Can not encode Channel");
+        bytes2hex(data.as_slice())
+    }
+
+    /// Decode from hex text created by `encode`
+    pub fn decode(text: &str) -> Result<Self, String> {
+        let data = match hex2bytes(text.trim()) {
+            Some(d) => d,
+            None => return Err(String::from("Invalid hex text of channel")),
+        };
+        let config = config::standard();
+        match bincode::decode_from_slice(data.as_slice(), config) {
+            Ok((channel, _)) => Ok(channel),
+            Err(err) => Err(format!("Can not decode channel: {}", err)),
+        }
+    }
+
+    /// Move to the next commitment with `local_balance`, `signature` is remote signature of it
+    fn advance(&mut self, local_balance: u64, signature: ByteData) {
+        self.number += 1;
+        self.local_balance = local_balance;
+        self.remote_balance = self.capacity - local_balance;
+        self.remote_signature = signature;
+    }
+
+    /// Save `secret` which revokes current remote commitment
+    fn revoke_remote(
+        &mut self,
+        secret: ByteData,
+        next_revocation_hash: ByteData,
+    ) -> Result<(), String> {
+        if hash_secret(&secret) != self.remote_revocation_hash {
+            return Err(String::from("Invalid revocation secret"));
+        }
+        self.revoked_secrets.push(secret);
+        self.remote_revocation_hash =
+            std::mem::replace(&mut self.remote_next_revocation_hash, next_revocation_hash);
+        Ok(())
+    }
+
+    /// Secret which revokes local commitment `number`
+    fn revocation_secret(&self, number: u64) -> ByteData {
+        let data = [self.seed.as_slice(), &number.to_le_bytes()].concat();
+        Vec::from(Sha256::digest(data).as_slice())
+    }
+
+    /// Hash of the secret which revokes local commitment `number`
+    fn revocation_hash(&self, number: u64) -> ByteData {
+        hash_secret(&self.revocation_secret(number))
+    }
+
+    /// Redeem script of funding output, public key of funder comes first
+    fn funding_script(&self) -> Script {
+        if self.is_funder {
+            multisig_script(&self.local_pub_key, &self.remote_pub_key)
+        } else {
+            multisig_script(&self.remote_pub_key, &self.local_pub_key)
+        }
+    }
+
+    /// Unlocking script of funding output with signatures of both peers
+    fn funding_unlock(&self, local_signature: ByteData, remote_signature: ByteData) -> Script {
+        let (first, second) = if self.is_funder {
+            (local_signature, remote_signature)
+        } else {
+            (remote_signature, local_signature)
+        };
+        Script(vec![
+            Op::PushData(first),
+            Op::PushData(second),
+            Op::PushData(self.funding_script().encode()),
+        ])
+    }
+
+    /// Unsigned transaction spending funding output to `outputs`
+    fn spend_funding(&self, outputs: Vec<TXOutput>) -> Transaction {
+        let input = TXInput {
+            tx_id: Some(self.funding_tx.id),
+            v_out_idx: Some(self.funding_idx),
+            script_sig: Script::default(),
+            sequence: 0,
+        };
+        let outputs = outputs
+            .into_iter()
+            .filter(|out| out.value > 0)
+            .map(Rc::new)
+            .collect();
+        Transaction::new(vec![Rc::new(input)], outputs, 0)
+    }
+
+    /// Commitment held by `owner`, the output of owner is revocable with `revocation_hash`
+    fn commitment(
+        &self,
+        owner_pub_key: &[u8],
+        owner_balance: u64,
+        other_pub_key: &[u8],
+        revocation_hash: &[u8],
+    ) -> Transaction {
+        let owner_output = TXOutput {
+            value: owner_balance,
+            script_pub_key: revocable_script(
+                revocation_hash,
+                other_pub_key,
+                self.delay,
+                owner_pub_key,
+            ),
+            asset: None,
+        };
+        let other_output = TXOutput {
+            value: self.capacity - owner_balance,
+            script_pub_key: Script::p2pkh(&hash_pub_key(other_pub_key)),
+            asset: None,
+        };
+        self.spend_funding(vec![owner_output, other_output])
+    }
+
+    /// Local commitment `number` with `local_balance`
+    fn local_commitment(&self, number: u64, local_balance: u64) -> Transaction {
+        self.commitment(
+            &self.local_pub_key,
+            local_balance,
+            &self.remote_pub_key,
+            &self.revocation_hash(number),
+        )
+    }
+
+    /// Check `signature` of counterparty on local commitment `number` with `local_balance`
+    fn verify_local_commitment(
+        &self,
+        number: u64,
+        local_balance: u64,
+        signature: &[u8],
+    ) -> Result<(), String> {
+        let tx = self.local_commitment(number, local_balance);
+        let sighash = tx.sighash(0, &self.funding_script());
+        if verify_signature(&self.remote_pub_key, &sighash, signature) {
+            Ok(())
+        } else {
+            Err(String::from("Invalid signature of commitment"))
+        }
+    }
+
+    /// Sign current remote commitment in which local peer has `local_balance`
+    fn sign_remote_commitment(&self, wallet: &Wallet, local_balance: u64) -> ByteData {
+        let tx = self.commitment(
+            &self.remote_pub_key,
+            self.capacity - local_balance,
+            &self.local_pub_key,
+            &self.remote_revocation_hash,
+        );
+        Vec::from(wallet.sign(&tx.sighash(0, &self.funding_script())).as_ref())
+    }
+
+    /// Sign next remote commitment in which local peer has `local_balance`
+    fn sign_next_remote_commitment(&self, wallet: &Wallet, local_balance: u64) -> ByteData {
+        let tx = self.commitment(
+            &self.remote_pub_key,
+            self.capacity - local_balance,
+            &self.local_pub_key,
+            &self.remote_next_revocation_hash,
+        );
+        Vec::from(wallet.sign(&tx.sighash(0, &self.funding_script())).as_ref())
+    }
+
+    /// Transaction which pays current balances to both peers, funder comes first
+    fn close_tx(&self) -> Transaction {
+        let local = TXOutput {
+            value: self.local_balance,
+            script_pub_key: Script::p2pkh(&hash_pub_key(&self.local_pub_key)),
+            asset: None,
+        };
+        let remote = TXOutput {
+            value: self.remote_balance,
+            script_pub_key: Script::p2pkh(&hash_pub_key(&self.remote_pub_key)),
+            asset: None,
+        };
+        if self.is_funder {
+            self.spend_funding(vec![local, remote])
+        } else {
+            self.spend_funding(vec![remote, local])
+        }
+    }
+}
+
+/// 2-of-2 multisig redeem script of funding output
+pub fn multisig_script(funder_pub_key: &[u8], acceptor_pub_key: &[u8]) -> Script {
+    Script::multisig(2, &[Vec::from(funder_pub_key), Vec::from(acceptor_pub_key)])
+}
+
+/// Locking script of commitment output which pays to it's owner. The owner can spend it after
+/// `delay` blocks, the other peer can spend it at any time with the revocation secret
+fn revocable_script(
+    revocation_hash: &[u8],
+    other_pub_key: &[u8],
+    delay: u32,
+    owner_pub_key: &[u8],
+) -> Script {
+    Script(vec![
+        Op::If,
+        Op::Sha256,
+        Op::PushData(Vec::from(revocation_hash)),
+        Op::EqualVerify,
+        Op::PushData(Vec::from(other_pub_key)),
+        Op::Else,
+        Op::Num(delay as i64),
+        Op::CheckSequenceVerify,
+        Op::Drop,
+        Op::PushData(Vec::from(owner_pub_key)),
+        Op::EndIf,
+        Op::CheckSig,
+    ])
+}
+
+/// Keys of revocable commitment output
+struct RevocableKeys<'a> {
+    revocation_hash: &'a [u8],
+    other_pub_key: &'a [u8],
+    owner_pub_key: &'a [u8],
+}
+
+/// Parse keys of revocable script, returns `None` for other scripts
+fn parse_revocable_script(script: &Script) -> Option<RevocableKeys<'_>> {
+    match script.0.as_slice() {
+        [Op::If, Op::Sha256, Op::PushData(revocation_hash), Op::EqualVerify, Op::PushData(other), Op::Else, Op::Num(_), Op::CheckSequenceVerify, Op::Drop, Op::PushData(owner), Op::EndIf, Op::CheckSig] => {
+            Some(RevocableKeys {
+                revocation_hash,
+                other_pub_key: other,
+                owner_pub_key: owner,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn hash_secret(secret: &[u8]) -> ByteData {
+    Vec::from(Sha256::digest(secret).as_slice())
+}
+
+/// Generate a random seed of revocation secrets
+fn generate_seed() -> ByteData {
+    let rng = rand::SystemRandom::new();
+    let mut seed = vec![0u8; REVOCATION_SEED_LEN];
+    rng.fill(&mut seed).expect("Generate revocation seed error");
+    seed
+}
+
+#[cfg(test)]
+mod channel_test {
+    use super::*;
+    use crate::script::{verify_script, ScriptContext};
+    use crate::wallet::derive_key_pair;
+
+    fn verify_spend(tx: &Transaction, prev: &TXOutput, redeem: &Script) -> Result<(), String> {
+        let ctx = ScriptContext {
+            sighash: tx.sighash(0, redeem),
+            lock_time: tx.lock_time,
+            sequence: tx.v_in[0].sequence,
+        };
+        verify_script(&tx.v_in[0].script_sig, &prev.script_pub_key, &ctx)
+    }
+
+    #[test]
+    fn reject_forged_message_length() {
+        // Funding transaction of open message claims 2^60 inputs
+        let mut data = vec![0];
+        data.extend(bincode::encode_to_vec([0u8; 32], config::standard()).unwrap());
+        data.push(253);
+        data.extend_from_slice(&(1u64 << 60).to_le_bytes());
+        assert!(ChannelMessage::decode(&bytes2hex(&data)).is_err());
+    }
+
+    #[test]
+    fn pay_and_punish_revoked_commitment() {
+        let alice = Wallet::new(derive_key_pair(&[0u8; 16], &[0]).as_slice());
+        let bob = Wallet::new(derive_key_pair(&[0u8; 16], &[1]).as_slice());
+        let funding_script = multisig_script(alice.public_key(), bob.public_key());
+        let funding_output = TXOutput {
+            value: 100,
+            script_pub_key: Script::p2sh(&funding_script.hash()),
+            asset: None,
+        };
+        let funding_tx = Transaction::new(vec![], vec![Rc::new(funding_output)], 0);
+
+        let (mut a, open) = Channel::open(
+            funding_tx,
+            0,
+            "alice",
+            alice.public_key(),
+            bob.public_key(),
+            6,
+        );
+        let (mut b, accept) = Channel::accept(open, "bob", &bob).unwrap();
+        let (funded, _) = a.receive(accept, &alice).unwrap();
+        b.receive(funded.unwrap(), &bob).unwrap();
+        let old_commitment = Channel::decode(&a.encode())
+            .unwrap()
+            .force_close(&alice)
+            .unwrap();
+
+        // Alice pays 30 to Bob
+        let update = a.pay(30, &alice).unwrap();
+        let (ack, _) = b.receive(update, &bob).unwrap();
+        let (revoke, _) = a.receive(ack.unwrap(), &alice).unwrap();
+        b.receive(revoke.unwrap(), &bob).unwrap();
+        assert_eq!((a.local_balance, b.local_balance), (70, 30));
+        assert_eq!(b.state, ChannelState::Open);
+
+        // Both commitments can spend the funding output
+        let commitment = b.force_close(&bob).unwrap();
+        let funding = Rc::clone(&a.funding_tx.v_out[0]);
+        assert!(verify_spend(&commitment, &funding, &funding_script).is_ok());
+        assert!(verify_spend(&old_commitment, &funding, &funding_script).is_ok());
+
+        // Bob takes all of the revoked commitment of Alice
+        let penalty = b.sweep(&old_commitment, &bob, &bob.get_address()).unwrap();
+        let revoked_output = &old_commitment.v_out[0];
+        assert!(verify_spend(&penalty, revoked_output, &revoked_output.script_pub_key).is_ok());
+        assert_eq!(penalty.v_out[0].value, 100);
+        // Alice can only spend it after the delay
+        let sweep = a
+            .sweep(&old_commitment, &alice, &alice.get_address())
+            .unwrap();
+        assert_eq!(sweep.v_in[0].sequence, 6);
+        assert!(verify_spend(&sweep, revoked_output, &revoked_output.script_pub_key).is_ok());
+    }
+}
@@ -1,11 +1,21 @@
+use std::collections::{BTreeMap, HashSet};
 use std::fmt::{Display, Formatter};
+use std::fs;
 
 use clap::{Parser, Subcommand};
 
-use crate::block_chain::BlockChain;
-use crate::tools::{bytes2hex, hash2str};
-use crate::transaction::{TXInput, TXOutput};
-use crate::wallet::Wallets;
+use crate::block::{ByteData, Hash};
+use crate::block_chain::{find_htlc, AssetInfo, BlockChain, TxOptions};
+use crate::channel::{multisig_script, Channel, ChannelMessage, DEFAULT_CHANNEL_DELAY};
+use crate::codec::Network;
+use crate::coin_selection::CoinSelection;
+use crate::partial_tx::PartialTx;
+use crate::script::Script;
+use crate::server::{scan_block_filters, start_node, submit_to_node, sync_light_client};
+use crate::spv::{find_coins, HeaderChain, HEADERS_FILE};
+use crate::tools::{bytes2hex, hash2str, hex2bytes};
+use crate::transaction::{mint_authority, Htlc, TXInput, TXOutput, Transaction};
+use crate::wallet::{address_script, hash_pub_key, pub_key_hash_address, Wallet, Wallets};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -17,9 +27,6 @@ pub struct Cli {
 pub fn run_cmd() {
     let cli = Cli::parse();
     match &cli.command {
-        Some(Commands::MineBlock { data }) => {
-            // block_chain.mine_block(data);
-        }
         Some(Commands::PrintChain) => {
             match BlockChain::get() {
                 Some(mut block_chain) => {
@@ -32,14 +39,36 @@ pub fn run_cmd() {
         Some(Commands::CreateChain { address }) => {
             BlockChain::create(String::from(address));
         }
-        Some(Commands::Send { from, to, amount }) => match BlockChain::get() {
+        Some(Commands::Send {
+            from,
+            to,
+            amount,
+            strategy,
+            lock_time,
+            relative_lock,
+            fee,
+            out,
+            no_mine,
+        }) => match BlockChain::get() {
             Some(mut block_chain) => {
                 println!("Send {} from {} to {}", amount, from, to);
-                match block_chain.new_tx(from.as_str(), to.as_str(), *amount) {
+                let options = TxOptions {
+                    strategy: *strategy,
+                    lock_time: *lock_time,
+                    relative_lock: *relative_lock,
+                    fee: *fee,
+                };
+                match block_chain.new_tx(from.as_str(), to.as_str(), *amount, &options) {
                     Ok(tx) => {
-                        println!("Create transaction");
-                        block_chain.mine_block(vec![tx]);
-                        println!("Mining block success");
+                        println!("Create transaction {}", hash2str(&tx.id));
+                        match out {
+                            // Time locked transaction can be saved and submitted later
+                            Some(file) => match fs::write(file, bytes2hex(&tx.encode())) {
+                                Ok(_) => println!("Transaction is saved to {}", file),
+                                Err(err) => println!("Write {} error: {}", file, err),
+                            },
+                            None => submit_tx(&mut block_chain, tx, *no_mine),
+                        }
                     }
                     Err(err) => {
                         println!("{}", err);
@@ -48,6 +77,626 @@ pub fn run_cmd() {
             }
             None => println!("Database not exits"),
         },
+        Some(Commands::SendData {
+            from,
+            data,
+            hex,
+            strategy,
+            fee,
+            no_mine,
+        }) => {
+            let data = if *hex {
+                match hex2bytes(data) {
+                    Some(d) => d,
+                    None => {
+                        println!("Invalid hex data");
+                        return;
+                    }
+                }
+            } else {
+                Vec::from(data.as_bytes())
+            };
+            match BlockChain::get() {
+                Some(mut block_chain) => {
+                    let options = TxOptions {
+                        strategy: *strategy,
+                        fee: *fee,
+                        ..TxOptions::default()
+                    };
+                    match block_chain.new_data_tx(from.as_str(), &data, &options) {
+                        Ok(tx) => {
+                            println!("Create transaction {}", hash2str(&tx.id));
+                            submit_tx(&mut block_chain, tx, *no_mine);
+                        }
+                        Err(err) => println!("{}", err),
+                    }
+                }
+                None => println!("Database not exits"),
+            }
+        }
+        Some(Commands::BlockData { hash }) => {
+            let hash: Option<Hash> = match hash {
+                Some(hash) => match parse_hash(hash) {
+                    Some(h) => Some(h),
+                    None => {
+                        println!("Invalid block hash");
+                        return;
+                    }
+                },
+                None => None,
+            };
+            let mut block_chain = match BlockChain::get() {
+                Some(block_chain) => block_chain,
+                None => {
+                    println!("Database not exits");
+                    return;
+                }
+            };
+            let block = match block_chain.get_block(hash.as_ref()) {
+                Some(block) => block,
+                None => {
+                    println!("Can not find block");
+                    return;
+                }
+            };
+            println!("Data of block {}:", hash2str(&block.hash));
+            for tx in block.transactions.iter() {
+                for (idx, out) in tx.v_out.iter().enumerate() {
+                    if let Some(data) = out.script_pub_key.carried_data() {
+                        // Show text data as it is, otherwise show it as hex
+                        let text = match std::str::from_utf8(data) {
+                            Ok(text) => String::from(text),
+                            Err(_) => bytes2hex(data),
+                        };
+                        println!("{}/{}: {}", hash2str(&tx.id), idx, text);
+                    }
+                }
+            }
+        }
+        Some(Commands::BlockFilter { hash }) => {
+            let mut block_chain = match BlockChain::get() {
+                Some(block_chain) => block_chain,
+                None => {
+                    println!("Database not exits");
+                    return;
+                }
+            };
+            let hash = match hash {
+                Some(hash) => match parse_hash(hash) {
+                    Some(h) => h,
+                    None => {
+                        println!("Invalid block hash");
+                        return;
+                    }
+                },
+                None => block_chain.tip,
+            };
+            match block_chain.get_filter(&hash) {
+                Some((filter, header)) => {
+                    println!("Filter of block {}:", hash2str(&hash));
+                    println!("Items: {}", filter.count);
+                    println!("Filter: {}", bytes2hex(&filter.data));
+                    println!("Filter hash: {}", hash2str(&filter.hash()));
+                    println!("Filter header: {}", hash2str(&header));
+                }
+                None => println!("Can not find block"),
+            }
+        }
+        Some(Commands::SwapInitiate {
+            from,
+            to,
+            amount,
+            lock_time,
+            secret_hash,
+        }) => {
+            let recipient =
+                match address_script(to).map(|script| script.p2pkh_hash().map(Vec::from)) {
+                    Ok(Some(hash)) => hash,
+                    _ => {
+                        println!("Recipient must be a pay to public key hash address");
+                        return;
+                    }
+                };
+            let refund = match Wallets::new().get_wallet(from) {
+                Some(wallet) => hash_pub_key(wallet.public_key()),
+                None => {
+                    println!("Can not get wallet for address {}", from);
+                    return;
+                }
+            };
+            // The initiator creates the secret, the participant uses hash of initiator's secret
+            let secret_hash = match secret_hash {
+                Some(hash) => match hex2bytes(hash) {
+                    Some(h) if h.len() == 32 => h,
+                    _ => {
+                        println!("Invalid secret hash");
+                        return;
+                    }
+                },
+                None => {
+                    let secret = Htlc::generate_secret();
+                    println!("Secret: {}", bytes2hex(&secret));
+                    Htlc::hash_secret(&secret)
+                }
+            };
+            println!("Secret hash: {}", bytes2hex(&secret_hash));
+            let htlc = Htlc {
+                secret_hash,
+                recipient,
+                refund,
+                lock_time: *lock_time,
+            };
+            match BlockChain::get() {
+                Some(mut block_chain) => {
+                    match block_chain.new_htlc_tx(from, &htlc, *amount, &TxOptions::default()) {
+                        Ok(tx) => {
+                            println!("Contract transaction {}", hash2str(&tx.id));
+                            submit_tx(&mut block_chain, tx, false);
+                        }
+                        Err(err) => println!("{}", err),
+                    }
+                }
+                None => println!("Database not exits"),
+            }
+        }
+        Some(Commands::SwapClaim { tx, secret, to }) => {
+            let secret = match hex2bytes(secret) {
+                Some(s) => s,
+                None => {
+                    println!("Invalid secret");
+                    return;
+                }
+            };
+            spend_htlc(tx, Some(&secret), to.as_deref());
+        }
+        Some(Commands::SwapRefund { tx, to }) => spend_htlc(tx, None, to.as_deref()),
+        Some(Commands::SwapAudit { tx }) => {
+            let tx_id = match parse_hash(tx) {
+                Some(id) => id,
+                None => {
+                    println!("Invalid transaction id");
+                    return;
+                }
+            };
+            let mut block_chain = match BlockChain::get() {
+                Some(block_chain) => block_chain,
+                None => {
+                    println!("Database not exits");
+                    return;
+                }
+            };
+            let (contract_tx, height) = match block_chain.find_transaction(&tx_id) {
+                Some(found) => found,
+                None => {
+                    println!("Can not find transaction {}", tx);
+                    return;
+                }
+            };
+            let (out_idx, out, htlc) = match find_htlc(&contract_tx) {
+                Some(found) => found,
+                None => {
+                    println!("Transaction doesn't have contract output");
+                    return;
+                }
+            };
+            println!("Contract output: {}/{}", hash2str(&tx_id), out_idx);
+            println!("Value: {}", out.value);
+            println!("Recipient: {}", pub_key_hash_address(&htlc.recipient));
+            println!("Refund: {}", pub_key_hash_address(&htlc.refund));
+            println!("Secret hash: {}", bytes2hex(&htlc.secret_hash));
+            println!("Lock time: {}", htlc.lock_time);
+            println!("Confirmations: {}", block_chain.tip_height() - height + 1);
+            match block_chain.find_spending_input(&tx_id, out_idx) {
+                Some((spending_tx, input)) => match Htlc::extract_secret(&input.script_sig) {
+                    Some(secret) => println!(
+                        "Claimed by {}, secret: {}",
+                        hash2str(&spending_tx.id),
+                        bytes2hex(secret)
+                    ),
+                    None => println!("Refunded by {}", hash2str(&spending_tx.id)),
+                },
+                None => println!("Unspent"),
+            }
+        }
+        Some(Commands::ChannelOpen {
+            from,
+            remote_pub_key,
+            amount,
+            delay,
+            channel,
+            message,
+        }) => {
+            let remote_pub_key = match hex2bytes(remote_pub_key) {
+                Some(key) => key,
+                None => {
+                    println!("Invalid public key");
+                    return;
+                }
+            };
+            let wallet = match Wallets::new().get_wallet(from) {
+                Some(w) => w,
+                None => {
+                    println!("Can not get wallet for address {}", from);
+                    return;
+                }
+            };
+            let mut block_chain = match BlockChain::get() {
+                Some(block_chain) => block_chain,
+                None => {
+                    println!("Database not exits");
+                    return;
+                }
+            };
+            // The funding transaction is submitted once the first commitment is signed
+            let redeem_script = multisig_script(wallet.public_key(), &remote_pub_key);
+            let funding_tx = match block_chain.new_funding_tx(
+                from,
+                &redeem_script,
+                *amount,
+                &TxOptions::default(),
+            ) {
+                Ok(tx) => tx,
+                Err(err) => {
+                    println!("{}", err);
+                    return;
+                }
+            };
+            let (state, msg) = Channel::open(
+                funding_tx,
+                0,
+                from,
+                wallet.public_key(),
+                &remote_pub_key,
+                *delay,
+            );
+            if let Err(err) = write_message(message, &msg).and(write_channel(channel, &state)) {
+                println!("{}", err);
+                return;
+            }
+            println!(
+                "Open channel of {}, send {} to the counterparty",
+                amount, message
+            );
+        }
+        Some(Commands::ChannelAccept {
+            address,
+            channel,
+            message,
+        }) => {
+            let wallet = match Wallets::new().get_wallet(address) {
+                Some(w) => w,
+                None => {
+                    println!("Can not get wallet for address {}", address);
+                    return;
+                }
+            };
+            let result = read_message(message)
+                .and_then(|msg| Channel::accept(msg, address, &wallet))
+                .and_then(|(state, msg)| {
+                    write_message(message, &msg)?;
+                    write_channel(channel, &state)?;
+                    Ok(state)
+                });
+            match result {
+                Ok(state) => println!(
+                    "Accept channel of {}, send {} to the counterparty",
+                    state.capacity, message
+                ),
+                Err(err) => println!("{}", err),
+            }
+        }
+        Some(Commands::ChannelPay {
+            channel,
+            amount,
+            message,
+        }) => {
+            let result = read_channel(channel).and_then(|mut state| {
+                let wallet = channel_wallet(&state)?;
+                let msg = state.pay(*amount, &wallet)?;
+                write_message(message, &msg)?;
+                write_channel(channel, &state)
+            });
+            match result {
+                Ok(_) => println!("Pay {}, send {} to the counterparty", amount, message),
+                Err(err) => println!("{}", err),
+            }
+        }
+        Some(Commands::ChannelReceive { channel, message }) => {
+            let mut state = match read_channel(channel) {
+                Ok(state) => state,
+                Err(err) => {
+                    println!("{}", err);
+                    return;
+                }
+            };
+            let result = channel_wallet(&state).and_then(|wallet| {
+                let msg = read_message(message)?;
+                state.receive(msg, &wallet)
+            });
+            let (reply, tx) = match result {
+                Ok(received) => received,
+                Err(err) => {
+                    println!("{}", err);
+                    return;
+                }
+            };
+            if let Err(err) = write_channel(channel, &state) {
+                println!("{}", err);
+                return;
+            }
+            println!(
+                "Channel is {:?}, local balance: {}, remote balance: {}",
+                state.state, state.local_balance, state.remote_balance
+            );
+            if let Some(reply) = reply {
+                if let Err(err) = write_message(message, &reply) {
+                    println!("{}", err);
+                    return;
+                }
+                println!("Send {} to the counterparty", message);
+            }
+            if let Some(tx) = tx {
+                match BlockChain::get() {
+                    Some(mut block_chain) => submit_tx(&mut block_chain, tx, false),
+                    None => println!("Database not exits"),
+                }
+            }
+        }
+        Some(Commands::ChannelClose { channel, message }) => {
+            let result = read_channel(channel).and_then(|mut state| {
+                let wallet = channel_wallet(&state)?;
+                let msg = state.close(&wallet)?;
+                write_message(message, &msg)?;
+                write_channel(channel, &state)
+            });
+            match result {
+                Ok(_) => println!("Close channel, send {} to the counterparty", message),
+                Err(err) => println!("{}", err),
+            }
+        }
+        Some(Commands::ChannelForceClose { channel }) => {
+            let result = read_channel(channel).and_then(|mut state| {
+                let wallet = channel_wallet(&state)?;
+                let tx = state.force_close(&wallet)?;
+                write_channel(channel, &state)?;
+                Ok(tx)
+            });
+            match (result, BlockChain::get()) {
+                (Ok(tx), Some(mut block_chain)) => submit_tx(&mut block_chain, tx, false),
+                (Err(err), _) => println!("{}", err),
+                (_, None) => println!("Database not exits"),
+            }
+        }
+        Some(Commands::ChannelSweep { channel, to }) => {
+            let mut block_chain = match BlockChain::get() {
+                Some(block_chain) => block_chain,
+                None => {
+                    println!("Database not exits");
+                    return;
+                }
+            };
+            let result = read_channel(channel).and_then(|state| {
+                let wallet = channel_wallet(&state)?;
+                let closing_tx = match block_chain
+                    .find_spending_input(&state.funding_tx.id, state.funding_idx)
+                {
+                    Some((tx, _)) => tx,
+                    None => return Err(String::from("Channel isn't closed on chain")),
+                };
+                let to = to.clone().unwrap_or_else(|| state.local_address.clone());
+                state.sweep(&closing_tx, &wallet, &to)
+            });
+            match result {
+                Ok(tx) => submit_tx(&mut block_chain, tx, false),
+                Err(err) => println!("{}", err),
+            }
+        }
+        Some(Commands::ChannelInfo { channel }) => match read_channel(channel) {
+            Ok(state) => {
+                println!(
+                    "Funding output: {}/{}",
+                    hash2str(&state.funding_tx.id),
+                    state.funding_idx
+                );
+                println!("Capacity: {}", state.capacity);
+                println!("State: {:?}", state.state);
+                println!("Commitment number: {}", state.number);
+                println!("Local balance: {}", state.local_balance);
+                println!("Remote balance: {}", state.remote_balance);
+                println!("Remote public key: {}", bytes2hex(&state.remote_pub_key));
+            }
+            Err(err) => println!("{}", err),
+        },
+        Some(Commands::IssueAsset {
+            from,
+            name,
+            amount,
+            mintable,
+        }) => match BlockChain::get() {
+            Some(mut block_chain) => {
+                let options = TxOptions::default();
+                match block_chain.new_issue_tx(from, name, *amount, *mintable, &options) {
+                    Ok(tx) => {
+                        if let Some((asset, _)) = tx.issued_assets().first() {
+                            println!("Issue asset {}: {}", name, hash2str(asset));
+                        }
+                        submit_tx(&mut block_chain, tx, false);
+                    }
+                    Err(err) => println!("{}", err),
+                }
+            }
+            None => println!("Database not exits"),
+        },
+        Some(Commands::MintAsset {
+            from,
+            asset,
+            to,
+            amount,
+        }) => {
+            let asset = match parse_hash(asset) {
+                Some(asset) => asset,
+                None => {
+                    println!("Invalid asset id");
+                    return;
+                }
+            };
+            match BlockChain::get() {
+                Some(mut block_chain) => {
+                    let to = to.as_deref().unwrap_or(from);
+                    let options = TxOptions::default();
+                    match block_chain.new_mint_tx(from, &asset, to, *amount, &options) {
+                        Ok(tx) => submit_tx(&mut block_chain, tx, false),
+                        Err(err) => println!("{}", err),
+                    }
+                }
+                None => println!("Database not exits"),
+            }
+        }
+        Some(Commands::SendAsset {
+            from,
+            to,
+            asset,
+            amount,
+            strategy,
+            fee,
+        }) => {
+            let asset = match parse_hash(asset) {
+                Some(asset) => asset,
+                None => {
+                    println!("Invalid asset id");
+                    return;
+                }
+            };
+            match BlockChain::get() {
+                Some(mut block_chain) => {
+                    let options = TxOptions {
+                        strategy: *strategy,
+                        fee: *fee,
+                        ..TxOptions::default()
+                    };
+                    match block_chain.new_asset_tx(from, to, &asset, *amount, &options) {
+                        Ok(tx) => submit_tx(&mut block_chain, tx, false),
+                        Err(err) => println!("{}", err),
+                    }
+                }
+                None => println!("Database not exits"),
+            }
+        }
+        Some(Commands::Assets) => match BlockChain::get() {
+            Some(mut block_chain) => {
+                for (asset, info) in block_chain.find_assets() {
+                    println!(
+                        "{} {}: supply {}{}",
+                        hash2str(&asset),
+                        info.name,
+                        info.supply,
+                        if info.mintable { " (mintable)" } else { "" }
+                    );
+                }
+            }
+            None => println!("Database not exits"),
+        },
+        Some(Commands::SubmitTx {
+            file,
+            no_mine,
+            node,
+            network,
+        }) => {
+            let tx = match fs::read_to_string(file) {
+                Ok(text) => hex2bytes(text.trim()).and_then(|data| Transaction::decode(&data)),
+                Err(err) => {
+                    println!("Read {} error: {}", file, err);
+                    return;
+                }
+            };
+            let tx = match tx {
+                Some(tx) => tx,
+                None => {
+                    println!("Invalid transaction in {}", file);
+                    return;
+                }
+            };
+            if let Some(addr) = node {
+                match submit_to_node(addr, tx, *network) {
+                    Ok(_) => println!("Transaction is sent to {}", addr),
+                    Err(err) => println!("Submit transaction failed: {}", err),
+                }
+                return;
+            }
+            match BlockChain::get() {
+                Some(mut block_chain) => submit_tx(&mut block_chain, tx, *no_mine),
+                None => println!("Database not exits"),
+            }
+        }
+        Some(Commands::BumpFee {
+            tx_id,
+            fee,
+            no_mine,
+        }) => {
+            let tx_id = match parse_hash(tx_id) {
+                Some(id) => id,
+                None => {
+                    println!("Invalid transaction id");
+                    return;
+                }
+            };
+            match BlockChain::get() {
+                Some(mut block_chain) => match block_chain.new_bump_fee_tx(&tx_id, *fee) {
+                    Ok(tx) => {
+                        println!("Create transaction {}", hash2str(&tx.id));
+                        submit_tx(&mut block_chain, tx, *no_mine);
+                    }
+                    Err(err) => println!("{}", err),
+                },
+                None => println!("Database not exits"),
+            }
+        }
+        Some(Commands::StartNode {
+            listen,
+            peer,
+            mine,
+            network,
+        }) => {
+            if let Err(err) = start_node(*listen, peer, mine.clone(), *network) {
+                println!("{}", err);
+            }
+        }
+        Some(Commands::Mine { address }) => match BlockChain::get() {
+            Some(mut block_chain) => match block_chain.mine_pending(address.as_deref()) {
+                Ok(_) => println!("Mining block success"),
+                Err(err) => println!("Mining block failed: {}", err),
+            },
+            None => println!("Database not exits"),
+        },
+        Some(Commands::Mempool) => match BlockChain::get() {
+            Some(mut block_chain) => {
+                let mempool = block_chain.get_mempool();
+                println!(
+                    "{} pending transactions, {} bytes",
+                    mempool.txs().len(),
+                    mempool.size()
+                );
+                for tx in mempool.txs() {
+                    println!("transaction: {}", hash2str(&tx.id));
+                    if let Some(fee) = mempool.fee(&tx.id) {
+                        println!("fee: {}", fee);
+                    }
+                    if tx.lock_time > 0 {
+                        println!("lock_time: {}", tx.lock_time);
+                    }
+                    println!("inputs:");
+                    for input in tx.v_in.iter() {
+                        println!("{}", input);
+                    }
+                    println!("outputs:");
+                    for out in tx.v_out.iter() {
+                        println!("{}", out)
+                    }
+                    println!();
+                }
+            }
+            None => println!("Database not exits"),
+        },
         Some(Commands::Balance { address }) => {
             let mut block_chain = match BlockChain::get() {
                 Some(block_chain) => block_chain,
@@ -56,53 +705,426 @@ pub fn run_cmd() {
                     return;
                 }
             };
-            // let pub_key_hash = extract_pub_key_hash(address);
-            // for block in BlockChainIter::new(&mut block_chain) {
-            //     for tx in block.transactions {
-            //         let is_spent = match tx.v_in.get(0) {
-            //             Some(input) => input.use_key(&pub_key_hash),
-            //             None => false,
-            //         };
-            //         let is_recv = match tx.v_out.get(0) {
-            //             Some(output) => output.is_locking_with_key(&pub_key_hash),
-            //             None => false,
-            //         };
-            //
-            //         if is_spent || is_recv {
-            //             println!("transaction: {}", hash2str(&tx.id));
-            //             println!("inputs:");
-            //             for input in tx.v_in {
-            //                 println!("{}", input);
-            //             }
-            //             println!("outputs:");
-            //             for out in tx.v_out {
-            //                 println!("{}", out)
-            //             }
-            //             println!();
-            //         }
-            //     }
-            // }
-
+            let mut balances = block_chain.get_asset_balances(address);
             println!(
                 "Balance of {}: {}",
                 address,
-                block_chain.get_balance(address)
+                balances.remove(&None).unwrap_or(0)
             );
+            let assets = block_chain.find_assets();
+            for (asset, balance) in balances {
+                if let Some(asset) = asset {
+                    println!("{}: {}", asset_name(&assets, &asset), balance);
+                }
+            }
+        }
+        Some(Commands::LightBalance {
+            node,
+            network,
+            confirmations,
+            private,
+        }) => {
+            let addresses = Wallets::new().get_addresses();
+            let scripts: Vec<Script> = addresses
+                .iter()
+                .filter_map(|address| address_script(address).ok())
+                .collect();
+            let mut chain = HeaderChain::load(HEADERS_FILE);
+            let txs = if *private {
+                scan_block_filters(
+                    node,
+                    *network,
+                    &mut chain,
+                    &scripts.iter().cloned().collect(),
+                )
+            } else {
+                sync_light_client(node, *network, &mut chain, scripts.clone())
+                    .and_then(|proofs| chain.verify_proofs(proofs))
+            };
+            if let Err(err) = chain.save(HEADERS_FILE) {
+                println!("{}", err);
+            }
+            let txs = match txs {
+                Ok(txs) => txs,
+                Err(err) => {
+                    println!("{}", err);
+                    return;
+                }
+            };
+            let tip_height = chain.tip_height().unwrap_or(0);
+            let coins = find_coins(&txs, &scripts.into_iter().collect());
+            println!("Best header height {}", tip_height);
+            for address in addresses {
+                let script = match address_script(&address) {
+                    Ok(script) => script,
+                    Err(_) => continue,
+                };
+                let (mut total, mut confirmed) = (0, 0);
+                for coin in coins.iter().filter(|coin| coin.script == script) {
+                    total += coin.value;
+                    if tip_height - coin.height + 1 >= *confirmations {
+                        confirmed += coin.value;
+                    }
+                }
+                println!(
+                    "Balance of {}: {}, {} with at least {} confirmations",
+                    address, total, confirmed, confirmations
+                );
+            }
         }
         Some(Commands::CreateWallet) => {
-            let mut wallets = Wallets::new();
-            let address = wallets.create_wallet();
+            let (address, mnemonic) = Wallets::new().create_wallet();
+            if let Some(mnemonic) = mnemonic {
+                println!("Write down your mnemonic to backup wallets: {}", mnemonic);
+            }
             println!("Your address is: {}", address);
         }
+        Some(Commands::ShowMnemonic) => match Wallets::new().mnemonic() {
+            Some(mnemonic) => println!("{}", mnemonic),
+            None => println!("Wallets have no mnemonic, create a wallet first"),
+        },
+        Some(Commands::RestoreWallet { phrase }) => {
+            // Used public key hashes in the chain, addresses can't be found without the chain
+            let used_key_hashes = match BlockChain::get() {
+                Some(mut block_chain) => block_chain.used_key_hashes(),
+                None => HashSet::new(),
+            };
+            let mut wallets = Wallets::new();
+            match wallets.restore(phrase, |key_hash| used_key_hashes.contains(key_hash)) {
+                Ok(addresses) => {
+                    println!("Restore {} addresses", addresses.len());
+                    for address in addresses {
+                        println!("{}", address);
+                    }
+                }
+                Err(err) => println!("{}", err),
+            }
+        }
+        Some(Commands::WatchAddress { address }) => match Wallets::new().add_watch_only(address) {
+            Ok(_) => println!("Watching address: {}", address),
+            Err(err) => println!("{}", err),
+        },
+        Some(Commands::WalletBalance) => {
+            let mut block_chain = match BlockChain::get() {
+                Some(block_chain) => block_chain,
+                None => {
+                    println!("Database not exits");
+                    return;
+                }
+            };
+            let wallets = Wallets::new();
+            let mut total = 0u64;
+            // Total balance of each asset
+            let mut asset_totals: BTreeMap<Hash, u64> = BTreeMap::new();
+            for address in wallets.get_addresses() {
+                let mut balances = block_chain.get_asset_balances(&address);
+                let balance = balances.remove(&None).unwrap_or(0);
+                for (asset, value) in balances {
+                    if let Some(asset) = asset {
+                        *asset_totals.entry(asset).or_default() += value;
+                    }
+                }
+                let kind = if wallets.is_watch_only(&address) {
+                    " (watch-only)"
+                } else if wallets.get_multisig_script(&address).is_some() {
+                    " (multisig)"
+                } else if wallets.is_change(&address) {
+                    " (change)"
+                } else {
+                    ""
+                };
+                println!("{}{}: {}", address, kind, balance);
+                total += balance;
+            }
+            println!("Total balance: {}", total);
+            let assets = block_chain.find_assets();
+            for (asset, value) in asset_totals {
+                println!("{}: {}", asset_name(&assets, &asset), value);
+            }
+        }
+        Some(Commands::History { address }) => {
+            let mut block_chain = match BlockChain::get() {
+                Some(block_chain) => block_chain,
+                None => {
+                    println!("Database not exits");
+                    return;
+                }
+            };
+            // Show history of all wallet addresses if no address is specified
+            let addresses = match address {
+                Some(address) => vec![address.clone()],
+                None => Wallets::new().get_addresses(),
+            };
+            let mut scripts = HashSet::new();
+            for address in addresses {
+                match address_script(&address) {
+                    Ok(script) => scripts.insert(script),
+                    Err(err) => {
+                        println!("{}", err);
+                        return;
+                    }
+                };
+            }
+            for tx in block_chain.find_history(&scripts) {
+                println!("transaction: {}", hash2str(&tx.id));
+                println!("inputs:");
+                for input in tx.v_in.iter() {
+                    println!("{}", input);
+                }
+                println!("outputs:");
+                for out in tx.v_out.iter() {
+                    println!("{}", out)
+                }
+                println!();
+            }
+        }
+        Some(Commands::ListAddresses) => {
+            let wallets = Wallets::new();
+            // Balance is only available when the chain exists
+            let mut block_chain = BlockChain::get();
+            for address in wallets.get_addresses() {
+                let balance = block_chain
+                    .as_mut()
+                    .map(|block_chain| block_chain.get_balance(&address));
+                println!("{}", address_line(&wallets, &address, balance));
+            }
+        }
+        Some(Commands::SetLabel { address, label }) => {
+            match Wallets::new().set_label(address, label) {
+                Ok(_) => println!("Set label of {}: {}", address, label),
+                Err(err) => println!("{}", err),
+            }
+        }
+        Some(Commands::PubKey { address }) => match Wallets::new().get_wallet(address) {
+            Some(wallet) => println!("{}", bytes2hex(wallet.public_key())),
+            None => println!("Can not get wallet for address {}", address),
+        },
+        Some(Commands::CreateMultisig { required, pub_keys }) => {
+            let pub_keys: Vec<ByteData> = match pub_keys.iter().map(|k| hex2bytes(k)).collect() {
+                Some(keys) => keys,
+                None => {
+                    println!("Invalid public key");
+                    return;
+                }
+            };
+            match Wallets::new().add_multisig(*required, &pub_keys) {
+                Ok(address) => println!("Multisig address: {}", address),
+                Err(err) => println!("{}", err),
+            }
+        }
+        Some(Commands::MultisigSpend {
+            from,
+            to,
+            amount,
+            strategy,
+            fee,
+            file,
+        }) => match BlockChain::get() {
+            Some(mut block_chain) => {
+                let options = TxOptions {
+                    strategy: *strategy,
+                    fee: *fee,
+                    ..TxOptions::default()
+                };
+                match block_chain.new_multisig_tx(from.as_str(), to.as_str(), *amount, &options) {
+                    Ok(partial_tx) => {
+                        if let Err(err) = write_partial_tx(file, &partial_tx) {
+                            println!("{}", err);
+                            return;
+                        }
+                        println!("Partial transaction is saved to {}", file);
+                    }
+                    Err(err) => println!("{}", err),
+                }
+            }
+            None => println!("Database not exits"),
+        },
+        Some(Commands::MultisigSign { file, address }) => {
+            let mut partial_tx = match read_partial_tx(file) {
+                Ok(tx) => tx,
+                Err(err) => {
+                    println!("{}", err);
+                    return;
+                }
+            };
+            let wallet = match Wallets::new().get_wallet(address) {
+                Some(w) => w,
+                None => {
+                    println!("Can not get wallet for address {}", address);
+                    return;
+                }
+            };
+            let signed = partial_tx.sign(&wallet);
+            if let Err(err) = write_partial_tx(file, &partial_tx) {
+                println!("{}", err);
+                return;
+            }
+            println!("Sign {} inputs", signed);
+            for (i, (collected, required)) in partial_tx.progress().iter().enumerate() {
+                println!("input {}: {} of {} signatures", i, collected, required);
+            }
+        }
+        Some(Commands::MultisigSubmit { file }) => {
+            let tx = match read_partial_tx(file).and_then(|partial_tx| partial_tx.finalize()) {
+                Ok(tx) => tx,
+                Err(err) => {
+                    println!("{}", err);
+                    return;
+                }
+            };
+            match BlockChain::get() {
+                Some(mut block_chain) => submit_tx(&mut block_chain, tx, false),
+                None => println!("Database not exits"),
+            }
+        }
+        Some(Commands::ExportKey { address }) => match Wallets::new().export_key(address) {
+            Some(key) => println!("{}", key),
+            None => println!("Can not find wallet for address {}", address),
+        },
+        Some(Commands::ImportKey { key, label, rescan }) => {
+            let address = match Wallets::new().import_key(key, label.as_deref()) {
+                Ok(address) => address,
+                Err(err) => {
+                    println!("{}", err);
+                    return;
+                }
+            };
+            println!("Import key of address: {}", address);
+            if *rescan {
+                match BlockChain::get() {
+                    Some(mut block_chain) => println!(
+                        "Balance of {}: {}",
+                        address,
+                        block_chain.get_balance(&address)
+                    ),
+                    None => println!("Database not exits"),
+                }
+            }
+        }
         None => {}
     }
 }
 
+/// Claim the contract of transaction `tx` with `secret`, or refund it if secret is `None`
+fn spend_htlc(tx: &str, secret: Option<&[u8]>, to: Option<&str>) {
+    let tx_id = match parse_hash(tx) {
+        Some(id) => id,
+        None => {
+            println!("Invalid transaction id");
+            return;
+        }
+    };
+    match BlockChain::get() {
+        Some(mut block_chain) => match block_chain.spend_htlc_tx(&tx_id, secret, to) {
+            Ok(tx) => {
+                println!("Create transaction {}", hash2str(&tx.id));
+                submit_tx(&mut block_chain, tx, false);
+            }
+            Err(err) => println!("{}", err),
+        },
+        None => println!("Database not exits"),
+    }
+}
+
+/// Wallet whose key signs for local peer of `channel`
+fn channel_wallet(channel: &Channel) -> Result<Wallet, String> {
+    Wallets::new()
+        .get_wallet(&channel.local_address)
+        .ok_or(format!(
+            "Can not get wallet for address {}",
+            channel.local_address
+        ))
+}
+
+/// Save channel state to `file` as hex text
+fn write_channel(file: &str, channel: &Channel) -> Result<(), String> {
+    fs::write(file, channel.encode()).map_err(|err| format!("Write {} error: {}", file, err))
+}
+
+/// Read channel state from hex text in `file`
+fn read_channel(file: &str) -> Result<Channel, String> {
+    let text = fs::read_to_string(file).map_err(|err| format!("Read {} error: {}", file, err))?;
+    Channel::decode(&text)
+}
+
+/// Save channel message to `file` as hex text
+fn write_message(file: &str, message: &ChannelMessage) -> Result<(), String> {
+    fs::write(file, message.encode()).map_err(|err| format!("Write {} error: {}", file, err))
+}
+
+/// Read channel message from hex text in `file`
+fn read_message(file: &str) -> Result<ChannelMessage, String> {
+    let text = fs::read_to_string(file).map_err(|err| format!("Read {} error: {}", file, err))?;
+    ChannelMessage::decode(&text)
+}
+
+/// Display name of `asset`, the mint authority of an asset is named after it
+fn asset_name(assets: &BTreeMap<Hash, AssetInfo>, asset: &Hash) -> String {
+    if let Some(info) = assets.get(asset) {
+        return format!("{} ({})", info.name, hash2str(asset));
+    }
+    match assets.iter().find(|(id, _)| mint_authority(id) == *asset) {
+        Some((_, info)) => format!("{} mint authority ({})", info.name, hash2str(asset)),
+        None => hash2str(asset),
+    }
+}
+
+/// Line of `address` listed by list-addresses, with it's label, kind and `balance`
+fn address_line(wallets: &Wallets, address: &str, balance: Option<u64>) -> String {
+    let mut line = String::from(address);
+    if let Some(label) = wallets.get_label(address) {
+        line += format!(" [{}]", label).as_str();
+    }
+    if wallets.is_watch_only(address) {
+        line += " (watch-only)";
+    } else if wallets.get_multisig_script(address).is_some() {
+        line += " (multisig)";
+    } else if wallets.is_change(address) {
+        line += " (change)";
+    }
+    if let Some(balance) = balance {
+        line += format!(": {}", balance).as_str();
+    }
+    line
+}
+
+/// Parse hex text of a block or transaction hash
+fn parse_hash(text: &str) -> Option<Hash> {
+    hex2bytes(text).and_then(|h| h.try_into().ok())
+}
+
+/// Add transaction to mempool, and mine pending transactions unless `no_mine` is set
+fn submit_tx(block_chain: &mut BlockChain, tx: Transaction, no_mine: bool) {
+    if let Err(err) = block_chain.submit_tx(tx) {
+        println!("Submit transaction failed: {}", err);
+        return;
+    }
+    println!("Transaction is added to mempool");
+    if no_mine {
+        return;
+    }
+    match block_chain.mine_pending(None) {
+        Ok(_) => println!("Mining block success"),
+        Err(err) => println!("Mining block failed: {}", err),
+    }
+}
+
+/// Save partial transaction to `file` as hex text
+fn write_partial_tx(file: &str, partial_tx: &PartialTx) -> Result<(), String> {
+    fs::write(file, partial_tx.encode()).map_err(|err| format!("Write {} error: {}", file, err))
+}
+
+/// Read partial transaction from hex text in `file`
+fn read_partial_tx(file: &str) -> Result<PartialTx, String> {
+    let text = fs::read_to_string(file).map_err(|err| format!("Read {} error: {}", file, err))?;
+    PartialTx::decode(&text)
+}
+
 impl Display for TXInput {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "tx_id: {}\nv_out_idx: {}\npublic key hash: {} \nsignature: {}",
+            "tx_id: {}\nv_out_idx: {}\nscript_sig: {}",
             match self.tx_id {
                 Some(hash) => hash2str(&hash),
                 None => String::from("None"),
@@ -111,11 +1133,7 @@ impl Display for TXInput {
                 Some(idx) => idx.to_string(),
                 None => String::from("None"),
             },
-            bytes2hex(self.pub_key.as_slice()),
-            match &self.signature {
-                Some(bytes) => bytes2hex(bytes.as_slice()),
-                None => String::from("None"),
-            }
+            self.script_sig,
         )
     }
 }
@@ -124,9 +1142,13 @@ impl Display for TXOutput {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "value: {}\nscript_pub_key: {}",
+            "value: {}{}\nscript_pub_key: {}",
             self.value,
-            bytes2hex(self.pub_key_hash.as_slice()),
+            match self.asset {
+                Some(asset) => format!(" of asset {}", hash2str(&asset)),
+                None => String::new(),
+            },
+            self.script_pub_key,
         )
     }
 }
@@ -136,20 +1158,390 @@ enum Commands {
     CreateChain {
         address: String,
     },
-    MineBlock {
+    Send {
+        #[arg(long)]
+        from: String,
+        #[arg(long)]
+        to: String,
+        #[arg(long)]
+        amount: u64,
+        /// Strategy to select unspent outputs
+        #[arg(long, value_enum, default_value_t)]
+        strategy: CoinSelection,
+        /// Block height, or timestamp if it's not less than 500000000, before which the
+        /// transaction can't be mined
+        #[arg(long, default_value_t = 0)]
+        lock_time: u64,
+        /// Number of blocks the spent outputs must be confirmed before the transaction is mined
+        #[arg(long, default_value_t = 0)]
+        relative_lock: u32,
+        /// Fee paid to miner
+        #[arg(long, default_value_t = 0)]
+        fee: u64,
+        /// Save the signed transaction to file instead of submitting it
+        #[arg(long)]
+        out: Option<String>,
+        /// Add the transaction to mempool without mining a block
+        #[arg(long)]
+        no_mine: bool,
+    },
+    /// Embed data in an unspendable output, the transaction is paid by `from`
+    SendData {
+        #[arg(long)]
+        from: String,
+        /// Text data, or hex data if `--hex` is set
+        #[arg(long)]
         data: String,
+        #[arg(long)]
+        hex: bool,
+        /// Strategy to select unspent outputs
+        #[arg(long, value_enum, default_value_t)]
+        strategy: CoinSelection,
+        /// Fee paid to miner
+        #[arg(long, default_value_t = 0)]
+        fee: u64,
+        /// Add the transaction to mempool without mining a block
+        #[arg(long)]
+        no_mine: bool,
     },
-    Send {
+    /// List data embedded in a block, the latest block is used if hash is not provided
+    BlockData {
+        hash: Option<String>,
+    },
+    /// Show filter of a block and it's filter header, the latest block is used if hash is not
+    /// provided
+    BlockFilter {
+        hash: Option<String>,
+    },
+    /// Lock coins in a hash time locked contract for atomic swap, a secret is generated unless
+    /// the secret hash of counterparty's contract is provided
+    SwapInitiate {
+        #[arg(long)]
+        from: String,
+        /// Address which can claim the contract with the secret
+        #[arg(long)]
+        to: String,
+        #[arg(long)]
+        amount: u64,
+        /// Block height or timestamp after which `from` can refund the contract
+        #[arg(long)]
+        lock_time: u64,
+        /// Secret hash of counterparty's contract
+        #[arg(long)]
+        secret_hash: Option<String>,
+    },
+    /// Claim a contract with the secret, it's revealed to the counterparty
+    SwapClaim {
+        /// Transaction id of the contract
+        #[arg(long)]
+        tx: String,
+        #[arg(long)]
+        secret: String,
+        /// Address receiving the coins, it's the recipient of contract by default
+        #[arg(long)]
+        to: Option<String>,
+    },
+    /// Refund a contract after it's lock time
+    SwapRefund {
+        /// Transaction id of the contract
+        #[arg(long)]
+        tx: String,
+        /// Address receiving the coins, it's the refund address of contract by default
+        #[arg(long)]
+        to: Option<String>,
+    },
+    /// Show details of a contract, and the secret if it's claimed
+    SwapAudit {
+        /// Transaction id of the contract
+        tx: String,
+    },
+    /// Open a payment channel funded by `from`, the open message is saved for the counterparty
+    ChannelOpen {
+        #[arg(long)]
+        from: String,
+        /// Hex public key of the counterparty
+        #[arg(long)]
+        remote_pub_key: String,
+        #[arg(long)]
+        amount: u64,
+        /// Number of blocks to wait before spending own output of a commitment
+        #[arg(long, default_value_t = DEFAULT_CHANNEL_DELAY)]
+        delay: u32,
+        /// File to save the channel state
+        #[arg(long)]
+        channel: String,
+        /// File to exchange channel messages
+        #[arg(long)]
+        message: String,
+    },
+    /// Accept a payment channel with key of `address`
+    ChannelAccept {
+        #[arg(long)]
+        address: String,
+        #[arg(long)]
+        channel: String,
+        #[arg(long)]
+        message: String,
+    },
+    /// Pay to the counterparty of a channel off chain
+    ChannelPay {
+        #[arg(long)]
+        channel: String,
+        #[arg(long)]
+        amount: u64,
+        #[arg(long)]
+        message: String,
+    },
+    /// Process a message from the counterparty, the reply is saved to the same file
+    ChannelReceive {
+        #[arg(long)]
+        channel: String,
+        #[arg(long)]
+        message: String,
+    },
+    /// Close a channel cooperatively, the counterparty submits the closing transaction
+    ChannelClose {
+        #[arg(long)]
+        channel: String,
+        #[arg(long)]
+        message: String,
+    },
+    /// Close a channel by submitting the local commitment
+    ChannelForceClose {
+        #[arg(long)]
+        channel: String,
+    },
+    /// Spend own delayed output of a commitment, or all of a revoked commitment of counterparty
+    ChannelSweep {
+        #[arg(long)]
+        channel: String,
+        /// Address receiving the coins, it's the channel address by default
+        #[arg(long)]
+        to: Option<String>,
+    },
+    /// Show state and balances of a channel
+    ChannelInfo {
+        #[arg(long)]
+        channel: String,
+    },
+    /// Issue a new asset to `from`
+    IssueAsset {
+        #[arg(long)]
+        from: String,
+        #[arg(long)]
+        name: String,
+        #[arg(long)]
+        amount: u64,
+        /// More value can be minted by the holder of mint authority
+        #[arg(long)]
+        mintable: bool,
+    },
+    /// Mint more value of a mintable asset, `from` must hold it's mint authority
+    MintAsset {
+        #[arg(long)]
+        from: String,
+        /// Id of the asset
+        #[arg(long)]
+        asset: String,
+        /// Address receiving minted value, it's `from` by default
+        #[arg(long)]
+        to: Option<String>,
+        #[arg(long)]
+        amount: u64,
+    },
+    /// Send value of an asset
+    SendAsset {
         #[arg(long)]
         from: String,
         #[arg(long)]
         to: String,
+        /// Id of the asset
+        #[arg(long)]
+        asset: String,
         #[arg(long)]
         amount: u64,
+        /// Strategy to select unspent outputs
+        #[arg(long, value_enum, default_value_t)]
+        strategy: CoinSelection,
+        /// Fee paid to miner
+        #[arg(long, default_value_t = 0)]
+        fee: u64,
+    },
+    /// List issued assets
+    Assets,
+    /// Submit a transaction saved by `send --out`
+    SubmitTx {
+        file: String,
+        /// Add the transaction to mempool without mining a block
+        #[arg(long)]
+        no_mine: bool,
+        /// Send the transaction to a running node instead, e.g. 127.0.0.1:3000
+        #[arg(long)]
+        node: Option<String>,
+        /// Network of the node
+        #[arg(long, value_enum, default_value_t)]
+        network: Network,
+    },
+    /// Replace a pending transaction with one paying higher fee from it's change output, the fee
+    /// is doubled if it's not provided
+    BumpFee {
+        tx_id: String,
+        #[arg(long)]
+        fee: Option<u64>,
+        /// Add the transaction to mempool without mining a block
+        #[arg(long)]
+        no_mine: bool,
+    },
+    /// Run a node which exchanges blocks and transactions with peers, the blockchain database is
+    /// used by the node until it's stopped. Blocks are downloaded from peers if the database
+    /// doesn't exist
+    StartNode {
+        /// Port to listen on
+        #[arg(long)]
+        listen: u16,
+        /// Address of peer to connect, e.g. 127.0.0.1:3000
+        #[arg(long)]
+        peer: Vec<String>,
+        /// Mine received transactions, and send the reward to this address
+        #[arg(long)]
+        mine: Option<String>,
+        /// Network to join, nodes of other networks are not connected
+        #[arg(long, value_enum, default_value_t)]
+        network: Network,
+    },
+    /// Mine a block with pending transactions, the reward is sent to address if it's provided
+    Mine {
+        #[arg(long)]
+        address: Option<String>,
     },
+    /// List pending transactions
+    Mempool,
     Balance {
         address: String,
     },
+    /// Show balances of wallet addresses as a light client, only block headers are downloaded
+    /// from the node, and transactions are verified by Merkle proofs
+    LightBalance {
+        /// Address of the node, e.g. 127.0.0.1:3000
+        #[arg(long)]
+        node: String,
+        /// Network of the node
+        #[arg(long, value_enum, default_value_t)]
+        network: Network,
+        /// Number of confirmations of confirmed balance
+        #[arg(long, default_value_t = 1)]
+        confirmations: u64,
+        /// Find transactions by block filters instead of sending addresses to the node, blocks
+        /// which may have the transactions are downloaded
+        #[arg(long)]
+        private: bool,
+    },
     PrintChain,
     CreateWallet,
+    /// Show mnemonic phrase of wallets
+    ShowMnemonic,
+    /// Restore wallets from mnemonic phrase
+    RestoreWallet {
+        phrase: String,
+    },
+    /// Watch an address without holding it's private key
+    WatchAddress {
+        address: String,
+    },
+    /// Show balance of every wallet address, including watch-only and change addresses
+    WalletBalance,
+    /// Show transactions of an address, or of all wallet addresses
+    History {
+        address: Option<String>,
+    },
+    /// List wallet addresses with their labels and balances
+    ListAddresses,
+    /// Set label of a wallet address, an empty label removes it
+    SetLabel {
+        address: String,
+        label: String,
+    },
+    /// Show public key of a wallet, which is shared with co-signers of multisig address
+    PubKey {
+        address: String,
+    },
+    /// Create a multisig address which requires signatures of some public keys to spend
+    CreateMultisig {
+        /// Number of required signatures
+        #[arg(long)]
+        required: usize,
+        /// Hex public keys of co-signers
+        pub_keys: Vec<String>,
+    },
+    /// Create a transaction spending from multisig address, and save it to file for co-signers
+    MultisigSpend {
+        #[arg(long)]
+        from: String,
+        #[arg(long)]
+        to: String,
+        #[arg(long)]
+        amount: u64,
+        /// Strategy to select unspent outputs
+        #[arg(long, value_enum, default_value_t)]
+        strategy: CoinSelection,
+        /// Fee paid to miner
+        #[arg(long, default_value_t = 0)]
+        fee: u64,
+        /// File to save the partial transaction
+        #[arg(long)]
+        file: String,
+    },
+    /// Sign a partial transaction in file with key of a wallet address
+    MultisigSign {
+        #[arg(long)]
+        file: String,
+        #[arg(long)]
+        address: String,
+    },
+    /// Submit a partial transaction in file once it has enough signatures
+    MultisigSubmit {
+        #[arg(long)]
+        file: String,
+    },
+    /// Export key pair of a wallet as text
+    ExportKey {
+        address: String,
+    },
+    /// Import key pair exported by `export-key`
+    ImportKey {
+        key: String,
+        /// Label of the address, the existing label is kept if it's not provided
+        #[arg(long)]
+        label: Option<String>,
+        /// Scan the chain for funds of imported key
+        #[arg(long)]
+        rescan: bool,
+    },
+}
+
+#[cfg(test)]
+mod cli_test {
+    use super::*;
+    use crate::wallet::derive_key_pair;
+
+    #[test]
+    fn list_addresses_with_saved_labels() {
+        let address = Wallet::new(derive_key_pair(&[0u8; 16], &[0]).as_slice()).get_address();
+        let mut wallets = Wallets::new();
+        wallets.add_watch_only(&address).unwrap();
+        assert!(wallets.set_label("unknown address", "payroll").is_err());
+        wallets.set_label(&address, "payroll").unwrap();
+
+        // Labels are saved, so that later commands list them
+        let mut wallets = Wallets::new();
+        assert_eq!(
+            address_line(&wallets, &address, Some(5)),
+            format!("{} [payroll] (watch-only): 5", address)
+        );
+        wallets.set_label(&address, "").unwrap();
+        assert_eq!(
+            address_line(&Wallets::new(), &address, None),
+            format!("{} (watch-only)", address)
+        );
+    }
 }
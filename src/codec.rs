@@ -0,0 +1,158 @@
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+
+use crate::message::Message;
+
+/// Max size of a message payload in bytes
+pub const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+/// Size of the command name in a frame, shorter names are padded with 0
+const COMMAND_SIZE: usize = 12;
+/// Size of the frame header: magic, command, payload length and checksum
+const HEADER_SIZE: usize = 4 + COMMAND_SIZE + 4 + 4;
+
+/// Network which a node runs in, nodes of different networks can't talk to each other
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Network {
+    #[default]
+    Main,
+    Test,
+    /// Local network for testing
+    Regtest,
+}
+
+impl Network {
+    /// Magic bytes at the start of every frame
+    pub fn magic(&self) -> [u8; 4] {
+        match self {
+            Network::Main => [0x49, 0x4c, 0x55, 0x4d],
+            Network::Test => [0x49, 0x4c, 0x55, 0x54],
+            Network::Regtest => [0x49, 0x4c, 0x55, 0x52],
+        }
+    }
+}
+
+/// First 4 bytes of double sha256 of `payload`
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let hash = Sha256::digest(Sha256::digest(payload));
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+/// Frame messages exchanged between nodes. A frame is made of magic bytes of the network, name of
+/// the command, length of the payload as 4 bytes in little end order, checksum of the payload,
+/// and the payload which is the encoded message. It doesn't do any IO, so it works on any stream
+/// of bytes
+pub struct Codec {
+    magic: [u8; 4],
+}
+
+impl Codec {
+    pub fn new(network: Network) -> Self {
+        Self {
+            magic: network.magic(),
+        }
+    }
+
+    /// Encode `message` to a frame
+    pub fn encode(&self, message: &Message) -> Vec<u8> {
+        let payload = message.encode();
+        let mut command = [0u8; COMMAND_SIZE];
+        let name = message.command().as_bytes();
+        command[..name.len()].copy_from_slice(name);
+        let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+        frame.extend_from_slice(&self.magic);
+        frame.extend_from_slice(&command);
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&checksum(&payload));
+        frame.extend_from_slice(&payload);
+        frame
+    }
+
+    /// Decode a frame at the start of `buf`, the frame is removed from `buf` once it's decoded.
+    /// Returns `None` if the frame is not complete, and error if the frame is invalid, then the
+    /// stream can't be trusted any more
+    pub fn decode(&self, buf: &mut Vec<u8>) -> Result<Option<Message>, String> {
+        if buf.len() >= 4 && buf[..4] != self.magic {
+            return Err(String::from("Invalid magic bytes"));
+        }
+        if buf.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+        let command = &buf[4..4 + COMMAND_SIZE];
+        let name_len = command.iter().position(|b| *b == 0).unwrap_or(COMMAND_SIZE);
+        if command[name_len..].iter().any(|b| *b != 0) {
+            return Err(String::from("Invalid command name"));
+        }
+        let name = String::from_utf8_lossy(&command[..name_len]).into_owned();
+        let len_offset = 4 + COMMAND_SIZE;
+        let len = u32::from_le_bytes(buf[len_offset..len_offset + 4].try_into().unwrap()) as usize;
+        if len > MAX_MESSAGE_SIZE {
+            return Err(format!("Message of {} bytes is too large", len));
+        }
+        if buf.len() < HEADER_SIZE + len {
+            return Ok(None);
+        }
+        let expected: [u8; 4] = buf[len_offset + 4..HEADER_SIZE].try_into().unwrap();
+        let payload: Vec<u8> = buf.drain(..HEADER_SIZE + len).skip(HEADER_SIZE).collect();
+        if checksum(&payload) != expected {
+            return Err(format!("Invalid checksum of {} message", name));
+        }
+        match Message::decode(&payload) {
+            Some(message) if message.command() == name => Ok(Some(message)),
+            Some(_) => Err(format!("Payload doesn't match command {}", name)),
+            None => Err(format!("Invalid payload of {} message", name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod codec_test {
+    use super::*;
+
+    #[test]
+    fn encode_and_decode_frames() {
+        let codec = Codec::new(Network::Main);
+        let mut buf = codec.encode(&Message::Ping(7));
+        buf.extend(codec.encode(&Message::GetAddr));
+        let second = buf.split_off(buf.len() - 3);
+        assert!(matches!(codec.decode(&mut buf), Ok(Some(Message::Ping(7)))));
+        // The second frame is not complete
+        assert!(matches!(codec.decode(&mut buf), Ok(None)));
+        buf.extend(second);
+        assert!(matches!(codec.decode(&mut buf), Ok(Some(Message::GetAddr))));
+        assert!(buf.is_empty());
+
+        // Frames of other networks are rejected
+        let mut buf = Codec::new(Network::Test).encode(&Message::Verack);
+        assert!(codec.decode(&mut buf).is_err());
+
+        // Corrupt payload is detected by checksum
+        let mut buf = codec.encode(&Message::Pong(1));
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        assert!(codec.decode(&mut buf).is_err());
+
+        // Command must match the payload
+        let mut buf = codec.encode(&Message::Pong(1));
+        buf[4..8].copy_from_slice(b"ping");
+        assert!(codec.decode(&mut buf).is_err());
+
+        // Oversized frame is rejected before it's payload is received
+        let mut buf = codec.encode(&Message::Verack);
+        buf[16..20].copy_from_slice(&(MAX_MESSAGE_SIZE as u32 + 1).to_le_bytes());
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn reject_huge_length_prefix() {
+        let codec = Codec::new(Network::Main);
+        let frame = codec.encode(&Message::Inv(vec![]));
+        // The payload claims 2^40 inventory items, which must not be allocated
+        let mut payload = vec![frame[HEADER_SIZE], 253];
+        payload.extend_from_slice(&(1u64 << 40).to_le_bytes());
+        let mut buf = frame[..4 + COMMAND_SIZE].to_vec();
+        buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&checksum(&payload));
+        buf.extend_from_slice(&payload);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use clap::ValueEnum;
+
+use crate::block::Hash;
+
+/// Maximum number of branches to explore before branch and bound gives up
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// An unspent output which can be selected as transaction input
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Coin {
+    /// The id of transaction which contains the output
+    pub tx_id: Hash,
+    /// The index of output in transaction outputs
+    pub out_idx: usize,
+    /// The amount of "coin" stored in output
+    pub value: u64,
+}
+
+/// Strategy to choose which unspent outputs are spent by a transaction, every strategy is
+/// deterministic, coins with the same value are ordered by transaction id and output index
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum CoinSelection {
+    /// Spend the biggest outputs first, which uses the fewest inputs
+    #[default]
+    LargestFirst,
+    /// Spend the smallest outputs first, which consolidates dust outputs
+    SmallestFirst,
+    /// Search a set of outputs whose value exactly matches the amount, so that no change is
+    /// needed, fall back to largest first if there is no exact match
+    BranchAndBound,
+    /// Avoid linking outputs together, spend the smallest single output which covers the amount,
+    /// fall back to largest first if no single output is enough
+    Privacy,
+}
+
+impl CoinSelection {
+    /// Select coins which accumulated value is not less than `amount`, returns `None` if all of
+    /// the coins are not enough. Nothing is selected for zero amount
+    pub fn select(&self, coins: &[Coin], amount: u64) -> Option<Vec<Coin>> {
+        if amount == 0 {
+            return Some(vec![]);
+        }
+        let total: u64 = coins.iter().map(|c| c.value).sum();
+        if total < amount {
+            return None;
+        }
+        let selected = match self {
+            CoinSelection::LargestFirst => accumulate(sorted(coins, true), amount),
+            CoinSelection::SmallestFirst => accumulate(sorted(coins, false), amount),
+            CoinSelection::BranchAndBound => branch_and_bound(coins, amount)
+                .unwrap_or_else(|| accumulate(sorted(coins, true), amount)),
+            CoinSelection::Privacy => sorted(coins, false)
+                .into_iter()
+                .find(|c| c.value >= amount)
+                .map(|c| vec![c])
+                .unwrap_or_else(|| accumulate(sorted(coins, true), amount)),
+        };
+        Some(selected)
+    }
+}
+
+/// Sort coins by value, coins with the same value are ordered by their output point
+fn sorted(coins: &[Coin], descending: bool) -> Vec<Coin> {
+    let mut coins = Vec::from(coins);
+    coins.sort_by(|a, b| {
+        let by_value = if descending {
+            b.value.cmp(&a.value)
+        } else {
+            a.value.cmp(&b.value)
+        };
+        by_value.then_with(|| (a.tx_id, a.out_idx).cmp(&(b.tx_id, b.out_idx)))
+    });
+    coins
+}
+
+/// Take coins in order until accumulated value is just bigger than amount
+fn accumulate(coins: Vec<Coin>, amount: u64) -> Vec<Coin> {
+    let mut acc_value = 0u64;
+    let mut selected = vec![];
+    for coin in coins {
+        if acc_value >= amount && !selected.is_empty() {
+            break;
+        }
+        acc_value += coin.value;
+        selected.push(coin);
+    }
+    selected
+}
+
+/// Depth first search for a set of coins whose value is exactly `amount`
+fn branch_and_bound(coins: &[Coin], amount: u64) -> Option<Vec<Coin>> {
+    let coins = sorted(coins, true);
+    // Value of remaining coins after each position, used to cut off branches which can't reach
+    // the amount
+    let mut remaining = vec![0u64; coins.len() + 1];
+    for i in (0..coins.len()).rev() {
+        remaining[i] = remaining[i + 1] + coins[i].value;
+    }
+
+    let mut selected = vec![];
+    let mut tries = 0usize;
+    if search(&coins, &remaining, 0, amount, &mut selected, &mut tries) {
+        Some(selected.into_iter().map(|i| coins[i]).collect())
+    } else {
+        None
+    }
+}
+
+fn search(
+    coins: &[Coin],
+    remaining: &[u64],
+    idx: usize,
+    target: u64,
+    selected: &mut Vec<usize>,
+    tries: &mut usize,
+) -> bool {
+    if target == 0 {
+        return !selected.is_empty();
+    }
+    *tries += 1;
+    if idx >= coins.len() || remaining[idx] < target || *tries > BNB_MAX_TRIES {
+        return false;
+    }
+    // Include current coin if it doesn't exceed the target
+    if coins[idx].value <= target {
+        selected.push(idx);
+        if search(
+            coins,
+            remaining,
+            idx + 1,
+            target - coins[idx].value,
+            selected,
+            tries,
+        ) {
+            return true;
+        }
+        selected.pop();
+    }
+    // Exclude current coin
+    search(coins, remaining, idx + 1, target, selected, tries)
+}
+
+#[cfg(test)]
+mod coin_selection_test {
+    use super::*;
+
+    fn coins(values: &[u64]) -> Vec<Coin> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| Coin {
+                tx_id: [i as u8; 32],
+                out_idx: 0,
+                value: *value,
+            })
+            .collect()
+    }
+
+    fn values(coins: Option<Vec<Coin>>) -> Vec<u64> {
+        coins.unwrap().iter().map(|c| c.value).collect()
+    }
+
+    #[test]
+    fn not_enough_funds() {
+        let coins = coins(&[1, 2, 3]);
+        assert!(CoinSelection::LargestFirst.select(&coins, 7).is_none());
+    }
+
+    #[test]
+    fn zero_amount_selects_nothing() {
+        let coins = coins(&[1, 2, 3]);
+        for strategy in CoinSelection::value_variants() {
+            assert_eq!(strategy.select(&coins, 0), Some(vec![]));
+        }
+        assert_eq!(CoinSelection::Privacy.select(&[], 0), Some(vec![]));
+    }
+
+    #[test]
+    fn largest_and_smallest_first() {
+        let coins = coins(&[5, 1, 20, 3]);
+        assert_eq!(
+            values(CoinSelection::LargestFirst.select(&coins, 22)),
+            vec![20, 5]
+        );
+        assert_eq!(
+            values(CoinSelection::SmallestFirst.select(&coins, 6)),
+            vec![1, 3, 5]
+        );
+    }
+
+    #[test]
+    fn branch_and_bound_finds_exact_match() {
+        let coins = coins(&[8, 7, 5, 4, 1]);
+        assert_eq!(
+            values(CoinSelection::BranchAndBound.select(&coins, 10)),
+            vec![5, 4, 1]
+        );
+        // No exact match, fall back to largest first
+        let coins = self::coins(&[8, 4]);
+        assert_eq!(
+            values(CoinSelection::BranchAndBound.select(&coins, 10)),
+            vec![8, 4]
+        );
+    }
+
+    #[test]
+    fn privacy_prefers_single_coin() {
+        let coins = coins(&[2, 3, 12, 30]);
+        assert_eq!(values(CoinSelection::Privacy.select(&coins, 5)), vec![12]);
+        assert_eq!(
+            values(CoinSelection::Privacy.select(&coins, 40)),
+            vec![30, 12]
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+
+use bincode::{Decode, Encode};
+use sha2::{Digest, Sha256};
+
+use crate::block::{Block, BlockHeader, Hash};
+use crate::pow::hash_transactions;
+use crate::transaction::Transaction;
+
+/// Number of bytes of a short transaction id
+const SHORT_ID_SIZE: usize = 6;
+
+/// Short id of transaction `tx_id` in block `block_hash`, the block hash is used as salt so that
+/// collisions can't be made for all blocks
+pub fn short_id(block_hash: &Hash, tx_id: &Hash) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(block_hash);
+    hasher.update(tx_id);
+    let hash: Hash = hasher.finalize().into();
+    let mut bytes = [0u8; 8];
+    bytes[..SHORT_ID_SIZE].copy_from_slice(&hash[..SHORT_ID_SIZE]);
+    u64::from_le_bytes(bytes)
+}
+
+/// A transaction sent in full in a compact block
+#[derive(Clone, Encode, Decode)]
+pub struct PrefilledTx {
+    /// Index of the transaction in the block
+    pub index: u32,
+    pub tx: Transaction,
+}
+
+/// A block whose transactions are replaced by short ids, except the coinbase transaction which
+/// can't be in the mempool of peers
+#[derive(Clone, Encode, Decode)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    /// Short ids of transactions which are not prefilled, in the order of the block
+    pub short_ids: Vec<u64>,
+    /// Prefilled transactions in the order of index
+    pub prefilled: Vec<PrefilledTx>,
+}
+
+impl CompactBlock {
+    pub fn new(block: &Block) -> Self {
+        let mut short_ids = vec![];
+        let mut prefilled = vec![];
+        for (index, tx) in block.transactions.iter().enumerate() {
+            if tx.is_coinbase_tx() {
+                prefilled.push(PrefilledTx {
+                    index: index as u32,
+                    tx: tx.clone(),
+                });
+            } else {
+                short_ids.push(short_id(&block.hash, &tx.id));
+            }
+        }
+        Self {
+            header: block.header(),
+            short_ids,
+            prefilled,
+        }
+    }
+
+    /// Number of transactions in the block
+    pub fn tx_count(&self) -> usize {
+        self.short_ids.len() + self.prefilled.len()
+    }
+}
+
+/// A block rebuilt from a compact block, transactions which are not found in the mempool are
+/// missing
+pub struct PartialBlock {
+    header: BlockHeader,
+    txs: Vec<Option<Transaction>>,
+}
+
+impl PartialBlock {
+    /// Rebuild block from `compact` with transactions in the mempool. A short id which matches
+    /// several transactions is treated as missing
+    pub fn new<'a>(
+        compact: CompactBlock,
+        mempool: impl Iterator<Item = &'a Transaction>,
+    ) -> Result<Self, String> {
+        let count = compact.tx_count();
+        let mut txs: Vec<Option<Transaction>> = vec![None; count];
+        for prefilled in compact.prefilled {
+            match txs.get_mut(prefilled.index as usize) {
+                Some(slot) if slot.is_none() => *slot = Some(prefilled.tx),
+                _ => return Err(String::from("Invalid index of prefilled transaction")),
+            }
+        }
+        // Index of each short id, it's `None` if there are duplicated short ids
+        let mut indexes: HashMap<u64, Option<usize>> = HashMap::new();
+        let mut slots = txs.iter().enumerate().filter(|(_, tx)| tx.is_none());
+        for id in compact.short_ids.iter() {
+            let (index, _) = slots.next().expect("Number of short ids is checked");
+            indexes
+                .entry(*id)
+                .and_modify(|index| *index = None)
+                .or_insert(Some(index));
+        }
+        let mut found: HashMap<usize, Option<&Transaction>> = HashMap::new();
+        for tx in mempool {
+            if let Some(Some(index)) = indexes.get(&short_id(&compact.header.hash, &tx.id)) {
+                found
+                    .entry(*index)
+                    .and_modify(|tx| *tx = None)
+                    .or_insert(Some(tx));
+            }
+        }
+        for (index, tx) in found {
+            if let Some(tx) = tx {
+                txs[index] = Some(tx.clone());
+            }
+        }
+        Ok(Self {
+            header: compact.header,
+            txs,
+        })
+    }
+
+    pub fn hash(&self) -> Hash {
+        self.header.hash
+    }
+
+    /// Indexes of missing transactions
+    pub fn missing(&self) -> Vec<u32> {
+        self.txs
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.is_none())
+            .map(|(index, _)| index as u32)
+            .collect()
+    }
+
+    /// Fill missing transactions in the order of their indexes
+    pub fn fill(&mut self, txs: Vec<Transaction>) -> Result<(), String> {
+        let missing = self.missing();
+        if txs.len() != missing.len() {
+            return Err(String::from("Number of transactions doesn't match"));
+        }
+        for (index, tx) in missing.into_iter().zip(txs) {
+            self.txs[index as usize] = Some(tx);
+        }
+        Ok(())
+    }
+
+    /// Get the whole block, returns error if transactions are missing or they don't match the
+    /// header, e.g. a wrong transaction is found for a short id
+    pub fn to_block(&self) -> Result<Block, String> {
+        let transactions: Vec<Transaction> = match self.txs.iter().cloned().collect() {
+            Some(txs) => txs,
+            None => return Err(String::from("Transactions are missing")),
+        };
+        if hash_transactions(&transactions) != self.header.tx_hash {
+            return Err(String::from("Transactions don't match the header"));
+        }
+        Ok(Block {
+            timestamp: self.header.timestamp,
+            transactions,
+            prev_block_hash: self.header.prev_block_hash,
+            hash: self.header.hash,
+            nonce: self.header.nonce,
+            height: self.header.height,
+        })
+    }
+}
+
+#[cfg(test)]
+mod compact_block_test {
+    use super::*;
+    use crate::wallet::{derive_key_pair, Wallet};
+
+    #[test]
+    fn rebuild_block_from_mempool() {
+        let address = Wallet::new(derive_key_pair(&[0u8; 16], &[0]).as_slice()).get_address();
+        // Coinbase transactions with different heights stand in for normal transactions
+        let txs: Vec<Transaction> = (0..4)
+            .map(|height| Transaction::new_coinbase_tx(&address, None, height, 0))
+            .collect();
+        let block = Block::new(txs.clone(), None, 0);
+        let compact = CompactBlock::new(&block);
+        assert_eq!(compact.prefilled.len(), 4);
+
+        // Pretend the last three transactions are not coinbase
+        let compact = CompactBlock {
+            header: block.header(),
+            short_ids: block.transactions[1..]
+                .iter()
+                .map(|tx| short_id(&block.hash, &tx.id))
+                .collect(),
+            prefilled: vec![PrefilledTx {
+                index: 0,
+                tx: block.transactions[0].clone(),
+            }],
+        };
+        let mempool = [txs[3].clone(), txs[1].clone()];
+        let mut partial = PartialBlock::new(compact.clone(), mempool.iter()).unwrap();
+        assert_eq!(partial.missing(), vec![2]);
+        assert!(partial.to_block().is_err());
+        assert!(partial.fill(vec![]).is_err());
+        partial.fill(vec![txs[2].clone()]).unwrap();
+        assert_eq!(partial.to_block().unwrap().hash, block.hash);
+
+        // Wrong transaction is detected by the header
+        let mut partial = PartialBlock::new(compact, mempool.iter()).unwrap();
+        partial.fill(vec![txs[0].clone()]).unwrap();
+        assert!(partial.to_block().is_err());
+    }
+}
@@ -3,10 +3,28 @@ extern crate core;
 use crate::cli::run_cmd;
 mod block;
 mod block_chain;
+mod block_filter;
+mod chain_sync;
+mod channel;
 mod cli;
+mod codec;
+mod coin_selection;
+mod compact_block;
+mod mempool;
+mod merkle;
+mod message;
+mod node;
+mod partial_tx;
+mod peer_manager;
 mod pow;
+mod script;
+mod server;
+#[cfg(test)]
+mod simulator;
+mod spv;
 mod tools;
 mod transaction;
+mod tx_relay;
 mod wallet;
 
 fn main() {
@@ -0,0 +1,239 @@
+use bincode::{config, Decode, Encode};
+use std::collections::{HashMap, HashSet};
+
+use crate::block::Hash;
+use crate::transaction::Transaction;
+
+/// Max bytes of pending transactions, transactions of the lowest fee rate are evicted beyond it
+pub const MAX_MEMPOOL_SIZE: usize = 5 * 1024 * 1024;
+/// Minimum fee per 1000 bytes of transactions relayed by peers
+pub const MIN_RELAY_FEE_RATE: u64 = 1;
+
+/// Transactions which are waiting to be mined, they are ordered by the time they are accepted,
+/// so a transaction always comes after the transactions it spends
+#[derive(Encode, Decode, Default)]
+pub struct Mempool {
+    txs: Vec<Transaction>,
+    /// Fees of pending transactions, so that they are not verified again to evict or replace them
+    fees: HashMap<Hash, u64>,
+}
+
+impl Mempool {
+    /// Pending transactions
+    pub fn txs(&self) -> &[Transaction] {
+        self.txs.as_slice()
+    }
+
+    /// Check whether transaction `tx_id` is pending
+    pub fn contains(&self, tx_id: &Hash) -> bool {
+        self.txs.iter().any(|tx| tx.id == *tx_id)
+    }
+
+    /// Add a verified transaction which pays `fee`
+    pub fn add(&mut self, tx: Transaction, fee: u64) {
+        self.fees.insert(tx.id, fee);
+        self.txs.push(tx);
+    }
+
+    /// Fee paid by pending transaction `tx_id`
+    pub fn fee(&self, tx_id: &Hash) -> Option<u64> {
+        self.fees.get(tx_id).copied()
+    }
+
+    /// Total bytes of pending transactions
+    pub fn size(&self) -> usize {
+        self.txs.iter().map(|tx| tx.encode().len()).sum()
+    }
+
+    /// Get pending transaction `tx_id`
+    pub fn get(&self, tx_id: &Hash) -> Option<&Transaction> {
+        self.txs.iter().find(|tx| tx.id == *tx_id)
+    }
+
+    /// Ids of pending transactions which spend any of the same outputs as `txs`
+    pub fn conflicts(&self, txs: &[Transaction]) -> HashSet<Hash> {
+        let ids: HashSet<Hash> = txs.iter().map(|tx| tx.id).collect();
+        let spent: HashSet<(Option<Hash>, Option<usize>)> = txs
+            .iter()
+            .filter(|tx| !tx.is_coinbase_tx())
+            .flat_map(|tx| tx.v_in.iter().map(|input| (input.tx_id, input.v_out_idx)))
+            .collect();
+        self.txs
+            .iter()
+            .filter(|tx| !ids.contains(&tx.id))
+            .filter(|tx| {
+                tx.v_in
+                    .iter()
+                    .any(|input| spent.contains(&(input.tx_id, input.v_out_idx)))
+            })
+            .map(|tx| tx.id)
+            .collect()
+    }
+
+    /// Ids of `tx_ids` and pending transactions which spend their outputs directly or indirectly
+    pub fn with_descendants(&self, tx_ids: &HashSet<Hash>) -> HashSet<Hash> {
+        let mut ids = tx_ids.clone();
+        // A transaction always comes after it's parents
+        for tx in self.txs.iter() {
+            let is_descendant = tx
+                .v_in
+                .iter()
+                .any(|input| matches!(input.tx_id, Some(id) if ids.contains(&id)));
+            if is_descendant {
+                ids.insert(tx.id);
+            }
+        }
+        ids
+    }
+
+    /// Remove transactions `tx_ids`
+    pub fn remove(&mut self, tx_ids: &HashSet<Hash>) {
+        self.txs.retain(|tx| !tx_ids.contains(&tx.id));
+        self.fees.retain(|tx_id, _| !tx_ids.contains(tx_id));
+    }
+
+    /// Evict transactions of the lowest fee rate with their descendants until the pending
+    /// transactions are not larger than `max_size` bytes, returns ids of evicted transactions
+    pub fn trim(&mut self, max_size: usize) -> HashSet<Hash> {
+        let sizes: HashMap<Hash, usize> = self
+            .txs
+            .iter()
+            .map(|tx| (tx.id, tx.encode().len()))
+            .collect();
+        let mut size: usize = sizes.values().sum();
+        let mut evicted = HashSet::new();
+        while size > max_size {
+            let lowest = self
+                .txs
+                .iter()
+                .filter(|tx| !evicted.contains(&tx.id))
+                .map(|tx| (tx.id, self.fee(&tx.id).unwrap_or(0), sizes[&tx.id]))
+                .reduce(|lowest, other| {
+                    if is_lower_fee_rate(other.1, other.2, lowest.1, lowest.2) {
+                        other
+                    } else {
+                        lowest
+                    }
+                });
+            let lowest = match lowest {
+                Some((tx_id, _, _)) => tx_id,
+                None => break,
+            };
+            for tx_id in self.with_descendants(&HashSet::from([lowest])) {
+                if evicted.insert(tx_id) {
+                    size -= sizes[&tx_id];
+                }
+            }
+        }
+        self.remove(&evicted);
+        evicted
+    }
+
+    /// Remove transactions which are mined in a block, transactions which spend the same outputs
+    /// as them are evicted with their descendants
+    pub fn remove_mined(&mut self, mined: &[Transaction]) {
+        let evicted = self.with_descendants(&self.conflicts(mined));
+        let mined_ids: HashSet<Hash> = mined.iter().map(|tx| tx.id).collect();
+        self.remove(&mined_ids);
+        self.remove(&evicted);
+    }
+
+    /// Serialize mempool to bytes
+    pub fn encode(&self) -> Vec<u8> {
+        let config = config::standard();
+        bincode::encode_to_vec(self, config).expect("Can not encode Mempool to byte data")
+    }
+
+    /// Deserialize mempool from bytes, mempool saved without fees is read with zero fees
+    pub fn decode(data: Vec<u8>) -> Self {
+        let config = config::standard();
+        if let Ok((mempool, len)) = bincode::decode_from_slice::<Mempool, _>(&data, config) {
+            if len == data.len() {
+                return mempool;
+            }
+        }
+        let (txs, _): (Vec<Transaction>, usize) =
+            bincode::decode_from_slice(&data, config).expect("Can not decode bytes to Mempool");
+        let fees = txs.iter().map(|tx| (tx.id, 0)).collect();
+        Self { txs, fees }
+    }
+}
+
+/// Check whether `fee` paid for `size` bytes is a lower rate than `other_fee` for `other_size`
+fn is_lower_fee_rate(fee: u64, size: usize, other_fee: u64, other_size: usize) -> bool {
+    (fee as u128) * (other_size as u128) < (other_fee as u128) * (size as u128)
+}
+
+#[cfg(test)]
+mod mempool_test {
+    use super::*;
+    use crate::script::Script;
+    use crate::transaction::TXInput;
+    use std::rc::Rc;
+
+    fn spend(tx_id: Hash, v_out_idx: usize, lock_time: u64) -> Transaction {
+        let input = TXInput {
+            tx_id: Some(tx_id),
+            v_out_idx: Some(v_out_idx),
+            script_sig: Script::default(),
+            sequence: 0,
+        };
+        Transaction::new(vec![Rc::new(input)], vec![], lock_time)
+    }
+
+    #[test]
+    fn evict_conflicts_with_descendants() {
+        let original = spend([1u8; 32], 0, 0);
+        let child = spend(original.id, 0, 0);
+        let grandchild = spend(child.id, 0, 0);
+        let other = spend([2u8; 32], 0, 0);
+        let mut mempool = Mempool::default();
+        for tx in [&original, &child, &grandchild, &other] {
+            mempool.add(tx.clone(), 0);
+        }
+
+        let replacement = spend([1u8; 32], 0, 1);
+        let conflicts = mempool.conflicts(&[replacement]);
+        assert_eq!(conflicts, HashSet::from([original.id]));
+        let evicted = mempool.with_descendants(&conflicts);
+        assert_eq!(
+            evicted,
+            HashSet::from([original.id, child.id, grandchild.id])
+        );
+
+        mempool.remove(&evicted);
+        assert_eq!(mempool.txs().len(), 1);
+        assert!(mempool.contains(&other.id));
+    }
+
+    #[test]
+    fn decode_mempool_without_fees() {
+        let tx = spend([1u8; 32], 0, 0);
+        let data = bincode::encode_to_vec(vec![tx.clone()], config::standard()).unwrap();
+        let mempool = Mempool::decode(data);
+        assert!(mempool.contains(&tx.id));
+        assert_eq!(mempool.fee(&tx.id), Some(0));
+
+        let mut mempool = Mempool::default();
+        mempool.add(tx.clone(), 3);
+        assert_eq!(Mempool::decode(mempool.encode()).fee(&tx.id), Some(3));
+    }
+
+    #[test]
+    fn trim_lowest_fee_rate_with_descendants() {
+        let cheap = spend([1u8; 32], 0, 0);
+        let child = spend(cheap.id, 0, 0);
+        let rich = spend([2u8; 32], 0, 0);
+        let mut mempool = Mempool::default();
+        mempool.add(cheap.clone(), 1);
+        mempool.add(child.clone(), 100);
+        mempool.add(rich.clone(), 10);
+
+        let max_size = mempool.size() - 1;
+        let evicted = mempool.trim(max_size);
+        assert_eq!(evicted, HashSet::from([cheap.id, child.id]));
+        assert!(mempool.contains(&rich.id));
+        assert_eq!(mempool.fee(&child.id), None);
+        assert!(mempool.trim(max_size).is_empty());
+    }
+}
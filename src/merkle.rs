@@ -0,0 +1,117 @@
+use bincode::{Decode, Encode};
+use sha2::{Digest, Sha256};
+
+use crate::block::Hash;
+
+fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Hashes of the next level of Merkle tree, the last hash of a level with odd number of hashes is
+/// moved up without hashing, so that different lists of leaves never have the same root
+fn next_level(level: &[Hash]) -> Vec<Hash> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_pair(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Root of Merkle tree whose leaves are `leaves`, it's hash of nothing if there are no leaves
+pub fn merkle_root(leaves: &[Hash]) -> Hash {
+    if leaves.is_empty() {
+        return Sha256::new().finalize().into();
+    }
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+/// Proof of a leaf in Merkle tree, it's made of the siblings on the path from the leaf to root
+#[derive(Clone, Debug, PartialEq, Eq, Encode, Decode)]
+pub struct MerkleProof {
+    /// Index of the leaf
+    pub index: u32,
+    /// Number of leaves in the tree
+    pub count: u32,
+    /// Siblings from bottom to top, levels where the node has no sibling are skipped
+    pub siblings: Vec<Hash>,
+}
+
+impl MerkleProof {
+    /// Build proof of leaf `index` in `leaves`
+    pub fn new(leaves: &[Hash], index: usize) -> Self {
+        let mut siblings = vec![];
+        let mut level = leaves.to_vec();
+        let mut idx = index;
+        while level.len() > 1 {
+            if let Some(sibling) = level.get(idx ^ 1) {
+                siblings.push(*sibling);
+            }
+            level = next_level(&level);
+            idx /= 2;
+        }
+        Self {
+            index: index as u32,
+            count: leaves.len() as u32,
+            siblings,
+        }
+    }
+
+    /// Root of the tree if `leaf` is at the position of the proof, returns `None` if the proof
+    /// is malformed
+    pub fn root(&self, leaf: &Hash) -> Option<Hash> {
+        if self.index >= self.count {
+            return None;
+        }
+        let mut hash = *leaf;
+        let mut idx = self.index;
+        let mut len = self.count;
+        let mut siblings = self.siblings.iter();
+        while len > 1 {
+            if idx % 2 == 1 {
+                hash = hash_pair(siblings.next()?, &hash);
+            } else if idx + 1 < len {
+                hash = hash_pair(&hash, siblings.next()?);
+            }
+            idx /= 2;
+            len = len.div_ceil(2);
+        }
+        // All of the siblings must be used
+        siblings.next().is_none().then_some(hash)
+    }
+}
+
+#[cfg(test)]
+mod merkle_test {
+    use super::*;
+
+    #[test]
+    fn prove_every_leaf() {
+        for count in 1..10u8 {
+            let leaves: Vec<Hash> = (0..count).map(|i| [i; 32]).collect();
+            let root = merkle_root(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = MerkleProof::new(&leaves, index);
+                assert_eq!(proof.root(leaf), Some(root));
+                assert_ne!(proof.root(&[99u8; 32]), Some(root));
+            }
+        }
+        // Duplicating the last leaf changes the root
+        let leaves = [[1u8; 32], [2u8; 32], [3u8; 32]];
+        let duplicated = [[1u8; 32], [2u8; 32], [3u8; 32], [3u8; 32]];
+        assert_ne!(merkle_root(&leaves), merkle_root(&duplicated));
+
+        let mut proof = MerkleProof::new(&leaves, 2);
+        proof.count = 2;
+        assert!(proof.root(&leaves[2]).is_none());
+    }
+}
@@ -0,0 +1,169 @@
+use bincode::{config, Decode, Encode};
+
+use crate::block::{Block, BlockHeader, Hash, TimeStamp};
+use crate::block_filter::BlockFilter;
+use crate::codec::MAX_MESSAGE_SIZE;
+use crate::compact_block::CompactBlock;
+use crate::script::Script;
+use crate::spv::TxProof;
+use crate::transaction::Transaction;
+
+/// Version of the protocol spoken by nodes
+pub const PROTOCOL_VERSION: u32 = 8;
+
+/// Information exchanged when two nodes are connected
+#[derive(Clone, Debug, PartialEq, Eq, Encode, Decode)]
+pub struct Version {
+    pub version: u32,
+    /// Random number to detect connection to the node itself
+    pub nonce: u64,
+    /// Height of the latest block, `None` if the node doesn't have any blocks
+    pub best_height: Option<u64>,
+    /// Hash of genesis block, nodes of different chains can't be connected
+    pub genesis: Option<Hash>,
+    /// Port which the node listens on, it's 0 if the node doesn't accept connections
+    pub listen_port: u16,
+}
+
+/// Address of a node which can be connected
+#[derive(Clone, Debug, PartialEq, Eq, Encode, Decode)]
+pub struct PeerAddress {
+    /// Socket address, e.g. 127.0.0.1:3000
+    pub addr: String,
+    /// Time the node is seen alive
+    pub last_seen: TimeStamp,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Encode, Decode)]
+pub enum InvKind {
+    Block,
+    Tx,
+}
+
+/// An item of inventory, which is a block or transaction known by the node
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Encode, Decode)]
+pub struct InvItem {
+    pub kind: InvKind,
+    pub hash: Hash,
+}
+
+/// Messages exchanged between nodes
+#[derive(Clone, Encode, Decode)]
+pub enum Message {
+    Version(Version),
+    /// Acknowledge the version message
+    Verack,
+    Ping(u64),
+    /// Reply ping with the same nonce
+    Pong(u64),
+    /// Announce blocks or transactions
+    Inv(Vec<InvItem>),
+    /// Request blocks or transactions in inventory
+    GetData(Vec<InvItem>),
+    /// Reply getdata with items which are not found
+    NotFound(Vec<InvItem>),
+    /// Request headers of blocks after the first block of locator which is in the chain
+    GetHeaders(Vec<Hash>),
+    Headers(Vec<BlockHeader>),
+    Block(Block),
+    Tx(Transaction),
+    /// Request addresses of known nodes
+    GetAddr,
+    Addr(Vec<PeerAddress>),
+    /// Announce a new block with short ids of it's transactions
+    CompactBlock(CompactBlock),
+    /// Request transactions of a block by their indexes, which are missing in a compact block
+    GetBlockTxn(Hash, Vec<u32>),
+    /// Reply getblocktxn with transactions of the block
+    BlockTxn(Hash, Vec<Transaction>),
+    /// Request mined transactions which pay to the scripts or spend outputs paid to them, it's
+    /// sent by light clients
+    GetTxProofs(Vec<Script>),
+    /// Reply gettxproofs with transactions and their Merkle proofs
+    TxProofs(Vec<TxProof>),
+    /// Request filters of blocks, it's sent by light clients
+    GetFilters(Vec<Hash>),
+    /// Reply getcfilters with blocks and their filters, unknown blocks are skipped
+    Filters(Vec<(Hash, BlockFilter)>),
+    /// Request filter headers of blocks
+    GetFilterHeaders(Vec<Hash>),
+    /// Reply getcfheaders with blocks and their filter headers, unknown blocks are skipped
+    FilterHeaders(Vec<(Hash, Hash)>),
+}
+
+impl Message {
+    /// Serialize message to bytes
+    pub fn encode(&self) -> Vec<u8> {
+        let config = config::standard();
+        bincode::encode_to_vec(self, config).expect("Can not encode Message to byte data")
+    }
+
+    /// Name of the command in a frame, it's at most 12 bytes
+    pub fn command(&self) -> &'static str {
+        match self {
+            Message::Version(_) => "version",
+            Message::Verack => "verack",
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+            Message::Inv(_) => "inv",
+            Message::GetData(_) => "getdata",
+            Message::NotFound(_) => "notfound",
+            Message::GetHeaders(_) => "getheaders",
+            Message::Headers(_) => "headers",
+            Message::Block(_) => "block",
+            Message::Tx(_) => "tx",
+            Message::GetAddr => "getaddr",
+            Message::Addr(_) => "addr",
+            Message::CompactBlock(_) => "cmpctblock",
+            Message::GetBlockTxn(..) => "getblocktxn",
+            Message::BlockTxn(..) => "blocktxn",
+            Message::GetTxProofs(_) => "gettxproofs",
+            Message::TxProofs(_) => "txproofs",
+            Message::GetFilters(_) => "getcfilters",
+            Message::Filters(_) => "cfilters",
+            Message::GetFilterHeaders(_) => "getcfheaders",
+            Message::FilterHeaders(_) => "cfheaders",
+        }
+    }
+
+    /// Deserialize message from bytes, returns `None` if the bytes are invalid. Lengths in the
+    /// bytes are limited by `MAX_MESSAGE_SIZE`, so that a forged length can't exhaust memory
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let config = config::standard().with_limit::<MAX_MESSAGE_SIZE>();
+        match bincode::decode_from_slice(data, config) {
+            Ok((message, len)) if len == data.len() => Some(message),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod message_test {
+    use super::*;
+
+    #[test]
+    fn encode_and_decode_message() {
+        let version = Version {
+            version: PROTOCOL_VERSION,
+            nonce: 7,
+            best_height: Some(3),
+            genesis: Some([1u8; 32]),
+            listen_port: 3000,
+        };
+        match Message::decode(&Message::Version(version.clone()).encode()) {
+            Some(Message::Version(decoded)) => assert_eq!(decoded, version),
+            _ => panic!("Decode version message failed"),
+        }
+
+        let items = vec![InvItem {
+            kind: InvKind::Tx,
+            hash: [2u8; 32],
+        }];
+        let data = Message::Inv(items.clone()).encode();
+        match Message::decode(&data) {
+            Some(Message::Inv(decoded)) => assert_eq!(decoded, items),
+            _ => panic!("Decode inv message failed"),
+        }
+        assert!(Message::decode(&data[..data.len() - 1]).is_none());
+    }
+}
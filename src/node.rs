@@ -0,0 +1,1324 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+
+use ring::rand;
+use ring::rand::SecureRandom;
+use sha2::{Digest, Sha256};
+
+use crate::block::{Block, BlockHeader, Hash, TimeStamp};
+use crate::block_chain::{BlockChain, TxError};
+use crate::block_filter::MAX_FILTERS;
+use crate::chain_sync::{ChainSync, MAX_HEADERS};
+use crate::compact_block::{CompactBlock, PartialBlock};
+use crate::message::{InvItem, InvKind, Message, PeerAddress, Version, PROTOCOL_VERSION};
+use crate::peer_manager::{PeerManager, BAN_SCORE, MAX_ADDR, MAX_INBOUND, MAX_OUTBOUND};
+use crate::script::Script;
+use crate::spv::MAX_PROOF_SCRIPTS;
+use crate::tools::hash2str;
+use crate::transaction::Transaction;
+use crate::tx_relay::{RateLimiter, TxRelay, TX_RATE_BURST, TX_RATE_PER_SECOND};
+
+/// Id of a connected peer
+pub type PeerId = usize;
+
+/// Interval of pinging peers in milliseconds
+const PING_INTERVAL: TimeStamp = 30_000;
+/// Peers which don't finish handshake or reply ping in this time are disconnected
+const PEER_TIMEOUT: TimeStamp = 60_000;
+/// Misbehavior score of sending an invalid transaction
+const INVALID_TX_SCORE: u32 = 10;
+/// Misbehavior score of sending too many addresses
+const TOO_MANY_ADDR_SCORE: u32 = 20;
+/// Misbehavior score of requesting proofs of too many scripts
+const TOO_MANY_SCRIPTS_SCORE: u32 = 20;
+/// Misbehavior score of requesting too many filters
+const TOO_MANY_FILTERS_SCORE: u32 = 20;
+/// Compact blocks are sent to peers of this version or later
+const COMPACT_BLOCK_VERSION: u32 = 4;
+/// Compact blocks whose missing transactions are not received in this time are given up, in
+/// milliseconds
+const PARTIAL_BLOCK_TIMEOUT: TimeStamp = 10_000;
+/// Max number of compact blocks waiting for missing transactions from a peer, the whole block is
+/// requested once there are more
+const MAX_PARTIAL_BLOCKS: usize = 3;
+/// Max number of announced blocks requested from a peer which are not received yet
+const MAX_REQUESTED_BLOCKS: usize = 16;
+/// Requested blocks which are not received in this time can be requested again, in milliseconds
+const BLOCK_REQUEST_TIMEOUT: TimeStamp = 60_000;
+/// Max number of transactions remembered as known by a peer
+const MAX_KNOWN_TXS: usize = 50_000;
+/// Number of peers which a fresh address is relayed to
+const ADDR_RELAY_PEERS: usize = 2;
+/// Addresses in an addr message with at most this number of addresses are relayed, larger
+/// messages are replies of getaddr
+const MAX_RELAY_ADDR: usize = 10;
+/// Addresses seen in this time are relayed, in milliseconds
+const ADDR_RELAY_AGE: TimeStamp = 10 * 60 * 1000;
+
+/// State of a connected peer
+struct Peer {
+    addr: String,
+    /// Address which the peer listens on, it's known once version message is received if the
+    /// connection is created by the peer
+    listen_addr: Option<String>,
+    /// Whether the connection is created by this node
+    outbound: bool,
+    /// Misbehavior score, the peer is banned once it reaches `BAN_SCORE`
+    score: u32,
+    /// Version message received from the peer
+    version: Option<Version>,
+    /// Whether the peer has acknowledged our version
+    verack: bool,
+    /// Height of the latest block the peer has
+    best_height: Option<u64>,
+    /// Time the peer is connected
+    connected_at: TimeStamp,
+    /// Nonce and sent time of the ping waiting for pong
+    ping: Option<(u64, TimeStamp)>,
+    /// Time the last ping is sent
+    last_ping: TimeStamp,
+    /// Announced blocks requested from the peer, value is the time of request
+    requested: HashMap<Hash, TimeStamp>,
+    /// Transactions the peer has, they are not announced to the peer again
+    known_txs: HashSet<Hash>,
+    /// Limit the rate of transactions sent by the peer
+    tx_limiter: RateLimiter,
+}
+
+impl Peer {
+    /// Whether version messages have been exchanged
+    fn is_ready(&self) -> bool {
+        self.version.is_some() && self.verack
+    }
+
+    /// Remember the peer has transaction `tx_id`, returns `false` if it's known already
+    fn add_known_tx(&mut self, tx_id: Hash) -> bool {
+        if self.known_txs.len() >= MAX_KNOWN_TXS {
+            self.known_txs.clear();
+        }
+        self.known_txs.insert(tx_id)
+    }
+}
+
+/// A node which keeps the chain in sync with it's peers, it doesn't do any IO itself. Messages
+/// received from peers are passed in, and messages to send are taken out
+pub struct Node {
+    /// It's `None` until genesis block is received from peers
+    block_chain: Option<BlockChain>,
+    peers: BTreeMap<PeerId, Peer>,
+    /// Nonce of our version message
+    nonce: u64,
+    listen_port: u16,
+    /// Address which receives reward of blocks mined by the node
+    mine_to: Option<String>,
+    /// Whether there are pending transactions which haven't been tried to mine
+    should_mine: bool,
+    sync: ChainSync,
+    peer_manager: PeerManager,
+    tx_relay: TxRelay,
+    /// Compact blocks waiting for missing transactions, with the peers sent them and the time
+    /// transactions are requested
+    partial_blocks: HashMap<Hash, (PeerId, TimeStamp, PartialBlock)>,
+    /// Messages waiting to be sent
+    outbox: Vec<(PeerId, Message)>,
+    /// Peers which should be disconnected
+    dropped: Vec<PeerId>,
+}
+
+impl Node {
+    pub fn new(
+        block_chain: Option<BlockChain>,
+        listen_port: u16,
+        mine_to: Option<String>,
+        peer_manager: PeerManager,
+    ) -> Self {
+        let rng = rand::SystemRandom::new();
+        let mut nonce = [0u8; 8];
+        rng.fill(&mut nonce).expect("Generate node nonce error");
+        Self {
+            block_chain,
+            peers: BTreeMap::new(),
+            nonce: u64::from_le_bytes(nonce),
+            listen_port,
+            mine_to,
+            should_mine: true,
+            sync: ChainSync::default(),
+            peer_manager,
+            tx_relay: TxRelay::default(),
+            partial_blocks: HashMap::new(),
+            outbox: vec![],
+            dropped: vec![],
+        }
+    }
+
+    /// A peer is connected, version message is sent if the connection is created by us
+    pub fn connect(&mut self, peer: PeerId, addr: &str, outbound: bool, now: TimeStamp) {
+        self.peers.insert(
+            peer,
+            Peer {
+                addr: String::from(addr),
+                listen_addr: if outbound {
+                    Some(String::from(addr))
+                } else {
+                    None
+                },
+                outbound,
+                score: 0,
+                version: None,
+                verack: false,
+                best_height: None,
+                connected_at: now,
+                ping: None,
+                last_ping: now,
+                requested: HashMap::new(),
+                known_txs: HashSet::new(),
+                tx_limiter: RateLimiter::new(TX_RATE_BURST, TX_RATE_PER_SECOND, now),
+            },
+        );
+        if outbound {
+            let version = self.version();
+            self.send(peer, Message::Version(version));
+        }
+    }
+
+    /// A peer is disconnected
+    pub fn disconnect(&mut self, peer: PeerId) {
+        if let Some(p) = self.peers.remove(&peer) {
+            println!("Peer {} is disconnected", p.addr);
+        }
+        self.sync.remove_peer(peer);
+        self.tx_relay.remove_peer(peer);
+        self.partial_blocks.retain(|_, (p, _, _)| *p != peer);
+    }
+
+    /// Whether a connection created by other nodes can be accepted
+    pub fn can_accept(&self) -> bool {
+        self.peers.values().filter(|p| !p.outbound).count() < MAX_INBOUND
+    }
+
+    /// Add address of a node which can be connected
+    pub fn add_address(&mut self, addr: &str, now: TimeStamp) {
+        self.peer_manager.add_address(addr, now);
+    }
+
+    /// Addresses to connect, so that there are enough outbound connections. `connecting` is the
+    /// number of outbound connections which are being created
+    pub fn addresses_to_connect(&mut self, connecting: usize, now: TimeStamp) -> Vec<String> {
+        let outbound = self.peers.values().filter(|p| p.outbound).count() + connecting;
+        if outbound >= MAX_OUTBOUND {
+            return vec![];
+        }
+        let connected: HashSet<String> = self
+            .peers
+            .values()
+            .filter_map(|p| p.listen_addr.clone())
+            .collect();
+        self.peer_manager
+            .select(MAX_OUTBOUND - outbound, &connected, now)
+    }
+
+    /// Connection to `addr` can't be created
+    pub fn connect_failed(&mut self, addr: &str) {
+        self.peer_manager.mark_failed(addr);
+    }
+
+    /// Take messages which should be sent to peers
+    pub fn take_outbox(&mut self) -> Vec<(PeerId, Message)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Take peers which should be disconnected
+    pub fn take_dropped(&mut self) -> Vec<PeerId> {
+        std::mem::take(&mut self.dropped)
+    }
+
+    /// Ping peers, drop peers which don't respond in time, continue synchronization and mine
+    /// pending transactions
+    pub fn tick(&mut self, now: TimeStamp) {
+        let mut pings = vec![];
+        for (id, peer) in self.peers.iter_mut() {
+            peer.requested
+                .retain(|_, time| now.saturating_sub(*time) <= BLOCK_REQUEST_TIMEOUT);
+            let timeout = match peer.ping {
+                Some((_, sent)) => now.saturating_sub(sent) > PEER_TIMEOUT,
+                None => !peer.is_ready() && now.saturating_sub(peer.connected_at) > PEER_TIMEOUT,
+            };
+            if timeout {
+                println!("Peer {} is timeout", peer.addr);
+                self.dropped.push(*id);
+            } else if peer.is_ready()
+                && peer.ping.is_none()
+                && now.saturating_sub(peer.last_ping) >= PING_INTERVAL
+            {
+                let nonce = now as u64;
+                peer.ping = Some((nonce, now));
+                peer.last_ping = now;
+                pings.push((*id, nonce));
+            }
+        }
+        for (id, nonce) in pings {
+            self.send(id, Message::Ping(nonce));
+        }
+        for id in self.sync.stalled_peers(now) {
+            if let Some(p) = self.peers.get(&id) {
+                if !self.dropped.contains(&id) {
+                    println!("Peer {} is stalled", p.addr);
+                    self.dropped.push(id);
+                }
+            }
+        }
+        self.partial_blocks.retain(|hash, (_, time, _)| {
+            let expired = now.saturating_sub(*time) > PARTIAL_BLOCK_TIMEOUT;
+            if expired {
+                println!(
+                    "Missing transactions of block {} are timeout",
+                    hash2str(hash)
+                );
+            }
+            !expired
+        });
+        self.tx_relay.expire(now);
+        self.start_sync(now);
+        self.request_downloads(now);
+        self.mine();
+        self.peer_manager.lift_bans(now);
+        self.peer_manager.save();
+    }
+
+    /// Handle message received from `peer`
+    pub fn receive(&mut self, peer: PeerId, message: Message, now: TimeStamp) {
+        let ready = match self.peers.get(&peer) {
+            Some(p) => p.is_ready(),
+            None => return,
+        };
+        match message {
+            Message::Version(version) => self.on_version(peer, version, now),
+            Message::Verack => self.on_verack(peer, now),
+            // Other messages are ignored before handshake is finished
+            _ if !ready => {}
+            Message::Ping(nonce) => self.send(peer, Message::Pong(nonce)),
+            Message::Pong(nonce) => {
+                if let Some(p) = self.peers.get_mut(&peer) {
+                    if matches!(p.ping, Some((n, _)) if n == nonce) {
+                        p.ping = None;
+                    }
+                }
+            }
+            Message::Inv(items) => self.on_inv(peer, items, now),
+            Message::GetData(items) => self.on_get_data(peer, items),
+            Message::NotFound(items) => self.on_not_found(peer, items, now),
+            Message::GetHeaders(locator) => self.on_get_headers(peer, locator),
+            Message::Headers(headers) => self.on_headers(peer, headers, now),
+            Message::Block(block) => self.on_block(peer, block, now),
+            Message::Tx(tx) => self.on_tx(peer, tx, now),
+            Message::GetAddr => {
+                let addresses = self.peer_manager.addresses(MAX_ADDR, now);
+                self.send(peer, Message::Addr(addresses));
+            }
+            Message::Addr(addresses) => self.on_addr(peer, addresses, now),
+            Message::CompactBlock(compact) => self.on_compact_block(peer, compact, now),
+            Message::GetBlockTxn(hash, indexes) => self.on_get_block_txn(peer, hash, indexes, now),
+            Message::BlockTxn(hash, txs) => self.on_block_txn(peer, hash, txs, now),
+            Message::GetTxProofs(scripts) => self.on_get_tx_proofs(peer, scripts, now),
+            Message::GetFilters(hashes) => self.on_get_filters(peer, hashes, false, now),
+            Message::GetFilterHeaders(hashes) => self.on_get_filters(peer, hashes, true, now),
+            // Only light clients request proofs and filters
+            Message::TxProofs(_) | Message::Filters(_) | Message::FilterHeaders(_) => {}
+        }
+    }
+
+    fn on_version(&mut self, peer: PeerId, version: Version, now: TimeStamp) {
+        let genesis = self.genesis();
+        let p = match self.peers.get_mut(&peer) {
+            Some(p) if p.version.is_none() => p,
+            _ => return,
+        };
+        if p.listen_addr.is_none() && version.listen_port != 0 {
+            p.listen_addr = p
+                .addr
+                .parse::<SocketAddr>()
+                .ok()
+                .map(|addr| SocketAddr::new(addr.ip(), version.listen_port).to_string());
+        }
+        let listen_addr = p.listen_addr.clone().unwrap_or_default();
+        if version.nonce == self.nonce {
+            println!("Drop connection to the node itself");
+            self.peer_manager.mark_local(&listen_addr);
+            self.dropped.push(peer);
+            return;
+        }
+        // The address observed by us is checked, the listen port is told by the peer
+        if self.peer_manager.is_banned(&p.addr, now) {
+            println!("Peer {} is banned", p.addr);
+            self.dropped.push(peer);
+            return;
+        }
+        match (genesis, version.genesis) {
+            (Some(ours), Some(theirs)) if ours != theirs => {
+                println!("Peer {} is in a different chain", p.addr);
+                self.peer_manager.remove_address(&listen_addr);
+                self.dropped.push(peer);
+                return;
+            }
+            _ => {}
+        }
+        println!(
+            "Peer {} is connected, version {}, height {}",
+            p.addr,
+            version.version,
+            match version.best_height {
+                Some(height) => height.to_string(),
+                None => String::from("None"),
+            }
+        );
+        p.best_height = version.best_height;
+        p.version = Some(version);
+        let outbound = p.outbound;
+        if !outbound {
+            let version = self.version();
+            self.send(peer, Message::Version(version));
+        }
+        self.send(peer, Message::Verack);
+        self.on_handshake(peer, now);
+    }
+
+    fn on_verack(&mut self, peer: PeerId, now: TimeStamp) {
+        if let Some(p) = self.peers.get_mut(&peer) {
+            if !p.verack {
+                p.verack = true;
+                self.on_handshake(peer, now);
+            }
+        }
+    }
+
+    /// Announce our latest block and pending transactions once handshake is finished, and
+    /// synchronize with the peer if it has more blocks
+    fn on_handshake(&mut self, peer: PeerId, now: TimeStamp) {
+        let (peer_addr, listen_addr, outbound) = match self.peers.get(&peer) {
+            Some(p) if p.is_ready() => (p.addr.clone(), p.listen_addr.clone(), p.outbound),
+            _ => return,
+        };
+        if let Some(addr) = listen_addr {
+            // Only the address we connect to is known to be reachable, the listen address of an
+            // inbound peer is told by itself like other gossip
+            if outbound {
+                self.peer_manager.mark_good(&addr, now);
+            } else {
+                self.peer_manager
+                    .add_relayed_address(&addr, now, &peer_addr);
+            }
+            // Tell other peers about the new node, so that it's found by the network
+            if !outbound {
+                let address = PeerAddress {
+                    addr,
+                    last_seen: now,
+                };
+                self.relay_addresses(peer, vec![address]);
+            }
+        }
+        // Learn more addresses from the nodes we choose to connect
+        if outbound {
+            self.send(peer, Message::GetAddr);
+        }
+        let mut items: Vec<InvItem> = vec![];
+        if let Some(block_chain) = self.block_chain.as_mut() {
+            items.push(InvItem {
+                kind: InvKind::Block,
+                hash: block_chain.tip,
+            });
+            for tx in block_chain.get_mempool().txs() {
+                items.push(InvItem {
+                    kind: InvKind::Tx,
+                    hash: tx.id,
+                });
+            }
+        }
+        if let Some(p) = self.peers.get_mut(&peer) {
+            for item in items.iter().filter(|item| item.kind == InvKind::Tx) {
+                p.add_known_tx(item.hash);
+            }
+        }
+        if !items.is_empty() {
+            self.send(peer, Message::Inv(items));
+        }
+        self.start_sync(now);
+    }
+
+    fn on_inv(&mut self, peer: PeerId, items: Vec<InvItem>, now: TimeStamp) {
+        let mut wanted = vec![];
+        for item in items {
+            let p = match self.peers.get_mut(&peer) {
+                Some(p) => p,
+                None => return,
+            };
+            if item.kind == InvKind::Tx {
+                p.add_known_tx(item.hash);
+            }
+            let known = match (self.block_chain.as_mut(), item.kind) {
+                (Some(block_chain), InvKind::Block) => block_chain.has_block(&item.hash),
+                (Some(block_chain), InvKind::Tx) => block_chain.get_mempool().contains(&item.hash),
+                // Transactions can't be verified without blocks
+                (None, InvKind::Tx) => true,
+                (None, InvKind::Block) => false,
+            };
+            if known {
+                continue;
+            }
+            match item.kind {
+                InvKind::Block => {
+                    if p.requested.contains_key(&item.hash)
+                        || p.requested.len() >= MAX_REQUESTED_BLOCKS
+                    {
+                        continue;
+                    }
+                    p.requested.insert(item.hash, now);
+                }
+                InvKind::Tx => {
+                    // Announced transactions are counted against the rate limit, so that a peer
+                    // can't make us request more transactions than it's allowed to send
+                    if !p.tx_limiter.allow(now) {
+                        println!(
+                            "Ignore transactions announced by {}, too many transactions",
+                            p.addr
+                        );
+                        break;
+                    }
+                    // A transaction is only requested from one peer at a time
+                    if !self.tx_relay.should_request(&item.hash, peer, now) {
+                        continue;
+                    }
+                }
+            }
+            wanted.push(item);
+        }
+        if !wanted.is_empty() {
+            self.send(peer, Message::GetData(wanted));
+        }
+    }
+
+    fn on_get_data(&mut self, peer: PeerId, items: Vec<InvItem>) {
+        let block_chain = match self.block_chain.as_mut() {
+            Some(block_chain) => block_chain,
+            None => return,
+        };
+        let p = match self.peers.get_mut(&peer) {
+            Some(p) => p,
+            None => return,
+        };
+        let mut replies = vec![];
+        let mut not_found = vec![];
+        for item in items {
+            let reply = match item.kind {
+                InvKind::Block => block_chain.get_block(Some(&item.hash)).map(Message::Block),
+                InvKind::Tx => block_chain.get_mempool().get(&item.hash).map(|tx| {
+                    p.add_known_tx(tx.id);
+                    Message::Tx(tx.clone())
+                }),
+            };
+            match reply {
+                Some(reply) => replies.push(reply),
+                None => not_found.push(item),
+            }
+        }
+        if !not_found.is_empty() {
+            replies.push(Message::NotFound(not_found));
+        }
+        for reply in replies {
+            self.send(peer, reply);
+        }
+    }
+
+    /// Blocks requested from `peer` are requested from other peers
+    fn on_not_found(&mut self, peer: PeerId, items: Vec<InvItem>, now: TimeStamp) {
+        for item in items.iter().filter(|item| item.kind == InvKind::Block) {
+            if let Some(p) = self.peers.get_mut(&peer) {
+                p.requested.remove(&item.hash);
+            }
+            self.sync.not_found(peer, &item.hash);
+        }
+        self.request_downloads(now);
+    }
+
+    fn on_get_headers(&mut self, peer: PeerId, locator: Vec<Hash>) {
+        let mut headers = vec![];
+        if let Some(block_chain) = self.block_chain.as_mut() {
+            let hashes = block_chain.main_chain_hashes();
+            // Blocks after the fork point are sent, or all of the blocks if there is no fork point
+            let start = locator
+                .iter()
+                .find_map(|hash| hashes.iter().position(|h| h == hash))
+                .map_or(0, |idx| idx + 1);
+            for hash in hashes.iter().skip(start).take(MAX_HEADERS) {
+                if let Some(block) = block_chain.get_block(Some(hash)) {
+                    headers.push(block.header());
+                }
+            }
+        }
+        // Empty headers are sent as well, so that the peer knows we don't have more blocks
+        self.send(peer, Message::Headers(headers));
+    }
+
+    fn on_headers(&mut self, peer: PeerId, headers: Vec<BlockHeader>, now: TimeStamp) {
+        let addr = match self.peers.get(&peer) {
+            Some(p) => p.addr.clone(),
+            None => return,
+        };
+        let block_chain = &mut self.block_chain;
+        let added = self.sync.add_headers(peer, &headers, |hash| {
+            block_chain
+                .as_mut()
+                .and_then(|block_chain| block_chain.get_block(Some(hash)))
+                .map(|block| block.height)
+        });
+        match added {
+            Ok(count) => {
+                if count > 0 {
+                    println!(
+                        "Receive {} headers from {}, best header height {}",
+                        count,
+                        addr,
+                        self.sync.best_header_height().unwrap_or(0)
+                    );
+                }
+                if let (Some(p), Some(last)) = (self.peers.get_mut(&peer), headers.last()) {
+                    p.best_height = p.best_height.max(Some(last.height));
+                }
+                // The peer may have more headers, they are requested after blocks are
+                // connected if there are too many headers
+                if headers.len() == MAX_HEADERS && !self.sync.is_full() {
+                    self.request_headers(peer, now);
+                }
+                self.request_downloads(now);
+            }
+            Err(err) => {
+                println!("Reject headers from {}: {}", addr, err);
+                self.misbehave(peer, BAN_SCORE, now);
+            }
+        }
+    }
+
+    fn on_block(&mut self, peer: PeerId, block: Block, now: TimeStamp) {
+        let addr = match self.peers.get_mut(&peer) {
+            Some(p) => {
+                p.requested.remove(&block.hash);
+                p.best_height = p.best_height.max(Some(block.height));
+                p.addr.clone()
+            }
+            None => return,
+        };
+        // The whole block replaces the compact block waiting for transactions
+        self.partial_blocks.remove(&block.hash);
+        if self.sync.is_requested(peer, &block.hash) {
+            match self.sync.add_block(peer, block) {
+                Ok(_) => self.connect_downloaded(now),
+                Err(err) => {
+                    println!("Reject block from {}: {}", addr, err);
+                    self.misbehave(peer, BAN_SCORE, now);
+                }
+            }
+            return;
+        }
+        self.accept_block(peer, block, now);
+    }
+
+    /// Add a block announced by `peer`, headers are requested if it's parent is unknown
+    fn accept_block(&mut self, peer: PeerId, block: Block, now: TimeStamp) {
+        let addr = match self.peers.get(&peer) {
+            Some(p) => p.addr.clone(),
+            None => return,
+        };
+        let block_chain = match self.block_chain.as_mut() {
+            Some(block_chain) => block_chain,
+            None => return,
+        };
+        if block_chain.has_block(&block.hash) {
+            return;
+        }
+        let has_parent = block
+            .prev_block_hash
+            .is_some_and(|hash| block_chain.has_block(&hash));
+        if !has_parent {
+            // Blocks between our chain and the block are missing
+            if !self.sync.is_requesting_headers() {
+                self.request_headers(peer, now);
+            }
+            return;
+        }
+        match block_chain.add_block(&block) {
+            Ok(true) => {
+                println!(
+                    "Add block {} at height {} from {}",
+                    hash2str(&block.hash),
+                    block.height,
+                    addr
+                );
+                self.should_mine = true;
+                self.tx_relay.clear_rejects();
+                self.announce_block(Some(peer), &block);
+            }
+            Ok(false) => {}
+            Err(err) => {
+                println!("Reject block from {}: {}", addr, err);
+                self.misbehave(peer, BAN_SCORE, now);
+            }
+        }
+    }
+
+    fn on_tx(&mut self, peer: PeerId, tx: Transaction, now: TimeStamp) {
+        let tx_id = tx.id;
+        let requested = self.tx_relay.received(&tx_id) == Some(peer);
+        let p = match self.peers.get_mut(&peer) {
+            Some(p) => p,
+            None => return,
+        };
+        p.add_known_tx(tx_id);
+        // Transactions over the rate limit are dropped without verification, requested ones are
+        // counted when they are announced
+        if !requested && !p.tx_limiter.allow(now) {
+            println!(
+                "Drop transaction {} from {}, too many transactions",
+                hash2str(&tx_id),
+                p.addr
+            );
+            return;
+        }
+        if self.tx_relay.is_rejected(&tx_id) {
+            return;
+        }
+        let block_chain = match self.block_chain.as_mut() {
+            Some(block_chain) => block_chain,
+            None => return,
+        };
+        if block_chain.get_mempool().contains(&tx_id) {
+            return;
+        }
+        match block_chain.submit_relayed_tx(tx) {
+            Ok(_) => {
+                println!("Add transaction {} to mempool", hash2str(&tx_id));
+                self.should_mine = true;
+                self.announce_tx(tx_id);
+            }
+            Err(err) => {
+                println!("Reject transaction {}: {}", hash2str(&tx_id), err);
+                self.tx_relay.reject(tx_id);
+                // Only transactions breaking consensus rules are misbehavior, a honest peer
+                // may relay a transaction which is conflicted or not final to us
+                if matches!(err, TxError::Invalid(_)) {
+                    self.misbehave(peer, INVALID_TX_SCORE, now);
+                }
+            }
+        }
+    }
+
+    fn on_compact_block(&mut self, peer: PeerId, compact: CompactBlock, now: TimeStamp) {
+        let header = compact.header.clone();
+        let addr = match self.peers.get_mut(&peer) {
+            Some(p) => {
+                p.best_height = p.best_height.max(Some(header.height));
+                p.addr.clone()
+            }
+            None => return,
+        };
+        let block_chain = match self.block_chain.as_mut() {
+            Some(block_chain) => block_chain,
+            None => return,
+        };
+        if block_chain.has_block(&header.hash) {
+            return;
+        }
+        // The same compact block from another peer replaces the one waiting for transactions, in
+        // case the other peer doesn't reply
+        if matches!(self.partial_blocks.get(&header.hash), Some((p, _, _)) if *p == peer) {
+            return;
+        }
+        if !header.is_valid_pow() {
+            println!("Reject compact block from {}: invalid proof of work", addr);
+            self.misbehave(peer, BAN_SCORE, now);
+            return;
+        }
+        let has_parent = header
+            .prev_block_hash
+            .is_some_and(|hash| block_chain.has_block(&hash));
+        if !has_parent {
+            if !self.sync.is_requesting_headers() {
+                self.request_headers(peer, now);
+            }
+            return;
+        }
+        let mempool = block_chain.get_mempool();
+        let partial = match PartialBlock::new(compact, mempool.txs().iter()) {
+            Ok(partial) => partial,
+            Err(err) => {
+                println!("Reject compact block from {}: {}", addr, err);
+                self.misbehave(peer, BAN_SCORE, now);
+                return;
+            }
+        };
+        self.partial_blocks.remove(&header.hash);
+        let missing = partial.missing();
+        let waiting = self
+            .partial_blocks
+            .values()
+            .filter(|(p, _, _)| *p == peer)
+            .count();
+        if missing.is_empty() {
+            self.finish_partial_block(peer, partial, now);
+        } else if waiting >= MAX_PARTIAL_BLOCKS {
+            println!(
+                "Too many blocks are waiting for transactions from {}, request the whole block",
+                addr
+            );
+            self.request_block(peer, header.hash, now);
+        } else {
+            println!(
+                "Request {} missing transactions of block {} from {}",
+                missing.len(),
+                hash2str(&header.hash),
+                addr
+            );
+            self.send(peer, Message::GetBlockTxn(header.hash, missing));
+            self.partial_blocks
+                .insert(header.hash, (peer, now, partial));
+        }
+    }
+
+    fn on_get_block_txn(&mut self, peer: PeerId, hash: Hash, indexes: Vec<u32>, now: TimeStamp) {
+        let block = match self
+            .block_chain
+            .as_mut()
+            .and_then(|block_chain| block_chain.get_block(Some(&hash)))
+        {
+            Some(block) => block,
+            None => return,
+        };
+        let txs: Option<Vec<_>> = indexes
+            .iter()
+            .map(|index| block.transactions.get(*index as usize).cloned())
+            .collect();
+        match txs {
+            Some(txs) => self.send(peer, Message::BlockTxn(hash, txs)),
+            None => self.misbehave(peer, BAN_SCORE, now),
+        }
+    }
+
+    fn on_block_txn(&mut self, peer: PeerId, hash: Hash, txs: Vec<Transaction>, now: TimeStamp) {
+        let mut partial = match self.partial_blocks.remove(&hash) {
+            Some((p, _, partial)) if p == peer => partial,
+            Some(item) => {
+                self.partial_blocks.insert(hash, item);
+                return;
+            }
+            None => return,
+        };
+        if let Err(err) = partial.fill(txs) {
+            println!("Reject transactions of block {}: {}", hash2str(&hash), err);
+            self.misbehave(peer, BAN_SCORE, now);
+            return;
+        }
+        self.finish_partial_block(peer, partial, now);
+    }
+
+    /// Add the block rebuilt from a compact block, the whole block is requested if transactions
+    /// don't match the header, e.g. a short id matches a wrong transaction in the mempool
+    fn finish_partial_block(&mut self, peer: PeerId, partial: PartialBlock, now: TimeStamp) {
+        match partial.to_block() {
+            Ok(block) => self.accept_block(peer, block, now),
+            Err(err) => {
+                println!(
+                    "Rebuild block {} failed: {}, request the whole block",
+                    hash2str(&partial.hash()),
+                    err
+                );
+                self.request_block(peer, partial.hash(), now);
+            }
+        }
+    }
+
+    /// Request the whole block `hash` from `peer`
+    fn request_block(&mut self, peer: PeerId, hash: Hash, now: TimeStamp) {
+        if let Some(p) = self.peers.get_mut(&peer) {
+            p.requested.insert(hash, now);
+        }
+        let item = InvItem {
+            kind: InvKind::Block,
+            hash,
+        };
+        self.send(peer, Message::GetData(vec![item]));
+    }
+
+    fn on_get_tx_proofs(&mut self, peer: PeerId, scripts: Vec<Script>, now: TimeStamp) {
+        if scripts.len() > MAX_PROOF_SCRIPTS {
+            self.misbehave(peer, TOO_MANY_SCRIPTS_SCORE, now);
+            return;
+        }
+        let proofs = match self.block_chain.as_mut() {
+            Some(block_chain) => block_chain.find_tx_proofs(&scripts.into_iter().collect()),
+            None => vec![],
+        };
+        self.send(peer, Message::TxProofs(proofs));
+    }
+
+    /// Reply filters of blocks `hashes`, or their filter headers if `headers` is true
+    fn on_get_filters(&mut self, peer: PeerId, hashes: Vec<Hash>, headers: bool, now: TimeStamp) {
+        if hashes.len() > MAX_FILTERS {
+            self.misbehave(peer, TOO_MANY_FILTERS_SCORE, now);
+            return;
+        }
+        let block_chain = match self.block_chain.as_mut() {
+            Some(block_chain) => block_chain,
+            None => return,
+        };
+        let filters = hashes
+            .into_iter()
+            .filter_map(|hash| block_chain.get_filter(&hash).map(|filter| (hash, filter)));
+        let reply = if headers {
+            Message::FilterHeaders(filters.map(|(hash, (_, header))| (hash, header)).collect())
+        } else {
+            Message::Filters(filters.map(|(hash, (filter, _))| (hash, filter)).collect())
+        };
+        self.send(peer, reply);
+    }
+
+    fn on_addr(&mut self, peer: PeerId, addresses: Vec<PeerAddress>, now: TimeStamp) {
+        if addresses.len() > MAX_ADDR {
+            self.misbehave(peer, TOO_MANY_ADDR_SCORE, now);
+            return;
+        }
+        let source = match self.peers.get(&peer) {
+            Some(p) => p.addr.clone(),
+            None => return,
+        };
+        let relay = addresses.len() <= MAX_RELAY_ADDR;
+        let mut fresh = vec![];
+        for mut address in addresses {
+            // Time in the future is not trusted
+            address.last_seen = address.last_seen.min(now);
+            // Addresses which are known already are not relayed again, so they don't loop
+            if self
+                .peer_manager
+                .add_relayed_address(&address.addr, address.last_seen, &source)
+                && relay
+                && now - address.last_seen <= ADDR_RELAY_AGE
+            {
+                fresh.push(address);
+            }
+        }
+        self.relay_addresses(peer, fresh);
+    }
+
+    /// Relay each of `addresses` to a few peers except `from`. The peers of an address are chosen
+    /// by hash of the address, so the address is always relayed to the same peers
+    fn relay_addresses(&mut self, from: PeerId, addresses: Vec<PeerAddress>) {
+        let peers: Vec<PeerId> = self
+            .peers
+            .iter()
+            .filter(|(id, p)| **id != from && p.is_ready())
+            .map(|(id, _)| *id)
+            .collect();
+        let mut messages: BTreeMap<PeerId, Vec<PeerAddress>> = BTreeMap::new();
+        for address in addresses {
+            let mut targets = peers.clone();
+            targets.sort_by_cached_key(|peer| {
+                let mut hasher = Sha256::new();
+                hasher.update(self.nonce.to_le_bytes());
+                hasher.update(address.addr.as_bytes());
+                hasher.update(peer.to_le_bytes());
+                hasher.finalize()
+            });
+            for peer in targets.into_iter().take(ADDR_RELAY_PEERS) {
+                messages.entry(peer).or_default().push(address.clone());
+            }
+        }
+        for (peer, addresses) in messages {
+            self.send(peer, Message::Addr(addresses));
+        }
+    }
+
+    /// Increase misbehavior score of `peer`, it's banned once the score reaches `BAN_SCORE`
+    fn misbehave(&mut self, peer: PeerId, score: u32, now: TimeStamp) {
+        let p = match self.peers.get_mut(&peer) {
+            Some(p) => p,
+            None => return,
+        };
+        p.score += score;
+        if p.score < BAN_SCORE {
+            return;
+        }
+        println!("Ban peer {} for misbehavior", p.addr);
+        self.peer_manager.ban(&p.addr, now);
+        if !self.dropped.contains(&peer) {
+            self.dropped.push(peer);
+        }
+    }
+
+    /// Request headers from a peer which has more blocks than us, unless headers are being
+    /// requested or there are too many headers whose blocks are not connected
+    fn start_sync(&mut self, now: TimeStamp) {
+        if self.sync.is_requesting_headers() || self.sync.is_full() {
+            return;
+        }
+        let height = self.best_height().max(self.sync.best_header_height());
+        let peer = self
+            .peers
+            .iter()
+            .find(|(_, p)| p.is_ready() && p.best_height > height)
+            .map(|(id, _)| *id);
+        if let Some(peer) = peer {
+            self.request_headers(peer, now);
+        }
+    }
+
+    /// Send getheaders to `peer`, the locator starts from the best header if it's not connected
+    fn request_headers(&mut self, peer: PeerId, now: TimeStamp) {
+        let mut locator: Vec<Hash> = self.sync.best_header_hash().into_iter().collect();
+        if let Some(block_chain) = self.block_chain.as_mut() {
+            locator.extend(block_chain.block_locator());
+        }
+        self.send(peer, Message::GetHeaders(locator));
+        self.sync.request_headers(peer, now);
+    }
+
+    /// Request blocks of the best header chain from peers which have them
+    fn request_downloads(&mut self, now: TimeStamp) {
+        let peers: Vec<(PeerId, u64)> = self
+            .peers
+            .iter()
+            .filter(|(id, p)| p.is_ready() && !self.dropped.contains(id))
+            .filter_map(|(id, p)| p.best_height.map(|height| (*id, height)))
+            .collect();
+        for (peer, height) in peers {
+            let items: Vec<InvItem> = self
+                .sync
+                .next_requests(peer, height, now)
+                .into_iter()
+                .map(|hash| InvItem {
+                    kind: InvKind::Block,
+                    hash,
+                })
+                .collect();
+            if !items.is_empty() {
+                self.send(peer, Message::GetData(items));
+            }
+        }
+    }
+
+    /// Connect downloaded blocks in order, and report the progress
+    fn connect_downloaded(&mut self, now: TimeStamp) {
+        let blocks = self.sync.take_connectable();
+        if blocks.is_empty() {
+            return;
+        }
+        for (peer, block) in blocks {
+            let added = match self.block_chain.as_mut() {
+                Some(block_chain) => block_chain.add_block(&block).map(|_| ()),
+                None => BlockChain::create_with_genesis(&block)
+                    .map(|block_chain| self.block_chain = Some(block_chain)),
+            };
+            if let Err(err) = added {
+                println!("Reject block {}: {}", hash2str(&block.hash), err);
+                self.sync.reset();
+                self.misbehave(peer, BAN_SCORE, now);
+                return;
+            }
+        }
+        self.tx_relay.clear_rejects();
+        let height = self.best_height().unwrap_or(0);
+        if let Some(percent) = self.sync.progress(height) {
+            println!(
+                "Sync progress: height {} of {} ({}%)",
+                height,
+                self.sync.best_header_height().unwrap_or(0),
+                percent
+            );
+        }
+        if !self.sync.is_downloading() {
+            self.should_mine = true;
+            if let Some(block) = self
+                .block_chain
+                .as_mut()
+                .and_then(|block_chain| block_chain.get_block(None))
+            {
+                self.announce_block(None, &block);
+            }
+        }
+        self.request_downloads(now);
+    }
+
+    /// Mine pending transactions if mining is enabled, the new block is announced to peers
+    fn mine(&mut self) {
+        if !self.should_mine {
+            return;
+        }
+        self.should_mine = false;
+        let (block_chain, address) = match (self.block_chain.as_mut(), self.mine_to.clone()) {
+            (Some(block_chain), Some(address)) => (block_chain, address),
+            _ => return,
+        };
+        if block_chain.get_mempool().txs().is_empty() {
+            return;
+        }
+        if let Err(err) = self.mine_block(&address) {
+            println!("Mining block failed: {}", err);
+        }
+    }
+
+    /// Mine a block with pending transactions, the reward is sent to `address`. The block is
+    /// announced to peers
+    pub fn mine_block(&mut self, address: &str) -> Result<Hash, String> {
+        let block_chain = self
+            .block_chain
+            .as_mut()
+            .ok_or("There are no blocks to mine on")?;
+        block_chain.mine_pending(Some(address))?;
+        self.tx_relay.clear_rejects();
+        let block = block_chain
+            .get_block(None)
+            .ok_or("Can not find the mined block")?;
+        self.announce_block(None, &block);
+        Ok(block.hash)
+    }
+
+    /// The blockchain of the node, it's `None` until genesis block is received
+    #[cfg(test)]
+    pub fn block_chain(&mut self) -> Option<&mut BlockChain> {
+        self.block_chain.as_mut()
+    }
+
+    fn version(&mut self) -> Version {
+        Version {
+            version: PROTOCOL_VERSION,
+            nonce: self.nonce,
+            best_height: self.best_height(),
+            genesis: self.genesis(),
+            listen_port: self.listen_port,
+        }
+    }
+
+    fn best_height(&mut self) -> Option<u64> {
+        self.block_chain
+            .as_mut()
+            .map(|block_chain| block_chain.tip_height())
+    }
+
+    fn genesis(&mut self) -> Option<Hash> {
+        self.block_chain
+            .as_mut()
+            .and_then(|block_chain| block_chain.main_chain_hashes().first().copied())
+    }
+
+    fn send(&mut self, peer: PeerId, message: Message) {
+        self.outbox.push((peer, message));
+    }
+
+    /// Announce transaction `tx_id` to peers which don't know it
+    fn announce_tx(&mut self, tx_id: Hash) {
+        let ids: Vec<PeerId> = self
+            .peers
+            .iter_mut()
+            .filter_map(|(id, p)| (p.is_ready() && p.add_known_tx(tx_id)).then_some(*id))
+            .collect();
+        let item = InvItem {
+            kind: InvKind::Tx,
+            hash: tx_id,
+        };
+        for id in ids {
+            self.send(id, Message::Inv(vec![item]));
+        }
+    }
+
+    /// Announce a new block to peers except `except`, peers supporting compact blocks get a
+    /// compact block, and others get an inv
+    fn announce_block(&mut self, except: Option<PeerId>, block: &Block) {
+        let peers: Vec<(PeerId, bool)> = self
+            .peers
+            .iter()
+            .filter(|(id, p)| p.is_ready() && Some(**id) != except)
+            .map(|(id, p)| {
+                let compact = p
+                    .version
+                    .as_ref()
+                    .is_some_and(|version| version.version >= COMPACT_BLOCK_VERSION);
+                (*id, compact)
+            })
+            .collect();
+        let compact = CompactBlock::new(block);
+        let item = InvItem {
+            kind: InvKind::Block,
+            hash: block.hash,
+        };
+        for (id, is_compact) in peers {
+            if is_compact {
+                self.send(id, Message::CompactBlock(compact.clone()));
+            } else {
+                self.send(id, Message::Inv(vec![item]));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod node_test {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::transaction::{TXInput, TXOutput};
+    use crate::wallet::{derive_key_pair, Wallet};
+
+    /// Connect inbound `peer` from `addr`, and exchange version messages with it
+    fn handshake(node: &mut Node, peer: PeerId, addr: &str, genesis: Hash, listen_port: u16) {
+        node.connect(peer, addr, false, 0);
+        let version = Version {
+            version: PROTOCOL_VERSION,
+            nonce: node.nonce.wrapping_add(1),
+            best_height: Some(0),
+            genesis: Some(genesis),
+            listen_port,
+        };
+        node.receive(peer, Message::Version(version), 0);
+        node.receive(peer, Message::Verack, 0);
+    }
+
+    /// Items requested by get data messages in `outbox`
+    fn requested_items(outbox: &[(PeerId, Message)]) -> Vec<InvItem> {
+        outbox
+            .iter()
+            .filter_map(|(_, message)| match message {
+                Message::GetData(items) => Some(items.clone()),
+                _ => None,
+            })
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    fn replace_and_expire_partial_blocks() {
+        let address = Wallet::new(derive_key_pair(&[0u8; 16], &[0]).as_slice()).get_address();
+        let genesis = Block::new_genesis_block(Transaction::new_coinbase_tx(&address, None, 0, 0));
+        let block_chain = BlockChain::create_in_memory(&genesis).unwrap();
+        let mut node = Node::new(Some(block_chain), 0, None, PeerManager::default());
+        for peer in 0..2 {
+            let addr = format!("127.0.0.{}:3000", peer + 1);
+            handshake(&mut node, peer, &addr, genesis.hash, 0);
+        }
+        node.take_outbox();
+
+        // Blocks whose second transaction is not in the mempool
+        let blocks: Vec<Block> = (0..MAX_PARTIAL_BLOCKS as u8 + 2)
+            .map(|i| {
+                let input = TXInput {
+                    tx_id: Some([i; 32]),
+                    v_out_idx: Some(0),
+                    script_sig: Script::default(),
+                    sequence: 0,
+                };
+                let output = Rc::new(TXOutput::new(1, &address));
+                let tx = Transaction::new(vec![Rc::new(input)], vec![output], 0);
+                let coinbase = Transaction::new_coinbase_tx(&address, None, 1, 0);
+                Block::new(vec![coinbase, tx], Some(genesis.hash), 1)
+            })
+            .collect();
+        let compact = |block: &Block| Message::CompactBlock(CompactBlock::new(block));
+        let is_get_block_txn = |outbox: &[(PeerId, Message)], to: PeerId| matches!(outbox, [(p, Message::GetBlockTxn(_, indexes))] if *p == to && indexes == &[1]);
+
+        node.receive(0, compact(&blocks[0]), 0);
+        assert!(is_get_block_txn(&node.take_outbox(), 0));
+        node.receive(0, compact(&blocks[0]), 0);
+        assert!(node.take_outbox().is_empty());
+        // Another peer's compact block replaces the one waiting for transactions
+        node.receive(1, compact(&blocks[0]), 100);
+        assert!(is_get_block_txn(&node.take_outbox(), 1));
+        assert!(matches!(
+            node.partial_blocks.get(&blocks[0].hash),
+            Some((1, 100, _))
+        ));
+
+        // The whole block is requested once too many blocks are waiting for the peer
+        for block in blocks[1..=MAX_PARTIAL_BLOCKS].iter() {
+            node.receive(0, compact(block), 0);
+            assert!(is_get_block_txn(&node.take_outbox(), 0));
+        }
+        node.receive(0, compact(&blocks[MAX_PARTIAL_BLOCKS + 1]), 0);
+        assert!(matches!(
+            node.take_outbox().as_slice(),
+            [(0, Message::GetData(items))] if items[0].hash == blocks[MAX_PARTIAL_BLOCKS + 1].hash
+        ));
+        assert_eq!(node.partial_blocks.len(), MAX_PARTIAL_BLOCKS + 1);
+
+        // Blocks waiting for transactions too long are given up
+        node.tick(PARTIAL_BLOCK_TIMEOUT + 1);
+        assert_eq!(node.partial_blocks.len(), 1);
+        node.tick(PARTIAL_BLOCK_TIMEOUT + 101);
+        assert!(node.partial_blocks.is_empty());
+    }
+
+    #[test]
+    fn limit_requests_of_announced_items() {
+        let address = Wallet::new(derive_key_pair(&[0u8; 16], &[0]).as_slice()).get_address();
+        let genesis = Block::new_genesis_block(Transaction::new_coinbase_tx(&address, None, 0, 0));
+        let block_chain = BlockChain::create_in_memory(&genesis).unwrap();
+        let mut node = Node::new(Some(block_chain), 0, None, PeerManager::default());
+        handshake(&mut node, 0, "127.0.0.1:3000", genesis.hash, 0);
+        node.take_outbox();
+        let items = |kind: InvKind, count: usize| -> Vec<InvItem> {
+            (0..count)
+                .map(|i| {
+                    let mut hash = [kind as u8 + 1; 32];
+                    hash[..8].copy_from_slice(&(i as u64).to_le_bytes());
+                    InvItem { kind, hash }
+                })
+                .collect()
+        };
+
+        // Blocks in flight from a peer are limited, they can be requested again after timeout
+        let blocks = items(InvKind::Block, MAX_REQUESTED_BLOCKS + 1);
+        node.receive(0, Message::Inv(blocks.clone()), 0);
+        assert_eq!(
+            requested_items(&node.take_outbox()).len(),
+            MAX_REQUESTED_BLOCKS
+        );
+        node.receive(0, Message::Inv(blocks.clone()), 0);
+        assert!(requested_items(&node.take_outbox()).is_empty());
+        let now = BLOCK_REQUEST_TIMEOUT + 1;
+        node.tick(now);
+        node.take_outbox();
+        node.receive(0, Message::Inv(blocks), now);
+        assert_eq!(
+            requested_items(&node.take_outbox()).len(),
+            MAX_REQUESTED_BLOCKS
+        );
+
+        // Announced transactions are counted against the rate limit
+        let txs = items(InvKind::Tx, 2 * TX_RATE_BURST as usize);
+        node.receive(0, Message::Inv(txs.clone()), now);
+        let requested = requested_items(&node.take_outbox());
+        assert!(!requested.is_empty() && requested.len() <= TX_RATE_BURST as usize);
+        node.receive(0, Message::Inv(txs), now);
+        assert!(requested_items(&node.take_outbox()).is_empty());
+    }
+
+    #[test]
+    fn ban_inbound_peer_by_socket_ip() {
+        let address = Wallet::new(derive_key_pair(&[0u8; 16], &[0]).as_slice()).get_address();
+        let genesis = Block::new_genesis_block(Transaction::new_coinbase_tx(&address, None, 0, 0));
+        let block_chain = BlockChain::create_in_memory(&genesis).unwrap();
+        let mut node = Node::new(Some(block_chain), 0, None, PeerManager::default());
+        // The peer claims to listen on a port which differs from the port it connects from
+        handshake(&mut node, 0, "127.0.0.5:51234", genesis.hash, 3000);
+        node.take_outbox();
+        assert!(node.take_dropped().is_empty());
+
+        // Asking for transactions which the block doesn't have is misbehavior
+        node.receive(0, Message::GetBlockTxn(genesis.hash, vec![5]), 0);
+        assert_eq!(node.take_dropped(), vec![0]);
+        node.disconnect(0);
+        assert!(node.peer_manager.is_banned("127.0.0.5:51234", 0));
+        assert!(node.peer_manager.is_banned("127.0.0.5:3000", 0));
+        assert!(!node
+            .addresses_to_connect(0, 0)
+            .contains(&String::from("127.0.0.5:3000")));
+
+        // It can't come back from another port or by claiming another listen port
+        handshake(&mut node, 1, "127.0.0.5:60000", genesis.hash, 4000);
+        assert_eq!(node.take_dropped(), vec![1]);
+    }
+}
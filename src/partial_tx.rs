@@ -0,0 +1,231 @@
+use bincode::{config, Decode, Encode};
+
+use crate::block::ByteData;
+use crate::codec::MAX_MESSAGE_SIZE;
+use crate::script::{Op, Script};
+use crate::tools::{bytes2hex, hex2bytes};
+use crate::transaction::Transaction;
+use crate::wallet::{verify_signature, Wallet};
+
+/// A transaction spending pay to script hash multisig outputs which is waiting for signatures of
+/// co-signers. It's passed between co-signers as hex text, every co-signer adds signatures of it's
+/// own key, the transaction can be finalized once each input has enough signatures
+#[derive(Encode, Decode)]
+pub struct PartialTx {
+    /// The unsigned transaction
+    pub tx: Transaction,
+    /// Multisig redeem scripts of outputs referenced by each input
+    pub redeem_scripts: Vec<Script>,
+    /// Collected signatures of each input, it's a list of public key and signature pairs
+    pub signatures: Vec<Vec<(ByteData, ByteData)>>,
+}
+
+impl PartialTx {
+    pub fn new(tx: Transaction, redeem_scripts: Vec<Script>) -> Self {
+        let signatures = vec![vec![]; tx.v_in.len()];
+        Self {
+            tx,
+            redeem_scripts,
+            signatures,
+        }
+    }
+
+    /// Sign every input whose multisig script contains public key of `wallet`, returns the number
+    /// of signed inputs
+    pub fn sign(&mut self, wallet: &Wallet) -> usize {
+        let pub_key = wallet.public_key();
+        let mut signed = 0;
+        for (i, script) in self.redeem_scripts.iter().enumerate() {
+            let is_signer = match script.multisig_keys() {
+                Some((_, pub_keys)) => pub_keys.contains(&pub_key),
+                None => false,
+            };
+            let is_signed = self.signatures[i].iter().any(|(k, _)| k == pub_key);
+            if !is_signer || is_signed {
+                continue;
+            }
+            let signature = wallet.sign(&self.tx.sighash(i, script));
+            self.signatures[i].push((Vec::from(pub_key), Vec::from(signature.as_ref())));
+            signed += 1;
+        }
+        signed
+    }
+
+    /// Add `signature` of `pub_key` to input `index`, it's verified against the sighash of the
+    /// input, so that a bad signature is found before the transaction is finalized
+    pub fn add_signature(
+        &mut self,
+        index: usize,
+        pub_key: &[u8],
+        signature: &[u8],
+    ) -> Result<(), String> {
+        self.check_signature(index, pub_key, signature)?;
+        if self.signatures[index].iter().any(|(k, _)| k == pub_key) {
+            return Err(format!("Input {} is signed by the key already", index));
+        }
+        self.signatures[index].push((Vec::from(pub_key), Vec::from(signature)));
+        Ok(())
+    }
+
+    /// Check `pub_key` is a key of multisig script of input `index`, and `signature` is it's
+    /// signature of the input
+    fn check_signature(
+        &self,
+        index: usize,
+        pub_key: &[u8],
+        signature: &[u8],
+    ) -> Result<(), String> {
+        let script = match self.redeem_scripts.get(index) {
+            Some(script) => script,
+            None => return Err(format!("Input {} doesn't exist", index)),
+        };
+        let is_signer = match script.multisig_keys() {
+            Some((_, pub_keys)) => pub_keys.contains(&pub_key),
+            None => false,
+        };
+        if !is_signer {
+            return Err(format!(
+                "Public key {} can't sign input {}",
+                bytes2hex(pub_key),
+                index
+            ));
+        }
+        if !verify_signature(pub_key, &self.tx.sighash(index, script), signature) {
+            return Err(format!("Invalid signature of input {}", index));
+        }
+        Ok(())
+    }
+
+    /// Number of collected and required signatures of each input
+    pub fn progress(&self) -> Vec<(usize, usize)> {
+        self.redeem_scripts
+            .iter()
+            .zip(&self.signatures)
+            .map(|(script, sigs)| {
+                let required = script.multisig_keys().map(|(m, _)| m).unwrap_or(0);
+                (sigs.len(), required)
+            })
+            .collect()
+    }
+
+    /// Build unlocking scripts with collected signatures and redeem script, signatures are ordered
+    /// as public keys in multisig script. Fails if any input doesn't have enough signatures
+    pub fn finalize(mut self) -> Result<Transaction, String> {
+        for (i, script) in self.redeem_scripts.iter().enumerate() {
+            let (required, pub_keys) = match script.multisig_keys() {
+                Some(keys) => keys,
+                None => return Err(format!("Input {} doesn't spend a multisig output", i)),
+            };
+            let mut script_sig: Vec<Op> = pub_keys
+                .iter()
+                .filter_map(|pub_key| {
+                    self.signatures[i]
+                        .iter()
+                        .find(|(k, _)| k == pub_key)
+                        .map(|(_, sig)| Op::PushData(sig.clone()))
+                })
+                .take(required)
+                .collect();
+            if script_sig.len() < required {
+                return Err(format!(
+                    "Input {} has {} of {} required signatures",
+                    i,
+                    script_sig.len(),
+                    required
+                ));
+            }
+            script_sig.push(Op::PushData(script.encode()));
+            self.tx.set_script_sig(i, Script(script_sig));
+        }
+        Ok(self.tx)
+    }
+
+    /// Encode to hex text, so that it can be passed to co-signers
+    pub fn encode(&self) -> String {
+        let config = config::standard();
+        let data = bincode::encode_to_vec(self, config).expect("Can not encode PartialTx");
+        bytes2hex(data.as_slice())
+    }
+
+    /// Decode from hex text created by `encode`
+    pub fn decode(text: &str) -> Result<Self, String> {
+        let data = match hex2bytes(text.trim()) {
+            Some(d) => d,
+            None => return Err(String::from("Invalid hex text of partial transaction")),
+        };
+        let config = config::standard().with_limit::<MAX_MESSAGE_SIZE>();
+        let decoded: Self = match bincode::decode_from_slice(data.as_slice(), config) {
+            Ok((partial_tx, _)) => partial_tx,
+            Err(err) => return Err(format!("Can not decode partial transaction: {}", err)),
+        };
+        let count = decoded.tx.v_in.len();
+        if decoded.redeem_scripts.len() != count || decoded.signatures.len() != count {
+            return Err(format!(
+                "Partial transaction has {} inputs, but {} redeem scripts and {} signature lists",
+                count,
+                decoded.redeem_scripts.len(),
+                decoded.signatures.len()
+            ));
+        }
+        // Signatures are added again, so that each of them is verified
+        let mut partial_tx = Self::new(decoded.tx, decoded.redeem_scripts);
+        for (index, signatures) in decoded.signatures.into_iter().enumerate() {
+            for (pub_key, signature) in signatures {
+                partial_tx.add_signature(index, &pub_key, &signature)?;
+            }
+        }
+        Ok(partial_tx)
+    }
+}
+
+#[cfg(test)]
+mod partial_tx_test {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::transaction::TXInput;
+    use crate::wallet::derive_key_pair;
+
+    #[test]
+    fn verify_signatures_of_decoded_tx() {
+        let wallets: Vec<Wallet> = (0..3)
+            .map(|i| Wallet::new(derive_key_pair(&[0u8; 16], &[i]).as_slice()))
+            .collect();
+        let pub_keys: Vec<ByteData> = wallets.iter().map(|w| Vec::from(w.public_key())).collect();
+        let script = Script::multisig(2, &pub_keys);
+        let address = wallets[0].get_address();
+        let mut tx = Transaction::new_coinbase_tx(&address, None, 1, 0);
+        tx.v_in = vec![Rc::new(TXInput {
+            tx_id: Some([1u8; 32]),
+            v_out_idx: Some(0),
+            script_sig: Script(vec![]),
+            sequence: 0,
+        })];
+        let mut partial_tx = PartialTx::new(tx, vec![script]);
+        assert_eq!(partial_tx.sign(&wallets[0]), 1);
+        let decoded = PartialTx::decode(&partial_tx.encode()).unwrap();
+        assert_eq!(decoded.progress(), vec![(1, 2)]);
+
+        // Signature of another input is rejected
+        let signature = wallets[1].sign(b"something else");
+        let pub_key = wallets[1].public_key();
+        assert!(partial_tx
+            .add_signature(0, pub_key, signature.as_ref())
+            .is_err());
+        partial_tx.signatures[0].push((Vec::from(pub_key), Vec::from(signature.as_ref())));
+        assert!(PartialTx::decode(&partial_tx.encode()).is_err());
+
+        // Signature lists must match the inputs
+        partial_tx.signatures = vec![];
+        assert!(PartialTx::decode(&partial_tx.encode()).is_err());
+    }
+
+    #[test]
+    fn reject_forged_length() {
+        // The transaction claims 2^60 inputs, which must not be allocated
+        let mut data = bincode::encode_to_vec([0u8; 32], config::standard()).unwrap();
+        data.push(253);
+        data.extend_from_slice(&(1u64 << 60).to_le_bytes());
+        assert!(PartialTx::decode(&bytes2hex(&data)).is_err());
+    }
+}
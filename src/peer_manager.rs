@@ -0,0 +1,436 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+
+use bincode::{config, Decode, Encode};
+
+use crate::block::TimeStamp;
+use crate::message::PeerAddress;
+
+/// File of the address book
+pub const PEERS_FILE: &str = "peers";
+/// File of seed addresses, one address per line, lines starting with # are comments
+pub const SEEDS_FILE: &str = "seeds";
+/// Max number of outbound connections
+pub const MAX_OUTBOUND: usize = 8;
+/// Max number of inbound connections
+pub const MAX_INBOUND: usize = 32;
+/// Max number of addresses in an addr message
+pub const MAX_ADDR: usize = 1000;
+/// Peers are banned once their misbehavior score reaches this value
+pub const BAN_SCORE: u32 = 100;
+/// Time of a ban in milliseconds
+const BAN_DURATION: TimeStamp = 24 * 60 * 60 * 1000;
+/// Time to wait before connecting to an address again in milliseconds
+const RETRY_INTERVAL: TimeStamp = 60_000;
+/// Max number of addresses in the address book
+const MAX_ADDRESSES: usize = 2000;
+/// Max number of addresses learned from peers of the same network group, so that a few peers
+/// can't flush the address book
+const MAX_ADDRESSES_PER_SOURCE: usize = 64;
+/// Addresses are forgot once connections to them fail this number of times in a row
+const MAX_FAILURES: u32 = 10;
+
+/// What we know about an address
+#[derive(Clone, Debug, Default, PartialEq, Eq, Encode, Decode)]
+struct AddressInfo {
+    /// Time the address is seen alive
+    last_seen: TimeStamp,
+    /// Time of the last connection attempt
+    last_attempt: TimeStamp,
+    /// Number of failed connection attempts since the last success
+    failures: u32,
+    /// Time the last connection to the address succeeds, it's 0 if it's never connected
+    last_success: TimeStamp,
+    /// Network group of the peer which tells the address, it's empty if the address isn't
+    /// learned from peers
+    source: String,
+}
+
+#[derive(Default, Encode, Decode)]
+struct AddressBook {
+    addresses: BTreeMap<String, AddressInfo>,
+    /// Banned IP addresses, value is the time the ban is lifted
+    bans: BTreeMap<String, TimeStamp>,
+}
+
+/// Keep addresses of known peers and banned peers, peers are identified by the address they
+/// listen on, but they are banned by IP address because the port is told by peers themselves.
+/// The address book is saved to file if it's loaded from file
+#[derive(Default)]
+pub struct PeerManager {
+    book: AddressBook,
+    file: Option<String>,
+    /// Addresses of this node, they are never added
+    local: HashSet<String>,
+    /// Addresses to bootstrap from, they are never forgot
+    seeds: HashSet<String>,
+    /// Whether there are changes which are not saved
+    dirty: bool,
+}
+
+impl PeerManager {
+    /// Load address book from `file`, it's empty if the file doesn't exist
+    pub fn load(file: &str) -> Self {
+        let book = match fs::read(file) {
+            Ok(data) => match bincode::decode_from_slice(data.as_slice(), config::standard()) {
+                Ok((book, _)) => book,
+                Err(err) => {
+                    println!("Decode address book error: {}", err);
+                    AddressBook::default()
+                }
+            },
+            Err(_) => AddressBook::default(),
+        };
+        Self {
+            book,
+            file: Some(String::from(file)),
+            local: HashSet::new(),
+            seeds: HashSet::new(),
+            dirty: false,
+        }
+    }
+
+    /// Save address book to file if it's changed
+    pub fn save(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        self.dirty = false;
+        if let Some(file) = self.file.as_ref() {
+            let data = bincode::encode_to_vec(&self.book, config::standard())
+                .expect("Can not encode address book");
+            if let Err(err) = fs::write(file, data) {
+                println!("Save address book error: {}", err);
+            }
+        }
+    }
+
+    /// Add seed addresses, they are tried after addresses learned from peers
+    pub fn add_seeds(&mut self, seeds: Vec<String>) {
+        for seed in seeds {
+            self.add_address(&seed, 0);
+            self.seeds.insert(seed);
+        }
+    }
+
+    /// Add an address which isn't learned from peers, e.g. it's provided by the user. It's
+    /// ignored if it's not a valid socket address. Returns whether the address is new or seen
+    /// more recently
+    pub fn add_address(&mut self, addr: &str, last_seen: TimeStamp) -> bool {
+        self.insert_address(addr, last_seen, String::new())
+    }
+
+    /// Add an address told by peer `source`. Addresses told by peers of the same network group
+    /// replace each other once there are too many of them, and they never replace addresses
+    /// which are connected successfully. Returns whether the address is new or seen more recently
+    pub fn add_relayed_address(&mut self, addr: &str, last_seen: TimeStamp, source: &str) -> bool {
+        match network_group(source) {
+            Some(group) => self.insert_address(addr, last_seen, group),
+            None => false,
+        }
+    }
+
+    fn insert_address(&mut self, addr: &str, last_seen: TimeStamp, source: String) -> bool {
+        if addr.parse::<SocketAddr>().is_err() || self.local.contains(addr) {
+            return false;
+        }
+        if let Some(info) = self.book.addresses.get_mut(addr) {
+            if info.last_seen >= last_seen {
+                return false;
+            }
+            info.last_seen = last_seen;
+            self.dirty = true;
+            return true;
+        }
+        // Forget the address which is seen earliest to make room, addresses of the same source
+        // are forgot first
+        let from_source = !source.is_empty()
+            && self
+                .book
+                .addresses
+                .values()
+                .filter(|info| info.source == source)
+                .count()
+                >= MAX_ADDRESSES_PER_SOURCE;
+        if from_source || self.book.addresses.len() >= MAX_ADDRESSES {
+            let mut oldest = self.oldest_address(|addr, info| {
+                (!from_source || info.source == source)
+                    && info.last_success == 0
+                    && !self.seeds.contains(addr)
+            });
+            // Addresses which are connected successfully are only replaced by ours, not gossip
+            if oldest.is_none() && source.is_empty() {
+                oldest = self.oldest_address(|_, _| true);
+            }
+            match oldest {
+                Some(oldest) => self.book.addresses.remove(&oldest),
+                None => return false,
+            };
+        }
+        let info = AddressInfo {
+            last_seen,
+            source,
+            ..AddressInfo::default()
+        };
+        self.book.addresses.insert(String::from(addr), info);
+        self.dirty = true;
+        true
+    }
+
+    /// The address which is seen earliest among the addresses matching `filter`
+    fn oldest_address(&self, filter: impl Fn(&str, &AddressInfo) -> bool) -> Option<String> {
+        self.book
+            .addresses
+            .iter()
+            .filter(|(addr, info)| filter(addr, info))
+            .min_by_key(|(_, info)| info.last_seen)
+            .map(|(addr, _)| addr.clone())
+    }
+
+    /// Forget an address, e.g. the node of the address is in a different chain
+    pub fn remove_address(&mut self, addr: &str) {
+        self.dirty |= self.book.addresses.remove(addr).is_some();
+    }
+
+    /// `addr` is the address of this node, it's forgot and never added again
+    pub fn mark_local(&mut self, addr: &str) {
+        self.remove_address(addr);
+        self.local.insert(String::from(addr));
+    }
+
+    /// Connection to `addr` succeeds
+    pub fn mark_good(&mut self, addr: &str, now: TimeStamp) {
+        self.add_address(addr, now);
+        if let Some(info) = self.book.addresses.get_mut(addr) {
+            info.failures = 0;
+            info.last_success = now;
+            self.dirty = true;
+        }
+    }
+
+    /// Connection to `addr` fails, the address is forgot if it fails too many times except it's
+    /// a seed
+    pub fn mark_failed(&mut self, addr: &str) {
+        if let Some(info) = self.book.addresses.get_mut(addr) {
+            info.failures += 1;
+            self.dirty = true;
+            if info.failures >= MAX_FAILURES && !self.seeds.contains(addr) {
+                println!("Forget unreachable address {}", addr);
+                self.book.addresses.remove(addr);
+            }
+        }
+    }
+
+    /// Ban IP address of `addr` until some time later, all ports of the IP address are banned
+    pub fn ban(&mut self, addr: &str, now: TimeStamp) {
+        if let Ok(addr) = addr.parse::<SocketAddr>() {
+            self.book
+                .bans
+                .insert(addr.ip().to_string(), now + BAN_DURATION);
+            self.dirty = true;
+        }
+    }
+
+    /// Whether IP address of `addr` is banned at `now`
+    pub fn is_banned(&self, addr: &str, now: TimeStamp) -> bool {
+        addr.parse::<SocketAddr>().is_ok_and(|addr| {
+            self.book
+                .bans
+                .get(&addr.ip().to_string())
+                .is_some_and(|until| *until > now)
+        })
+    }
+
+    /// Remove expired bans
+    pub fn lift_bans(&mut self, now: TimeStamp) {
+        let count = self.book.bans.len();
+        self.book.bans.retain(|_, until| *until > now);
+        self.dirty |= self.book.bans.len() != count;
+    }
+
+    /// Addresses which are seen most recently, they are shared with peers
+    pub fn addresses(&self, limit: usize, now: TimeStamp) -> Vec<PeerAddress> {
+        let mut addresses: Vec<PeerAddress> = self
+            .book
+            .addresses
+            .iter()
+            .filter(|(addr, _)| !self.is_banned(addr, now))
+            .map(|(addr, info)| PeerAddress {
+                addr: addr.clone(),
+                last_seen: info.last_seen,
+            })
+            .collect();
+        addresses.sort_by_key(|address| Reverse(address.last_seen));
+        addresses.truncate(limit);
+        addresses
+    }
+
+    /// Select at most `count` addresses to connect, addresses which are connected, banned or
+    /// tried recently are skipped. The selected addresses are marked as tried at `now`
+    pub fn select(
+        &mut self,
+        count: usize,
+        connected: &HashSet<String>,
+        now: TimeStamp,
+    ) -> Vec<String> {
+        let mut candidates: Vec<(&String, &AddressInfo)> = self
+            .book
+            .addresses
+            .iter()
+            .filter(|(addr, _)| !connected.contains(*addr) && !self.is_banned(addr, now))
+            .filter(|(_, info)| {
+                info.last_attempt == 0 || now.saturating_sub(info.last_attempt) >= RETRY_INTERVAL
+            })
+            .collect();
+        // Addresses which fail less and are seen more recently are preferred
+        candidates.sort_by(|(_, a), (_, b)| {
+            a.failures
+                .cmp(&b.failures)
+                .then(b.last_seen.cmp(&a.last_seen))
+        });
+        let selected: Vec<String> = candidates
+            .into_iter()
+            .take(count)
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        for addr in selected.iter() {
+            if let Some(info) = self.book.addresses.get_mut(addr) {
+                info.last_attempt = now;
+            }
+        }
+        self.dirty |= !selected.is_empty();
+        selected
+    }
+}
+
+/// Network group of socket address `addr`, which is the first 16 bits of an IPv4 address or the
+/// first 32 bits of an IPv6 address. Peers of a group are likely run by the same operator
+fn network_group(addr: &str) -> Option<String> {
+    match addr.parse::<SocketAddr>().ok()?.ip() {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            Some(format!("{}.{}", octets[0], octets[1]))
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            Some(format!("{:x}:{:x}", segments[0], segments[1]))
+        }
+    }
+}
+
+/// Load seed addresses from `file`, there are no seeds if the file doesn't exist
+pub fn load_seeds(file: &str) -> Vec<String> {
+    let text = match fs::read_to_string(file) {
+        Ok(text) => text,
+        Err(_) => return vec![],
+    };
+    let mut seeds = vec![];
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.parse::<SocketAddr>() {
+            Ok(_) => seeds.push(String::from(line)),
+            Err(_) => println!("Invalid seed address {}", line),
+        }
+    }
+    seeds
+}
+
+#[cfg(test)]
+mod peer_manager_test {
+    use super::*;
+
+    #[test]
+    fn select_addresses_and_ban() {
+        let mut manager = PeerManager::default();
+        manager.add_address("127.0.0.1:3000", 10);
+        manager.add_address("127.0.0.2:3001", 20);
+        manager.add_address("127.0.0.3:3002", 30);
+        manager.add_address("not an address", 40);
+        manager.mark_failed("127.0.0.3:3002");
+        manager.ban("127.0.0.2:3001", 100);
+        assert!(manager.is_banned("127.0.0.2:3001", 100));
+        // The IP address is banned, whatever port the peer claims to listen on
+        assert!(manager.is_banned("127.0.0.2:0", 100));
+        assert!(!manager.is_banned("127.0.0.1:3001", 100));
+
+        let connected = HashSet::new();
+        assert_eq!(
+            manager.select(8, &connected, 100),
+            vec!["127.0.0.1:3000", "127.0.0.3:3002"]
+        );
+        // Addresses are not tried again in a while
+        assert!(manager.select(8, &connected, 200).is_empty());
+        let addresses = manager.addresses(MAX_ADDR, 200);
+        assert_eq!(addresses.len(), 2);
+        assert_eq!(addresses[0].addr, "127.0.0.3:3002");
+
+        // The ban is lifted after a while
+        manager.lift_bans(100 + BAN_DURATION);
+        assert!(!manager.is_banned("127.0.0.2:3001", 100 + BAN_DURATION));
+        assert_eq!(
+            manager.select(8, &connected, 100 + BAN_DURATION),
+            vec!["127.0.0.2:3001", "127.0.0.1:3000", "127.0.0.3:3002"]
+        );
+    }
+
+    #[test]
+    fn forget_unreachable_addresses() {
+        let mut manager = PeerManager::default();
+        manager.add_seeds(vec![String::from("127.0.0.1:4000")]);
+        assert!(manager.add_address("127.0.0.1:3000", 10));
+        assert!(!manager.add_address("127.0.0.1:3000", 5));
+        // Seeds are tried after addresses learned from peers
+        assert_eq!(
+            manager.select(8, &HashSet::new(), 100),
+            vec!["127.0.0.1:3000", "127.0.0.1:4000"]
+        );
+        for _ in 0..MAX_FAILURES {
+            manager.mark_failed("127.0.0.1:3000");
+            manager.mark_failed("127.0.0.1:4000");
+        }
+        let addresses = manager.addresses(MAX_ADDR, 100);
+        assert_eq!(addresses.len(), 1);
+        assert_eq!(addresses[0].addr, "127.0.0.1:4000");
+    }
+
+    #[test]
+    fn keep_address_book_from_flooding() {
+        let mut manager = PeerManager::default();
+        manager.add_address("10.0.0.1:3000", 10);
+        manager.mark_good("10.0.0.1:3000", 10);
+        assert!(manager.add_relayed_address("10.1.0.1:3000", 20, "10.1.0.2:4000"));
+        assert!(!manager.add_relayed_address("10.1.0.3:3000", 20, "not an address"));
+
+        // Addresses from a network group replace each other
+        for i in 0..MAX_ADDRESSES {
+            let addr = format!("192.168.{}.{}:3000", i / 256, i % 256);
+            manager.add_relayed_address(&addr, 100 + i as TimeStamp, "172.16.0.1:4000");
+        }
+        let count = |manager: &PeerManager, source: &str| {
+            manager
+                .book
+                .addresses
+                .values()
+                .filter(|info| info.source == source)
+                .count()
+        };
+        assert_eq!(count(&manager, "172.16"), MAX_ADDRESSES_PER_SOURCE);
+        assert_eq!(count(&manager, "10.1"), 1);
+
+        // A full address book forgets gossip, but not the address which is connected
+        for group in 0..MAX_ADDRESSES / MAX_ADDRESSES_PER_SOURCE + 1 {
+            for i in 0..MAX_ADDRESSES_PER_SOURCE {
+                let addr = format!("10.{}.1.{}:3000", group + 2, i);
+                let source = format!("10.{}.0.1:4000", group + 2);
+                manager.add_relayed_address(&addr, 10_000, &source);
+            }
+        }
+        assert_eq!(manager.book.addresses.len(), MAX_ADDRESSES);
+        assert!(manager.book.addresses.contains_key("10.0.0.1:3000"));
+        assert!(!manager.book.addresses.contains_key("10.1.0.1:3000"));
+    }
+}
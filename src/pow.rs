@@ -1,4 +1,5 @@
 use crate::block::{Hash, TimeStamp};
+use crate::merkle::merkle_root;
 use crate::transaction::Transaction;
 use sha2::{Digest, Sha256};
 
@@ -9,21 +10,14 @@ pub const TARGET_BITS: u8 = 1;
 /// Proof of work algorithm, return the hash value which meet the requirements, and nonce value
 pub fn pow(
     timestamp: TimeStamp,
-    transactions: &Vec<Transaction>,
+    transactions: &[Transaction],
     prev_block_hash: &Option<Hash>,
 ) -> (Hash, u64) {
+    let tx_hash = hash_transactions(transactions);
     let mut nonce = 1u64;
     let mut hash: Hash;
     loop {
-        let mut hasher = Sha256::new()
-            .chain_update(timestamp.to_string())
-            .chain_update(hash_transactions(transactions));
-        if let Some(pre_hash) = prev_block_hash {
-            hasher.update(pre_hash);
-        }
-        // Nonce should be appended to the end (as bytes in little end order) to calculate hash value
-        hasher.update(nonce.to_le_bytes());
-        hash = hasher.finalize().try_into().unwrap();
+        hash = hash_block(timestamp, &tx_hash, prev_block_hash, nonce);
         // Check if hash value is meet requirements
         if validate_hash(&hash) {
             break;
@@ -40,12 +34,29 @@ pub fn pow(
     (hash, nonce)
 }
 
-fn hash_transactions(transactions: &Vec<Transaction>) -> Hash {
-    let mut hasher = Sha256::new();
-    for tx in transactions {
-        hasher.update(tx.id);
+/// Calculate hash value of block with `nonce`, `tx_hash` is the hash of it's transactions
+pub fn hash_block(
+    timestamp: TimeStamp,
+    tx_hash: &Hash,
+    prev_block_hash: &Option<Hash>,
+    nonce: u64,
+) -> Hash {
+    let mut hasher = Sha256::new()
+        .chain_update(timestamp.to_string())
+        .chain_update(tx_hash);
+    if let Some(pre_hash) = prev_block_hash {
+        hasher.update(pre_hash);
     }
-    hasher.finalize().try_into().unwrap()
+    // Nonce should be appended to the end (as bytes in little end order) to calculate hash value
+    hasher.update(nonce.to_le_bytes());
+    hasher.finalize().into()
+}
+
+/// Merkle root of transaction ids, which is committed by block hash. A transaction can be
+/// proved to be in block without other transactions
+pub fn hash_transactions(transactions: &[Transaction]) -> Hash {
+    let ids: Vec<Hash> = transactions.iter().map(|tx| tx.id).collect();
+    merkle_root(&ids)
 }
 
 /// Validate the hash value has meet the requirements, i.e. some bits in front of hash should be 0
@@ -0,0 +1,614 @@
+use bincode::{config, Decode, Encode};
+use sha2::{Digest, Sha256};
+use std::fmt::{Display, Formatter};
+
+use crate::block::{ByteData, Hash};
+use crate::codec::MAX_MESSAGE_SIZE;
+use crate::tools::bytes2hex;
+use crate::transaction::LOCK_TIME_THRESHOLD;
+use crate::wallet::{hash_pub_key, verify_signature};
+
+/// Maximum number of items in the stack during script execution
+const MAX_STACK_SIZE: usize = 1000;
+/// Maximum number of operations in a script
+const MAX_SCRIPT_OPS: usize = 200;
+/// Maximum number of public keys in a multisig script
+pub const MAX_MULTISIG_KEYS: usize = 16;
+/// Maximum number of bytes carried by a data output
+pub const MAX_DATA_CARRIER_LEN: usize = 80;
+
+/// Operations of script, the script is executed from left to right with a stack of bytes data
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Encode, Decode)]
+pub enum Op {
+    /// Push bytes data onto the stack
+    PushData(ByteData),
+    /// Push a number onto the stack
+    Num(i64),
+    /// Execute following operations if top item is true, until `Else` or `EndIf`
+    If,
+    /// Execute following operations if top item is false, until `Else` or `EndIf`
+    NotIf,
+    /// Execute following operations if the preceding `If` or `NotIf` wasn't executed
+    Else,
+    /// End of conditional block
+    EndIf,
+    /// Fail if top item is false, top item is removed
+    Verify,
+    /// Mark the output as unspendable, the script fails immediately
+    Return,
+    /// Duplicate top item
+    Dup,
+    /// Remove top item
+    Drop,
+    /// Swap the top two items
+    Swap,
+    /// Push true if the top two items are equal, false otherwise
+    Equal,
+    /// Same as `Equal`, then `Verify`
+    EqualVerify,
+    /// Push true if the top two numbers are equal, false otherwise
+    NumEqual,
+    /// Push true if second number is less than top number
+    LessThan,
+    /// Push true if second number is greater than top number
+    GreaterThan,
+    /// Replace top item with SHA256(item)
+    Sha256,
+    /// Replace top item with RIPEMD160(SHA256(item))
+    Hash160,
+    /// Check the signature (second item) of transaction with public key (top item)
+    CheckSig,
+    /// Same as `CheckSig`, then `Verify`
+    CheckSigVerify,
+    /// Pop number N, N public keys, number M, and M signatures, push true if every signature
+    /// matches one of the public keys, signatures must be in the same order as public keys
+    CheckMultiSig,
+    /// Same as `CheckMultiSig`, then `Verify`
+    CheckMultiSigVerify,
+    /// Fail if lock time of transaction hasn't reached top number, they must be both block
+    /// heights or both timestamps. Top item is not removed
+    CheckLockTimeVerify,
+    /// Fail if relative lock of the spending input is less than top number. Top item is not
+    /// removed
+    CheckSequenceVerify,
+}
+
+impl Display for Op {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Op::PushData(data) => return write!(f, "{}", bytes2hex(data)),
+            Op::Num(n) => return write!(f, "{}", n),
+            Op::If => "OP_IF",
+            Op::NotIf => "OP_NOTIF",
+            Op::Else => "OP_ELSE",
+            Op::EndIf => "OP_ENDIF",
+            Op::Verify => "OP_VERIFY",
+            Op::Return => "OP_RETURN",
+            Op::Dup => "OP_DUP",
+            Op::Drop => "OP_DROP",
+            Op::Swap => "OP_SWAP",
+            Op::Equal => "OP_EQUAL",
+            Op::EqualVerify => "OP_EQUALVERIFY",
+            Op::NumEqual => "OP_NUMEQUAL",
+            Op::LessThan => "OP_LESSTHAN",
+            Op::GreaterThan => "OP_GREATERTHAN",
+            Op::Sha256 => "OP_SHA256",
+            Op::Hash160 => "OP_HASH160",
+            Op::CheckSig => "OP_CHECKSIG",
+            Op::CheckSigVerify => "OP_CHECKSIGVERIFY",
+            Op::CheckMultiSig => "OP_CHECKMULTISIG",
+            Op::CheckMultiSigVerify => "OP_CHECKMULTISIGVERIFY",
+            Op::CheckLockTimeVerify => "OP_CHECKLOCKTIMEVERIFY",
+            Op::CheckSequenceVerify => "OP_CHECKSEQUENCEVERIFY",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Locking script of output, or unlocking script of input
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Encode, Decode)]
+pub struct Script(pub Vec<Op>);
+
+impl Display for Script {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let ops: Vec<String> = self.0.iter().map(|op| op.to_string()).collect();
+        write!(f, "{}", ops.join(" "))
+    }
+}
+
+impl Script {
+    /// Pay to public key hash, the output can be unlocked by signature and public key whose hash
+    /// equals to `pub_key_hash`
+    pub fn p2pkh(pub_key_hash: &[u8]) -> Self {
+        Self(vec![
+            Op::Dup,
+            Op::Hash160,
+            Op::PushData(Vec::from(pub_key_hash)),
+            Op::EqualVerify,
+            Op::CheckSig,
+        ])
+    }
+
+    /// Unlocking script of pay to public key hash output
+    pub fn p2pkh_unlock(signature: &[u8], pub_key: &[u8]) -> Self {
+        Self(vec![
+            Op::PushData(Vec::from(signature)),
+            Op::PushData(Vec::from(pub_key)),
+        ])
+    }
+
+    /// Public key hash of pay to public key hash locking script, returns `None` for other scripts
+    pub fn p2pkh_hash(&self) -> Option<&[u8]> {
+        match self.0.as_slice() {
+            [Op::Dup, Op::Hash160, Op::PushData(hash), Op::EqualVerify, Op::CheckSig] => {
+                Some(hash.as_slice())
+            }
+            _ => None,
+        }
+    }
+
+    /// Provably unspendable script carrying `data`, the script fails at `Return` before the data
+    /// is used, so that the output can be removed from unspent outputs
+    pub fn data_carrier(data: &[u8]) -> Self {
+        Self(vec![Op::Return, Op::PushData(Vec::from(data))])
+    }
+
+    /// Data carried by data carrier script, returns `None` for other scripts
+    pub fn carried_data(&self) -> Option<&[u8]> {
+        match self.0.as_slice() {
+            [Op::Return, Op::PushData(data)] => Some(data.as_slice()),
+            _ => None,
+        }
+    }
+
+    /// Whether the script fails regardless of unlocking script, outputs locked with it can never
+    /// be spent
+    pub fn is_unspendable(&self) -> bool {
+        matches!(self.0.first(), Some(Op::Return))
+    }
+
+    /// Pay to script hash, the output can be unlocked by revealing the redeem script whose hash
+    /// equals to `script_hash`, and satisfying the redeem script
+    pub fn p2sh(script_hash: &[u8]) -> Self {
+        Self(vec![
+            Op::Hash160,
+            Op::PushData(Vec::from(script_hash)),
+            Op::Equal,
+        ])
+    }
+
+    /// Script hash of pay to script hash locking script, returns `None` for other scripts
+    pub fn p2sh_hash(&self) -> Option<&[u8]> {
+        match self.0.as_slice() {
+            [Op::Hash160, Op::PushData(hash), Op::Equal] => Some(hash.as_slice()),
+            _ => None,
+        }
+    }
+
+    /// Redeem script revealed by unlocking script of pay to script hash output, it's the last
+    /// pushed data
+    pub fn redeem_script(&self) -> Option<Script> {
+        match self.0.last() {
+            Some(Op::PushData(data)) => Script::decode(data),
+            _ => None,
+        }
+    }
+
+    /// Hash of the script, it's RIPEMD160(SHA256(script bytes))
+    pub fn hash(&self) -> ByteData {
+        hash_pub_key(self.encode().as_slice())
+    }
+
+    /// Serialize script to bytes
+    pub fn encode(&self) -> ByteData {
+        let config = config::standard();
+        bincode::encode_to_vec(self, config).expect("Can not encode Script to byte data")
+    }
+
+    /// Deserialize script from bytes, returns `None` if the bytes are not a valid script. The
+    /// bytes may come from unlocking scripts of peers, so lengths in them are limited
+    pub fn decode(data: &[u8]) -> Option<Script> {
+        let config = config::standard().with_limit::<MAX_MESSAGE_SIZE>();
+        match bincode::decode_from_slice(data, config) {
+            Ok((script, len)) if len == data.len() => Some(script),
+            _ => None,
+        }
+    }
+
+    /// M-of-N multisig, the output can be unlocked by `required` signatures of `pub_keys`
+    pub fn multisig(required: usize, pub_keys: &[ByteData]) -> Self {
+        let mut ops = vec![Op::Num(required as i64)];
+        for pub_key in pub_keys {
+            ops.push(Op::PushData(pub_key.clone()));
+        }
+        ops.push(Op::Num(pub_keys.len() as i64));
+        ops.push(Op::CheckMultiSig);
+        Self(ops)
+    }
+
+    /// Required number of signatures and public keys of multisig locking script, returns `None`
+    /// for other scripts
+    pub fn multisig_keys(&self) -> Option<(usize, Vec<&[u8]>)> {
+        match self.0.as_slice() {
+            [Op::Num(m), keys @ .., Op::Num(n), Op::CheckMultiSig] => {
+                let pub_keys: Vec<&[u8]> = keys
+                    .iter()
+                    .filter_map(|op| match op {
+                        Op::PushData(key) => Some(key.as_slice()),
+                        _ => None,
+                    })
+                    .collect();
+                let valid = pub_keys.len() == keys.len()
+                    && *n as usize == keys.len()
+                    && *m >= 1
+                    && *m <= *n;
+                valid.then_some((*m as usize, pub_keys))
+            }
+            _ => None,
+        }
+    }
+
+    /// Check whether the script only pushes data onto the stack
+    pub fn is_push_only(&self) -> bool {
+        self.0
+            .iter()
+            .all(|op| matches!(op, Op::PushData(_) | Op::Num(_)))
+    }
+}
+
+/// Transaction data which is required by script execution
+pub struct ScriptContext {
+    /// The message signed by the unlocking input
+    pub sighash: Hash,
+    /// Lock time of the spending transaction
+    pub lock_time: u64,
+    /// Relative lock of the unlocking input
+    pub sequence: u32,
+}
+
+/// Verify `script_sig` of an input unlocks `script_pub_key` of the referenced output. The unlocking
+/// script is executed first, then the locking script is executed with the remaining stack, it
+/// succeeds if the top item is true at the end.
+///
+/// For pay to script hash output, the redeem script revealed by unlocking script is executed
+/// as well, with the items pushed by unlocking script except the redeem script itself
+pub fn verify_script(
+    script_sig: &Script,
+    script_pub_key: &Script,
+    ctx: &ScriptContext,
+) -> Result<(), String> {
+    if !script_sig.is_push_only() {
+        return Err(String::from("Unlocking script can only push data"));
+    }
+    let mut stack = vec![];
+    execute(script_sig, &mut stack, ctx)?;
+    let unlocking_stack = stack.clone();
+    execute(script_pub_key, &mut stack, ctx)?;
+    check_result(&stack)?;
+
+    if script_pub_key.p2sh_hash().is_some() {
+        let mut stack = unlocking_stack;
+        let redeem_script = match Script::decode(&pop(&mut stack)?) {
+            Some(script) => script,
+            None => return Err(String::from("Invalid redeem script")),
+        };
+        execute(&redeem_script, &mut stack, ctx)?;
+        check_result(&stack)?;
+    }
+    Ok(())
+}
+
+/// Script succeeds if the top item is true
+fn check_result(stack: &[ByteData]) -> Result<(), String> {
+    match stack.last() {
+        Some(top) if is_true(top) => Ok(()),
+        _ => Err(String::from("Script evaluated to false")),
+    }
+}
+
+/// Execute `script` with `stack`
+fn execute(script: &Script, stack: &mut Vec<ByteData>, ctx: &ScriptContext) -> Result<(), String> {
+    if script.0.len() > MAX_SCRIPT_OPS {
+        return Err(String::from("Too many operations in script"));
+    }
+    // Conditions of nested conditional blocks, an operation is only executed if all of them are true
+    let mut conditions: Vec<bool> = vec![];
+    for op in &script.0 {
+        let executing = conditions.iter().all(|c| *c);
+        match op {
+            Op::If | Op::NotIf => {
+                let mut condition = false;
+                if executing {
+                    condition = is_true(&pop(stack)?);
+                    if *op == Op::NotIf {
+                        condition = !condition;
+                    }
+                }
+                conditions.push(condition);
+            }
+            Op::Else => match conditions.last_mut() {
+                Some(condition) => *condition = !*condition,
+                None => return Err(String::from("OP_ELSE without OP_IF")),
+            },
+            Op::EndIf => {
+                if conditions.pop().is_none() {
+                    return Err(String::from("OP_ENDIF without OP_IF"));
+                }
+            }
+            _ if !executing => {}
+            Op::PushData(data) => stack.push(data.clone()),
+            Op::Num(n) => stack.push(encode_num(*n)),
+            Op::Verify => {
+                if !is_true(&pop(stack)?) {
+                    return Err(String::from("OP_VERIFY failed"));
+                }
+            }
+            Op::Return => return Err(String::from("OP_RETURN executed")),
+            Op::Dup => {
+                let top = stack.last().ok_or("Stack is empty")?.clone();
+                stack.push(top);
+            }
+            Op::Drop => {
+                pop(stack)?;
+            }
+            Op::Swap => {
+                let a = pop(stack)?;
+                let b = pop(stack)?;
+                stack.push(a);
+                stack.push(b);
+            }
+            Op::Equal | Op::EqualVerify => {
+                let a = pop(stack)?;
+                let b = pop(stack)?;
+                if *op == Op::EqualVerify {
+                    if a != b {
+                        return Err(String::from("OP_EQUALVERIFY failed"));
+                    }
+                } else {
+                    stack.push(encode_bool(a == b));
+                }
+            }
+            Op::NumEqual | Op::LessThan | Op::GreaterThan => {
+                let a = decode_num(&pop(stack)?)?;
+                let b = decode_num(&pop(stack)?)?;
+                let result = match op {
+                    Op::NumEqual => b == a,
+                    Op::LessThan => b < a,
+                    _ => b > a,
+                };
+                stack.push(encode_bool(result));
+            }
+            Op::Sha256 => {
+                let data = pop(stack)?;
+                stack.push(Vec::from(Sha256::digest(data).as_slice()));
+            }
+            Op::Hash160 => {
+                let data = pop(stack)?;
+                stack.push(hash_pub_key(data.as_slice()));
+            }
+            Op::CheckSig | Op::CheckSigVerify => {
+                let pub_key = pop(stack)?;
+                let signature = pop(stack)?;
+                let valid = verify_signature(&pub_key, &ctx.sighash, &signature);
+                if *op == Op::CheckSigVerify {
+                    if !valid {
+                        return Err(String::from("OP_CHECKSIGVERIFY failed"));
+                    }
+                } else {
+                    stack.push(encode_bool(valid));
+                }
+            }
+            Op::CheckMultiSig | Op::CheckMultiSigVerify => {
+                let valid = check_multisig(stack, ctx)?;
+                if *op == Op::CheckMultiSigVerify {
+                    if !valid {
+                        return Err(String::from("OP_CHECKMULTISIGVERIFY failed"));
+                    }
+                } else {
+                    stack.push(encode_bool(valid));
+                }
+            }
+            Op::CheckLockTimeVerify => {
+                let lock_time = decode_num(stack.last().ok_or("Stack is empty")?)?;
+                if lock_time < 0 {
+                    return Err(String::from("Negative lock time"));
+                }
+                let lock_time = lock_time as u64;
+                let same_kind =
+                    (lock_time < LOCK_TIME_THRESHOLD) == (ctx.lock_time < LOCK_TIME_THRESHOLD);
+                if !same_kind || ctx.lock_time < lock_time {
+                    return Err(String::from("OP_CHECKLOCKTIMEVERIFY failed"));
+                }
+            }
+            Op::CheckSequenceVerify => {
+                let sequence = decode_num(stack.last().ok_or("Stack is empty")?)?;
+                if sequence < 0 || (ctx.sequence as i64) < sequence {
+                    return Err(String::from("OP_CHECKSEQUENCEVERIFY failed"));
+                }
+            }
+        }
+        if stack.len() > MAX_STACK_SIZE {
+            return Err(String::from("Stack overflow"));
+        }
+    }
+    if !conditions.is_empty() {
+        return Err(String::from("Unbalanced conditional"));
+    }
+    Ok(())
+}
+
+/// Pop public keys and signatures of multisig from `stack`, and check every signature matches a
+/// public key in order
+fn check_multisig(stack: &mut Vec<ByteData>, ctx: &ScriptContext) -> Result<bool, String> {
+    let n = decode_num(&pop(stack)?)?;
+    if n < 0 || n as usize > MAX_MULTISIG_KEYS {
+        return Err(String::from("Invalid number of multisig public keys"));
+    }
+    let mut pub_keys = vec![];
+    for _ in 0..n {
+        pub_keys.push(pop(stack)?);
+    }
+    // Public keys were pushed in order, so they are popped in reverse order
+    pub_keys.reverse();
+    let m = decode_num(&pop(stack)?)?;
+    if m < 0 || m > n {
+        return Err(String::from("Invalid number of multisig signatures"));
+    }
+    let mut signatures = vec![];
+    for _ in 0..m {
+        signatures.push(pop(stack)?);
+    }
+    signatures.reverse();
+
+    // Each signature must match a public key after the one matched by previous signature
+    let mut keys = pub_keys.iter();
+    for signature in &signatures {
+        let matched = keys.any(|pub_key| verify_signature(pub_key, &ctx.sighash, signature));
+        if !matched {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn pop(stack: &mut Vec<ByteData>) -> Result<ByteData, String> {
+    stack.pop().ok_or_else(|| String::from("Stack is empty"))
+}
+
+/// Bytes data is false if all of it's bytes are 0, otherwise it's true
+fn is_true(data: &[u8]) -> bool {
+    data.iter().any(|b| *b != 0)
+}
+
+fn encode_bool(b: bool) -> ByteData {
+    if b {
+        vec![1]
+    } else {
+        vec![]
+    }
+}
+
+/// Numbers are stored in stack as little endian bytes
+fn encode_num(n: i64) -> ByteData {
+    Vec::from(n.to_le_bytes())
+}
+
+/// Decode little endian number, which is at most 8 bytes
+fn decode_num(data: &[u8]) -> Result<i64, String> {
+    if data.len() > 8 {
+        return Err(String::from("Number overflow"));
+    }
+    let mut bytes = [0u8; 8];
+    bytes[..data.len()].copy_from_slice(data);
+    Ok(i64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod script_test {
+    use super::*;
+    use crate::wallet::{derive_key_pair, Wallet};
+
+    fn ctx() -> ScriptContext {
+        ScriptContext {
+            sighash: [7u8; 32],
+            lock_time: 0,
+            sequence: 0,
+        }
+    }
+
+    #[test]
+    fn reject_forged_redeem_script_length() {
+        // The redeem script claims 2^40 operations, which must not be allocated
+        let mut data = vec![253];
+        data.extend_from_slice(&(1u64 << 40).to_le_bytes());
+        let unlock = Script(vec![Op::PushData(data)]);
+        assert!(unlock.redeem_script().is_none());
+    }
+
+    #[test]
+    fn pay_to_public_key_hash() {
+        let wallet = Wallet::new(derive_key_pair(&[0u8; 16], &[0]).as_slice());
+        let lock = Script::p2pkh(&hash_pub_key(wallet.public_key()));
+        let signature = wallet.sign(&ctx().sighash);
+        let unlock = Script::p2pkh_unlock(signature.as_ref(), wallet.public_key());
+        assert!(verify_script(&unlock, &lock, &ctx()).is_ok());
+
+        // Signature of another message must be rejected
+        let signature = wallet.sign(&[8u8; 32]);
+        let unlock = Script::p2pkh_unlock(signature.as_ref(), wallet.public_key());
+        assert!(verify_script(&unlock, &lock, &ctx()).is_err());
+    }
+
+    #[test]
+    fn multisig_requires_enough_signatures() {
+        let wallets: Vec<Wallet> = (0..3)
+            .map(|i| Wallet::new(derive_key_pair(&[0u8; 16], &[i]).as_slice()))
+            .collect();
+        let pub_keys: Vec<ByteData> = wallets.iter().map(|w| Vec::from(w.public_key())).collect();
+        let lock = Script::multisig(2, &pub_keys);
+        assert_eq!(lock.multisig_keys().unwrap().0, 2);
+        let sign = |i: usize| Op::PushData(Vec::from(wallets[i].sign(&ctx().sighash).as_ref()));
+
+        assert!(verify_script(&Script(vec![sign(0), sign(2)]), &lock, &ctx()).is_ok());
+        // Signatures must be in the same order as public keys
+        assert!(verify_script(&Script(vec![sign(2), sign(0)]), &lock, &ctx()).is_err());
+        // One signature is not enough
+        assert!(verify_script(&Script(vec![sign(1)]), &lock, &ctx()).is_err());
+    }
+
+    #[test]
+    fn pay_to_script_hash() {
+        let wallet = Wallet::new(derive_key_pair(&[0u8; 16], &[0]).as_slice());
+        let redeem = Script::multisig(1, &[Vec::from(wallet.public_key())]);
+        let lock = Script::p2sh(&redeem.hash());
+        let signature = Op::PushData(Vec::from(wallet.sign(&ctx().sighash).as_ref()));
+
+        let unlock = Script(vec![signature.clone(), Op::PushData(redeem.encode())]);
+        assert_eq!(unlock.redeem_script(), Some(redeem.clone()));
+        assert!(verify_script(&unlock, &lock, &ctx()).is_ok());
+
+        // Matching script hash is not enough, the redeem script must be satisfied
+        let unlock = Script(vec![Op::Num(0), Op::PushData(redeem.encode())]);
+        assert!(verify_script(&unlock, &lock, &ctx()).is_err());
+        // Another redeem script doesn't match the script hash
+        let other = Script(vec![Op::Num(1)]);
+        let unlock = Script(vec![signature, Op::PushData(other.encode())]);
+        assert!(verify_script(&unlock, &lock, &ctx()).is_err());
+    }
+
+    #[test]
+    fn conditional_branches() {
+        let lock = Script(vec![
+            Op::If,
+            Op::Num(2),
+            Op::Else,
+            Op::Num(3),
+            Op::EndIf,
+            Op::GreaterThan,
+        ]);
+        // 5 > 2 when the IF branch is taken
+        let unlock = Script(vec![Op::Num(5), Op::Num(1)]);
+        assert!(verify_script(&unlock, &lock, &ctx()).is_ok());
+        // 1 > 3 is false when the ELSE branch is taken
+        let unlock = Script(vec![Op::Num(1), Op::Num(0)]);
+        assert!(verify_script(&unlock, &lock, &ctx()).is_err());
+    }
+
+    #[test]
+    fn reject_non_push_unlocking_script() {
+        let lock = Script(vec![Op::Num(1)]);
+        let unlock = Script(vec![Op::Return]);
+        assert!(verify_script(&unlock, &lock, &ctx()).is_err());
+        let unlock = Script(vec![Op::If]);
+        assert!(verify_script(&unlock, &Script(vec![Op::EndIf]), &ctx()).is_err());
+    }
+
+    #[test]
+    fn data_carrier_is_unspendable() {
+        let lock = Script::data_carrier(b"hello");
+        assert_eq!(lock.carried_data(), Some(&b"hello"[..]));
+        assert!(lock.is_unspendable());
+        assert!(!Script::p2sh(&[0u8; 20]).is_unspendable());
+        assert!(verify_script(&Script::default(), &lock, &ctx()).is_err());
+    }
+}
@@ -0,0 +1,430 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::block::ByteData;
+use crate::block_chain::BlockChain;
+use crate::block_filter::{filter_header, outpoint_item, script_item, MAX_FILTERS};
+use crate::chain_sync::MAX_HEADERS;
+use crate::codec::{Codec, Network};
+use crate::message::{InvItem, InvKind, Message, Version, PROTOCOL_VERSION};
+use crate::node::{Node, PeerId};
+use crate::peer_manager::{load_seeds, PeerManager, PEERS_FILE, SEEDS_FILE};
+use crate::script::Script;
+use crate::spv::{HeaderChain, TxProof};
+use crate::tools::{get_timestamp, hash2str};
+use crate::transaction::Transaction;
+
+/// Time to sleep when there is nothing to do
+const IDLE_INTERVAL: Duration = Duration::from_millis(20);
+/// Time to wait for a connection to be created
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+/// Time to wait for a reply from the node which a command talks to
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A TCP connection to a peer, messages are framed by `Codec`
+struct Connection {
+    stream: TcpStream,
+    codec: Codec,
+    /// Received bytes which haven't formed a whole message
+    read_buf: Vec<u8>,
+    /// Bytes waiting to be written
+    write_buf: Vec<u8>,
+    /// Whether the peer has closed the connection
+    closed: bool,
+}
+
+impl Connection {
+    fn new(stream: TcpStream, network: Network) -> Result<Self, String> {
+        stream
+            .set_nonblocking(true)
+            .map_err(|err| format!("Set non-blocking error: {}", err))?;
+        Ok(Self {
+            stream,
+            codec: Codec::new(network),
+            read_buf: vec![],
+            write_buf: vec![],
+            closed: false,
+        })
+    }
+
+    /// Read available bytes and parse whole messages, returns error if the peer sends invalid
+    /// data. Messages sent before the connection is closed are still returned
+    fn read_messages(&mut self) -> Result<Vec<Message>, String> {
+        let mut buf = [0u8; 4096];
+        while !self.closed {
+            match self.stream.read(&mut buf) {
+                Ok(0) => self.closed = true,
+                Ok(n) => self.read_buf.extend_from_slice(&buf[..n]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(format!("Read error: {}", err)),
+            }
+        }
+        let mut messages = vec![];
+        while let Some(message) = self.codec.decode(&mut self.read_buf)? {
+            messages.push(message);
+        }
+        Ok(messages)
+    }
+
+    fn queue(&mut self, message: &Message) {
+        let frame = self.codec.encode(message);
+        self.write_buf.extend_from_slice(&frame);
+    }
+
+    /// Write as many queued bytes as possible
+    fn flush(&mut self) -> Result<(), String> {
+        while !self.write_buf.is_empty() {
+            match self.stream.write(&self.write_buf) {
+                Ok(0) => return Err(String::from("Connection is closed")),
+                Ok(n) => {
+                    self.write_buf.drain(..n);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(format!("Write error: {}", err)),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run a node which listens on `port` and connects to `peers` and addresses in the address book,
+/// it keeps running until the process is killed. Pending transactions are mined once they are
+/// received if `mine_to` is provided. Only nodes of the same `network` can be connected
+pub fn start_node(
+    port: u16,
+    peers: &[String],
+    mine_to: Option<String>,
+    network: Network,
+) -> Result<(), String> {
+    let listener = TcpListener::bind(("0.0.0.0", port))
+        .map_err(|err| format!("Listen on port {} error: {}", port, err))?;
+    listener
+        .set_nonblocking(true)
+        .map_err(|err| format!("Set non-blocking error: {}", err))?;
+    let block_chain = BlockChain::get();
+    if block_chain.is_none() {
+        println!("Waiting for blocks from peers");
+    }
+    let mut peer_manager = PeerManager::load(PEERS_FILE);
+    peer_manager.add_seeds(load_seeds(SEEDS_FILE));
+    let mut node = Node::new(block_chain, port, mine_to, peer_manager);
+    let mut connections: BTreeMap<PeerId, Connection> = BTreeMap::new();
+    let mut next_id: PeerId = 0;
+    // Outbound connections are created in other threads, so that the loop is not blocked
+    let (connected_sender, connected_receiver) = mpsc::channel();
+    let mut connecting: usize = 0;
+    println!("Node is listening on port {}", port);
+
+    for addr in peers {
+        match addr.parse::<SocketAddr>() {
+            Ok(_) => node.add_address(addr, get_timestamp()),
+            Err(_) => println!("Invalid peer address {}", addr),
+        }
+    }
+
+    loop {
+        let mut is_idle = true;
+        for addr in node.addresses_to_connect(connecting, get_timestamp()) {
+            let sender = connected_sender.clone();
+            connecting += 1;
+            thread::spawn(move || {
+                let stream = addr
+                    .parse::<SocketAddr>()
+                    .map_err(|err| err.to_string())
+                    .and_then(|a| {
+                        TcpStream::connect_timeout(&a, CONNECT_TIMEOUT)
+                            .map_err(|err| err.to_string())
+                    });
+                // The receiver is dropped only when the node stops
+                let _ = sender.send((addr, stream));
+            });
+        }
+        while let Ok((addr, stream)) = connected_receiver.try_recv() {
+            connecting -= 1;
+            match stream.and_then(|stream| Connection::new(stream, network)) {
+                Ok(connection) => {
+                    connections.insert(next_id, connection);
+                    node.connect(next_id, &addr, true, get_timestamp());
+                    next_id += 1;
+                }
+                Err(err) => {
+                    println!("Connect to {} error: {}", addr, err);
+                    node.connect_failed(&addr);
+                }
+            }
+        }
+        loop {
+            match listener.accept() {
+                // Too many connections, the stream is closed once it's dropped
+                Ok(_) if !node.can_accept() => {}
+                Ok((stream, addr)) => match Connection::new(stream, network) {
+                    Ok(connection) => {
+                        connections.insert(next_id, connection);
+                        node.connect(next_id, &addr.to_string(), false, get_timestamp());
+                        next_id += 1;
+                    }
+                    Err(err) => println!("Accept connection from {} error: {}", addr, err),
+                },
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                // The error may be temporary, e.g. too many open files, accept again later
+                Err(err) => {
+                    println!("Accept connection error: {}", err);
+                    break;
+                }
+            }
+        }
+
+        let mut closed = vec![];
+        for (id, connection) in connections.iter_mut() {
+            match connection.read_messages() {
+                Ok(messages) => {
+                    is_idle &= messages.is_empty();
+                    for message in messages {
+                        node.receive(*id, message, get_timestamp());
+                    }
+                    if connection.closed {
+                        closed.push(*id);
+                    }
+                }
+                Err(_) => closed.push(*id),
+            }
+        }
+        node.tick(get_timestamp());
+
+        for (id, message) in node.take_outbox() {
+            if let Some(connection) = connections.get_mut(&id) {
+                connection.queue(&message);
+            }
+        }
+        for (id, connection) in connections.iter_mut() {
+            if connection.flush().is_err() {
+                closed.push(*id);
+            }
+        }
+        closed.extend(node.take_dropped());
+        for id in closed {
+            if connections.remove(&id).is_some() {
+                node.disconnect(id);
+            }
+        }
+
+        if is_idle {
+            thread::sleep(IDLE_INTERVAL);
+        }
+    }
+}
+
+/// A short lived connection to a node, it's used by commands talking to a running node
+struct Client {
+    addr: String,
+    connection: Connection,
+    /// Received messages which are not handled yet
+    received: VecDeque<Message>,
+}
+
+impl Client {
+    /// Connect to the node listening on `addr` and finish handshake
+    fn connect(addr: &str, network: Network) -> Result<Self, String> {
+        let socket = addr
+            .parse::<SocketAddr>()
+            .map_err(|err| format!("Invalid node address {}: {}", addr, err))?;
+        let stream = TcpStream::connect_timeout(&socket, CONNECT_TIMEOUT)
+            .map_err(|err| format!("Connect to {} error: {}", addr, err))?;
+        let mut client = Self {
+            addr: String::from(addr),
+            connection: Connection::new(stream, network)?,
+            received: VecDeque::new(),
+        };
+        // We don't listen on any port, and we don't have any blocks to share
+        client.send(&Message::Version(Version {
+            version: PROTOCOL_VERSION,
+            nonce: get_timestamp() as u64,
+            best_height: None,
+            genesis: None,
+            listen_port: 0,
+        }));
+        client.wait(|message| matches!(message, Message::Version(_)).then_some(()))?;
+        client.send(&Message::Verack);
+        client.wait(|message| matches!(message, Message::Verack).then_some(()))?;
+        Ok(client)
+    }
+
+    fn send(&mut self, message: &Message) {
+        self.connection.queue(message);
+    }
+
+    /// Wait for a message accepted by `accept`, messages before it are dropped except pings which
+    /// are replied
+    fn wait<T>(&mut self, mut accept: impl FnMut(&Message) -> Option<T>) -> Result<T, String> {
+        let deadline = Instant::now() + CLIENT_TIMEOUT;
+        loop {
+            while let Some(message) = self.received.pop_front() {
+                if let Message::Ping(nonce) = message {
+                    self.send(&Message::Pong(nonce));
+                } else if let Some(result) = accept(&message) {
+                    return Ok(result);
+                }
+            }
+            self.connection.flush()?;
+            let messages = self.connection.read_messages()?;
+            if messages.is_empty() {
+                if self.connection.closed {
+                    return Err(format!("Connection is closed by {}", self.addr));
+                }
+                if Instant::now() > deadline {
+                    return Err(format!("Node {} doesn't respond", self.addr));
+                }
+                thread::sleep(IDLE_INTERVAL);
+            }
+            self.received.extend(messages);
+        }
+    }
+
+    /// Download headers to `chain` until the node doesn't have more
+    fn sync_headers(&mut self, chain: &mut HeaderChain) -> Result<(), String> {
+        loop {
+            self.send(&Message::GetHeaders(chain.locator()));
+            let headers = self.wait(|message| match message {
+                Message::Headers(headers) => Some(headers.clone()),
+                _ => None,
+            })?;
+            let count = chain.add_headers(&headers)?;
+            if count > 0 {
+                println!(
+                    "Receive {} headers, height {}",
+                    count,
+                    chain.tip_height().unwrap_or(0)
+                );
+            }
+            if headers.len() < MAX_HEADERS {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Write queued messages before the connection is closed
+    fn finish(mut self) -> Result<(), String> {
+        let deadline = Instant::now() + CLIENT_TIMEOUT;
+        loop {
+            self.connection.flush()?;
+            if self.connection.write_buf.is_empty() {
+                return Ok(());
+            }
+            if Instant::now() > deadline {
+                return Err(format!("Node {} doesn't respond", self.addr));
+            }
+            thread::sleep(IDLE_INTERVAL);
+        }
+    }
+}
+
+/// Send transaction to the node listening on `addr`, the node relays it to it's peers if it's
+/// valid. It returns once the transaction is sent, whether it's accepted is not known
+pub fn submit_to_node(addr: &str, tx: Transaction, network: Network) -> Result<(), String> {
+    let mut client = Client::connect(addr, network)?;
+    client.send(&Message::Tx(tx));
+    client.finish()
+}
+
+/// Download headers from the node listening on `addr` to `chain`, and request transactions of
+/// `scripts` with their proofs. Blocks are never downloaded
+pub fn sync_light_client(
+    addr: &str,
+    network: Network,
+    chain: &mut HeaderChain,
+    scripts: Vec<Script>,
+) -> Result<Vec<TxProof>, String> {
+    let mut client = Client::connect(addr, network)?;
+    client.sync_headers(chain)?;
+    client.send(&Message::GetTxProofs(scripts));
+    client.wait(|message| match message {
+        Message::TxProofs(proofs) => Some(proofs.clone()),
+        _ => None,
+    })
+}
+
+/// Download headers from the node listening on `addr` to `chain`, and find transactions of
+/// `scripts` by block filters, so that the node doesn't know the scripts. Filters are checked by
+/// the filter header chain of the same node, so they are trusted from that single node, it can
+/// hide transactions by wrong filters. Blocks whose filters match are downloaded and verified by
+/// their headers. Returns the transactions with their heights
+pub fn scan_block_filters(
+    addr: &str,
+    network: Network,
+    chain: &mut HeaderChain,
+    scripts: &HashSet<Script>,
+) -> Result<Vec<(Transaction, u64)>, String> {
+    let mut client = Client::connect(addr, network)?;
+    client.sync_headers(chain)?;
+    let mut items: Vec<ByteData> = scripts.iter().filter_map(script_item).collect();
+    let mut txs = vec![];
+    let mut prev_header = [0u8; 32];
+    for hashes in chain.main_chain().chunks(MAX_FILTERS) {
+        client.send(&Message::GetFilterHeaders(hashes.to_vec()));
+        let headers = client.wait(|message| match message {
+            Message::FilterHeaders(headers) => Some(headers.clone()),
+            _ => None,
+        })?;
+        client.send(&Message::GetFilters(hashes.to_vec()));
+        let filters = client.wait(|message| match message {
+            Message::Filters(filters) => Some(filters.clone()),
+            _ => None,
+        })?;
+        if headers.len() != hashes.len() || filters.len() != hashes.len() {
+            return Err(format!("Node {} doesn't have filters of all blocks", addr));
+        }
+        for ((hash, (h1, header)), (h2, filter)) in hashes.iter().zip(headers).zip(filters) {
+            prev_header = filter_header(&filter.hash(), &prev_header);
+            if h1 != *hash || h2 != *hash || header != prev_header {
+                return Err(format!("Invalid filter of block {}", hash2str(hash)));
+            }
+            if !filter.matches_any(hash, &items) {
+                continue;
+            }
+            client.send(&Message::GetData(vec![InvItem {
+                kind: InvKind::Block,
+                hash: *hash,
+            }]));
+            let block = client.wait(|message| match message {
+                Message::Block(block) if block.hash == *hash => Some(Ok(block.clone())),
+                Message::NotFound(items) if items.iter().any(|item| item.hash == *hash) => {
+                    Some(Err(format!(
+                        "Node {} doesn't have block {}",
+                        addr,
+                        hash2str(hash)
+                    )))
+                }
+                _ => None,
+            })??;
+            let height = chain.verify_block(&block)?;
+            for tx in block.transactions {
+                // Outputs paid to the scripts are added to items, so that inputs spending them match
+                let spends = tx
+                    .v_in
+                    .iter()
+                    .any(|input| match input.tx_id.zip(input.v_out_idx) {
+                        Some((tx_id, out_idx)) => items.contains(&outpoint_item(&tx_id, out_idx)),
+                        None => false,
+                    });
+                let paid: Vec<usize> = tx
+                    .v_out
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, out)| scripts.contains(&out.script_pub_key))
+                    .map(|(out_idx, _)| out_idx)
+                    .collect();
+                items.extend(paid.iter().map(|out_idx| outpoint_item(&tx.id, *out_idx)));
+                if spends || !paid.is_empty() {
+                    txs.push((tx, height));
+                }
+            }
+        }
+    }
+    Ok(txs)
+}
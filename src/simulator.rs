@@ -0,0 +1,339 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::block::{Block, Hash, TimeStamp};
+use crate::block_chain::BlockChain;
+use crate::message::Message;
+use crate::node::Node;
+use crate::peer_manager::PeerManager;
+use crate::tools::set_mock_time;
+use crate::transaction::Transaction;
+use crate::wallet::{derive_key_pair, Wallet};
+
+/// Nodes tick at this interval of simulated time, in milliseconds
+const TICK_INTERVAL: TimeStamp = 10;
+/// Latency of links which are not configured, in milliseconds
+const DEFAULT_LATENCY: TimeStamp = 50;
+/// Port simulated nodes listen on
+const LISTEN_PORT: u16 = 8333;
+/// Port of connections created by simulated nodes
+const CONNECT_PORT: u16 = 40000;
+/// Simulated time when the simulation starts
+const START_TIME: TimeStamp = 1_700_000_000_000;
+
+/// Address of node `index` which receives mining reward
+pub fn reward_address(index: usize) -> String {
+    Wallet::new(derive_key_pair(&[7u8; 16], &[index as u32]).as_slice()).get_address()
+}
+
+/// Socket address of node `index`
+fn socket_address(index: usize, port: u16) -> String {
+    format!("10.0.{}.{}:{}", index / 256, index % 256, port)
+}
+
+/// Index of the node listening on `addr`
+fn node_index(addr: &str) -> Option<usize> {
+    let ip = addr.strip_suffix(&format!(":{}", LISTEN_PORT))?;
+    let mut parts = ip.strip_prefix("10.0.")?.split('.');
+    let high: usize = parts.next()?.parse().ok()?;
+    let low: usize = parts.next()?.parse().ok()?;
+    Some(high * 256 + low)
+}
+
+/// A message on it's way
+struct Envelope {
+    from: usize,
+    to: usize,
+    message: Message,
+}
+
+/// Run nodes in memory, messages between them are delivered by a simulated network with latency,
+/// partitions and loss. Time is simulated as well, so that everything happens in the same order
+/// in every run. The peer id of a node in other nodes is it's index
+pub struct Simulator {
+    now: TimeStamp,
+    nodes: Vec<Node>,
+    /// Connected pairs of nodes, the smaller index is first
+    links: BTreeSet<(usize, usize)>,
+    /// Latency of links which are configured, the smaller index is first
+    latency: HashMap<(usize, usize), TimeStamp>,
+    /// Probability of losing a message
+    loss: f64,
+    /// Partition of each node, messages between different partitions are lost
+    partitions: Vec<usize>,
+    /// Messages waiting to be delivered, key is the delivery time and sequence number
+    in_flight: BTreeMap<(TimeStamp, u64), Envelope>,
+    sequence: u64,
+    /// State of the random number generator deciding message loss
+    seed: u64,
+}
+
+impl Simulator {
+    /// Create `count` nodes sharing the same genesis block, nodes are not connected
+    pub fn new(count: usize) -> Self {
+        set_mock_time(Some(START_TIME));
+        let coinbase = Transaction::new_coinbase_tx(&reward_address(0), None, 0, 0);
+        let genesis = Block::new_genesis_block(coinbase);
+        let nodes = (0..count)
+            .map(|_| {
+                let block_chain = BlockChain::create_in_memory(&genesis)
+                    .expect("Create blockchain in memory error");
+                Node::new(Some(block_chain), LISTEN_PORT, None, PeerManager::default())
+            })
+            .collect();
+        Self {
+            now: START_TIME,
+            nodes,
+            links: BTreeSet::new(),
+            latency: HashMap::new(),
+            loss: 0.0,
+            partitions: vec![0; count],
+            in_flight: BTreeMap::new(),
+            sequence: 0,
+            seed: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    /// Latest block of node `index`, and it's height
+    pub fn tip(&mut self, index: usize) -> (Hash, u64) {
+        let block_chain = self.nodes[index]
+            .block_chain()
+            .expect("Simulated node has blocks");
+        (block_chain.tip, block_chain.tip_height())
+    }
+
+    /// Set latency of the link between `a` and `b` in milliseconds
+    pub fn set_latency(&mut self, a: usize, b: usize, latency: TimeStamp) {
+        self.latency.insert((a.min(b), a.max(b)), latency);
+    }
+
+    /// Set probability of losing a message
+    pub fn set_loss(&mut self, loss: f64) {
+        self.loss = loss;
+    }
+
+    /// Split nodes into partitions, nodes which are not listed are in a partition of their own
+    pub fn partition(&mut self, groups: &[&[usize]]) {
+        let count = self.nodes.len();
+        self.partitions = (0..count).map(|index| groups.len() + index).collect();
+        for (group, nodes) in groups.iter().enumerate() {
+            for index in nodes.iter() {
+                self.partitions[*index] = group;
+            }
+        }
+    }
+
+    /// Remove partitions
+    pub fn heal(&mut self) {
+        self.partitions = vec![0; self.nodes.len()];
+    }
+
+    /// Connect node `from` to node `to`
+    pub fn connect(&mut self, from: usize, to: usize) {
+        if from == to || !self.links.insert((from.min(to), from.max(to))) {
+            return;
+        }
+        let now = self.now;
+        self.nodes[from].connect(to, &socket_address(to, LISTEN_PORT), true, now);
+        self.nodes[to].connect(from, &socket_address(from, CONNECT_PORT), false, now);
+        self.collect(from);
+        self.collect(to);
+    }
+
+    /// Node `index` learns the address of node `to`, e.g. from it's seed file
+    pub fn add_address(&mut self, index: usize, to: usize) {
+        let now = self.now;
+        self.nodes[index].add_address(&socket_address(to, LISTEN_PORT), now);
+    }
+
+    /// Close the connection between `a` and `b`
+    pub fn disconnect(&mut self, a: usize, b: usize) {
+        if self.links.remove(&(a.min(b), a.max(b))) {
+            self.nodes[a].disconnect(b);
+            self.nodes[b].disconnect(a);
+        }
+    }
+
+    /// Node `index` mines a block with it's pending transactions, returns hash of the block
+    pub fn mine(&mut self, index: usize) -> Hash {
+        let hash = self.nodes[index]
+            .mine_block(&reward_address(index))
+            .expect("Mining block failed");
+        self.collect(index);
+        hash
+    }
+
+    /// Advance simulated time by `duration` milliseconds, messages are delivered and nodes tick
+    /// in the meantime
+    pub fn run(&mut self, duration: TimeStamp) {
+        let end = self.now + duration;
+        while self.now < end {
+            self.now += TICK_INTERVAL;
+            set_mock_time(Some(self.now));
+            while let Some(entry) = self.in_flight.first_entry() {
+                if entry.key().0 > self.now {
+                    break;
+                }
+                let envelope = entry.remove();
+                if self.is_reachable(envelope.from, envelope.to) {
+                    self.nodes[envelope.to].receive(envelope.from, envelope.message, self.now);
+                    self.collect(envelope.to);
+                }
+            }
+            for index in 0..self.nodes.len() {
+                // Connections are created at once in the simulation
+                let addresses = self.nodes[index].addresses_to_connect(0, self.now);
+                for addr in addresses {
+                    match node_index(&addr).filter(|to| *to < self.nodes.len()) {
+                        Some(to) => self.connect(index, to),
+                        None => self.nodes[index].connect_failed(&addr),
+                    }
+                }
+                self.nodes[index].tick(self.now);
+                self.collect(index);
+            }
+        }
+    }
+
+    /// Whether all of the nodes have the same latest block
+    pub fn is_converged(&mut self) -> bool {
+        let tip = self.tip(0);
+        (1..self.nodes.len()).all(|index| self.tip(index) == tip)
+    }
+
+    /// Whether a message from `from` to `to` can be delivered
+    fn is_reachable(&self, from: usize, to: usize) -> bool {
+        self.links.contains(&(from.min(to), from.max(to)))
+            && self.partitions[from] == self.partitions[to]
+    }
+
+    /// Take messages sent by node `index` into the network, and close connections dropped by it
+    fn collect(&mut self, index: usize) {
+        for (to, message) in self.nodes[index].take_outbox() {
+            if !self.is_reachable(index, to) || self.is_lost() {
+                continue;
+            }
+            let latency = self
+                .latency
+                .get(&(index.min(to), index.max(to)))
+                .copied()
+                .unwrap_or(DEFAULT_LATENCY);
+            self.sequence += 1;
+            let envelope = Envelope {
+                from: index,
+                to,
+                message,
+            };
+            self.in_flight
+                .insert((self.now + latency, self.sequence), envelope);
+        }
+        for peer in self.nodes[index].take_dropped() {
+            self.disconnect(index, peer);
+        }
+    }
+
+    /// Decide whether a message is lost with a xorshift random number generator
+    fn is_lost(&mut self) -> bool {
+        if self.loss <= 0.0 {
+            return false;
+        }
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        (self.seed as f64 / u64::MAX as f64) < self.loss
+    }
+}
+
+impl Drop for Simulator {
+    fn drop(&mut self) {
+        set_mock_time(None);
+    }
+}
+
+#[cfg(test)]
+mod simulator_test {
+    use super::*;
+
+    #[test]
+    fn relay_blocks_between_nodes() {
+        let mut sim = Simulator::new(3);
+        sim.connect(0, 1);
+        sim.connect(1, 2);
+        sim.set_latency(1, 2, 300);
+        sim.run(1000);
+        sim.mine(0);
+        sim.run(200);
+        // The block hasn't reached the last node yet
+        assert_eq!(sim.tip(1).1, 1);
+        assert_eq!(sim.tip(2).1, 0);
+        let hash = sim.mine(0);
+        sim.run(2000);
+        assert!(sim.is_converged());
+        assert_eq!(sim.tip(2), (hash, 2));
+    }
+
+    #[test]
+    fn reorganize_after_partition_heals() {
+        let mut sim = Simulator::new(4);
+        for a in 0..4 {
+            for b in a + 1..4 {
+                sim.connect(a, b);
+            }
+        }
+        sim.run(1000);
+        sim.partition(&[&[0, 1], &[2, 3]]);
+        let short = sim.mine(0);
+        sim.run(1000);
+        sim.mine(2);
+        sim.run(1000);
+        let long = sim.mine(2);
+        sim.run(1000);
+        assert_eq!(sim.tip(1), (short, 1));
+        assert_eq!(sim.tip(3), (long, 2));
+
+        // The shorter branch is replaced once the next block of the longer branch is received
+        sim.heal();
+        let tip = sim.mine(3);
+        sim.run(5000);
+        assert!(sim.is_converged());
+        assert_eq!(sim.tip(0), (tip, 3));
+    }
+
+    #[test]
+    fn discover_peers_from_seed() {
+        let mut sim = Simulator::new(4);
+        for index in 1..4 {
+            sim.add_address(index, 0);
+        }
+        sim.run(5000);
+        // Nodes find each other through the seed, and stay connected without it
+        for a in 1..4 {
+            for b in a + 1..4 {
+                assert!(sim.links.contains(&(a, b)));
+            }
+        }
+        sim.partition(&[&[1, 2, 3]]);
+        let tip = sim.mine(1);
+        sim.run(2000);
+        assert_eq!(sim.tip(3), (tip, 1));
+    }
+
+    #[test]
+    fn converge_with_message_loss() {
+        let mut sim = Simulator::new(3);
+        sim.connect(0, 1);
+        sim.connect(1, 2);
+        sim.run(1000);
+        sim.set_loss(0.2);
+        for index in 0..6 {
+            sim.mine(index % 3);
+            sim.run(2000);
+        }
+        // Lost messages are recovered by the next block, and dropped peers are connected again
+        sim.set_loss(0.0);
+        sim.run(120_000);
+        let tip = sim.mine(0);
+        sim.run(30_000);
+        assert!(sim.is_converged());
+        assert_eq!(sim.tip(2).0, tip);
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+
+use bincode::{config, Decode, Encode};
+
+use crate::block::{Block, BlockHeader, Hash};
+use crate::block_chain::build_locator;
+use crate::merkle::MerkleProof;
+use crate::script::Script;
+use crate::tools::hash2str;
+use crate::transaction::Transaction;
+
+/// File of headers downloaded by light client
+pub const HEADERS_FILE: &str = "headers";
+/// Max number of scripts in a gettxproofs message
+pub const MAX_PROOF_SCRIPTS: usize = 1000;
+
+/// A transaction with proof that it's in block `block_hash`
+#[derive(Clone, Encode, Decode)]
+pub struct TxProof {
+    pub block_hash: Hash,
+    pub tx: Transaction,
+    pub proof: MerkleProof,
+}
+
+/// Block headers verified by proof of work, the chain with the most blocks is the best chain.
+/// Light client keeps headers instead of blocks
+#[derive(Default, Encode, Decode)]
+pub struct HeaderChain {
+    headers: BTreeMap<Hash, BlockHeader>,
+    /// The highest header
+    tip: Option<Hash>,
+}
+
+impl HeaderChain {
+    /// Load headers from `file`, the chain is empty if the file doesn't exist
+    pub fn load(file: &str) -> Self {
+        match fs::read(file) {
+            Ok(data) => match bincode::decode_from_slice(data.as_slice(), config::standard()) {
+                Ok((chain, _)) => chain,
+                Err(err) => {
+                    println!("Decode headers error: {}", err);
+                    Self::default()
+                }
+            },
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn save(&self, file: &str) -> Result<(), String> {
+        let data =
+            bincode::encode_to_vec(self, config::standard()).expect("Can not encode headers");
+        fs::write(file, data).map_err(|err| format!("Save headers error: {}", err))
+    }
+
+    pub fn tip_height(&self) -> Option<u64> {
+        self.tip
+            .and_then(|hash| self.headers.get(&hash))
+            .map(|header| header.height)
+    }
+
+    /// Hashes of the best chain in order of height
+    pub fn main_chain(&self) -> Vec<Hash> {
+        let mut hashes = vec![];
+        let mut cur = self.tip;
+        while let Some(header) = cur.and_then(|hash| self.headers.get(&hash)) {
+            hashes.push(header.hash);
+            cur = header.prev_block_hash;
+        }
+        hashes.reverse();
+        hashes
+    }
+
+    /// Locator of the best chain, which is sent in getheaders message
+    pub fn locator(&self) -> Vec<Hash> {
+        build_locator(&self.main_chain())
+    }
+
+    /// Verify and add headers, the first header must be genesis if the chain is empty. Returns
+    /// the number of new headers
+    pub fn add_headers(&mut self, headers: &[BlockHeader]) -> Result<usize, String> {
+        let mut count = 0;
+        for header in headers {
+            if self.headers.contains_key(&header.hash) {
+                continue;
+            }
+            if !header.is_valid_pow() {
+                return Err(format!(
+                    "Header {} has invalid proof of work",
+                    hash2str(&header.hash)
+                ));
+            }
+            let expected_height = match header.prev_block_hash {
+                None if self.headers.is_empty() => 0,
+                None => {
+                    return Err(format!(
+                        "Header {} is genesis of another chain",
+                        hash2str(&header.hash)
+                    ))
+                }
+                Some(prev) => match self.headers.get(&prev) {
+                    Some(parent) => parent.height + 1,
+                    None => {
+                        return Err(format!(
+                            "Parent of header {} is unknown",
+                            hash2str(&header.hash)
+                        ))
+                    }
+                },
+            };
+            if header.height != expected_height {
+                return Err(format!(
+                    "Header {} has invalid height",
+                    hash2str(&header.hash)
+                ));
+            }
+            if self.tip_height() < Some(header.height) {
+                self.tip = Some(header.hash);
+            }
+            self.headers.insert(header.hash, header.clone());
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Verify `block` is in the best chain and it's transactions match the header, returns it's
+    /// height
+    pub fn verify_block(&self, block: &Block) -> Result<u64, String> {
+        let header = match self.headers.get(&block.hash) {
+            Some(header) => header,
+            None => return Err(format!("Block {} is unknown", hash2str(&block.hash))),
+        };
+        if !block.transactions.iter().all(|tx| tx.has_valid_id()) || block.header() != *header {
+            return Err(format!(
+                "Block {} doesn't match the header",
+                hash2str(&block.hash)
+            ));
+        }
+        Ok(header.height)
+    }
+
+    /// Verify transactions are in blocks of the best chain, returns them with their block
+    /// heights. Transactions are not verified by scripts, they are trusted because they are
+    /// mined. Note that transactions omitted by the node can't be detected
+    pub fn verify_proofs(&self, proofs: Vec<TxProof>) -> Result<Vec<(Transaction, u64)>, String> {
+        let heights: HashMap<Hash, u64> = self
+            .main_chain()
+            .into_iter()
+            .enumerate()
+            .map(|(height, hash)| (hash, height as u64))
+            .collect();
+        let mut txs = vec![];
+        for TxProof {
+            block_hash,
+            tx,
+            proof,
+        } in proofs
+        {
+            let height = match heights.get(&block_hash) {
+                Some(height) => *height,
+                None => {
+                    return Err(format!(
+                        "Block {} is not in the best chain",
+                        hash2str(&block_hash)
+                    ))
+                }
+            };
+            let tx_hash = self.headers[&block_hash].tx_hash;
+            if !tx.has_valid_id() || proof.root(&tx.id) != Some(tx_hash) {
+                return Err(format!("Invalid proof of transaction {}", hash2str(&tx.id)));
+            }
+            txs.push((tx, height));
+        }
+        Ok(txs)
+    }
+}
+
+/// An unspent output which is found by light client
+pub struct LightCoin {
+    pub value: u64,
+    pub script: Script,
+    /// Height of the block which the output is in
+    pub height: u64,
+}
+
+/// Unspent native outputs paid to `scripts` in proven transactions `txs`
+pub fn find_coins(txs: &[(Transaction, u64)], scripts: &HashSet<Script>) -> Vec<LightCoin> {
+    let spent: HashSet<(Hash, usize)> = txs
+        .iter()
+        .flat_map(|(tx, _)| tx.v_in.iter())
+        .filter_map(|input| input.tx_id.zip(input.v_out_idx))
+        .collect();
+    let mut coins = vec![];
+    for (tx, height) in txs {
+        for (out_idx, out) in tx.v_out.iter().enumerate() {
+            if out.asset.is_none()
+                && scripts.contains(&out.script_pub_key)
+                && !spent.contains(&(tx.id, out_idx))
+            {
+                coins.push(LightCoin {
+                    value: out.value,
+                    script: out.script_pub_key.clone(),
+                    height: *height,
+                });
+            }
+        }
+    }
+    coins
+}
+
+#[cfg(test)]
+mod spv_test {
+    use super::*;
+    use crate::block::Block;
+    use crate::wallet::{address_script, derive_key_pair, Wallet};
+
+    #[test]
+    fn verify_headers_and_proofs() {
+        let address = Wallet::new(derive_key_pair(&[0u8; 16], &[0]).as_slice()).get_address();
+        let other = Wallet::new(derive_key_pair(&[0u8; 16], &[1]).as_slice()).get_address();
+        let mut blocks: Vec<Block> = vec![];
+        for height in 0..3 {
+            let coinbase = Transaction::new_coinbase_tx(&address, None, height, 0);
+            let other_coinbase = Transaction::new_coinbase_tx(&other, None, height, 0);
+            let prev = blocks.last().map(|b| b.hash);
+            blocks.push(Block::new(vec![coinbase, other_coinbase], prev, height));
+        }
+        let headers: Vec<BlockHeader> = blocks.iter().map(|b| b.header()).collect();
+        let mut chain = HeaderChain::default();
+        assert!(chain.add_headers(&headers[1..]).is_err());
+        assert_eq!(chain.add_headers(&headers), Ok(3));
+        assert_eq!(chain.tip_height(), Some(2));
+        assert_eq!(chain.locator()[0], blocks[2].hash);
+
+        let proof = |block: &Block, index: usize| {
+            let ids: Vec<Hash> = block.transactions.iter().map(|tx| tx.id).collect();
+            TxProof {
+                block_hash: block.hash,
+                tx: block.transactions[index].clone(),
+                proof: MerkleProof::new(&ids, index),
+            }
+        };
+        let txs = chain
+            .verify_proofs(vec![proof(&blocks[0], 0), proof(&blocks[2], 0)])
+            .unwrap();
+        let scripts = HashSet::from([address_script(&address).unwrap()]);
+        let coins = find_coins(&txs, &scripts);
+        assert_eq!(coins.len(), 2);
+        assert_eq!(coins[1].height, 2);
+
+        // The transaction is not in the block
+        let mut forged = proof(&blocks[1], 1);
+        forged.block_hash = blocks[2].hash;
+        assert!(chain.verify_proofs(vec![forged]).is_err());
+    }
+}
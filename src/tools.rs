@@ -1,11 +1,27 @@
 use crate::block::{Hash, TimeStamp};
+use std::cell::Cell;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+thread_local! {
+    /// Time returned by `get_timestamp` instead of the system time in this thread
+    static MOCK_TIME: Cell<Option<TimeStamp>> = const { Cell::new(None) };
+}
+
+/// Current time in milliseconds, it's the mock time if it's set
 pub fn get_timestamp() -> TimeStamp {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_millis()
+    MOCK_TIME.with(|time| time.get()).unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+    })
+}
+
+/// Replace the system time with `time` in this thread, so that simulation is deterministic.
+/// `None` restores the system time
+#[cfg(test)]
+pub fn set_mock_time(time: Option<TimeStamp>) {
+    MOCK_TIME.with(|mock| mock.set(time));
 }
 
 /// Transfer binary data to hex string
@@ -22,3 +38,15 @@ pub fn hash2str(hash: &Hash) -> String {
     let s = hash.map(|n| format!("{:02x}", n)).concat();
     format!("0x{}", s)
 }
+
+/// Transfer hex string to binary data, the "0x" prefix is optional
+pub fn hex2bytes(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.strip_prefix("0x").unwrap_or(hex);
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -1,48 +1,223 @@
 use bincode::{config, Decode, Encode};
+use ring::rand::{self, SecureRandom};
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::rc::Rc;
 
-use crate::block::{ByteData, Hash};
-use crate::wallet::{extract_pub_key_hash, hash_pub_key};
+use crate::block::{ByteData, Hash, TimeStamp};
+use crate::codec::MAX_MESSAGE_SIZE;
+use crate::script::{verify_script, Op, Script, ScriptContext};
+use crate::tools::hash2str;
+use crate::wallet::address_script;
 
-const SUBSIDY: u64 = 50;
+/// Reward of mining a block
+pub const SUBSIDY: u64 = 50;
+/// Lock time below this value is a block height, otherwise it's a timestamp in milliseconds
+pub const LOCK_TIME_THRESHOLD: u64 = 500_000_000;
+/// Length of secret of hash time locked contract
+const HTLC_SECRET_LEN: usize = 32;
 
 /// Transaction are composed of inputs and outputs, one input must refer to a output in another
 /// transaction, the generated output may have no inputs referred to
-#[derive(Encode, Decode)]
+#[derive(Clone, Encode, Decode)]
 pub struct Transaction {
     pub id: Hash,
     pub v_in: Vec<Rc<TXInput>>,
     pub v_out: Vec<Rc<TXOutput>>,
+    /// The transaction can't be mined until this block height or timestamp, 0 means no lock
+    pub lock_time: u64,
+    /// Assets issued by the transaction
+    pub issuance: Option<Issuance>,
 }
 
 impl Transaction {
+    pub fn new(v_in: Vec<Rc<TXInput>>, v_out: Vec<Rc<TXOutput>>, lock_time: u64) -> Self {
+        Self {
+            id: hash_transaction(&v_in, &v_out, lock_time, &None),
+            v_in,
+            v_out,
+            lock_time,
+            issuance: None,
+        }
+    }
+
+    /// Set assets issued by the transaction, the transaction id is updated as well
+    pub fn with_issuance(mut self, issuance: Issuance) -> Self {
+        self.issuance = Some(issuance);
+        self.id = hash_transaction(&self.v_in, &self.v_out, self.lock_time, &self.issuance);
+        self
+    }
+
+    /// Assets and their values issued by the transaction, a new mintable asset also issues it's
+    /// mint authority
+    pub fn issued_assets(&self) -> Vec<(Hash, u64)> {
+        let issuance = match &self.issuance {
+            Some(issuance) => issuance,
+            None => return vec![],
+        };
+        match issuance.asset {
+            Some(asset) => vec![(asset, issuance.amount)],
+            None => {
+                let first_input = match self.v_in.first() {
+                    Some(input) => input,
+                    None => return vec![],
+                };
+                let asset = asset_id(
+                    &first_input.tx_id.unwrap_or_default(),
+                    first_input.v_out_idx.unwrap_or(0),
+                );
+                let mut issued = vec![(asset, issuance.amount)];
+                if issuance.mintable {
+                    issued.push((mint_authority(&asset), 1));
+                }
+                issued
+            }
+        }
+    }
+
     /// Create a coinbase transaction, which will be inserted at start of each block, it's have no
-    /// referred outputs (it's only have one empty input), the generated output is rewards for miners
-    pub fn new_coinbase_tx(to: &str, data: Option<String>) -> Self {
+    /// referred outputs (it's only have one empty input), the generated output is rewards for miners.
+    /// The block `height` is pushed in input, so that every coinbase transaction has unique id.
+    /// Miners collect `fees` of transactions in block as well
+    pub fn new_coinbase_tx(to: &str, data: Option<String>, height: u64, fees: u64) -> Self {
         let data = data.unwrap_or(format!("Reword to {}", to));
         let tx_in = vec![Rc::new(TXInput {
             tx_id: None,
             v_out_idx: None,
-            signature: None,
-            pub_key: Vec::from(data),
+            script_sig: Script(vec![Op::Num(height as i64), Op::PushData(Vec::from(data))]),
+            sequence: 0,
         })];
-        let tx_out = vec![Rc::new(TXOutput::new(SUBSIDY, to))];
-        Self {
-            id: hash_transaction(&tx_in, &tx_out),
-            v_in: tx_in,
-            v_out: tx_out,
-        }
+        let tx_out = vec![Rc::new(TXOutput::new(SUBSIDY + fees, to))];
+        Self::new(tx_in, tx_out, 0)
     }
 
     /// Determine whether it is a coinbase transaction
     pub fn is_coinbase_tx(&self) -> bool {
         self.v_in.len() == 1 && self.v_in[0].tx_id.is_none()
     }
+
+    /// Check whether the transaction can be mined in block of `height` created at `timestamp`
+    pub fn is_final(&self, height: u64, timestamp: TimeStamp) -> bool {
+        if self.lock_time == 0 {
+            return true;
+        }
+        if self.lock_time < LOCK_TIME_THRESHOLD {
+            self.lock_time <= height
+        } else {
+            self.lock_time as TimeStamp <= timestamp
+        }
+    }
+
+    /// Whether the id is hash of the transaction
+    pub fn has_valid_id(&self) -> bool {
+        self.id == hash_transaction(&self.v_in, &self.v_out, self.lock_time, &self.issuance)
+    }
+
+    /// Serialize transaction to bytes
+    pub fn encode(&self) -> Vec<u8> {
+        let config = config::standard();
+        bincode::encode_to_vec(self, config).expect("Can not encode Transaction to byte data")
+    }
+
+    /// Deserialize transaction from bytes, returns `None` if the bytes are invalid or too large
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let config = config::standard().with_limit::<MAX_MESSAGE_SIZE>();
+        match bincode::decode_from_slice(data, config) {
+            Ok((tx, _)) => Some(tx),
+            Err(_) => None,
+        }
+    }
+
+    /// The message signed by input `input_idx`, it's hash of the transaction whose unlocking
+    /// scripts are cleared, except the signing input is replaced with `script_pub_key` of the
+    /// referenced output
+    pub fn sighash(&self, input_idx: usize, script_pub_key: &Script) -> Hash {
+        let v_in = self
+            .v_in
+            .iter()
+            .enumerate()
+            .map(|(i, input)| {
+                Rc::new(TXInput {
+                    tx_id: input.tx_id,
+                    v_out_idx: input.v_out_idx,
+                    script_sig: if i == input_idx {
+                        script_pub_key.clone()
+                    } else {
+                        Script::default()
+                    },
+                    sequence: input.sequence,
+                })
+            })
+            .collect();
+        hash_transaction(&v_in, &self.v_out, self.lock_time, &self.issuance)
+    }
+
+    /// Set unlocking script of input `input_idx`, the transaction id is updated as well
+    pub fn set_script_sig(&mut self, input_idx: usize, script_sig: Script) {
+        let input = &self.v_in[input_idx];
+        self.v_in[input_idx] = Rc::new(TXInput {
+            tx_id: input.tx_id,
+            v_out_idx: input.v_out_idx,
+            script_sig,
+            sequence: input.sequence,
+        });
+        self.id = hash_transaction(&self.v_in, &self.v_out, self.lock_time, &self.issuance);
+    }
+
+    /// Verify every input unlocks it's referenced output, `prev_outputs` are the referenced
+    /// outputs in the same order as inputs
+    pub fn verify(&self, prev_outputs: &[Rc<TXOutput>]) -> Result<(), String> {
+        if self.is_coinbase_tx() {
+            return Ok(());
+        }
+        if prev_outputs.len() != self.v_in.len() {
+            return Err(format!(
+                "Transaction {} has {} inputs, but {} referenced outputs",
+                hash2str(&self.id),
+                self.v_in.len(),
+                prev_outputs.len()
+            ));
+        }
+        for (i, (input, prev)) in self.v_in.iter().zip(prev_outputs).enumerate() {
+            // Inputs spending pay to script hash output sign the redeem script instead
+            let script_code = if prev.script_pub_key.p2sh_hash().is_some() {
+                match input.script_sig.redeem_script() {
+                    Some(script) => script,
+                    None => {
+                        return Err(format!(
+                            "Input {} of transaction {} doesn't reveal redeem script",
+                            i,
+                            hash2str(&self.id)
+                        ))
+                    }
+                }
+            } else {
+                prev.script_pub_key.clone()
+            };
+            let ctx = ScriptContext {
+                sighash: self.sighash(i, &script_code),
+                lock_time: self.lock_time,
+                sequence: input.sequence,
+            };
+            if let Err(err) = verify_script(&input.script_sig, &prev.script_pub_key, &ctx) {
+                return Err(format!(
+                    "Input {} of transaction {} is invalid: {}",
+                    i,
+                    hash2str(&self.id),
+                    err
+                ));
+            }
+        }
+        Ok(())
+    }
 }
 
-pub fn hash_transaction(v_in: &Vec<Rc<TXInput>>, v_out: &Vec<Rc<TXOutput>>) -> Hash {
+pub fn hash_transaction(
+    v_in: &Vec<Rc<TXInput>>,
+    v_out: &Vec<Rc<TXOutput>>,
+    lock_time: u64,
+    issuance: &Option<Issuance>,
+) -> Hash {
     let mut hasher = Sha256::new();
     let config = config::standard();
     let inputs = bincode::encode_to_vec(v_in, config).expect("Can not encode transaction inputs");
@@ -50,7 +225,42 @@ pub fn hash_transaction(v_in: &Vec<Rc<TXInput>>, v_out: &Vec<Rc<TXOutput>>) -> H
     let outputs =
         bincode::encode_to_vec(v_out, config).expect("Can not encode transaction outputs");
     hasher.update(outputs);
-    hasher.finalize().try_into().unwrap()
+    hasher.update(lock_time.to_le_bytes());
+    if let Some(issuance) = issuance {
+        let issuance =
+            bincode::encode_to_vec(issuance, config).expect("Can not encode transaction issuance");
+        hasher.update(issuance);
+    }
+    hasher.finalize().into()
+}
+
+/// Issuance of a new asset, or more value of an existing mintable asset
+#[derive(Clone, Encode, Decode)]
+pub struct Issuance {
+    /// The asset to mint, `None` issues a new asset whose id is derived from the first input
+    pub asset: Option<Hash>,
+    pub amount: u64,
+    /// Whether more value of the new asset can be minted by the holder of it's mint authority
+    pub mintable: bool,
+    /// Name of the new asset
+    pub name: String,
+}
+
+/// Id of the asset issued by transaction whose first input spends output `out_idx` of
+/// transaction `tx_id`, it's unique because an output can only be spent once
+pub fn asset_id(tx_id: &Hash, out_idx: usize) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(tx_id);
+    hasher.update((out_idx as u64).to_le_bytes());
+    hasher.finalize().into()
+}
+
+/// Id of the mint authority of `asset`, an output of it must be spent to mint more `asset`
+pub fn mint_authority(asset: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(asset);
+    hasher.update(b"mint authority");
+    hasher.finalize().into()
 }
 
 /// Transaction input
@@ -60,16 +270,11 @@ pub struct TXInput {
     pub tx_id: Option<Hash>,
     /// The index of referenced output in transaction outputs
     pub v_out_idx: Option<usize>,
-    pub signature: Option<ByteData>,
-    pub pub_key: ByteData,
-}
-
-impl TXInput {
-    /// Check whether a input was uses a specific key to unlock an output
-    pub fn use_key(&self, pub_key_hash: &[u8]) -> bool {
-        let locking_hash = hash_pub_key(self.pub_key.as_ref());
-        pub_key_hash == locking_hash
-    }
+    /// Unlocking script, which satisfies locking script of referenced output
+    pub script_sig: Script,
+    /// Relative lock, the number of blocks the referenced output must be confirmed before the
+    /// input can be mined, 0 means no lock
+    pub sequence: u32,
 }
 
 /// Transaction output
@@ -77,23 +282,210 @@ impl TXInput {
 pub struct TXOutput {
     /// The amount of "coin" stored in output, and it's indivisible
     pub value: u64,
-    pub pub_key_hash: ByteData,
+    /// Locking script, which defines conditions to spend the output
+    pub script_pub_key: Script,
+    /// The asset of value, `None` is the native coin
+    pub asset: Option<Hash>,
 }
 
 impl TXOutput {
+    /// Create an output locked to `address`, which is a pay to public key hash address or a pay
+    /// to script hash address
     pub fn new(value: u64, address: &str) -> Self {
+        let script_pub_key = address_script(address).unwrap_or_else(|err| panic!("{}", err));
         Self {
             value,
-            pub_key_hash: extract_pub_key_hash(address),
+            script_pub_key,
+            asset: None,
+        }
+    }
+
+    /// Public key hash which locks the output, returns `None` if it isn't a pay to public key
+    /// hash output
+    pub fn pub_key_hash(&self) -> Option<&[u8]> {
+        self.script_pub_key.p2pkh_hash()
+    }
+}
+
+/// Hash time locked contract of atomic swap, the output can be claimed by the owner of
+/// `recipient` public key hash with the secret whose SHA256 hash is `secret_hash`, or refunded to
+/// the owner of `refund` public key hash once `lock_time` is reached
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Htlc {
+    pub secret_hash: ByteData,
+    pub recipient: ByteData,
+    pub refund: ByteData,
+    pub lock_time: u64,
+}
+
+impl Htlc {
+    /// Generate a random secret of swap
+    pub fn generate_secret() -> ByteData {
+        let rng = rand::SystemRandom::new();
+        let mut secret = vec![0u8; HTLC_SECRET_LEN];
+        rng.fill(&mut secret).expect("Generate swap secret error");
+        secret
+    }
+
+    /// Hash of `secret` which locks the contract
+    pub fn hash_secret(secret: &[u8]) -> ByteData {
+        Vec::from(Sha256::digest(secret).as_slice())
+    }
+
+    /// Locking script of the contract
+    pub fn script(&self) -> Script {
+        Script(vec![
+            Op::If,
+            Op::Sha256,
+            Op::PushData(self.secret_hash.clone()),
+            Op::EqualVerify,
+            Op::Dup,
+            Op::Hash160,
+            Op::PushData(self.recipient.clone()),
+            Op::Else,
+            Op::Num(self.lock_time as i64),
+            Op::CheckLockTimeVerify,
+            Op::Drop,
+            Op::Dup,
+            Op::Hash160,
+            Op::PushData(self.refund.clone()),
+            Op::EndIf,
+            Op::EqualVerify,
+            Op::CheckSig,
+        ])
+    }
+
+    /// Parse contract from locking script, returns `None` if it isn't a contract script
+    pub fn from_script(script: &Script) -> Option<Self> {
+        match script.0.as_slice() {
+            [Op::If, Op::Sha256, Op::PushData(secret_hash), Op::EqualVerify, Op::Dup, Op::Hash160, Op::PushData(recipient), Op::Else, Op::Num(lock_time), Op::CheckLockTimeVerify, Op::Drop, Op::Dup, Op::Hash160, Op::PushData(refund), Op::EndIf, Op::EqualVerify, Op::CheckSig] => {
+                Some(Self {
+                    secret_hash: secret_hash.clone(),
+                    recipient: recipient.clone(),
+                    refund: refund.clone(),
+                    lock_time: *lock_time as u64,
+                })
+            }
+            _ => None,
         }
     }
 
-    /// Check whether provided public key hash was used to lock the output
-    pub fn is_locking_with_key(&self, pub_key_hash: &[u8]) -> bool {
-        self.pub_key_hash == pub_key_hash
+    /// Unlocking script which claims the contract with `secret`
+    pub fn claim_script(signature: &[u8], pub_key: &[u8], secret: &[u8]) -> Script {
+        Script(vec![
+            Op::PushData(Vec::from(signature)),
+            Op::PushData(Vec::from(pub_key)),
+            Op::PushData(Vec::from(secret)),
+            Op::Num(1),
+        ])
+    }
+
+    /// Unlocking script which refunds the contract after lock time
+    pub fn refund_script(signature: &[u8], pub_key: &[u8]) -> Script {
+        Script(vec![
+            Op::PushData(Vec::from(signature)),
+            Op::PushData(Vec::from(pub_key)),
+            Op::Num(0),
+        ])
+    }
+
+    /// Secret revealed by the unlocking script which claims a contract, returns `None` if the
+    /// script refunds it
+    pub fn extract_secret(script_sig: &Script) -> Option<&[u8]> {
+        match script_sig.0.as_slice() {
+            [Op::PushData(_), Op::PushData(_), Op::PushData(secret), Op::Num(1)] => {
+                Some(secret.as_slice())
+            }
+            _ => None,
+        }
     }
 }
 
 /// Unspent transaction outputs, key is transaction id, value is unspend output and it's index in
 /// this transaction
+#[allow(clippy::upper_case_acronyms)]
 pub type UTXO = HashMap<Hash, Vec<(Rc<TXOutput>, usize)>>;
+
+#[cfg(test)]
+mod transaction_test {
+    use super::*;
+    use crate::wallet::{derive_key_pair, hash_pub_key, Wallet};
+
+    #[test]
+    fn reject_forged_length() {
+        // The transaction claims 2^60 inputs, which must not be allocated
+        let mut data = bincode::encode_to_vec([0u8; 32], config::standard()).unwrap();
+        data.push(253);
+        data.extend_from_slice(&(1u64 << 60).to_le_bytes());
+        assert!(Transaction::decode(&data).is_none());
+    }
+
+    #[test]
+    fn lock_time_by_height_and_timestamp() {
+        let mut tx = Transaction::new(vec![], vec![], 10);
+        assert!(!tx.is_final(9, 0));
+        assert!(tx.is_final(10, 0));
+
+        tx.lock_time = LOCK_TIME_THRESHOLD + 1000;
+        assert!(!tx.is_final(u32::MAX as u64, LOCK_TIME_THRESHOLD as TimeStamp));
+        assert!(tx.is_final(0, (LOCK_TIME_THRESHOLD + 1000) as TimeStamp));
+    }
+
+    #[test]
+    fn claim_and_refund_htlc() {
+        let recipient = Wallet::new(derive_key_pair(&[0u8; 16], &[0]).as_slice());
+        let refund = Wallet::new(derive_key_pair(&[0u8; 16], &[1]).as_slice());
+        let secret = Htlc::generate_secret();
+        let htlc = Htlc {
+            secret_hash: Htlc::hash_secret(&secret),
+            recipient: hash_pub_key(recipient.public_key()),
+            refund: hash_pub_key(refund.public_key()),
+            lock_time: 10,
+        };
+        let lock = htlc.script();
+        assert_eq!(Htlc::from_script(&lock), Some(htlc));
+
+        let ctx = |lock_time| ScriptContext {
+            sighash: [1u8; 32],
+            lock_time,
+            sequence: 0,
+        };
+        let sig = recipient.sign(&ctx(0).sighash);
+        let claim = Htlc::claim_script(sig.as_ref(), recipient.public_key(), &secret);
+        assert!(verify_script(&claim, &lock, &ctx(0)).is_ok());
+        assert_eq!(Htlc::extract_secret(&claim), Some(secret.as_slice()));
+        let wrong = Htlc::claim_script(sig.as_ref(), recipient.public_key(), &[0u8; 32]);
+        assert!(verify_script(&wrong, &lock, &ctx(0)).is_err());
+
+        let sig = refund.sign(&ctx(0).sighash);
+        let unlock = Htlc::refund_script(sig.as_ref(), refund.public_key());
+        assert!(verify_script(&unlock, &lock, &ctx(9)).is_err());
+        assert!(verify_script(&unlock, &lock, &ctx(10)).is_ok());
+    }
+
+    #[test]
+    fn issue_new_asset_with_mint_authority() {
+        let input = Rc::new(TXInput {
+            tx_id: Some([3u8; 32]),
+            v_out_idx: Some(1),
+            script_sig: Script::default(),
+            sequence: 0,
+        });
+        let issuance = Issuance {
+            asset: None,
+            amount: 100,
+            mintable: true,
+            name: String::from("GOLD"),
+        };
+        let tx = Transaction::new(vec![input], vec![], 0);
+        let id = tx.id;
+        let tx = tx.with_issuance(issuance);
+        assert_ne!(tx.id, id);
+
+        let asset = asset_id(&[3u8; 32], 1);
+        assert_eq!(
+            tx.issued_assets(),
+            vec![(asset, 100), (mint_authority(&asset), 1)]
+        );
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::block::{Hash, TimeStamp};
+use crate::node::PeerId;
+
+/// Max number of transactions in the recently rejected cache
+const MAX_REJECTS: usize = 10_000;
+/// A transaction is requested from another peer if it's not received in this time, in
+/// milliseconds
+const TX_REQUEST_TIMEOUT: TimeStamp = 5_000;
+/// Max number of transactions requested from a peer which are not received yet
+const MAX_TX_IN_FLIGHT: usize = 100;
+/// Max number of transactions a peer can send at once
+pub const TX_RATE_BURST: u64 = 100;
+/// Number of transactions a peer can send per second in the long run
+pub const TX_RATE_PER_SECOND: u64 = 10;
+
+/// Token bucket which limits how often something can be done, tokens are refilled over time
+pub struct RateLimiter {
+    capacity: u64,
+    per_second: u64,
+    /// Available tokens in thousandths
+    tokens: u64,
+    last_refill: TimeStamp,
+}
+
+impl RateLimiter {
+    pub fn new(capacity: u64, per_second: u64, now: TimeStamp) -> Self {
+        Self {
+            capacity,
+            per_second,
+            tokens: capacity * 1000,
+            last_refill: now,
+        }
+    }
+
+    /// Take a token at `now`, returns `false` if there are no tokens left
+    pub fn allow(&mut self, now: TimeStamp) -> bool {
+        let elapsed = now.saturating_sub(self.last_refill) as u64;
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity * 1000);
+        self.last_refill = now;
+        if self.tokens < 1000 {
+            return false;
+        }
+        self.tokens -= 1000;
+        true
+    }
+}
+
+/// Keep track of requested and rejected transactions, so that a transaction is not fetched or
+/// verified again and again
+#[derive(Default)]
+pub struct TxRelay {
+    /// Recently rejected transactions
+    rejected: HashSet<Hash>,
+    /// Rejected transactions in the order of rejection, old ones are forgot first
+    rejected_order: VecDeque<Hash>,
+    /// Requested transactions, value is the peer and time of request
+    requested: HashMap<Hash, (PeerId, TimeStamp)>,
+    /// Number of requested transactions of each peer
+    in_flight: HashMap<PeerId, usize>,
+}
+
+impl TxRelay {
+    /// Whether transaction `tx_id` announced by `peer` should be requested. It's not requested if
+    /// it's rejected recently, or it's requested from another peer and may still arrive, or too
+    /// many transactions are requested from the peer
+    pub fn should_request(&mut self, tx_id: &Hash, peer: PeerId, now: TimeStamp) -> bool {
+        if self.rejected.contains(tx_id) {
+            return false;
+        }
+        match self.requested.get(tx_id) {
+            Some((p, time)) if *p != peer && now.saturating_sub(*time) <= TX_REQUEST_TIMEOUT => {
+                false
+            }
+            Some((p, _)) if *p == peer => {
+                self.requested.insert(*tx_id, (peer, now));
+                true
+            }
+            _ => {
+                if self.in_flight.get(&peer).copied().unwrap_or(0) >= MAX_TX_IN_FLIGHT {
+                    return false;
+                }
+                self.received(tx_id);
+                self.requested.insert(*tx_id, (peer, now));
+                *self.in_flight.entry(peer).or_insert(0) += 1;
+                true
+            }
+        }
+    }
+
+    /// Transaction `tx_id` is received, returns the peer it's requested from
+    pub fn received(&mut self, tx_id: &Hash) -> Option<PeerId> {
+        let (peer, _) = self.requested.remove(tx_id)?;
+        if let Some(count) = self.in_flight.get_mut(&peer) {
+            *count -= 1;
+            if *count == 0 {
+                self.in_flight.remove(&peer);
+            }
+        }
+        Some(peer)
+    }
+
+    /// Forget requests which are timeout, so that transactions never sent don't stay forever
+    pub fn expire(&mut self, now: TimeStamp) {
+        let expired: Vec<Hash> = self
+            .requested
+            .iter()
+            .filter(|(_, (_, time))| now.saturating_sub(*time) > TX_REQUEST_TIMEOUT)
+            .map(|(tx_id, _)| *tx_id)
+            .collect();
+        for tx_id in expired {
+            self.received(&tx_id);
+        }
+    }
+
+    /// Whether transaction `tx_id` is rejected recently
+    pub fn is_rejected(&self, tx_id: &Hash) -> bool {
+        self.rejected.contains(tx_id)
+    }
+
+    /// Remember a rejected transaction
+    pub fn reject(&mut self, tx_id: Hash) {
+        if !self.rejected.insert(tx_id) {
+            return;
+        }
+        self.rejected_order.push_back(tx_id);
+        if self.rejected_order.len() > MAX_REJECTS {
+            if let Some(oldest) = self.rejected_order.pop_front() {
+                self.rejected.remove(&oldest);
+            }
+        }
+    }
+
+    /// Forget rejected transactions, they may become valid once the chain is changed
+    pub fn clear_rejects(&mut self) {
+        self.rejected.clear();
+        self.rejected_order.clear();
+    }
+
+    /// Forget requests to a disconnected peer
+    pub fn remove_peer(&mut self, peer: PeerId) {
+        self.requested.retain(|_, (p, _)| *p != peer);
+        self.in_flight.remove(&peer);
+    }
+}
+
+#[cfg(test)]
+mod tx_relay_test {
+    use super::*;
+
+    #[test]
+    fn limit_rate_of_transactions() {
+        let mut limiter = RateLimiter::new(3, 10, 0);
+        assert!(limiter.allow(0));
+        assert!(limiter.allow(0));
+        assert!(limiter.allow(0));
+        assert!(!limiter.allow(50));
+        // A token is refilled every 100 milliseconds
+        assert!(limiter.allow(100));
+        assert!(!limiter.allow(100));
+        assert!(limiter.allow(10_000));
+    }
+
+    #[test]
+    fn request_and_reject_transactions() {
+        let mut relay = TxRelay::default();
+        let tx_id = [1u8; 32];
+        assert!(relay.should_request(&tx_id, 0, 0));
+        // Wait for the first peer before requesting from another one
+        assert!(!relay.should_request(&tx_id, 1, TX_REQUEST_TIMEOUT));
+        assert!(relay.should_request(&tx_id, 1, TX_REQUEST_TIMEOUT + 1));
+
+        relay.received(&tx_id);
+        relay.reject(tx_id);
+        assert!(!relay.should_request(&tx_id, 0, TX_REQUEST_TIMEOUT + 2));
+        for i in 0..MAX_REJECTS {
+            let mut id = [0u8; 32];
+            id[..8].copy_from_slice(&(i as u64).to_le_bytes());
+            id[31] = 2;
+            relay.reject(id);
+        }
+        // The oldest rejection is forgot
+        assert!(!relay.is_rejected(&tx_id));
+        relay.clear_rejects();
+        assert!(relay.should_request(&[2u8; 32], 0, 0));
+    }
+
+    #[test]
+    fn limit_and_expire_requests() {
+        let mut relay = TxRelay::default();
+        let tx_ids: Vec<Hash> = (0..=MAX_TX_IN_FLIGHT)
+            .map(|i| {
+                let mut id = [0u8; 32];
+                id[..8].copy_from_slice(&(i as u64).to_le_bytes());
+                id
+            })
+            .collect();
+        for tx_id in tx_ids.iter().take(MAX_TX_IN_FLIGHT) {
+            assert!(relay.should_request(tx_id, 0, 0));
+        }
+        // Too many transactions are requested from the peer, but not from others
+        assert!(!relay.should_request(&tx_ids[MAX_TX_IN_FLIGHT], 0, 0));
+        assert!(relay.should_request(&tx_ids[MAX_TX_IN_FLIGHT], 1, 0));
+
+        assert_eq!(relay.received(&tx_ids[0]), Some(0));
+        assert_eq!(relay.received(&tx_ids[0]), None);
+        assert!(relay.should_request(&tx_ids[0], 0, 0));
+
+        relay.expire(TX_REQUEST_TIMEOUT + 1);
+        assert!(relay.requested.is_empty());
+        assert!(relay.in_flight.is_empty());
+        assert!(relay.should_request(&tx_ids[1], 0, TX_REQUEST_TIMEOUT + 1));
+    }
+}
@@ -1,42 +1,166 @@
 use bincode::{config, Decode, Encode};
-use ring::rand;
+use bip39::Mnemonic;
+use ring::hmac;
+use ring::rand::{self, SecureRandom};
 use ring::signature::{Ed25519KeyPair, KeyPair, Signature, UnparsedPublicKey, ED25519};
 use ripemd::Ripemd160;
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+#[cfg(not(test))]
 use std::fs::{create_dir_all, File};
-use std::io::{Read, Write};
+#[cfg(not(test))]
+use std::io::{ErrorKind, Read, Write};
+#[cfg(not(test))]
 use std::path::Path;
 
 use crate::block::ByteData;
+use crate::script::{Script, MAX_MULTISIG_KEYS};
+use crate::tools::bytes2hex;
 
 /// Address version number
 pub const ADDR_VERSION: u8 = 0;
 /// Address checksum length
 pub const ADDR_CHECKSUM_LEN: u8 = 4;
+/// Version number of pay to script hash address
+pub const P2SH_ADDR_VERSION: u8 = 5;
+/// Length of ed25519 public key
+pub const PUB_KEY_LEN: usize = 32;
+/// Version number of exported key text
+pub const EXPORT_KEY_VERSION: u8 = 0x80;
 
 const WALLETS_FILE: &str = "wallets";
+/// Version of wallets file format, it's saved before wallets. The file without version is the
+/// first format, which only has key pairs of addresses
+const WALLETS_VERSION: u32 = 1;
+
+/// Entropy length of generated mnemonic, 16 bytes make a 12 words phrase
+const MNEMONIC_ENTROPY_LEN: usize = 16;
+/// HMAC key of SLIP-0010 master key generation for ed25519 curve
+const ED25519_SEED_KEY: &[u8] = b"ed25519 seed";
+/// Ed25519 only supports hardened derivation, every index is offset by this value
+const HARDENED_OFFSET: u32 = 0x8000_0000;
+/// Derivation branch of receiving addresses, keys are derived at path m/0'/index'
+const RECEIVE_BRANCH: u32 = 0;
+/// Derivation branch of change addresses, keys are derived at path m/1'/index'
+const CHANGE_BRANCH: u32 = 1;
+/// How many consecutive unused addresses are checked before restore stops
+const RESTORE_GAP_LIMIT: u32 = 20;
+/// PKCS#8 v2 document of ed25519 key pair is "prefix || seed || middle || public key"
+const PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x53, 0x02, 0x01, 0x01, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+const PKCS8_MIDDLE: [u8; 5] = [0xa1, 0x23, 0x03, 0x21, 0x00];
 
 #[derive(Encode, Decode)]
 pub struct Wallets {
-    wallets: HashMap<String, ByteData>,
+    /// Mnemonic phrase of the master seed, it's generated when the first wallet is created
+    mnemonic: Option<String>,
+    /// Index of the next receiving key to derive
+    next_index: u32,
+    /// Index of the next change key to derive
+    next_change_index: u32,
+    /// Saved wallets, key is address
+    wallets: HashMap<String, WalletEntry>,
+}
+
+#[derive(Encode, Decode)]
+struct WalletEntry {
+    /// PKCS#8 bytes data of the key pair, it's `None` for watch-only address
+    key_pair: Option<ByteData>,
+    /// Name of the address given by user
+    label: Option<String>,
+    /// Whether the address is generated to receive change of transactions
+    is_change: bool,
+    /// Redeem script of pay to script hash address, the keys of multisig redeem script are held
+    /// by co-signers
+    script: Option<Script>,
+}
+
+impl WalletEntry {
+    fn new(key_pair: ByteData) -> Self {
+        Self {
+            key_pair: Some(key_pair),
+            label: None,
+            is_change: false,
+            script: None,
+        }
+    }
+
+    /// Entry of an address whose private key is not held by wallets
+    fn without_key(script: Option<Script>) -> Self {
+        Self {
+            key_pair: None,
+            label: None,
+            is_change: false,
+            script,
+        }
+    }
 }
 
 impl Wallets {
+    /// Load wallets from file, it panics if the file can't be decoded, so that the keys in it are
+    /// never overwritten
     pub fn new() -> Self {
-        let wallets = Option::unwrap_or(Self::load(), HashMap::new());
-        Self { wallets }
+        match Self::load() {
+            Ok(Some(wallets)) => wallets,
+            Ok(None) => Self {
+                mnemonic: None,
+                next_index: 0,
+                next_change_index: 0,
+                wallets: HashMap::new(),
+            },
+            Err(err) => panic!("{}, {} is kept untouched", err, WALLETS_FILE),
+        }
+    }
+
+    /// Mnemonic phrase to backup the wallets, it's `None` before any wallet is created
+    pub fn mnemonic(&self) -> Option<&str> {
+        self.mnemonic.as_deref()
     }
 
-    /// Create a new wallet, and return it's address
-    pub fn create_wallet(&mut self) -> String {
-        // Create a new wallet
-        let key_pair = Wallet::create_key_pair();
+    /// Create a new wallet, and return it's address. The mnemonic phrase is generated for the
+    /// first wallet, and it's returned as well to be shown to the user
+    pub fn create_wallet(&mut self) -> (String, Option<String>) {
+        let mut mnemonic = None;
+        if self.mnemonic.is_none() {
+            mnemonic = Some(generate_mnemonic());
+            self.mnemonic = mnemonic.clone();
+        }
+        (self.create_derived_wallet(RECEIVE_BRANCH), mnemonic)
+    }
+
+    /// Address of the next change key, it's not saved until `create_change_address` is called.
+    /// Returns `None` if wallets have no mnemonic to derive it
+    pub fn next_change_address(&self) -> Option<String> {
+        let seed = self.seed()?;
+        let key_pair = derive_key_pair(&seed, &[CHANGE_BRANCH, self.next_change_index]);
+        Some(Wallet::new(key_pair.as_slice()).get_address())
+    }
+
+    /// Create a new wallet to receive change of a transaction, and return it's address. Returns
+    /// `None` if wallets have no mnemonic to derive it
+    pub fn create_change_address(&mut self) -> Option<String> {
+        self.mnemonic.as_ref()?;
+        Some(self.create_derived_wallet(CHANGE_BRANCH))
+    }
+
+    /// Derive the next key of `branch` from master seed, and save it as a new wallet
+    fn create_derived_wallet(&mut self, branch: u32) -> String {
+        let seed = self.seed().expect("Wallets have no mnemonic");
+        let next_index = if branch == CHANGE_BRANCH {
+            &mut self.next_change_index
+        } else {
+            &mut self.next_index
+        };
+        let key_pair = derive_key_pair(&seed, &[branch, *next_index]);
+        *next_index += 1;
         let wallet = Wallet::new(key_pair.as_slice());
         let address = wallet.get_address();
 
         // Save wallet to file
-        self.wallets.insert(address.clone(), key_pair);
+        let mut entry = WalletEntry::new(key_pair);
+        entry.is_change = branch == CHANGE_BRANCH;
+        self.wallets.insert(address.clone(), entry);
         if let Err(err) = self.save() {
             eprintln!("Save wallet error: {}", err)
         }
@@ -44,91 +168,312 @@ impl Wallets {
         address
     }
 
-    /// Get addressed of saved wallets
+    /// Get addressed of saved wallets, including watch-only addresses
     pub fn get_addresses(&self) -> Vec<String> {
-        self.wallets.keys().map(String::clone).collect()
+        let mut addresses: Vec<String> = self.wallets.keys().map(String::clone).collect();
+        addresses.sort();
+        addresses
     }
 
-    /// Get wallet for specified address
+    /// Get wallet for specified address, watch-only address has no wallet
     pub fn get_wallet(&self, address: &str) -> Option<Wallet> {
         self.wallets
             .get(address)
+            .and_then(|entry| entry.key_pair.as_ref())
             .map(|key_pair| Wallet::new(key_pair.as_slice()))
     }
 
-    /// Load wallets data from file
-    fn load() -> Option<HashMap<String, ByteData>> {
-        // Open the file which save wallets data
-        let mut file = match File::open(WALLETS_FILE) {
-            Ok(f) => f,
-            Err(err) => {
-                println!("Open {} error: {}", WALLETS_FILE, err);
-                return None;
-            }
+    /// Check whether `address` is saved as watch-only
+    pub fn is_watch_only(&self, address: &str) -> bool {
+        matches!(
+            self.wallets.get(address),
+            Some(WalletEntry {
+                key_pair: None,
+                script: None,
+                ..
+            })
+        )
+    }
+
+    /// Get redeem script of multisig `address`, returns `None` if it isn't a multisig address
+    pub fn get_multisig_script(&self, address: &str) -> Option<&Script> {
+        self.wallets.get(address)?.script.as_ref()
+    }
+
+    /// Add a pay to script hash address whose redeem script requires `required` signatures of
+    /// `pub_keys` to spend, and return the address
+    pub fn add_multisig(
+        &mut self,
+        required: usize,
+        pub_keys: &[ByteData],
+    ) -> Result<String, String> {
+        if required == 0 || required > pub_keys.len() || pub_keys.len() > MAX_MULTISIG_KEYS {
+            return Err(format!(
+                "Invalid multisig {} of {}, at most {} public keys are supported",
+                required,
+                pub_keys.len(),
+                MAX_MULTISIG_KEYS
+            ));
+        }
+        if let Some(pub_key) = pub_keys.iter().find(|k| k.len() != PUB_KEY_LEN) {
+            return Err(format!("Invalid public key {}", bytes2hex(pub_key)));
+        }
+        let redeem_script = Script::multisig(required, pub_keys);
+        let address = script_address(&redeem_script);
+        self.wallets.insert(
+            address.clone(),
+            WalletEntry::without_key(Some(redeem_script)),
+        );
+        self.save()?;
+        Ok(address)
+    }
+
+    /// Check whether `address` is generated to receive change
+    pub fn is_change(&self, address: &str) -> bool {
+        matches!(
+            self.wallets.get(address),
+            Some(WalletEntry {
+                is_change: true,
+                ..
+            })
+        )
+    }
+
+    /// Watch an address without holding it's private key
+    pub fn add_watch_only(&mut self, address: &str) -> Result<(), String> {
+        if !validate_address(address) {
+            return Err(format!("Invalid address {}", address));
+        }
+        if self.wallets.contains_key(address) {
+            return Err(format!("Address {} is already in wallets", address));
+        }
+        self.wallets
+            .insert(String::from(address), WalletEntry::without_key(None));
+        self.save()
+    }
+
+    /// Get label of `address`
+    pub fn get_label(&self, address: &str) -> Option<&str> {
+        self.wallets.get(address)?.label.as_deref()
+    }
+
+    /// Set label of `address`, an empty label removes the existing one
+    pub fn set_label(&mut self, address: &str, label: &str) -> Result<(), String> {
+        let entry = match self.wallets.get_mut(address) {
+            Some(e) => e,
+            None => return Err(format!("Address {} is not in wallets", address)),
         };
+        entry.label = if label.is_empty() {
+            None
+        } else {
+            Some(String::from(label))
+        };
+        self.save()
+    }
+
+    /// Export key pair of `address` as text, it's a string base58 encode with version, PKCS#8
+    /// bytes data of the key pair and checksum
+    pub fn export_key(&self, address: &str) -> Option<String> {
+        let key_pair = self.wallets.get(address)?.key_pair.as_ref()?;
+        let payload = [[EXPORT_KEY_VERSION].as_slice(), key_pair.as_slice()].concat();
+        let checksum = checksum(payload.as_slice());
+        Some(bs58::encode([payload, checksum].concat()).into_string())
+    }
 
-        // Read wallets bytes data
-        let mut raw_data = vec![];
-        if let Err(err) = file.read_to_end(&mut raw_data) {
-            println!("Read {} error: {}", WALLETS_FILE, err);
-            return None;
+    /// Import key pair from text created by `export_key`, and return it's address
+    pub fn import_key(&mut self, key: &str, label: Option<&str>) -> Result<String, String> {
+        let key_pair = decode_exported_key(key)?;
+        let address = self.add_key(key_pair, label);
+        self.save()?;
+
+        Ok(address)
+    }
+
+    /// Add key pair and return it's address. Label and change flag of an existing address are
+    /// kept, the label is only replaced by `label`
+    fn add_key(&mut self, key_pair: ByteData, label: Option<&str>) -> String {
+        let address = Wallet::new(key_pair.as_slice()).get_address();
+        let entry = self
+            .wallets
+            .entry(address.clone())
+            .or_insert_with(|| WalletEntry::without_key(None));
+        // The address may be watched before it's key is imported
+        entry.key_pair = Some(key_pair);
+        if let Some(label) = label {
+            entry.label = (!label.is_empty()).then(|| String::from(label));
         }
+        address
+    }
 
-        // Convert bytes data to wallets instance
-        let config = config::standard();
-        let wallets = match bincode::decode_from_slice(raw_data.as_slice(), config) {
-            Ok((wallets, _)) => wallets,
-            Err(err) => {
-                println!("Can not decode bytes to Wallets, {}", err);
-                return None;
-            }
+    /// Restore wallets from mnemonic `phrase`, `is_used` tells whether a public key hash has been
+    /// used in the chain. Receiving and change keys are derived one by one, until
+    /// `RESTORE_GAP_LIMIT` consecutive keys are unused, then all keys up to the last used one are
+    /// saved.
+    ///
+    /// Returns restored addresses
+    pub fn restore(
+        &mut self,
+        phrase: &str,
+        mut is_used: impl FnMut(&[u8]) -> bool,
+    ) -> Result<Vec<String>, String> {
+        if self.mnemonic.is_some() {
+            return Err(String::from("Wallets already have a mnemonic"));
+        }
+        let mnemonic = match Mnemonic::parse(phrase) {
+            Ok(m) => m,
+            Err(err) => return Err(format!("Invalid mnemonic: {}", err)),
         };
-        Some(wallets)
+        let seed = mnemonic.to_seed("");
+
+        self.mnemonic = Some(mnemonic.to_string());
+        let mut addresses = vec![];
+        for branch in [RECEIVE_BRANCH, CHANGE_BRANCH] {
+            // Find derived keys until there are enough unused keys in a row
+            let mut key_pairs = vec![];
+            let mut index = 0u32;
+            let mut used_count = 0usize;
+            while index - (used_count as u32) < RESTORE_GAP_LIMIT {
+                let key_pair = derive_key_pair(&seed, &[branch, index]);
+                let wallet = Wallet::new(key_pair.as_slice());
+                let used = is_used(hash_pub_key(wallet.public_key()).as_slice());
+                key_pairs.push((wallet.get_address(), key_pair));
+                index += 1;
+                if used {
+                    used_count = key_pairs.len();
+                }
+            }
+            key_pairs.truncate(used_count);
+
+            if branch == CHANGE_BRANCH {
+                self.next_change_index = used_count as u32;
+            } else {
+                self.next_index = used_count as u32;
+            }
+            for (address, key_pair) in key_pairs {
+                addresses.push(address.clone());
+                let mut entry = WalletEntry::new(key_pair);
+                entry.is_change = branch == CHANGE_BRANCH;
+                self.wallets.insert(address, entry);
+            }
+        }
+        // Save restored wallets to file
+        self.save()?;
+
+        Ok(addresses)
+    }
+
+    /// Master seed derived from mnemonic
+    fn seed(&self) -> Option<[u8; 64]> {
+        let mnemonic = Mnemonic::parse(self.mnemonic.as_ref()?).expect("Invalid saved mnemonic");
+        Some(mnemonic.to_seed(""))
+    }
+
+    /// Load wallets data from file, returns `None` if the file doesn't exist
+    fn load() -> Result<Option<Self>, String> {
+        match read_wallets_file()? {
+            Some(raw_data) => decode_wallets(raw_data.as_slice()).map(Some),
+            None => Ok(None),
+        }
     }
 
     /// Save wallets to file
     fn save(&self) -> Result<(), String> {
-        // Create parent directory if it doesn't exist
-        if let Some(p) = Path::new(WALLETS_FILE).parent() {
-            if let Err(err) = create_dir_all(p) {
-                return Err(format!("Create dir {} error: {}", p.to_string_lossy(), err));
-            }
+        // Convert wallets struct instance to bytes, the format version is the first
+        let config = config::standard();
+        match bincode::encode_to_vec((WALLETS_VERSION, self), config) {
+            Ok(data) => write_wallets_file(data.as_slice()),
+            Err(err) => Err(format!("Encode wallets error: {}", err)),
         }
+    }
+}
 
-        // Convert wallets struct instance to bytes
-        let config = config::standard();
-        let encoded = bincode::encode_to_vec(self, config);
-        let bytes = match encoded {
-            Ok(ref data) => data.as_slice(),
-            Err(err) => return Err(format!("Encode wallets error: {}", err)),
-        };
+/// Read bytes data of wallets file, returns `None` if the file doesn't exist
+#[cfg(not(test))]
+fn read_wallets_file() -> Result<Option<ByteData>, String> {
+    // Open the file which save wallets data
+    let mut file = match File::open(WALLETS_FILE) {
+        Ok(f) => f,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(format!("Open {} error: {}", WALLETS_FILE, err)),
+    };
 
-        // Save wallets bytes data to file
-        if let Err(err) = File::create(WALLETS_FILE).and_then(|mut f| f.write_all(bytes)) {
-            return Err(format!("Save wallets error: {}", err));
+    // Read wallets bytes data
+    let mut raw_data = vec![];
+    if let Err(err) = file.read_to_end(&mut raw_data) {
+        return Err(format!("Read {} error: {}", WALLETS_FILE, err));
+    }
+    Ok(Some(raw_data))
+}
+
+/// Write bytes data to wallets file
+#[cfg(not(test))]
+fn write_wallets_file(bytes: &[u8]) -> Result<(), String> {
+    // Create parent directory if it doesn't exist
+    if let Some(p) = Path::new(WALLETS_FILE).parent() {
+        if let Err(err) = create_dir_all(p) {
+            return Err(format!("Create dir {} error: {}", p.to_string_lossy(), err));
         }
+    }
 
-        Ok(())
+    // Save wallets bytes data to file
+    if let Err(err) = File::create(WALLETS_FILE).and_then(|mut f| f.write_all(bytes)) {
+        return Err(format!("Save wallets error: {}", err));
     }
+    Ok(())
 }
 
-pub struct Wallet {
-    keypair: Ed25519KeyPair,
+#[cfg(test)]
+thread_local! {
+    /// Wallets file of tests is kept in memory, so that tests never touch the file of the user,
+    /// and each test thread has it's own wallets
+    static TEST_WALLETS_FILE: std::cell::RefCell<Option<ByteData>> =
+        const { std::cell::RefCell::new(None) };
 }
 
-impl Wallet {
-    /// Create key pair bytes data
-    pub fn create_key_pair() -> ByteData {
-        let rng = rand::SystemRandom::new();
+#[cfg(test)]
+fn read_wallets_file() -> Result<Option<ByteData>, String> {
+    Ok(TEST_WALLETS_FILE.with(|file| file.borrow().clone()))
+}
 
-        let pkcs8_bytes = match Ed25519KeyPair::generate_pkcs8(&rng) {
-            Ok(v) => v,
-            Err(err) => panic!("Generate key pair error: {}", err),
-        };
+#[cfg(test)]
+fn write_wallets_file(bytes: &[u8]) -> Result<(), String> {
+    TEST_WALLETS_FILE.with(|file| *file.borrow_mut() = Some(Vec::from(bytes)));
+    Ok(())
+}
 
-        Vec::from(pkcs8_bytes.as_ref())
+/// Decode wallets saved by `Wallets::save`, wallets of the first format are migrated
+fn decode_wallets(data: &[u8]) -> Result<Wallets, String> {
+    let config = config::standard();
+    if let Ok(((version, wallets), len)) =
+        bincode::decode_from_slice::<(u32, Wallets), _>(data, config)
+    {
+        if len == data.len() {
+            return match version {
+                WALLETS_VERSION => Ok(wallets),
+                _ => Err(format!("Unsupported wallets version {}", version)),
+            };
+        }
     }
+    // The first format is a map from address to key pair
+    match bincode::decode_from_slice::<HashMap<String, ByteData>, _>(data, config) {
+        Ok((key_pairs, len)) if len == data.len() => Ok(Wallets {
+            mnemonic: None,
+            next_index: 0,
+            next_change_index: 0,
+            wallets: key_pairs
+                .into_iter()
+                .map(|(address, key_pair)| (address, WalletEntry::new(key_pair)))
+                .collect(),
+        }),
+        _ => Err(String::from("Can not decode bytes to Wallets")),
+    }
+}
+
+pub struct Wallet {
+    keypair: Ed25519KeyPair,
+}
 
+impl Wallet {
     pub fn new(key_pair: &[u8]) -> Self {
         let keypair = Ed25519KeyPair::from_pkcs8(key_pair).unwrap();
         Self { keypair }
@@ -142,32 +487,113 @@ impl Wallet {
         self.keypair.sign(data)
     }
 
-    pub fn verify(&self, data: &ByteData, signature: &[u8]) -> bool {
-        let pub_key = UnparsedPublicKey::new(&ED25519, self.public_key());
-        pub_key.verify(data, signature).is_ok()
-    }
-
     /// Address are consists of three parts, version, public key hash, and checksum, the final
     /// address value is base58 encoded
     pub fn get_address(&self) -> String {
-        // Bytes data of address version
-        let version = ADDR_VERSION.to_le_bytes();
         // Public key
         let pub_key = self.keypair.public_key().as_ref();
         // The hash value of public key
-        let pub_key_hash = hash_pub_key(pub_key);
+        pub_key_hash_address(hash_pub_key(pub_key).as_slice())
+    }
+}
 
-        // Calculate checksum, it is former 4 bytes of SHA256(SHA256(version, public key)
-        let hash1 = Sha256::new()
-            .chain_update(version)
-            .chain_update(pub_key_hash.as_slice())
-            .finalize();
-        let hash2 = Sha256::new().chain_update(hash1).finalize();
-        let checksum = hash2[0..ADDR_CHECKSUM_LEN as usize].as_ref();
+/// Address of pay to public key hash output, whose public key hash is `pub_key_hash`
+pub fn pub_key_hash_address(pub_key_hash: &[u8]) -> String {
+    encode_address(ADDR_VERSION, pub_key_hash)
+}
+
+/// Address of pay to script hash output, it's consists of version, hash of redeem script, and
+/// checksum
+pub fn script_address(redeem_script: &Script) -> String {
+    encode_address(P2SH_ADDR_VERSION, redeem_script.hash().as_slice())
+}
 
-        // The address is a string base58 encode with version, public key hash and checksum
-        bs58::encode([version.as_slice(), pub_key_hash.as_slice(), checksum].concat()).into_string()
+/// The address is a string base58 encode with version, hash and checksum
+fn encode_address(version: u8, hash: &[u8]) -> String {
+    // Calculate checksum of version and hash
+    let payload = [version.to_le_bytes().as_slice(), hash].concat();
+    let checksum = checksum(payload.as_slice());
+    bs58::encode([payload, checksum].concat()).into_string()
+}
+
+/// Decode key pair from text created by `Wallets::export_key`, returns PKCS#8 bytes data
+fn decode_exported_key(key: &str) -> Result<ByteData, String> {
+    let data = match bs58::decode(key.trim()).into_vec() {
+        Ok(d) => d,
+        Err(err) => return Err(format!("Decode key error: {}", err)),
+    };
+    if data.len() <= 1 + ADDR_CHECKSUM_LEN as usize {
+        return Err(String::from("Invalid key length"));
+    }
+
+    // Verify checksum and version
+    let (payload, sum) = data.split_at(data.len() - ADDR_CHECKSUM_LEN as usize);
+    if checksum(payload) != sum {
+        return Err(String::from("Invalid key checksum"));
+    }
+    if payload[0] != EXPORT_KEY_VERSION {
+        return Err(format!("Unsupported key version {}", payload[0]));
     }
+
+    // Make sure it's a valid key pair
+    let key_pair = &payload[1..];
+    if let Err(err) = Ed25519KeyPair::from_pkcs8(key_pair) {
+        return Err(format!("Invalid key pair: {}", err));
+    }
+    Ok(Vec::from(key_pair))
+}
+
+/// Checksum of `data`, it is former 4 bytes of SHA256(SHA256(data))
+fn checksum(data: &[u8]) -> ByteData {
+    let hash1 = Sha256::new().chain_update(data).finalize();
+    let hash2 = Sha256::new().chain_update(hash1).finalize();
+    Vec::from(&hash2[0..ADDR_CHECKSUM_LEN as usize])
+}
+
+/// Generate a mnemonic phrase with random entropy
+fn generate_mnemonic() -> String {
+    let rng = rand::SystemRandom::new();
+    let mut entropy = [0u8; MNEMONIC_ENTROPY_LEN];
+    rng.fill(&mut entropy)
+        .expect("Generate mnemonic entropy error");
+    Mnemonic::from_entropy(&entropy)
+        .expect("Invalid mnemonic entropy")
+        .to_string()
+}
+
+/// Derive ed25519 key pair from master `seed` along `path` with SLIP-0010, each index in path is
+/// hardened. Returns PKCS#8 bytes data of the key pair
+pub fn derive_key_pair(seed: &[u8], path: &[u32]) -> ByteData {
+    // Master private key and chain code
+    let key = hmac::Key::new(hmac::HMAC_SHA512, ED25519_SEED_KEY);
+    let mut node = hmac::sign(&key, seed);
+    for index in path {
+        // Child key is HMAC-SHA512(chain code, 0x00 || private key || hardened index)
+        let (private_key, chain_code) = node.as_ref().split_at(32);
+        let key = hmac::Key::new(hmac::HMAC_SHA512, chain_code);
+        let mut ctx = hmac::Context::with_key(&key);
+        ctx.update(&[0u8]);
+        ctx.update(private_key);
+        ctx.update(&(index | HARDENED_OFFSET).to_be_bytes());
+        node = ctx.sign();
+    }
+
+    // Wrap private key into PKCS#8 document
+    let private_key = &node.as_ref()[..32];
+    let key_pair = Ed25519KeyPair::from_seed_unchecked(private_key).expect("Invalid derived key");
+    [
+        PKCS8_PREFIX.as_slice(),
+        private_key,
+        PKCS8_MIDDLE.as_slice(),
+        key_pair.public_key().as_ref(),
+    ]
+    .concat()
+}
+
+/// Verify ed25519 `signature` of `data` with `pub_key`
+pub fn verify_signature(pub_key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    let pub_key = UnparsedPublicKey::new(&ED25519, pub_key);
+    pub_key.verify(data, signature).is_ok()
 }
 
 /// Calculate hash of the public key, it will be hashed twice with RIPEMD160(SHA256(public key))
@@ -178,7 +604,35 @@ pub fn hash_pub_key(pub_key: &[u8]) -> ByteData {
     Vec::from(hash.as_slice())
 }
 
-/// Extract public key hash from address
+/// Check whether `address` is a well-formed address with valid version and checksum
+pub fn validate_address(address: &str) -> bool {
+    address_version(address).is_some()
+}
+
+/// Version of well-formed `address`, returns `None` if the address is invalid
+fn address_version(address: &str) -> Option<u8> {
+    let data = bs58::decode(address).into_vec().ok()?;
+    if data.len() <= 1 + ADDR_CHECKSUM_LEN as usize {
+        return None;
+    }
+    let (payload, sum) = data.split_at(data.len() - ADDR_CHECKSUM_LEN as usize);
+    let version = payload[0];
+    let valid =
+        checksum(payload) == sum && (version == ADDR_VERSION || version == P2SH_ADDR_VERSION);
+    valid.then_some(version)
+}
+
+/// Locking script of outputs sent to `address`, which is a pay to public key hash address or a
+/// pay to script hash address
+pub fn address_script(address: &str) -> Result<Script, String> {
+    match address_version(address) {
+        Some(ADDR_VERSION) => Ok(Script::p2pkh(&extract_pub_key_hash(address))),
+        Some(_) => Ok(Script::p2sh(&extract_pub_key_hash(address))),
+        None => Err(format!("Invalid address {}", address)),
+    }
+}
+
+/// Extract public key hash (or script hash) from address
 pub fn extract_pub_key_hash(address: &str) -> ByteData {
     match bs58::decode(address).into_vec() {
         Ok(a) => {
@@ -189,3 +643,110 @@ pub fn extract_pub_key_hash(address: &str) -> ByteData {
         Err(err) => panic!("Decode address error: {}", err),
     }
 }
+
+#[cfg(test)]
+mod wallet_test {
+    use super::*;
+
+    const PHRASE: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon \
+        abandon abandon about";
+
+    #[test]
+    fn derived_key_is_deterministic() {
+        let seed = Mnemonic::parse(PHRASE).unwrap().to_seed("");
+        let key1 = derive_key_pair(&seed, &[RECEIVE_BRANCH, 0]);
+        let key2 = derive_key_pair(&seed, &[RECEIVE_BRANCH, 0]);
+        let key3 = derive_key_pair(&seed, &[RECEIVE_BRANCH, 1]);
+        assert_eq!(key1, key2);
+        assert_ne!(key1, key3);
+        // Derived document must be accepted as a valid PKCS#8 key pair
+        assert!(Ed25519KeyPair::from_pkcs8(key1.as_slice()).is_ok());
+    }
+
+    #[test]
+    fn migrate_wallets_file() {
+        let key_pair = derive_key_pair(&[1u8; 16], &[RECEIVE_BRANCH, 0]);
+        let address = Wallet::new(key_pair.as_slice()).get_address();
+        let old: HashMap<String, ByteData> = HashMap::from([(address.clone(), key_pair.clone())]);
+        let data = bincode::encode_to_vec(old, config::standard()).unwrap();
+        let wallets = decode_wallets(&data).unwrap();
+        assert_eq!(wallets.get_addresses(), vec![address.clone()]);
+        assert!(wallets.get_wallet(&address).is_some());
+        assert!(wallets.mnemonic().is_none());
+
+        let data = bincode::encode_to_vec((WALLETS_VERSION, &wallets), config::standard()).unwrap();
+        assert_eq!(
+            decode_wallets(&data).unwrap().get_addresses(),
+            vec![address]
+        );
+        let data = bincode::encode_to_vec((WALLETS_VERSION + 1, &wallets), config::standard());
+        assert!(decode_wallets(&data.unwrap()).is_err());
+        assert!(decode_wallets(&[0xff, 0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn export_and_import_key() {
+        let key_pair = derive_key_pair(&[1u8; 16], &[RECEIVE_BRANCH, 0]);
+        let address = Wallet::new(key_pair.as_slice()).get_address();
+        let wallets = Wallets {
+            mnemonic: None,
+            next_index: 0,
+            next_change_index: 0,
+            wallets: HashMap::from([(address.clone(), WalletEntry::new(key_pair.clone()))]),
+        };
+        let key = wallets.export_key(&address).unwrap();
+        assert_eq!(decode_exported_key(&key), Ok(key_pair.clone()));
+
+        // Corrupted text must be rejected by checksum
+        let mut data = bs58::decode(&key).into_vec().unwrap();
+        data[10] ^= 1;
+        let corrupted = bs58::encode(data).into_string();
+        assert!(decode_exported_key(&corrupted).is_err());
+
+        // Importing an existing key keeps it's label and change flag
+        let mut wallets = wallets;
+        let entry = wallets.wallets.get_mut(&address).unwrap();
+        entry.label = Some(String::from("savings"));
+        entry.is_change = true;
+        assert_eq!(wallets.add_key(key_pair.clone(), None), address);
+        assert_eq!(wallets.get_label(&address), Some("savings"));
+        assert!(wallets.is_change(&address));
+        wallets.add_key(key_pair, Some("cold"));
+        assert_eq!(wallets.get_label(&address), Some("cold"));
+        assert!(wallets.is_change(&address));
+    }
+
+    #[test]
+    fn watch_only_address() {
+        let mut wallets = Wallets::new();
+        let key_pair = derive_key_pair(&[1u8; 16], &[RECEIVE_BRANCH, 0]);
+        let address = Wallet::new(key_pair.as_slice()).get_address();
+        assert!(wallets.add_watch_only("invalid address").is_err());
+        assert!(wallets.add_watch_only(&address).is_ok());
+        assert!(wallets.add_watch_only(&address).is_err());
+        assert!(wallets.is_watch_only(&address));
+        // There is no key to sign with or export
+        assert!(wallets.get_wallet(&address).is_none());
+        assert!(wallets.export_key(&address).is_none());
+
+        // The address is saved, and listed with other addresses
+        let wallets = Wallets::new();
+        assert_eq!(wallets.get_addresses(), vec![address.clone()]);
+        assert!(wallets.is_watch_only(&address));
+    }
+
+    #[test]
+    fn derived_key_matches_slip10_vector() {
+        // SLIP-0010 test vector 1 for ed25519, chain m/0'
+        let seed = [
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+            0x0e, 0x0f,
+        ];
+        let key_pair = derive_key_pair(&seed, &[0]);
+        let private_key = &key_pair[PKCS8_PREFIX.len()..PKCS8_PREFIX.len() + 32];
+        assert_eq!(
+            crate::tools::bytes2hex(private_key),
+            "0x68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3"
+        );
+    }
+}